@@ -5,8 +5,10 @@
 // as per Cargo.toml: https://github.com/consensus-shipyard/libp2p-bitswap/blob/7dd9cececda3e4a8f6e14c200a4b457159d8db33/Cargo.toml#L7
 //
 // License headers added post-fork.
-//! Handles the `/ipfs/bitswap/1.0.0` and `/ipfs/bitswap/1.1.0` protocols. This
-//! allows exchanging IPFS blocks.
+//! Handles the `/ipfs-embed/bitswap/1.2.0` protocol, which exchanges Bitswap 1.2
+//! messages, and the legacy `/ipfs-embed/bitswap/1.0.0` protocol. This allows
+//! exchanging IPFS blocks between nodes using this crate; it doesn't interoperate
+//! with IPFS nodes speaking `/ipfs/bitswap/1.2.0`.
 //!
 //! # Usage
 //!
@@ -15,9 +17,10 @@
 #[cfg(feature = "compat")]
 use crate::compat::{CompatMessage, CompatProtocol, InboundMessage};
 use crate::protocol::{
-    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapResponse, RequestType,
+    BitswapCodec, BitswapProtocol, BitswapReply, BitswapRequest, BitswapResponse, RequestType,
 };
 use crate::query::{QueryEvent, QueryId, QueryManager, Request, Response};
+use crate::session::DEFAULT_SESSION_FANOUT;
 use crate::stats::*;
 use fnv::FnvHashMap;
 #[cfg(feature = "compat")]
@@ -46,10 +49,14 @@ use libp2p::{
     swarm::NetworkBehaviour,
 };
 use prometheus::Registry;
-use std::{pin::Pin, time::Duration};
+use std::collections::VecDeque;
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
 /// Bitswap response channel.
-pub type Channel = ResponseChannel<BitswapResponse>;
+pub type Channel = ResponseChannel<BitswapReply>;
 
 /// Event emitted by the bitswap behaviour.
 #[derive(Debug)]
@@ -60,6 +67,28 @@ pub enum BitswapEvent {
     Progress(QueryId, usize),
     /// A get or sync query completed.
     Complete(QueryId, Result<()>),
+    /// A peer answered a request sent on behalf of a query, or failed to.
+    /// Only emitted if [BitswapConfig::report_peers] is enabled.
+    PeerResponse(QueryId, PeerId, PeerResponse),
+}
+
+/// Outcome of a single request sent to a peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PeerResponse {
+    /// The peer answered whether it has the block.
+    Have { have: bool, elapsed: Duration },
+    /// The peer sent the block, which may not have matched the CID.
+    Block {
+        bytes: usize,
+        valid: bool,
+        elapsed: Duration,
+    },
+    /// The peer refused to send the block for now, e.g. to stay within its bandwidth budget.
+    Throttled { elapsed: Duration },
+    /// The peer could not be dialed, or the connection closed before it answered.
+    Unreachable,
+    /// The request failed, e.g. it timed out.
+    Failure,
 }
 
 /// Trait implemented by a block store.
@@ -76,11 +105,25 @@ pub trait BitswapStore: Send + Sync + 'static {
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
 }
 
+/// Decides which inbound block requests are served, e.g. to enforce a bandwidth budget.
+pub trait BitswapServePolicy: Send + 'static {
+    /// Whether to look up and send a block requested by a peer.
+    /// If not, the peer is told that the request was throttled.
+    fn allow(&mut self, peer_id: &PeerId) -> bool;
+    /// Called with the size of every block sent to a peer.
+    fn served(&mut self, peer_id: &PeerId, bytes: usize);
+}
+
 /// Bitswap configuration.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct BitswapConfig {
     /// Timeout of a request.
     pub request_timeout: Duration,
+    /// Emit a [BitswapEvent::PeerResponse] for every response or failure of a peer.
+    pub report_peers: bool,
+    /// Number of peers that had blocks of the DAG being synced which are asked for each
+    /// further block, before falling back on all providers.
+    pub session_fanout: usize,
 }
 
 impl BitswapConfig {
@@ -88,6 +131,8 @@ impl BitswapConfig {
     pub fn new() -> Self {
         Self {
             request_timeout: Duration::from_secs(10),
+            report_peers: false,
+            session_fanout: DEFAULT_SESSION_FANOUT,
         }
     }
 }
@@ -119,10 +164,18 @@ pub struct Bitswap<P: StoreParams> {
     query_manager: QueryManager,
     /// Requests.
     requests: FnvHashMap<BitswapId, QueryId>,
+    /// Whether to report the responses of individual peers.
+    report_peers: bool,
+    /// Time the requests were sent at, if peers are reported.
+    sent_at: FnvHashMap<BitswapId, Instant>,
+    /// Peer responses waiting to be returned from `poll`.
+    events: VecDeque<BitswapEvent>,
     /// Db request channel.
     db_tx: mpsc::UnboundedSender<DbRequest<P>>,
     /// Db response channel.
     db_rx: mpsc::UnboundedReceiver<DbResponse>,
+    /// Optional policy to decide which block requests to serve.
+    serve_policy: Option<Box<dyn BitswapServePolicy>>,
     /// Compat peers.
     #[cfg(feature = "compat")]
     compat: FnvHashSet<PeerId>,
@@ -133,7 +186,9 @@ impl<P: StoreParams> Bitswap<P> {
     pub fn new<S: BitswapStore<Params = P>>(config: BitswapConfig, store: S) -> Self {
         let rr_config =
             request_response::Config::default().with_request_timeout(config.request_timeout);
-        let protocols = std::iter::once((BitswapProtocol, ProtocolSupport::Full));
+        let protocols = [BitswapProtocol::Bitswap12, BitswapProtocol::Legacy]
+            .iter()
+            .map(|protocol| (*protocol, ProtocolSupport::Full));
         let inner = request_response::Behaviour::with_codec(
             BitswapCodec::<P>::default(),
             protocols,
@@ -142,15 +197,24 @@ impl<P: StoreParams> Bitswap<P> {
         let (db_tx, db_rx) = start_db_thread(store);
         Self {
             inner,
-            query_manager: Default::default(),
+            query_manager: QueryManager::new(config.session_fanout),
             requests: Default::default(),
+            report_peers: config.report_peers,
+            sent_at: Default::default(),
+            events: Default::default(),
             db_tx,
             db_rx,
+            serve_policy: None,
             #[cfg(feature = "compat")]
             compat: Default::default(),
         }
     }
 
+    /// Sets the policy consulted before serving blocks to peers.
+    pub fn set_serve_policy<T: BitswapServePolicy>(&mut self, policy: T) {
+        self.serve_policy = Some(Box::new(policy));
+    }
+
     /// Adds an address for a peer.
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
         #[allow(deprecated)]
@@ -203,18 +267,20 @@ impl<P: StoreParams> Bitswap<P> {
         registry.register(Box::new(THROTTLED_OUTBOUND.clone()))?;
         registry.register(Box::new(OUTBOUND_FAILURE.clone()))?;
         registry.register(Box::new(INBOUND_FAILURE.clone()))?;
+        registry.register(Box::new(SESSION_BROADCASTS_TOTAL.clone()))?;
         Ok(())
     }
 }
 
 enum DbRequest<P: StoreParams> {
-    Bitswap(BitswapChannel, BitswapRequest),
+    /// A request from a peer, and whether it's allowed to be served by the policy.
+    Bitswap(PeerId, BitswapChannel, BitswapRequest, bool),
     Insert(Block<P>),
     MissingBlocks(QueryId, Cid),
 }
 
 enum DbResponse {
-    Bitswap(BitswapChannel, BitswapResponse),
+    Bitswap(PeerId, BitswapChannel, BitswapReply),
     MissingBlocks(QueryId, Result<Vec<Cid>>),
 }
 
@@ -230,7 +296,7 @@ fn start_db_thread<S: BitswapStore>(
         let mut requests: mpsc::UnboundedReceiver<DbRequest<S::Params>> = requests;
         while let Some(request) = futures::executor::block_on(requests.next()) {
             match request {
-                DbRequest::Bitswap(channel, request) => {
+                DbRequest::Bitswap(peer_id, channel, request, allowed) => {
                     let response = match request.ty {
                         RequestType::Have => {
                             let have = store.contains(&request.cid).ok().unwrap_or_default();
@@ -242,6 +308,11 @@ fn start_db_thread<S: BitswapStore>(
                             tracing::trace!("have {}", have);
                             BitswapResponse::Have(have)
                         }
+                        RequestType::Block if !allowed => {
+                            RESPONSES_TOTAL.with_label_values(&["throttled"]).inc();
+                            tracing::trace!("throttled {}", peer_id);
+                            BitswapResponse::Throttled
+                        }
                         RequestType::Block => {
                             let block = store.get(&request.cid).ok().unwrap_or_default();
                             if let Some(data) = block {
@@ -256,8 +327,12 @@ fn start_db_thread<S: BitswapStore>(
                             }
                         }
                     };
+                    let reply = BitswapReply {
+                        cid: Some(request.cid),
+                        response,
+                    };
                     responses
-                        .unbounded_send(DbResponse::Bitswap(channel, response))
+                        .unbounded_send(DbResponse::Bitswap(peer_id, channel, reply))
                         .ok();
                 }
                 DbRequest::Insert(block) => {
@@ -279,36 +354,99 @@ fn start_db_thread<S: BitswapStore>(
 
 impl<P: StoreParams> Bitswap<P> {
     /// Processes an incoming bitswap request.
-    fn inject_request(&mut self, channel: BitswapChannel, request: BitswapRequest) {
+    fn inject_request(
+        &mut self,
+        peer_id: PeerId,
+        channel: BitswapChannel,
+        request: BitswapRequest,
+    ) {
+        let allowed = match (&request.ty, &mut self.serve_policy) {
+            (RequestType::Block, Some(policy)) => policy.allow(&peer_id),
+            _ => true,
+        };
         self.db_tx
-            .unbounded_send(DbRequest::Bitswap(channel, request))
+            .unbounded_send(DbRequest::Bitswap(peer_id, channel, request, allowed))
             .ok();
     }
 
+    /// Remembers which query a request belongs to.
+    fn insert_request(&mut self, rid: BitswapId, id: QueryId) {
+        self.requests.insert(rid, id);
+        if self.report_peers {
+            self.sent_at.insert(rid, Instant::now());
+        }
+    }
+
+    /// Queues a peer response event for the root query of a request, if peers are reported.
+    fn report_peer(&mut self, rid: &BitswapId, id: QueryId, peer: PeerId, response: PeerResponse) {
+        if !self.report_peers {
+            return;
+        }
+        self.sent_at.remove(rid);
+        if let Some(info) = self.query_manager.query_info(id) {
+            self.events
+                .push_back(BitswapEvent::PeerResponse(info.root, peer, response));
+        }
+    }
+
+    /// Time elapsed since a request was sent, if peers are reported.
+    fn elapsed(&self, rid: &BitswapId) -> Duration {
+        self.sent_at
+            .get(rid)
+            .map(|t| t.elapsed())
+            .unwrap_or_default()
+    }
+
     /// Processes an incoming bitswap response.
-    fn inject_response(&mut self, id: BitswapId, peer: PeerId, response: BitswapResponse) {
-        if let Some(id) = self.requests.remove(&id) {
-            match response {
+    fn inject_response(&mut self, rid: BitswapId, peer: PeerId, reply: BitswapReply) {
+        if let Some(id) = self.requests.remove(&rid) {
+            let elapsed = self.elapsed(&rid);
+            let unexpected = match (reply.cid, self.query_manager.query_info(id)) {
+                (Some(cid), Some(info)) => cid != info.cid,
+                _ => false,
+            };
+            if unexpected {
+                tracing::debug!("bitswap response from {} about another cid", peer);
+                self.report_peer(&rid, id, peer, PeerResponse::Failure);
+                self.query_manager
+                    .inject_response(id, Response::Have(peer, false));
+                return;
+            }
+            match reply.response {
                 BitswapResponse::Have(have) => {
+                    // Report first, the query info is gone once the request is completed.
+                    self.report_peer(&rid, id, peer, PeerResponse::Have { have, elapsed });
                     self.query_manager
                         .inject_response(id, Response::Have(peer, have));
                 }
                 BitswapResponse::Block(data) => {
                     if let Some(info) = self.query_manager.query_info(id) {
                         let len = data.len();
-                        if let Ok(block) = Block::new(info.cid, data) {
+                        let valid = if let Ok(block) = Block::new(info.cid, data) {
                             RECEIVED_BLOCK_BYTES.inc_by(len as u64);
                             self.db_tx.unbounded_send(DbRequest::Insert(block)).ok();
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, true));
+                            true
                         } else {
                             tracing::error!("received invalid block");
                             RECEIVED_INVALID_BLOCK_BYTES.inc_by(len as u64);
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, false));
-                        }
+                            false
+                        };
+                        let response = PeerResponse::Block {
+                            bytes: len,
+                            valid,
+                            elapsed,
+                        };
+                        self.report_peer(&rid, id, peer, response);
+                        self.query_manager
+                            .inject_response(id, Response::Block(peer, valid));
                     }
                 }
+                BitswapResponse::Throttled => {
+                    // The query moves on to the other providers, like after a missing block.
+                    self.report_peer(&rid, id, peer, PeerResponse::Throttled { elapsed });
+                    self.query_manager
+                        .inject_response(id, Response::Block(peer, false));
+                }
             }
         }
     }
@@ -537,11 +675,19 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                     match msg {
                         CompatMessage::Request(req) => {
                             tracing::trace!("received compat request");
-                            self.inject_request(BitswapChannel::Compat(peer_id, req.cid), req);
+                            self.inject_request(
+                                peer_id,
+                                BitswapChannel::Compat(peer_id, req.cid),
+                                req,
+                            );
                         }
                         CompatMessage::Response(cid, res) => {
                             tracing::trace!("received compat response");
-                            self.inject_response(BitswapId::Compat(cid), peer_id, res);
+                            let reply = BitswapReply {
+                                cid: Some(cid),
+                                response: res,
+                            };
+                            self.inject_response(BitswapId::Compat(cid), peer_id, reply);
                         }
                     }
                 }
@@ -553,23 +699,33 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
         let mut exit = false;
         while !exit {
             exit = true;
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(ToSwarm::GenerateEvent(event));
+            }
             while let Poll::Ready(Some(response)) = Pin::new(&mut self.db_rx).poll_next(cx) {
                 exit = false;
                 match response {
-                    DbResponse::Bitswap(channel, response) => match channel {
-                        BitswapChannel::Bitswap(channel) => {
-                            self.inner.send_response(channel, response).ok();
+                    DbResponse::Bitswap(peer_id, channel, reply) => {
+                        if let (BitswapResponse::Block(data), Some(policy)) =
+                            (&reply.response, &mut self.serve_policy)
+                        {
+                            policy.served(&peer_id, data.len());
                         }
-                        #[cfg(feature = "compat")]
-                        BitswapChannel::Compat(peer_id, cid) => {
-                            let compat = CompatMessage::Response(cid, response);
-                            return Poll::Ready(FromSwarm::NotifyHandler {
-                                peer_id,
-                                handler: NotifyHandler::Any,
-                                event: EitherOutput::Second(compat),
-                            });
+                        match channel {
+                            BitswapChannel::Bitswap(channel) => {
+                                self.inner.send_response(channel, reply).ok();
+                            }
+                            #[cfg(feature = "compat")]
+                            BitswapChannel::Compat(peer_id, cid) => {
+                                let compat = CompatMessage::Response(cid, reply.response);
+                                return Poll::Ready(FromSwarm::NotifyHandler {
+                                    peer_id,
+                                    handler: NotifyHandler::Any,
+                                    event: EitherOutput::Second(compat),
+                                });
+                            }
                         }
-                    },
+                    }
                     DbResponse::MissingBlocks(id, res) => match res {
                         Ok(missing) => {
                             MISSING_BLOCKS_TOTAL.inc_by(missing.len() as u64);
@@ -594,7 +750,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                 cid,
                             };
                             let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
+                            self.insert_request(BitswapId::Bitswap(rid), id);
                         }
                         Request::Block(peer_id, cid) => {
                             let req = BitswapRequest {
@@ -602,7 +758,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                 cid,
                             };
                             let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
+                            self.insert_request(BitswapId::Bitswap(rid), id);
                         }
                         Request::MissingBlocks(cid) => {
                             self.db_tx
@@ -640,7 +796,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                             request_id: _,
                             request,
                             channel,
-                        } => self.inject_request(BitswapChannel::Bitswap(channel), request),
+                        } => self.inject_request(peer, BitswapChannel::Bitswap(channel), request),
                         request_response::Message::Response {
                             request_id,
                             response,
@@ -664,7 +820,8 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                         _ => unreachable!(),
                                     };
                                     let request = BitswapRequest { ty, cid: info.cid };
-                                    self.requests.insert(BitswapId::Compat(info.cid), id);
+                                    self.sent_at.remove(&BitswapId::Bitswap(request_id));
+                                    self.insert_request(BitswapId::Compat(info.cid), id);
                                     tracing::trace!("adding compat peer {}", peer);
                                     self.compat.insert(peer);
                                     return Poll::Ready(FromSwarm::NotifyHandler {
@@ -677,7 +834,14 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                 }
                             }
                         }
-                        if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id)) {
+                        let rid = BitswapId::Bitswap(request_id);
+                        if let Some(id) = self.requests.remove(&rid) {
+                            let response = match error {
+                                OutboundFailure::DialFailure
+                                | OutboundFailure::ConnectionClosed => PeerResponse::Unreachable,
+                                _ => PeerResponse::Failure,
+                            };
+                            self.report_peer(&rid, id, peer, response);
                             self.query_manager
                                 .inject_response(id, Response::Have(peer, false));
                         }
@@ -767,6 +931,10 @@ mod tests {
 
     impl Peer {
         fn new() -> Self {
+            Self::with_config(BitswapConfig::new())
+        }
+
+        fn with_config(config: BitswapConfig) -> Self {
             // Create a public/private key pair, either random or based on a seed.
             let id_keys = identity::Keypair::generate_ed25519();
             let peer_id = id_keys.public().to_peer_id();
@@ -780,7 +948,7 @@ mod tests {
                     yamux::Config::default,
                 )
                 .unwrap()
-                .with_behaviour(|_| Bitswap::new(BitswapConfig::new(), store.clone()))
+                .with_behaviour(|_| Bitswap::new(config, store.clone()))
                 .unwrap()
                 .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
                 .build();
@@ -867,6 +1035,111 @@ mod tests {
         assert_complete_ok(peer2.next().await, id);
     }
 
+    #[async_std::test]
+    async fn test_bitswap_report_peers() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            report_peers: true,
+            ..BitswapConfig::new()
+        });
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        match peer2.next().await {
+            Some(BitswapEvent::PeerResponse(
+                id2,
+                peer,
+                PeerResponse::Block { bytes, valid, .. },
+            )) => {
+                assert_eq!(id2, id);
+                assert_eq!(peer, peer1);
+                assert_eq!(bytes, block.data().len());
+                assert!(valid);
+            }
+            event => panic!("{:?} is not a peer response event", event),
+        }
+
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    /// Serves blocks while allowed, and counts the bytes sent.
+    #[derive(Clone, Default)]
+    struct TestPolicy(Arc<Mutex<(bool, usize)>>);
+
+    impl BitswapServePolicy for TestPolicy {
+        fn allow(&mut self, _: &PeerId) -> bool {
+            self.0.lock().unwrap().0
+        }
+        fn served(&mut self, _: &PeerId, bytes: usize) {
+            self.0.lock().unwrap().1 += bytes;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_serve_policy() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            report_peers: true,
+            ..BitswapConfig::new()
+        });
+        peer2.add_address(&peer1);
+
+        let policy = TestPolicy::default();
+        peer1
+            .swarm()
+            .behaviour_mut()
+            .set_serve_policy(policy.clone());
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        // The peer is told it was throttled rather than that the block is missing.
+        match peer2.next().await {
+            Some(BitswapEvent::PeerResponse(id2, peer, PeerResponse::Throttled { .. })) => {
+                assert_eq!(id2, id);
+                assert_eq!(peer, peer1);
+            }
+            event => panic!("{:?} is not a throttled peer response event", event),
+        }
+        match peer2.next().await {
+            Some(BitswapEvent::Complete(id2, Err(_))) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        assert_eq!(policy.0.lock().unwrap().1, 0);
+
+        policy.0.lock().unwrap().0 = true;
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        match peer2.next().await {
+            Some(BitswapEvent::PeerResponse(_, _, PeerResponse::Block { valid, .. })) => {
+                assert!(valid)
+            }
+            event => panic!("{:?} is not a block peer response event", event),
+        }
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(policy.0.lock().unwrap().1, block.data().len());
+    }
+
     #[async_std::test]
     async fn test_bitswap_cancel_get() {
         tracing_try_init();
@@ -923,6 +1196,61 @@ mod tests {
         assert_complete_ok(peer2.next().await, id);
     }
 
+    #[async_std::test]
+    async fn test_bitswap_sync_session() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            report_peers: true,
+            ..BitswapConfig::new()
+        });
+        let peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer2.add_address(&peer3);
+
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        let b2 = create_block(ipld!({
+            "prev": b1.cid(),
+            "n": 2,
+        }));
+        peer1.store().insert(*b0.cid(), b0.data().to_vec());
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        peer1.store().insert(*b2.cid(), b2.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        let peer3 = peer3.spawn("peer3");
+
+        let id = peer2.swarm().behaviour_mut().sync(
+            *b2.cid(),
+            vec![peer3, peer1],
+            std::iter::once(*b2.cid()),
+        );
+
+        let mut responses = FnvHashMap::<PeerId, usize>::default();
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::PeerResponse(_, peer, _)) => {
+                    *responses.entry(peer).or_default() += 1;
+                }
+                Some(BitswapEvent::Progress(..)) => {}
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+
+        // Once peer1 turned out to have the DAG, only peer1 is asked for the rest of it.
+        assert_eq!(responses.get(&peer3), Some(&1));
+        assert_eq!(responses.get(&peer1), Some(&4));
+        assert_eq!(peer2.store().len(), 3);
+    }
+
     #[async_std::test]
     async fn test_bitswap_cancel_sync() {
         tracing_try_init();
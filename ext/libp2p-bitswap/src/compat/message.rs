@@ -13,7 +13,7 @@ use prost::Message;
 use std::convert::TryFrom;
 use std::io;
 
-mod bitswap_pb {
+pub(crate) mod bitswap_pb {
     include!(concat!(env!("OUT_DIR"), "/bitswap_pb.rs"));
 }
 
@@ -42,10 +42,13 @@ impl CompatMessage {
                 wantlist.entries.push(entry);
                 msg.wantlist = Some(wantlist);
             }
-            CompatMessage::Response(cid, BitswapResponse::Have(have)) => {
+            // The standard protocol can't tell a throttled request apart from a missing block.
+            CompatMessage::Response(cid, res @ BitswapResponse::Have(_))
+            | CompatMessage::Response(cid, res @ BitswapResponse::Throttled) => {
+                let have = matches!(res, BitswapResponse::Have(true));
                 let block_presence = bitswap_pb::message::BlockPresence {
                     cid: cid.to_bytes(),
-                    r#type: if *have {
+                    r#type: if have {
                         bitswap_pb::message::BlockPresenceType::Have
                     } else {
                         bitswap_pb::message::BlockPresenceType::DontHave
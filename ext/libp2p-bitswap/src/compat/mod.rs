@@ -12,6 +12,9 @@ mod protocol;
 pub use message::CompatMessage;
 pub use protocol::{CompatProtocol, InboundMessage};
 
+#[cfg(test)]
+pub(crate) use message::bitswap_pb;
+
 fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, e)
 }
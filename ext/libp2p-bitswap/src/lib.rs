@@ -13,10 +13,14 @@
 mod behaviour;
 #[cfg(feature = "compat")]
 mod compat;
+mod message;
 mod protocol;
 mod query;
+mod session;
 mod stats;
 
-pub use crate::behaviour::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, Channel};
-pub use crate::protocol::{BitswapRequest, BitswapResponse};
+pub use crate::behaviour::{
+    Bitswap, BitswapConfig, BitswapEvent, BitswapServePolicy, BitswapStore, Channel, PeerResponse,
+};
+pub use crate::protocol::{BitswapReply, BitswapRequest, BitswapResponse};
 pub use crate::query::QueryId;
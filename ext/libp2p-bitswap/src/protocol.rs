@@ -5,6 +5,9 @@
 // as per Cargo.toml: https://github.com/consensus-shipyard/libp2p-bitswap/blob/7dd9cececda3e4a8f6e14c200a4b457159d8db33/Cargo.toml#L7
 //
 // License headers added post-fork.
+use crate::message::{
+    BitswapMessage, BlockPresence, BlockPresenceType, Payload, Prefix, WantType, WantlistEntry,
+};
 use async_trait::async_trait;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libipld::cid::Cid;
@@ -19,12 +22,40 @@ use unsigned_varint::{aio, io::ReadError};
 // version codec hash size (u64 varint is max 10 bytes) + digest
 const MAX_CID_SIZE: usize = 4 * 10 + 64;
 
-#[derive(Clone, Debug)]
-pub struct BitswapProtocol;
+// protobuf keys and lengths around the CID or block in a bitswap 1.2 message
+const MAX_MESSAGE_OVERHEAD: usize = 64;
+
+/// Protocols to exchange requests and responses over, in order of preference.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BitswapProtocol {
+    /// Bitswap 1.2 messages carrying a single want or answer.
+    Bitswap12,
+    /// The original ipfs-embed encoding, spoken by nodes from before bitswap 1.2.
+    Legacy,
+}
+
+impl BitswapProtocol {
+    fn max_request_size(&self) -> usize {
+        match self {
+            Self::Bitswap12 => MAX_CID_SIZE + MAX_MESSAGE_OVERHEAD,
+            Self::Legacy => MAX_CID_SIZE + 1,
+        }
+    }
+
+    fn max_response_size<P: StoreParams>(&self) -> usize {
+        match self {
+            Self::Bitswap12 => P::MAX_BLOCK_SIZE + MAX_CID_SIZE + MAX_MESSAGE_OVERHEAD,
+            Self::Legacy => P::MAX_BLOCK_SIZE + 1,
+        }
+    }
+}
 
 impl AsRef<str> for BitswapProtocol {
     fn as_ref(&self) -> &str {
-        "/ipfs-embed/bitswap/1.0.0"
+        match self {
+            Self::Bitswap12 => "/ipfs-embed/bitswap/1.2.0",
+            Self::Legacy => "/ipfs-embed/bitswap/1.0.0",
+        }
     }
 }
 
@@ -36,7 +67,7 @@ pub struct BitswapCodec<P> {
 
 impl<P: StoreParams> Default for BitswapCodec<P> {
     fn default() -> Self {
-        let capacity = usize::max(P::MAX_BLOCK_SIZE, MAX_CID_SIZE) + 1;
+        let capacity = BitswapProtocol::Bitswap12.max_response_size::<P>();
         debug_assert!(capacity <= u32::MAX as usize);
         Self {
             _marker: PhantomData,
@@ -45,13 +76,9 @@ impl<P: StoreParams> Default for BitswapCodec<P> {
     }
 }
 
-#[async_trait]
-impl<P: StoreParams> request_response::Codec for BitswapCodec<P> {
-    type Protocol = BitswapProtocol;
-    type Request = BitswapRequest;
-    type Response = BitswapResponse;
-
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+impl<P: StoreParams> BitswapCodec<P> {
+    /// Reads a length prefixed message into the buffer.
+    async fn read_frame<T>(&mut self, io: &mut T, max_len: usize) -> io::Result<()>
     where
         T: AsyncRead + Send + Unpin,
     {
@@ -59,39 +86,77 @@ impl<P: StoreParams> request_response::Codec for BitswapCodec<P> {
             ReadError::Io(e) => e,
             err => other(err),
         })?);
-        if msg_len > MAX_CID_SIZE + 1 {
+        if msg_len > max_len {
             return Err(invalid_data(MessageTooLarge(msg_len)));
         }
         self.buffer.resize(msg_len, 0);
         io.read_exact(&mut self.buffer).await?;
-        let request = BitswapRequest::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(request)
+        Ok(())
+    }
+
+    /// Writes the message in the buffer with a length prefix.
+    async fn write_frame<T>(&mut self, io: &mut T, max_len: usize) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        if self.buffer.len() > max_len {
+            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
+        }
+        let mut buf = unsigned_varint::encode::u32_buffer();
+        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
+        io.write_all(msg_len).await?;
+        io.write_all(&self.buffer).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: StoreParams> request_response::Codec for BitswapCodec<P> {
+    type Protocol = BitswapProtocol;
+    type Request = BitswapRequest;
+    type Response = BitswapReply;
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        self.read_frame(io, protocol.max_request_size()).await?;
+        match protocol {
+            BitswapProtocol::Bitswap12 => {
+                BitswapRequest::from_message(BitswapMessage::from_bytes(&self.buffer)?)
+            }
+            BitswapProtocol::Legacy => BitswapRequest::from_bytes(&self.buffer),
+        }
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Send + Unpin,
     {
-        let msg_len = u32_to_usize(aio::read_u32(&mut *io).await.map_err(|e| match e {
-            ReadError::Io(e) => e,
-            err => other(err),
-        })?);
-        if msg_len > P::MAX_BLOCK_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(msg_len)));
+        self.read_frame(io, protocol.max_response_size::<P>())
+            .await?;
+        match protocol {
+            BitswapProtocol::Bitswap12 => {
+                BitswapReply::from_message::<P>(BitswapMessage::from_bytes(&self.buffer)?)
+            }
+            BitswapProtocol::Legacy => Ok(BitswapReply {
+                cid: None,
+                response: BitswapResponse::from_bytes(&self.buffer)?,
+            }),
         }
-        self.buffer.resize(msg_len, 0);
-        io.read_exact(&mut self.buffer).await?;
-        let response = BitswapResponse::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(response)
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
@@ -99,20 +164,16 @@ impl<P: StoreParams> request_response::Codec for BitswapCodec<P> {
         T: AsyncWrite + Send + Unpin,
     {
         self.buffer.clear();
-        req.write_to(&mut self.buffer)?;
-        if self.buffer.len() > MAX_CID_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
+        match protocol {
+            BitswapProtocol::Bitswap12 => req.to_message().write_to(&mut self.buffer),
+            BitswapProtocol::Legacy => req.write_to(&mut self.buffer)?,
         }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        self.write_frame(io, protocol.max_request_size()).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
@@ -120,21 +181,20 @@ impl<P: StoreParams> request_response::Codec for BitswapCodec<P> {
         T: AsyncWrite + Send + Unpin,
     {
         self.buffer.clear();
-        res.write_to(&mut self.buffer)?;
-        if self.buffer.len() > P::MAX_BLOCK_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
+        match protocol {
+            BitswapProtocol::Bitswap12 => res.into_message()?.write_to(&mut self.buffer),
+            BitswapProtocol::Legacy => res.response.write_to(&mut self.buffer)?,
         }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        self.write_frame(io, protocol.max_response_size::<P>())
+            .await
     }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum RequestType {
+    /// WANT_HAVE
     Have,
+    /// WANT_BLOCK
     Block,
 }
 
@@ -179,6 +239,40 @@ impl BitswapRequest {
         let cid = Cid::try_from(&bytes[1..]).map_err(invalid_data)?;
         Ok(Self { ty, cid })
     }
+
+    /// bitswap 1.2 message with the request as its only want
+    pub(crate) fn to_message(&self) -> BitswapMessage {
+        let entry = WantlistEntry {
+            cid: self.cid,
+            priority: 1,
+            cancel: false,
+            want_type: match self.ty {
+                RequestType::Have => WantType::Have,
+                RequestType::Block => WantType::Block,
+            },
+            // There is always a response, but this way any peer will send one.
+            send_dont_have: true,
+        };
+        BitswapMessage {
+            wantlist: vec![entry],
+            ..Default::default()
+        }
+    }
+
+    /// read back the request from a bitswap 1.2 message, which must have a single want
+    pub(crate) fn from_message(msg: BitswapMessage) -> io::Result<Self> {
+        let mut wants = msg.wantlist.into_iter().filter(|entry| !entry.cancel);
+        match (wants.next(), wants.next()) {
+            (Some(entry), None) => Ok(Self {
+                ty: match entry.want_type {
+                    WantType::Have => RequestType::Have,
+                    WantType::Block => RequestType::Block,
+                },
+                cid: entry.cid,
+            }),
+            _ => Err(invalid_data(UnexpectedMessage("expected a single want"))),
+        }
+    }
 }
 
 /// Response to a [BitswapRequest]
@@ -188,18 +282,24 @@ pub enum BitswapResponse {
     Have(bool),
     /// block bytes
     Block(Vec<u8>),
+    /// the request was refused for now, e.g. to stay within a bandwidth budget
+    ///
+    /// Only peers speaking Bitswap 1.2 are told; legacy peers get `Have(false)`.
+    Throttled,
 }
 
 impl BitswapResponse {
-    /// write binary representation of the request
+    /// write binary representation of the request, in the legacy format
+    ///
+    /// Legacy peers don't know about throttling and reject unknown message types,
+    /// so a throttled request is answered as if we didn't have the block.
     pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match self {
-            BitswapResponse::Have(have) => {
-                if *have {
-                    w.write_all(&[0])?;
-                } else {
-                    w.write_all(&[2])?;
-                }
+            BitswapResponse::Have(true) => {
+                w.write_all(&[0])?;
+            }
+            BitswapResponse::Have(false) | BitswapResponse::Throttled => {
+                w.write_all(&[2])?;
             }
             BitswapResponse::Block(data) => {
                 w.write_all(&[1])?;
@@ -220,6 +320,67 @@ impl BitswapResponse {
     }
 }
 
+/// [BitswapResponse] as it goes over the wire.
+///
+/// Bitswap 1.2 messages name the CID they answer about, so the response can be checked
+/// against the request. The legacy protocol leaves it implicit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitswapReply {
+    /// CID the response is about, unless it was received over the legacy protocol.
+    pub cid: Option<Cid>,
+    /// The response.
+    pub response: BitswapResponse,
+}
+
+impl BitswapReply {
+    /// bitswap 1.2 message with the response as its only block or block presence
+    ///
+    /// A throttled request is answered with an empty message.
+    pub(crate) fn into_message(self) -> io::Result<BitswapMessage> {
+        let cid = self.cid;
+        let cid = || cid.ok_or_else(|| invalid_data(UnexpectedMessage("response without a CID")));
+        let mut msg = BitswapMessage::default();
+        match self.response {
+            BitswapResponse::Have(have) => msg.block_presences.push(BlockPresence {
+                cid: cid()?,
+                ty: if have {
+                    BlockPresenceType::Have
+                } else {
+                    BlockPresenceType::DontHave
+                },
+            }),
+            BitswapResponse::Block(data) => msg.payload.push(Payload {
+                prefix: Prefix::from(&cid()?),
+                data,
+            }),
+            BitswapResponse::Throttled => {}
+        }
+        Ok(msg)
+    }
+
+    /// read back the response from a bitswap 1.2 message, which must have a single block or
+    /// block presence, or be empty if the request was throttled
+    pub(crate) fn from_message<P: StoreParams>(mut msg: BitswapMessage) -> io::Result<Self> {
+        match (msg.block_presences.pop(), msg.payload.pop()) {
+            (None, None) => Ok(Self {
+                cid: None,
+                response: BitswapResponse::Throttled,
+            }),
+            (Some(presence), None) if msg.block_presences.is_empty() => Ok(Self {
+                cid: Some(presence.cid),
+                response: BitswapResponse::Have(presence.ty == BlockPresenceType::Have),
+            }),
+            (None, Some(payload)) if msg.payload.is_empty() => Ok(Self {
+                cid: Some(payload.prefix.to_cid::<P::Hashes>(&payload.data)?),
+                response: BitswapResponse::Block(payload.data),
+            }),
+            _ => Err(invalid_data(UnexpectedMessage(
+                "expected a single block or block presence",
+            ))),
+        }
+    }
+}
+
 fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
@@ -241,11 +402,18 @@ pub struct UnknownMessageType(u8);
 #[error("message too large {0}")]
 pub struct MessageTooLarge(usize);
 
+#[derive(Debug, Error)]
+#[error("unexpected message: {0}")]
+pub struct UnexpectedMessage(&'static str);
+
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use futures::io::Cursor;
     use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
     use multihash::MultihashDigest;
+    use request_response::Codec;
 
     pub fn create_cid(bytes: &[u8]) -> Cid {
         let digest = Code::Blake3_256.digest(bytes);
@@ -286,4 +454,125 @@ pub(crate) mod tests {
             assert_eq!(&BitswapResponse::from_bytes(&buf).unwrap(), response);
         }
     }
+
+    #[test]
+    fn test_legacy_throttled_response_is_dont_have() {
+        let mut buf = Vec::new();
+        BitswapResponse::Throttled.write_to(&mut buf).unwrap();
+        assert_eq!(buf, [2]);
+        assert_eq!(
+            BitswapResponse::from_bytes(&buf).unwrap(),
+            BitswapResponse::Have(false)
+        );
+        // Old peers don't know any other message types.
+        assert!(BitswapResponse::from_bytes(&[3]).is_err());
+    }
+
+    #[test]
+    fn test_request_message_encode_decode() {
+        for ty in [RequestType::Have, RequestType::Block] {
+            let request = BitswapRequest {
+                ty,
+                cid: create_cid(&b"request"[..]),
+            };
+            let msg = request.to_message();
+            assert!(msg.wantlist[0].send_dont_have);
+            assert_eq!(BitswapRequest::from_message(msg).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn test_request_message_needs_single_want() {
+        let mut msg = BitswapRequest {
+            ty: RequestType::Have,
+            cid: create_cid(&b"request"[..]),
+        }
+        .to_message();
+        msg.wantlist.push(msg.wantlist[0].clone());
+        assert!(BitswapRequest::from_message(msg.clone()).is_err());
+
+        // Cancels don't count.
+        msg.wantlist[1].cancel = true;
+        assert!(BitswapRequest::from_message(msg.clone()).is_ok());
+
+        msg.wantlist.clear();
+        assert!(BitswapRequest::from_message(msg).is_err());
+    }
+
+    #[test]
+    fn test_reply_message_encode_decode() {
+        let data = b"block_response".to_vec();
+        let cid = create_cid(&data);
+        let replies = [
+            BitswapReply {
+                cid: Some(cid),
+                response: BitswapResponse::Have(true),
+            },
+            BitswapReply {
+                cid: Some(cid),
+                response: BitswapResponse::Have(false),
+            },
+            BitswapReply {
+                cid: Some(cid),
+                response: BitswapResponse::Block(data),
+            },
+            BitswapReply {
+                cid: None,
+                response: BitswapResponse::Throttled,
+            },
+        ];
+        for reply in replies {
+            let msg = reply.clone().into_message().unwrap();
+            assert_eq!(
+                BitswapReply::from_message::<DefaultParams>(msg).unwrap(),
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn test_reply_message_needs_cid() {
+        let reply = BitswapReply {
+            cid: None,
+            response: BitswapResponse::Have(true),
+        };
+        assert!(reply.into_message().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_codec_roundtrip() {
+        let request = BitswapRequest {
+            ty: RequestType::Block,
+            cid: create_cid(&b"block_response"[..]),
+        };
+        let reply = BitswapReply {
+            cid: Some(request.cid),
+            response: BitswapResponse::Block(b"block_response".to_vec()),
+        };
+        for protocol in [BitswapProtocol::Bitswap12, BitswapProtocol::Legacy] {
+            let mut codec = BitswapCodec::<DefaultParams>::default();
+
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_request(&protocol, &mut io, request)
+                .await
+                .unwrap();
+            io.set_position(0);
+            let request2 = codec.read_request(&protocol, &mut io).await.unwrap();
+            assert_eq!(request2, request);
+
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_response(&protocol, &mut io, reply.clone())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let reply2 = codec.read_response(&protocol, &mut io).await.unwrap();
+            assert_eq!(reply2.response, reply.response);
+            match protocol {
+                BitswapProtocol::Bitswap12 => assert_eq!(reply2.cid, reply.cid),
+                BitswapProtocol::Legacy => assert_eq!(reply2.cid, None),
+            }
+        }
+    }
 }
@@ -5,7 +5,8 @@
 // as per Cargo.toml: https://github.com/consensus-shipyard/libp2p-bitswap/blob/7dd9cececda3e4a8f6e14c200a4b457159d8db33/Cargo.toml#L7
 //
 // License headers added post-fork.
-use crate::stats::{REQUESTS_TOTAL, REQUEST_DURATION_SECONDS};
+use crate::session::{Session, DEFAULT_SESSION_FANOUT};
+use crate::stats::{REQUESTS_TOTAL, REQUEST_DURATION_SECONDS, SESSION_BROADCASTS_TOTAL};
 use fnv::{FnvHashMap, FnvHashSet};
 use libipld::Cid;
 use libp2p::PeerId;
@@ -119,13 +120,19 @@ struct GetState {
     have: FnvHashSet<QueryId>,
     block: Option<QueryId>,
     providers: Vec<PeerId>,
+    /// Peers to ask if none of the ones asked first have the block.
+    spare: Vec<PeerId>,
+    /// Peers that had the block, or said so.
+    holders: Vec<PeerId>,
+    /// Peers that didn't have the block, or failed to answer.
+    dont_have: Vec<PeerId>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct SyncState {
     missing: FnvHashSet<QueryId>,
     children: FnvHashSet<QueryId>,
-    providers: Vec<PeerId>,
+    session: Session,
 }
 
 enum Transition<S, C> {
@@ -133,14 +140,31 @@ enum Transition<S, C> {
     Complete(C),
 }
 
-#[derive(Default)]
 pub struct QueryManager {
     id_counter: u64,
     queries: FnvHashMap<QueryId, Query>,
     events: VecDeque<QueryEvent>,
+    /// Number of session peers asked for each block of a sync query.
+    session_fanout: usize,
+}
+
+impl Default for QueryManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_FANOUT)
+    }
 }
 
 impl QueryManager {
+    /// Creates a query manager.
+    pub fn new(session_fanout: usize) -> Self {
+        Self {
+            id_counter: 0,
+            queries: Default::default(),
+            events: Default::default(),
+            session_fanout,
+        }
+    }
+
     /// Start a new subquery.
     fn start_query(
         &mut self,
@@ -199,12 +223,49 @@ impl QueryManager {
         )
     }
 
+    /// Asks peers for a block: the first one for the block itself if there is no block
+    /// query in progress, the others whether they have it.
+    fn ask(
+        &mut self,
+        root: QueryId,
+        parent: QueryId,
+        cid: Cid,
+        state: &mut GetState,
+        peers: impl IntoIterator<Item = PeerId>,
+    ) {
+        for peer in peers {
+            if state.block.is_none() {
+                state.block = Some(self.block(root, parent, peer, cid));
+            } else {
+                state.have.insert(self.have(root, parent, peer, cid));
+            }
+        }
+    }
+
     /// Starts a query to locate and retrieve a block. Panics if no providers are supplied.
     pub fn get(
         &mut self,
         parent: Option<QueryId>,
         cid: Cid,
         providers: impl Iterator<Item = PeerId>,
+    ) -> QueryId {
+        self.start_get(parent, cid, providers, Vec::new())
+    }
+
+    /// Starts a query to retrieve a block of a sync query from the peers of its session.
+    fn session_get(&mut self, parent: QueryId, cid: Cid, session: &Session) -> QueryId {
+        let (peers, spare) = session.select();
+        self.start_get(Some(parent), cid, peers.into_iter(), spare)
+    }
+
+    /// Starts a get query, which falls back on the spare peers if none of the providers has
+    /// the block.
+    fn start_get(
+        &mut self,
+        parent: Option<QueryId>,
+        cid: Cid,
+        providers: impl Iterator<Item = PeerId>,
+        spare: Vec<PeerId>,
     ) -> QueryId {
         let timer = REQUEST_DURATION_SECONDS
             .with_label_values(&["get"])
@@ -213,14 +274,11 @@ impl QueryManager {
         self.id_counter += 1;
         let root = parent.unwrap_or(id);
         tracing::trace!("{} {} get", root, id);
-        let mut state = GetState::default();
-        for peer in providers {
-            if state.block.is_none() {
-                state.block = Some(self.block(root, id, peer, cid));
-            } else {
-                state.have.insert(self.have(root, id, peer, cid));
-            }
-        }
+        let mut state = GetState {
+            spare,
+            ..Default::default()
+        };
+        self.ask(root, id, cid, &mut state, providers);
         assert!(state.block.is_some());
         let query = Query {
             hdr: Header {
@@ -251,16 +309,18 @@ impl QueryManager {
         let id = QueryId(self.id_counter);
         self.id_counter += 1;
         tracing::trace!("{} {} sync", id, id);
-        let mut state = SyncState::default();
+        let mut state = SyncState {
+            missing: Default::default(),
+            children: Default::default(),
+            session: Session::new(providers, self.session_fanout),
+        };
         for cid in missing {
-            state
-                .missing
-                .insert(self.get(Some(id), cid, providers.iter().copied()));
+            let get = self.session_get(id, cid, &state.session);
+            state.missing.insert(get);
         }
         if state.missing.is_empty() {
             state.children.insert(self.missing_blocks(id, cid));
         }
-        state.providers = providers;
         let query = Query {
             hdr: Header {
                 id,
@@ -319,7 +379,11 @@ impl QueryManager {
     /// Advances a get query state machine using a transition function.
     fn get_query<F>(&mut self, id: QueryId, f: F)
     where
-        F: FnOnce(&mut Self, &Header, GetState) -> Transition<GetState, Result<(), Cid>>,
+        F: FnOnce(
+            &mut Self,
+            &Header,
+            GetState,
+        ) -> Transition<GetState, (Result<(), Cid>, GetState)>,
     {
         if let Some(mut parent) = self.queries.remove(&id) {
             let state = if let State::Get(state) = parent.state {
@@ -332,12 +396,12 @@ impl QueryManager {
                     parent.state = State::Get(state);
                     self.queries.insert(id, parent);
                 }
-                Transition::Complete(res) => {
+                Transition::Complete((res, state)) => {
                     match res {
                         Ok(()) => tracing::trace!("{} {} get ok", parent.hdr.root, parent.hdr.id),
                         Err(_) => tracing::trace!("{} {} get err", parent.hdr.root, parent.hdr.id),
                     }
-                    self.recv_get(parent.hdr, res);
+                    self.recv_get(parent.hdr, res, state);
                 }
             }
         }
@@ -375,8 +439,8 @@ impl QueryManager {
     ///
     /// Marks the in progress query as complete and updates the set of peers that have
     /// a block. If there isn't an in progress block query a new block query will be
-    /// started. If no block query can be started either a provider query is started or
-    /// the get query is marked as complete with a block-not-found error.
+    /// started. If no block query can be started the spare peers are asked, and if there
+    /// are none the get query is marked as complete with a block-not-found error.
     fn recv_have(&mut self, query: Header, peer_id: PeerId, have: bool) {
         self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
             state.have.remove(&query.id);
@@ -385,6 +449,9 @@ impl QueryManager {
             }
             if have {
                 state.providers.push(peer_id);
+                state.holders.push(peer_id);
+            } else {
+                state.dont_have.push(peer_id);
             }
             if state.block.is_none() && !state.providers.is_empty() {
                 state.block = Some(mgr.block(
@@ -394,11 +461,16 @@ impl QueryManager {
                     query.cid,
                 ));
             }
+            if state.have.is_empty() && state.block.is_none() && !state.spare.is_empty() {
+                SESSION_BROADCASTS_TOTAL.inc();
+                let spare = std::mem::take(&mut state.spare);
+                mgr.ask(parent.root, parent.id, query.cid, &mut state, spare);
+            }
             if state.have.is_empty() && state.block.is_none() && state.providers.is_empty() {
                 if state.providers.is_empty() {
-                    return Transition::Complete(Err(query.cid));
+                    return Transition::Complete((Err(query.cid), state));
                 } else {
-                    return Transition::Complete(Ok(()));
+                    return Transition::Complete((Ok(()), state));
                 }
             }
             Transition::Next(state)
@@ -412,7 +484,10 @@ impl QueryManager {
         if block {
             self.get_query(query.parent.unwrap(), |_mgr, _parent, mut state| {
                 state.providers.push(peer_id);
-                Transition::Complete(Ok(()))
+                if !state.holders.contains(&peer_id) {
+                    state.holders.push(peer_id);
+                }
+                Transition::Complete((Ok(()), state))
             });
         } else {
             self.recv_have(query, peer_id, block);
@@ -429,11 +504,8 @@ impl QueryManager {
         self.sync_query(query.parent.unwrap(), |mgr, parent, mut state| {
             state.children.remove(&query.id);
             for cid in missing {
-                state.missing.insert(mgr.get(
-                    Some(parent.root),
-                    cid,
-                    state.providers.iter().copied(),
-                ));
+                let get = mgr.session_get(parent.root, cid, &state.session);
+                state.missing.insert(get);
             }
             *num_missing_ref = state.missing.len();
             if state.missing.is_empty() && state.children.is_empty() {
@@ -450,12 +522,18 @@ impl QueryManager {
 
     /// Processes the response of a get query.
     ///
-    /// If it is part of a sync query a new missing blocks query is started. Otherwise
-    /// the get query emits a `complete` event.
-    fn recv_get(&mut self, query: Header, res: Result<(), Cid>) {
+    /// If it is part of a sync query, its session learns which peers had the block and a
+    /// new missing blocks query is started. Otherwise the get query emits a `complete` event.
+    fn recv_get(&mut self, query: Header, res: Result<(), Cid>, get: GetState) {
         if let Some(id) = query.parent {
             self.sync_query(id, |mgr, parent, mut state| {
                 state.missing.remove(&query.id);
+                for peer in get.holders {
+                    state.session.have(peer);
+                }
+                for peer in get.dont_have {
+                    state.session.dont_have(peer);
+                }
                 if res.is_err() {
                     Transition::Complete(res)
                 } else {
@@ -512,6 +590,7 @@ impl QueryManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::tests::create_cid;
 
     fn tracing_try_init() {
         tracing_subscriber::fmt()
@@ -537,6 +616,15 @@ mod tests {
         }
     }
 
+    fn assert_progress(event: Option<QueryEvent>, id: QueryId, missing: usize) {
+        if let Some(QueryEvent::Progress(id2, missing2)) = event {
+            assert_eq!(id, id2);
+            assert_eq!(missing, missing2);
+        } else {
+            panic!("{:?} is not a progress event", event);
+        }
+    }
+
     fn assert_complete(event: Option<QueryEvent>, id: QueryId, res: Result<(), Cid>) {
         if let Some(QueryEvent::Complete(id2, res2)) = event {
             assert_eq!(id, id2);
@@ -664,4 +752,79 @@ mod tests {
         mgr.inject_response(id1, Response::MissingBlocks(vec![]));
         assert_complete(mgr.next(), id, Ok(()));
     }
+
+    /// Starts a sync of a root block with a single child, where only the second provider
+    /// has the root. Returns the sync query and the request for the child.
+    fn sync_learning_holder(
+        mgr: &mut QueryManager,
+        providers: &[PeerId],
+        root: Cid,
+        child: Cid,
+    ) -> (QueryId, QueryId) {
+        let id = mgr.sync(root, providers.to_vec(), std::iter::once(root));
+
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], root));
+        let id2 = assert_request(mgr.next(), Request::Have(providers[1], root));
+        let id3 = assert_request(mgr.next(), Request::Have(providers[2], root));
+
+        mgr.inject_response(id1, Response::Have(providers[0], false));
+        mgr.inject_response(id2, Response::Have(providers[1], true));
+        mgr.inject_response(id3, Response::Have(providers[2], false));
+
+        let id1 = assert_request(mgr.next(), Request::Block(providers[1], root));
+        mgr.inject_response(id1, Response::Block(providers[1], true));
+
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(root));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![child]));
+
+        // Only the peer that had the root is asked for the child.
+        let id1 = assert_request(mgr.next(), Request::Block(providers[1], child));
+        assert_progress(mgr.next(), id, 1);
+        assert!(mgr.next().is_none());
+
+        (id, id1)
+    }
+
+    #[test]
+    fn test_sync_query_asks_session_peers_first() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(3);
+        let root = create_cid(&b"root"[..]);
+        let child = create_cid(&b"child"[..]);
+
+        let (id, id1) = sync_learning_holder(&mut mgr, &providers, root, child);
+
+        mgr.inject_response(id1, Response::Block(providers[1], true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(child));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![]));
+
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_sync_query_falls_back_to_spare_peers() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(3);
+        let root = create_cid(&b"root"[..]);
+        let child = create_cid(&b"child"[..]);
+
+        let (id, id1) = sync_learning_holder(&mut mgr, &providers, root, child);
+
+        // The session peer doesn't have the child, so everyone else is asked.
+        mgr.inject_response(id1, Response::Block(providers[1], false));
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], child));
+        let id2 = assert_request(mgr.next(), Request::Have(providers[2], child));
+
+        mgr.inject_response(id1, Response::Block(providers[0], false));
+        mgr.inject_response(id2, Response::Have(providers[2], true));
+
+        let id1 = assert_request(mgr.next(), Request::Block(providers[2], child));
+        mgr.inject_response(id1, Response::Block(providers[2], true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(child));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![]));
+
+        assert_complete(mgr.next(), id, Ok(()));
+    }
 }
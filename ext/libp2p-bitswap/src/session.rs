@@ -0,0 +1,134 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Sessions remember which peers had the blocks of the DAG a sync query is retrieving.
+//!
+//! The blocks of a DAG are usually held by the same few peers, so once some are known, the
+//! wants for further blocks go to them rather than to every provider. The other providers are
+//! only asked if none of those peers has a block.
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+/// Default for [crate::BitswapConfig::session_fanout].
+pub const DEFAULT_SESSION_FANOUT: usize = 3;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PeerStats {
+    /// Number of blocks the peer had.
+    have: u32,
+    /// Number of blocks the peer didn't have, or failed to answer about.
+    dont_have: u32,
+}
+
+impl PeerStats {
+    fn score(&self) -> i64 {
+        i64::from(self.have) - i64::from(self.dont_have)
+    }
+}
+
+/// Peer selection for the blocks of a DAG.
+#[derive(Debug)]
+pub struct Session {
+    /// Providers given when the session started, in order of preference.
+    providers: Vec<PeerId>,
+    /// Responses of the providers so far.
+    stats: FnvHashMap<PeerId, PeerStats>,
+    /// Maximum number of peers known to hold the DAG that are asked for a block.
+    fanout: usize,
+}
+
+impl Session {
+    /// Creates a session that knows nothing about its providers yet.
+    pub fn new(providers: Vec<PeerId>, fanout: usize) -> Self {
+        Self {
+            providers,
+            stats: Default::default(),
+            fanout: fanout.max(1),
+        }
+    }
+
+    /// Records that a peer had a block, or said so.
+    pub fn have(&mut self, peer: PeerId) {
+        let stats = self.stats.entry(peer).or_default();
+        stats.have = stats.have.saturating_add(1);
+    }
+
+    /// Records that a peer didn't have a block.
+    pub fn dont_have(&mut self, peer: PeerId) {
+        let stats = self.stats.entry(peer).or_default();
+        stats.dont_have = stats.dont_have.saturating_add(1);
+    }
+
+    /// Splits the providers into the peers to ask for the next block, and spare peers to ask
+    /// if none of them has it.
+    ///
+    /// Until some peer had a block all providers are asked, without spares. After that the
+    /// peers that had blocks are asked, the ones with the best record first.
+    pub fn select(&self) -> (Vec<PeerId>, Vec<PeerId>) {
+        let mut ranked = self.providers.clone();
+        // The sort is stable, so ties keep the order of the providers.
+        ranked.sort_by_key(|peer| std::cmp::Reverse(self.score(peer)));
+
+        let mut peers = Vec::new();
+        let mut spare = Vec::new();
+        for peer in ranked {
+            if peers.len() < self.fanout && self.holds(&peer) {
+                peers.push(peer);
+            } else {
+                spare.push(peer);
+            }
+        }
+        if peers.is_empty() {
+            (spare, peers)
+        } else {
+            (peers, spare)
+        }
+    }
+
+    fn score(&self, peer: &PeerId) -> i64 {
+        self.stats
+            .get(peer)
+            .map(PeerStats::score)
+            .unwrap_or_default()
+    }
+
+    fn holds(&self, peer: &PeerId) -> bool {
+        self.stats.get(peer).map_or(false, |stats| stats.have > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::random()).collect()
+    }
+
+    #[test]
+    fn test_select_all_providers_until_a_peer_has_blocks() {
+        let providers = gen_peers(3);
+        let mut session = Session::new(providers.clone(), 2);
+        assert_eq!(session.select(), (providers.clone(), vec![]));
+
+        session.dont_have(providers[0]);
+        let (peers, spare) = session.select();
+        assert_eq!(peers, vec![providers[1], providers[2], providers[0]]);
+        assert!(spare.is_empty());
+    }
+
+    #[test]
+    fn test_select_best_holders_first() {
+        let providers = gen_peers(5);
+        let mut session = Session::new(providers.clone(), 2);
+        session.have(providers[1]);
+        session.have(providers[3]);
+        session.have(providers[3]);
+        session.have(providers[4]);
+        session.dont_have(providers[4]);
+        session.dont_have(providers[0]);
+
+        let (peers, spare) = session.select();
+        assert_eq!(peers, vec![providers[3], providers[1]]);
+        assert_eq!(spare, vec![providers[2], providers[4], providers[0]]);
+    }
+}
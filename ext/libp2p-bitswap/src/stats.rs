@@ -90,4 +90,9 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
+    pub static ref SESSION_BROADCASTS_TOTAL: IntCounter = IntCounter::new(
+        "bitswap_session_broadcasts_total",
+        "Number of wants sent to all providers of a sync because none of its session peers had the block.",
+    )
+    .unwrap();
 }
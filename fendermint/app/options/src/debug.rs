@@ -3,10 +3,12 @@
 
 use std::path::PathBuf;
 
-use crate::parse::parse_eth_address;
-use clap::{Args, Subcommand};
+use crate::parse::{parse_actor_address, parse_cid, parse_eth_address};
+use cid::Cid;
+use clap::{Args, Subcommand, ValueEnum};
 use fvm_shared::address::Address;
 use ipc_api::subnet_id::SubnetID;
+use multiaddr::Multiaddr;
 
 #[derive(Args, Debug)]
 pub struct DebugArgs {
@@ -21,6 +23,21 @@ pub enum DebugCommands {
         #[command(subcommand)]
         command: DebugIpcCommands,
     },
+    /// Inspect the ledger state in the database of a node, without running the node.
+    State {
+        #[command(subcommand)]
+        command: DebugStateCommands,
+    },
+    /// Re-execute a block on top of its pre-state and compare the outcome with what the network committed.
+    Replay(DebugReplayArgs),
+    /// Check the integrity of the database of a node, without running the node.
+    Db {
+        #[command(subcommand)]
+        command: DebugDbCommands,
+    },
+    /// Print the peers, subnet providers, in-flight queries and rate limits of the IPLD Resolver
+    /// of a running node, through its admin endpoint.
+    Resolver(DebugResolverArgs),
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -65,3 +82,241 @@ pub struct DebugExportTopDownEventsArgs {
     #[arg(long)]
     pub events_file: PathBuf,
 }
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DebugStateCommands {
+    /// List the actors in the state tree.
+    Actors(DebugStateActorsArgs),
+    /// Dump the state of an actor as JSON.
+    Actor(DebugStateActorArgs),
+    /// Dump the storage slots of an EVM contract.
+    Storage(DebugStateActorArgs),
+    /// Compare two states and list the actors, and optionally the storage slots,
+    /// which were added, removed or changed between them.
+    Diff(DebugStateDiffArgs),
+    /// Export the actors of a state into one file per kind of actor, for offline analysis.
+    Export(DebugStateExportArgs),
+}
+
+/// Location of the database to open in read-only mode.
+#[derive(Args, Debug, Clone)]
+pub struct DebugStateDbArgs {
+    /// Path to the RocksDB directory of the node, e.g. `~/.fendermint/data/rocksdb`.
+    ///
+    /// The debug tools don't support the redb backend.
+    #[arg(long)]
+    pub db_dir: PathBuf,
+
+    /// Path to the cold store, if the node is running in archive mode.
+    #[arg(long)]
+    pub cold_dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DebugDbCommands {
+    /// Walk the state trees and verify that every block they reference is present and matches its CID.
+    ///
+    /// Prints a JSON report, and fails if there are missing or corrupt blocks.
+    Check(DebugDbCheckArgs),
+    /// Report the disk usage of each column family, and project how much the state would take
+    /// with different values of the `db.state_hist_size` setting.
+    ///
+    /// Prints a JSON report.
+    Stats(DebugDbStatsArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugDbCheckArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// Check every state retained in the history, not just the latest one.
+    #[arg(long)]
+    pub all: bool,
+
+    /// Restore missing and corrupt blocks from a snapshot archive.
+    ///
+    /// The database is opened for writing, so the node must not be running.
+    #[arg(long, conflicts_with = "repair_from_peer")]
+    pub repair_from_snapshot: Option<PathBuf>,
+
+    /// Restore missing and corrupt blocks by resolving them from IPLD Resolver peers,
+    /// given by their full multiaddress ending in `/p2p/<peer-id>`; can be repeated.
+    ///
+    /// The database is opened for writing, so the node must not be running.
+    #[arg(long, requires = "subnet_id")]
+    pub repair_from_peer: Vec<Multiaddr>,
+
+    /// Subnet the peers provide data for; needed to repair from peers.
+    #[arg(long)]
+    pub subnet_id: Option<SubnetID>,
+
+    /// Network name override of the peers, as in the `resolver.network.network_name` setting.
+    #[arg(long, default_value = "")]
+    pub network_name: String,
+
+    /// How long to keep trying to resolve the blocks from peers, in seconds.
+    #[arg(long, default_value_t = 120)]
+    pub repair_timeout: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugDbStatsArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// Number of the most recent retained heights to walk to estimate how many new blocks each height adds.
+    #[arg(long, default_value_t = 10)]
+    pub sample_heights: usize,
+
+    /// Sizes of the state history to project the disk usage for.
+    #[arg(long, value_delimiter = ',', default_value = "100,1000,10000")]
+    pub retention: Vec<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugStateActorsArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// Height to look at the state at, which is what queries at that height would see; by default the latest state.
+    #[arg(long)]
+    pub height: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugStateActorArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// Height to look at the state at, which is what queries at that height would see; by default the latest state.
+    #[arg(long)]
+    pub height: Option<u64>,
+
+    /// Address of the actor; either an ID or robust Filecoin address, or a 0x prefixed Ethereum address.
+    #[arg(long, short, value_parser = parse_actor_address)]
+    pub address: Address,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugStateDiffArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// The state to compare from; a block height or a state root CID.
+    #[arg(long, value_parser = parse_state_ref)]
+    pub from: StateRef,
+
+    /// The state to compare to; a block height or a state root CID.
+    #[arg(long, value_parser = parse_state_ref)]
+    pub to: StateRef,
+
+    /// Compare the storage of changed EVM contracts slot by slot.
+    #[arg(long, default_value_t = false)]
+    pub storage: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugStateExportArgs {
+    #[command(flatten)]
+    pub db: DebugStateDbArgs,
+
+    /// Height to export the state at, which is what queries at that height would see; by default the latest state.
+    #[arg(long)]
+    pub height: Option<u64>,
+
+    /// Directory to write the files to; it is created if it doesn't exist.
+    #[arg(long, short)]
+    pub output_dir: PathBuf,
+
+    /// Format of the exported files.
+    #[arg(long, short, default_value = "jsonl")]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    Jsonl,
+    /// Columnar Apache Parquet files, compressed with zstd.
+    Parquet,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugReplayArgs {
+    /// Height of the block to re-execute.
+    #[arg(long)]
+    pub height: u64,
+
+    /// Path to the RocksDB directory of the node to take the pre-state from.
+    #[arg(
+        long,
+        required_unless_present = "snapshot",
+        conflicts_with = "snapshot"
+    )]
+    pub db_dir: Option<PathBuf>,
+
+    /// Path to the cold store, if the node is running in archive mode.
+    #[arg(long, requires = "db_dir")]
+    pub cold_dir: Option<PathBuf>,
+
+    /// Snapshot archive to take the pre-state from; it has to be taken at the height before the block.
+    #[arg(long)]
+    pub snapshot: Option<PathBuf>,
+
+    /// CometBFT RPC endpoint to fetch the block and the committed results from.
+    #[arg(
+        long,
+        env = "TENDERMINT_RPC_URL",
+        default_value = "http://127.0.0.1:26657"
+    )]
+    pub rpc_url: url::Url,
+
+    /// Compare the resulting state with the one in the database, if it differs.
+    #[arg(long, default_value_t = false, requires = "db_dir")]
+    pub diff: bool,
+
+    /// Subnet the block belongs to; needed to replay blocks which commit parent finality,
+    /// because the top-down messages and validator changes are fetched from the parent.
+    #[arg(long, requires_all = ["parent_endpoint", "parent_gateway", "parent_registry"])]
+    pub subnet_id: Option<SubnetID>,
+
+    /// Endpoint to the RPC of the subnet's parent.
+    #[arg(long, requires = "subnet_id")]
+    pub parent_endpoint: Option<url::Url>,
+
+    /// HTTP basic authentication token.
+    #[arg(long, requires = "parent_endpoint")]
+    pub parent_auth_token: Option<String>,
+
+    /// IPC gateway of the parent; 20 byte Ethereum address in 0x prefixed hex format
+    #[arg(long, value_parser = parse_eth_address, requires = "subnet_id")]
+    pub parent_gateway: Option<Address>,
+
+    /// IPC registry of the parent; 20 byte Ethereum address in 0x prefixed hex format
+    #[arg(long, value_parser = parse_eth_address, requires = "subnet_id")]
+    pub parent_registry: Option<Address>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DebugResolverArgs {
+    /// Admin endpoint of the node; it has to be enabled with the `admin.enabled` setting.
+    #[arg(long, default_value = "http://127.0.0.1:9185")]
+    pub admin_url: url::Url,
+}
+
+/// Reference to a state in the database.
+#[derive(Debug, Clone)]
+pub enum StateRef {
+    /// The state at a height, if it's still in the history.
+    Height(u64),
+    /// The root of a state tree whose blocks are in the database, e.g. one found in the logs.
+    Root(Cid),
+}
+
+fn parse_state_ref(s: &str) -> Result<StateRef, String> {
+    match s.parse::<u64>() {
+        Ok(height) => Ok(StateRef::Height(height)),
+        Err(_) => parse_cid(s).map(StateRef::Root),
+    }
+}
@@ -35,5 +35,9 @@ pub enum EthCommands {
         /// Seconds to wait between trying to connect to the websocket.
         #[arg(long, short = 'd', default_value = "5")]
         connect_retry_delay: u64,
+
+        /// Delete the block index and rebuild it from the CometBFT history; needs the indexer to be enabled.
+        #[arg(long, default_value_t = false)]
+        reindex: bool,
     },
 }
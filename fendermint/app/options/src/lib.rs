@@ -11,7 +11,7 @@ use lazy_static::lazy_static;
 
 use self::{
     eth::EthArgs, genesis::GenesisArgs, key::KeyArgs, materializer::MaterializerArgs, rpc::RpcArgs,
-    run::RunArgs,
+    run::RunArgs, snapshot::SnapshotArgs,
 };
 
 pub mod config;
@@ -22,6 +22,7 @@ pub mod key;
 pub mod materializer;
 pub mod rpc;
 pub mod run;
+pub mod snapshot;
 
 mod parse;
 
@@ -145,6 +146,8 @@ pub enum Commands {
     Rpc(RpcArgs),
     /// Subcommands related to the Ethereum API facade.
     Eth(EthArgs),
+    /// Subcommands related to exporting and importing snapshot archives.
+    Snapshot(SnapshotArgs),
     /// Subcommands related to the Testnet Materializer.
     #[clap(aliases  = &["mat", "matr", "mate"])]
     Materializer(MaterializerArgs),
@@ -108,3 +108,12 @@ pub fn parse_eth_address(s: &str) -> Result<Address, String> {
         Err(e) => Err(format!("not a valid ethereum address: {e}")),
     }
 }
+
+/// Parse either a 0x prefixed Ethereum address or a Filecoin address.
+pub fn parse_actor_address(s: &str) -> Result<Address, String> {
+    if s.starts_with("0x") {
+        parse_eth_address(s)
+    } else {
+        parse_address(s)
+    }
+}
@@ -90,6 +90,23 @@ pub enum RpcQueryCommands {
         #[arg(long, short, value_parser = parse_address)]
         address: Address,
     },
+    /// Get the state of an actor at heights sampled from a range; print it as JSON.
+    ///
+    /// The query height is ignored; heights no longer in the state history are left out.
+    ActorStateRange {
+        /// Address of the actor to query.
+        #[arg(long, short, value_parser = parse_address)]
+        address: Address,
+        /// First block height to sample.
+        #[arg(long)]
+        from: u64,
+        /// Last block height to sample.
+        #[arg(long)]
+        to: u64,
+        /// Number of blocks between samples.
+        #[arg(long, default_value_t = 1)]
+        step: u64,
+    },
     /// Get the slowly changing state parameters.
     StateParams,
 }
@@ -0,0 +1,113 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use url::Url;
+
+#[derive(Args, Debug)]
+pub struct SnapshotArgs {
+    #[command(subcommand)]
+    pub command: SnapshotCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotCommands {
+    /// List the snapshots in the snapshots directory of the node.
+    List,
+    /// Package a snapshot, along with any snapshots it builds on, into a single archive file.
+    Export(SnapshotExportArgs),
+    /// Import the state from a snapshot archive into the database of a fresh node,
+    /// so that it can start from the snapshot height without state sync.
+    Import(SnapshotImportArgs),
+    /// Check that a snapshot archive is complete and uncorrupted.
+    Verify(SnapshotVerifyArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotExportArgs {
+    /// Block height of the snapshot to export; by default the latest one.
+    #[arg(long)]
+    pub height: Option<u64>,
+
+    /// Path to the archive file to create.
+    #[arg(long, short)]
+    pub archive: PathBuf,
+
+    /// Upload the archive to object storage as well.
+    #[command(flatten)]
+    pub s3: S3Args,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotImportArgs {
+    /// Path to the archive file; if an S3 URL is given, it is downloaded to this path first.
+    #[arg(long, short)]
+    pub archive: PathBuf,
+
+    /// Copy the snapshots into the snapshots directory as well, so that they can be offered to peers.
+    #[arg(long, default_value_t = false)]
+    pub install: bool,
+
+    #[command(flatten)]
+    pub s3: S3Args,
+
+    #[command(flatten)]
+    pub light_client: LightClientArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotVerifyArgs {
+    /// Path to the archive file; if an S3 URL is given, it is downloaded to this path first.
+    #[arg(long, short)]
+    pub archive: PathBuf,
+
+    /// Load the state into memory and check that it is complete, not just the checksums.
+    #[arg(long, default_value_t = false)]
+    pub load: bool,
+
+    #[command(flatten)]
+    pub s3: S3Args,
+
+    #[command(flatten)]
+    pub light_client: LightClientArgs,
+}
+
+/// Location of an archive in S3 compatible object storage.
+///
+/// Credentials are taken from the standard `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+/// environment variables, and the region from `AWS_REGION`.
+#[derive(Args, Debug, Clone)]
+pub struct S3Args {
+    /// Object URL in the form of `s3://<bucket>/<key>`.
+    #[arg(long)]
+    pub s3_url: Option<Url>,
+
+    /// Custom endpoint for S3 compatible storage, e.g. `http://127.0.0.1:9000` for a local MinIO.
+    #[arg(long, env = "AWS_ENDPOINT_URL", requires = "s3_url")]
+    pub s3_endpoint: Option<Url>,
+}
+
+/// Check the application hash of the snapshots against block headers verified with a light client,
+/// starting from a block the operator trusts.
+///
+/// Loading the state is what ties the contents of a snapshot to the hash in its manifest.
+#[derive(Args, Debug, Clone)]
+pub struct LightClientArgs {
+    /// CometBFT RPC endpoint to fetch block headers from.
+    #[arg(long, requires_all = ["trust_height", "trust_hash"])]
+    pub trust_rpc_url: Option<Url>,
+
+    /// Height of a block known to be on the correct chain.
+    #[arg(long, requires = "trust_rpc_url")]
+    pub trust_height: Option<u64>,
+
+    /// Hex encoded hash of the block at the trusted height.
+    #[arg(long, requires = "trust_rpc_url")]
+    pub trust_hash: Option<String>,
+
+    /// How long the validators of a block can be trusted, in seconds; should be shorter than the unbonding period.
+    #[arg(long, default_value_t = 604800)]
+    pub trust_period: u64,
+}
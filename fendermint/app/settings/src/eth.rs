@@ -7,12 +7,13 @@ use ipc_provider::config::deserialize::{
 };
 use serde::Deserialize;
 use serde_with::{serde_as, DurationSeconds};
+use std::path::PathBuf;
 use std::time::Duration;
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin};
 
 use ipc_observability::config::TracingSettings;
 
-use crate::{IsHumanReadable, MetricsSettings, SocketAddress};
+use crate::{home_relative, IsHumanReadable, MetricsSettings, SocketAddress};
 
 /// Ethereum API facade settings.
 #[serde_as]
@@ -27,8 +28,24 @@ pub struct EthSettings {
     pub metrics: MetricsSettings,
     pub cors: CorsOpt,
     pub tracing: TracingSettings,
+    /// Persist Ethereum blocks and receipts as they are committed, rather than rebuilding them on every request.
+    pub indexer: Option<EthIndexerSettings>,
 }
 
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct EthIndexerSettings {
+    /// Directory of the index database.
+    db_dir: PathBuf,
+    /// How often to look for new blocks to index.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub poll_interval: Duration,
+    /// Height to start indexing from when the index is empty; by default the earliest block CometBFT still has.
+    pub start_height: Option<u64>,
+}
+
+home_relative!(EthIndexerSettings { db_dir });
+
 #[serde_as]
 #[derive(Debug, Clone, Deserialize)]
 pub struct GasOpt {
@@ -101,6 +101,18 @@ pub struct AbciSettings {
     pub block_max_msgs: usize,
 }
 
+/// Embedded database storing the application state and the blockstores.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    #[default]
+    RocksDb,
+    /// Memory-mapped, pure Rust store without background compaction.
+    ///
+    /// It doesn't support garbage collection or archive mode.
+    Redb,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 /// Indicate the FVM account kind for generating addresses from a key.
@@ -131,14 +143,59 @@ impl Display for DbCompaction {
     }
 }
 
+#[serde_as]
 #[derive(Debug, Deserialize, Clone)]
 pub struct DbSettings {
+    /// Which database implementation to use.
+    #[serde(default)]
+    pub backend: DbBackend,
     /// Length of the app state history to keep in the database before pruning; 0 means unlimited.
     ///
     /// This affects how long we can go back in state queries.
     pub state_hist_size: u64,
-    /// How to compact the datastore.
+    /// How to compact the datastore; only applies to RocksDB.
     pub compaction_style: DbCompaction,
+    /// How often to delete the blocks which aren't reachable from the retained state history
+    /// or the snapshots; leave it empty to disable garbage collection.
+    ///
+    /// It only has an effect if the state history is pruned.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub gc_interval: Option<Duration>,
+    /// Keep all historical state, moving old blocks to a cold store instead of deleting them.
+    pub archive: Option<ArchiveSettings>,
+}
+
+/// Archive mode, where the state history is never pruned, but the blocks which are only
+/// reachable from old states are moved out of the main database into a cold store.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ArchiveSettings {
+    /// Number of recent states whose blocks are kept in the main database.
+    pub hot_hist_size: u64,
+    /// Directory of the cold store database.
+    cold_dir: PathBuf,
+    /// Compression of the cold store, trading lookup speed for space.
+    pub compression_type: String,
+}
+
+home_relative!(ArchiveSettings { cold_dir });
+
+impl DbSettings {
+    /// Check that the settings can be used together.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.backend == DbBackend::Redb && (self.gc_interval.is_some() || self.archive.is_some())
+        {
+            bail!("garbage collection and archive mode are not supported by the redb backend");
+        }
+        if self.archive.is_some() {
+            if self.gc_interval.is_none() {
+                bail!("archive mode needs a `gc_interval` to move blocks to the cold store");
+            }
+            if self.state_hist_size != 0 {
+                bail!("archive mode keeps the whole state history; `state_hist_size` must be 0");
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Settings affecting how we deal with failures in trying to send transactions to the local CometBFT node.
@@ -243,8 +300,17 @@ pub struct SnapshotSettings {
     /// How often to poll CometBFT to see whether it has caught up with the chain.
     #[serde_as(as = "DurationSeconds<u64>")]
     pub sync_poll_interval: Duration,
+    /// Number of delta snapshots to export after a full one, each containing only
+    /// the blocks which changed since the previous snapshot. 0 disables deltas.
+    ///
+    /// Exporting a delta keeps the CIDs of all blocks in the base state in memory.
+    pub max_deltas: usize,
+    /// Level of zstd compression to apply to snapshot chunks; leave it empty to disable compression.
+    pub compression_level: Option<i32>,
     /// Temporary directory for downloads.
     download_dir: Option<PathBuf>,
+    /// Verify offered snapshots against block headers checked by a light client.
+    pub light_client: Option<SnapshotLightClientSettings>,
 }
 
 impl SnapshotSettings {
@@ -253,6 +319,22 @@ impl SnapshotSettings {
     }
 }
 
+#[serde_as]
+#[derive(Debug, Deserialize, Clone)]
+pub struct SnapshotLightClientSettings {
+    /// CometBFT RPC endpoint of a trusted peer to fetch block headers from.
+    ///
+    /// The node's own CometBFT can't serve headers while it is being synced.
+    pub rpc_url: Url,
+    /// Height of a block known to be on the correct chain.
+    pub trust_height: BlockHeight,
+    /// Hex encoded hash of the block at the trusted height.
+    pub trust_hash: String,
+    /// How long the validators of a block can be trusted; should be shorter than the unbonding period.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub trust_period: Duration,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct MetricsSettings {
     /// Enable the export of metrics over HTTP.
@@ -261,6 +343,14 @@ pub struct MetricsSettings {
     pub listen: SocketAddress,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminSettings {
+    /// Enable the HTTP endpoint to change settings of the running node.
+    pub enabled: bool,
+    /// HTTP listen address of the admin endpoint; it has no authentication, so it should not be exposed.
+    pub listen: SocketAddress,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     /// Home directory configured on the CLI, to which all paths in settings can be set relative.
@@ -284,6 +374,7 @@ pub struct Settings {
     pub abci: AbciSettings,
     pub db: DbSettings,
     pub metrics: MetricsSettings,
+    pub admin: AdminSettings,
     pub snapshots: SnapshotSettings,
     pub eth: EthSettings,
     pub fvm: FvmSettings,
@@ -348,7 +439,14 @@ impl Settings {
     /// Try to parse the config into [Settings].
     fn parse(config: Config) -> Result<Self, ConfigError> {
         // Deserialize (and thus freeze) the entire configuration.
-        config.try_deserialize()
+        let settings: Self = config.try_deserialize()?;
+
+        settings
+            .db
+            .validate()
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+
+        Ok(settings)
     }
 
     /// The configured home directory.
@@ -557,4 +655,43 @@ mod tests {
         );
         assert!(settings.is_ok());
     }
+
+    #[test]
+    fn parse_archive_settings() {
+        let archive = vec![
+            ("FM_DB__ARCHIVE__HOT_HIST_SIZE", "100"),
+            ("FM_DB__ARCHIVE__COLD_DIR", "data/cold"),
+            ("FM_DB__ARCHIVE__COMPRESSION_TYPE", "lz4"),
+        ];
+
+        let with = |vars: Vec<(&'static str, &'static str)>| {
+            let vars = archive.iter().cloned().chain(vars).collect();
+            with_env_vars(vars, || try_parse_config(""))
+        };
+
+        // No garbage collection to move the blocks to the cold store.
+        let settings = with(vec![]);
+        assert!(
+            matches!(settings, Err(ConfigError::Message(ref msg)) if msg.contains("gc_interval"))
+        );
+
+        // Pruned state history.
+        let settings = with(vec![
+            ("FM_DB__GC_INTERVAL", "60"),
+            ("FM_DB__STATE_HIST_SIZE", "1000"),
+        ]);
+        assert!(
+            matches!(settings, Err(ConfigError::Message(ref msg)) if msg.contains("state_hist_size"))
+        );
+
+        // Only RocksDB has a cold store.
+        let settings = with(vec![
+            ("FM_DB__GC_INTERVAL", "60"),
+            ("FM_DB__BACKEND", "redb"),
+        ]);
+        assert!(matches!(settings, Err(ConfigError::Message(ref msg)) if msg.contains("redb")));
+
+        let settings = with(vec![("FM_DB__GC_INTERVAL", "60")]).unwrap();
+        assert_eq!(settings.db.archive.map(|a| a.hot_hist_size), Some(100));
+    }
 }
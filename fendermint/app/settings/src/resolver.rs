@@ -1,7 +1,7 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use std::{path::PathBuf, time::Duration};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use serde::Deserialize;
 use serde_with::{serde_as, DurationSeconds};
@@ -9,7 +9,7 @@ use serde_with::{serde_as, DurationSeconds};
 use ipc_api::subnet_id::SubnetID;
 use multiaddr::Multiaddr;
 
-use crate::{home_relative, IsHumanReadable};
+use crate::{home_relative, IsHumanReadable, SocketAddress};
 
 #[serde_as]
 #[derive(Debug, Clone, Deserialize)]
@@ -22,6 +22,11 @@ pub struct ResolverSettings {
     pub membership: MembershipSettings,
     pub connection: ConnectionSettings,
     pub content: ContentSettings,
+    pub content_api: ContentApiSettings,
+    pub scoring: ScoringSettings,
+    pub nat: NatSettings,
+    pub address_book: AddressBookSettings,
+    pub erasure: ErasureSettings,
 }
 
 /// Settings describing the subnet hierarchy, not the physical network.
@@ -81,6 +86,8 @@ pub struct MembershipSettings {
 pub struct ConnectionSettings {
     /// The address where we will listen to incoming connections.
     pub listen_addr: Multiaddr,
+    /// An additional address to listen on for QUIC connections.
+    pub quic_listen_addr: Option<Multiaddr>,
     /// A list of known external addresses this node is reachable on.
     pub external_addresses: Vec<Multiaddr>,
     /// Maximum number of incoming connections.
@@ -107,4 +114,125 @@ pub struct ContentSettings {
     /// 0 means no limit.
     #[serde_as(as = "DurationSeconds<u64>")]
     pub rate_limit_period: Duration,
+    /// Maximum size of a sub-DAG sent or accepted in a single graph response.
+    pub graph_max_response_bytes: u32,
+    /// Maximum total size of a DAG that applications can ask us to provide.
+    pub max_provided_bytes: u64,
+    /// Maximum number of root CIDs provided at the same time.
+    pub max_provided_cids: usize,
+    /// Maximum time a CID can be provided for before it has to be provided again.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_provide_ttl: Duration,
+    /// Maximum total size of content resolved from its providers on behalf of applications.
+    pub max_resolved_bytes: u64,
+    /// Total number of bytes served to remote peers in a time period.
+    ///
+    /// 0 means no limit.
+    pub egress_bytes: u64,
+    /// Length of the time period at which the egress budgets refill.
+    ///
+    /// 0 means no limit.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub egress_period: Duration,
+    /// Number of bytes served in a time period to peers resolving content from specific subnets.
+    #[serde_as(as = "HashMap<IsHumanReadable, _>")]
+    pub subnet_egress_bytes: HashMap<SubnetID, u64>,
+    /// Subnets served even when the total egress budget is exhausted,
+    /// in addition to the subnet of the node, which always has priority.
+    #[serde_as(as = "Vec<IsHumanReadable>")]
+    pub priority_subnets: Vec<SubnetID>,
+}
+
+/// Settings of the JSON-RPC endpoint through which applications store, provide and resolve content.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContentApiSettings {
+    pub enabled: bool,
+    /// HTTP listen address of the endpoint; it has no authentication, so it should not be exposed.
+    pub listen: SocketAddress,
+    /// Maximum size of a single block stored through the API.
+    pub max_block_size: usize,
+    /// Maximum time to wait for content to be resolved.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub resolve_timeout: Duration,
+    /// Minimum time to keep content stored or resolved through the API for, unless it's provided for longer.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub retention: Duration,
+    /// How often to delete the content whose retention expired.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub gc_interval: Duration,
+}
+
+/// Configuration for NAT traversal.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatSettings {
+    /// Find out whether we are publicly reachable by asking peers to dial us back.
+    pub enable_autonat: bool,
+    /// Relay connections for peers which are not publicly reachable.
+    pub enable_relay_server: bool,
+    /// Dial peers through relays, which is necessary to reach peers behind NAT.
+    pub enable_relay_client: bool,
+    /// Relays to reserve a slot on, so that peers can reach us through them.
+    ///
+    /// The addresses must end with a `/p2p/<peer-id>` part.
+    pub relay_addresses: Vec<Multiaddr>,
+    /// Try to upgrade relayed connections to direct ones with hole punching.
+    pub enable_hole_punching: bool,
+    /// Maximum duration of a connection we relay for others.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub relay_max_circuit_duration: Duration,
+    /// Maximum number of bytes we relay on a single connection for others.
+    pub relay_max_circuit_bytes: u64,
+}
+
+/// Configuration for keeping score of peers.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScoringSettings {
+    /// Peers whose score falls below this (negative) value are disconnected and banned.
+    pub ban_threshold: f64,
+    /// How long a peer stays banned.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub ban_duration: Duration,
+    /// Time it takes for a score to lose half of its value.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub decay_half_life: Duration,
+}
+
+/// Configuration for persisting the known peers across restarts.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressBookSettings {
+    /// Save the routing table and the provider records, and reload them on startup.
+    pub enabled: bool,
+    /// File to save the known peers to, relative to the `home_dir`.
+    path: PathBuf,
+    /// Addresses of peers we haven't been connected to for longer than this are not reloaded.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_age: Duration,
+    /// How often to save the known peers.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub save_interval: Duration,
+}
+
+home_relative!(AddressBookSettings { path });
+
+/// Configuration for erasure coding content into chunks gossiped to the validators
+/// of a subnet and its parent.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErasureSettings {
+    /// Receive the chunks published in the subnets we provide data for or pinned,
+    /// and allow publishing our own.
+    pub enabled: bool,
+    /// Number of chunks the content is split into; this many of them are enough to reconstruct it.
+    pub data_chunks: usize,
+    /// Number of extra chunks, which can be lost without losing the content.
+    pub parity_chunks: usize,
+    /// Maximum size of the serialized content to encode or reconstruct.
+    pub max_bytes: u64,
+    /// How long to wait for enough chunks of some content to arrive.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub chunk_ttl: Duration,
 }
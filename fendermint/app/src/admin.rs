@@ -0,0 +1,282 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! HTTP endpoint to change settings of the running node.
+//!
+//! It has no authentication, so it should only listen on a local interface.
+//!
+//! * `GET /state-history` returns the current size and range of the retained state history.
+//! * `PUT /state-history` with a body like `{"state_hist_size": 1000}` changes the size.
+//!   The change only lasts until the node is restarted, after which `db.state_hist_size`
+//!   from the settings applies again. In archive mode the history is never pruned, so it can't be changed.
+//! * `GET /resolver` returns the peers, subnet providers, in-flight queries and rate limits of the IPLD Resolver.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use fendermint_storage::{Codec, Encode, KVReadable, KVStore, KVWritable};
+use fendermint_vm_interpreter::fvm::state::FvmStateParams;
+use fvm_ipld_blockstore::Blockstore;
+use ipc_ipld_resolver::ResolverInfo;
+use serde::{Deserialize, Serialize};
+
+use crate::{App, AppState, AppStoreKey, BlockHeight};
+
+/// Retention of the state history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateHistory {
+    /// Number of heights to keep; 0 means unlimited.
+    pub state_hist_size: u64,
+    /// Oldest height still in the history.
+    #[serde(default)]
+    pub oldest_height: Option<BlockHeight>,
+    /// Height of the latest committed state.
+    #[serde(default)]
+    pub latest_height: Option<BlockHeight>,
+}
+
+/// Change the retention of the state history at runtime.
+pub trait StateHistoryAdmin: Send + Sync {
+    fn state_history(&self) -> anyhow::Result<StateHistory>;
+    fn set_state_hist_size(&self, size: u64);
+}
+
+impl<DB, SS, S, I> StateHistoryAdmin for App<DB, SS, S, I>
+where
+    S: KVStore
+        + Codec<AppState>
+        + Encode<AppStoreKey>
+        + Encode<BlockHeight>
+        + Codec<FvmStateParams>,
+    S::Namespace: Send + Sync,
+    DB: KVWritable<S> + KVReadable<S> + Clone + Send + Sync + 'static,
+    SS: Blockstore + Clone + Send + Sync + 'static,
+    I: Send + Sync,
+{
+    fn state_history(&self) -> anyhow::Result<StateHistory> {
+        let (oldest, latest) = self.state_hist_range()?;
+        Ok(StateHistory {
+            state_hist_size: self.state_hist_size(),
+            oldest_height: Some(oldest),
+            latest_height: Some(latest),
+        })
+    }
+
+    fn set_state_hist_size(&self, size: u64) {
+        App::set_state_hist_size(self, size)
+    }
+}
+
+/// Inspect the IPLD Resolver at runtime.
+#[async_trait]
+pub trait ResolverAdmin: Send + Sync {
+    async fn resolver_info(&self) -> anyhow::Result<ResolverInfo>;
+}
+
+#[async_trait]
+impl<V> ResolverAdmin for ipc_ipld_resolver::Client<V>
+where
+    V: Send + Sync + 'static,
+{
+    async fn resolver_info(&self) -> anyhow::Result<ResolverInfo> {
+        self.inspect().await
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    state_history: Arc<dyn StateHistoryAdmin>,
+    /// In archive mode the whole state history is kept, so its size can't be changed.
+    archive: bool,
+    /// Only available if the resolver is enabled.
+    resolver: Option<Arc<dyn ResolverAdmin>>,
+}
+
+type AdminResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+/// Serve the admin endpoint until the process is stopped.
+pub async fn listen(
+    listen_addr: SocketAddr,
+    state_history: Arc<dyn StateHistoryAdmin>,
+    archive: bool,
+    resolver: Option<Arc<dyn ResolverAdmin>>,
+) -> anyhow::Result<()> {
+    let router = make_router(AdminState {
+        state_history,
+        archive,
+        resolver,
+    });
+    let server = axum::Server::try_bind(&listen_addr)?.serve(router.into_make_service());
+    tracing::info!(?listen_addr, "bound admin endpoint");
+    server.await?;
+    Ok(())
+}
+
+fn make_router(state: AdminState) -> Router {
+    Router::new()
+        .route(
+            "/state-history",
+            get(get_state_history).put(put_state_history),
+        )
+        .route("/resolver", get(get_resolver))
+        .with_state(state)
+}
+
+async fn get_state_history(State(state): State<AdminState>) -> AdminResult<StateHistory> {
+    state
+        .state_history
+        .state_history()
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn put_state_history(
+    State(state): State<AdminState>,
+    Json(request): Json<StateHistory>,
+) -> AdminResult<StateHistory> {
+    if state.archive {
+        return Err((
+            StatusCode::CONFLICT,
+            "the state history is not pruned in archive mode".to_owned(),
+        ));
+    }
+
+    state
+        .state_history
+        .set_state_hist_size(request.state_hist_size);
+
+    state
+        .state_history
+        .state_history()
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn get_resolver(State(state): State<AdminState>) -> AdminResult<ResolverInfo> {
+    let resolver = state.resolver.ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "the IPLD Resolver is not enabled".to_owned(),
+        )
+    })?;
+
+    resolver
+        .resolver_info()
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::bail;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::Json;
+
+    use super::{
+        get_resolver, get_state_history, put_state_history, AdminState, StateHistory,
+        StateHistoryAdmin,
+    };
+
+    #[derive(Default)]
+    struct TestHistory {
+        size: AtomicU64,
+        broken: bool,
+    }
+
+    impl StateHistoryAdmin for TestHistory {
+        fn state_history(&self) -> anyhow::Result<StateHistory> {
+            if self.broken {
+                bail!("no state history");
+            }
+            Ok(StateHistory {
+                state_hist_size: self.size.load(Ordering::Relaxed),
+                oldest_height: Some(10),
+                latest_height: Some(20),
+            })
+        }
+
+        fn set_state_hist_size(&self, size: u64) {
+            self.size.store(size, Ordering::Relaxed)
+        }
+    }
+
+    fn admin_state(history: TestHistory, archive: bool) -> (Arc<TestHistory>, AdminState) {
+        let history = Arc::new(history);
+        let state = AdminState {
+            state_history: history.clone(),
+            archive,
+            resolver: None,
+        };
+        (history, state)
+    }
+
+    fn request(size: u64) -> Json<StateHistory> {
+        Json(StateHistory {
+            state_hist_size: size,
+            oldest_height: None,
+            latest_height: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn get_and_put_state_history() {
+        let (history, state) = admin_state(TestHistory::default(), false);
+
+        let Json(current) = get_state_history(State(state.clone())).await.unwrap();
+        assert_eq!(current.state_hist_size, 0);
+        assert_eq!(current.oldest_height, Some(10));
+        assert_eq!(current.latest_height, Some(20));
+
+        let Json(changed) = put_state_history(State(state), request(1000))
+            .await
+            .unwrap();
+        assert_eq!(changed.state_hist_size, 1000);
+        assert_eq!(history.size.load(Ordering::Relaxed), 1000);
+    }
+
+    #[tokio::test]
+    async fn put_state_history_rejected_in_archive_mode() {
+        let (history, state) = admin_state(TestHistory::default(), true);
+
+        let (status, _) = put_state_history(State(state), request(1000))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(history.size.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn state_history_error() {
+        let history = TestHistory {
+            broken: true,
+            ..Default::default()
+        };
+        let (_, state) = admin_state(history, false);
+
+        let (status, msg) = get_state_history(State(state)).await.unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(msg.contains("no state history"));
+    }
+
+    #[tokio::test]
+    async fn resolver_not_enabled() {
+        let (_, state) = admin_state(TestHistory::default(), false);
+
+        let (status, _) = get_resolver(State(state)).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,8 +1,10 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: Apache-2.0, MIT
 use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use crate::gc::PendingStateRoot;
 use crate::observe::{
     BlockCommitted, BlockProposalEvaluated, BlockProposalReceived, BlockProposalSent, Message,
     MpoolReceived,
@@ -11,7 +13,7 @@ use crate::validators::ValidatorCache;
 use crate::AppExitCode;
 use crate::BlockHeight;
 use crate::{tmconv::*, VERSION};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_stm::{atomically, atomically_or_err};
 use async_trait::async_trait;
 use cid::Cid;
@@ -23,25 +25,27 @@ use fendermint_storage::{
     Codec, Encode, KVCollection, KVRead, KVReadable, KVStore, KVWritable, KVWrite,
 };
 use fendermint_vm_core::Timestamp;
-use fendermint_vm_interpreter::bytes::{
-    BytesMessageApplyRes, BytesMessageCheckRes, BytesMessageQuery, BytesMessageQueryRes,
-};
+use fendermint_vm_interpreter::bytes::{decode_query, BytesMessageApplyRes, BytesMessageCheckRes};
 use fendermint_vm_interpreter::chain::{ChainEnv, ChainMessageApplyRet, IllegalMessage};
 use fendermint_vm_interpreter::fvm::state::{
     empty_state_tree, CheckStateRef, FvmExecState, FvmQueryState, FvmStateParams,
     FvmUpdatableParams,
 };
 use fendermint_vm_interpreter::fvm::store::ReadOnlyBlockstore;
-use fendermint_vm_interpreter::fvm::{EndBlockOutput, FvmApplyRet};
+use fendermint_vm_interpreter::fvm::{EndBlockOutput, FvmApplyRet, FvmQueryRet};
 use fendermint_vm_interpreter::genesis::{read_genesis_car, GenesisAppState};
 use fendermint_vm_interpreter::signed::InvalidSignature;
 use fendermint_vm_interpreter::{
     CheckInterpreter, ExecInterpreter, ProposalInterpreter, QueryInterpreter,
 };
-use fendermint_vm_message::query::FvmQueryHeight;
+use fendermint_vm_message::query::{
+    ActorStateSample, FvmQuery, FvmQueryHeight, HeightRange, MAX_CALL_BATCH_SIZE,
+    MAX_HEIGHT_RANGE_SAMPLES,
+};
 use fendermint_vm_snapshot::{SnapshotClient, SnapshotError};
 use fvm::engine::MultiEngine;
 use fvm_ipld_blockstore::Blockstore;
+use fvm_shared::address::Address;
 use fvm_shared::chainid::ChainID;
 use fvm_shared::clock::ChainEpoch;
 use fvm_shared::econ::TokenAmount;
@@ -54,6 +58,11 @@ use tendermint::abci::{request, response};
 use tendermint::consensus::params::Params as TendermintConsensusParams;
 use tracing::instrument;
 
+/// ABCI query path to list the evidence of parent finality vote equivocations observed by this node.
+///
+/// The response value is the IPLD encoded list of `EquivocationEvidence`.
+pub const EQUIVOCATIONS_QUERY_PATH: &str = "/topdown/equivocations";
+
 #[derive(Serialize)]
 #[repr(u8)]
 pub enum AppStoreKey {
@@ -72,6 +81,8 @@ pub enum AppError {
     IllegalMessage = 53,
     /// The genesis block hasn't been initialized yet.
     NotInitialized = 54,
+    /// The query was well formed, but can't be answered as it is.
+    InvalidQuery = 55,
 }
 
 /// The application state record we keep a history of in the database.
@@ -86,6 +97,19 @@ pub struct AppState {
 }
 
 impl AppState {
+    pub fn block_height(&self) -> BlockHeight {
+        self.block_height
+    }
+
+    /// The oldest height for which the state history is still kept.
+    pub fn oldest_state_height(&self) -> BlockHeight {
+        self.oldest_state_height
+    }
+
+    pub fn state_params(&self) -> &FvmStateParams {
+        &self.state_params
+    }
+
     pub fn state_root(&self) -> Cid {
         self.state_params.state_root
     }
@@ -156,12 +180,14 @@ where
     snapshots: Option<SnapshotClient>,
     /// State accumulating changes during block execution.
     exec_state: Arc<tokio::sync::Mutex<Option<FvmExecState<SS>>>>,
+    /// Root of the execution state between flushing it and committing it.
+    pending_state_root: PendingStateRoot,
     /// Projected (partial) state accumulating during transaction checks.
     check_state: CheckStateRef<SS>,
     /// How much history to keep.
     ///
-    /// Zero means unlimited.
-    state_hist_size: u64,
+    /// Zero means unlimited. It can be changed while the node is running.
+    state_hist_size: Arc<AtomicU64>,
     /// Caches the validators.
     validators_cache: Arc<tokio::sync::Mutex<Option<ValidatorCache>>>,
 }
@@ -191,11 +217,12 @@ where
             halt_height: config.halt_height,
             namespace: config.app_namespace,
             state_hist: KVCollection::new(config.state_hist_namespace),
-            state_hist_size: config.state_hist_size,
+            state_hist_size: Arc::new(AtomicU64::new(config.state_hist_size)),
             interpreter: Arc::new(interpreter),
             chain_env,
             snapshots,
             exec_state: Arc::new(tokio::sync::Mutex::new(None)),
+            pending_state_root: Default::default(),
             check_state: Arc::new(tokio::sync::Mutex::new(None)),
             validators_cache: Arc::new(tokio::sync::Mutex::new(None)),
         };
@@ -261,8 +288,29 @@ where
         }
     }
 
+    /// The current size of the state history to keep; 0 means unlimited.
+    pub fn state_hist_size(&self) -> u64 {
+        self.state_hist_size.load(Ordering::Relaxed)
+    }
+
+    /// Change the size of the state history to keep.
+    ///
+    /// Shrinking it prunes the excess history at the next commit. Growing it only
+    /// retains more of the states committed from then on; pruned ones are not restored.
+    pub fn set_state_hist_size(&self, size: u64) {
+        let prev = self.state_hist_size.swap(size, Ordering::Relaxed);
+        tracing::info!(prev, size, "state history size changed");
+    }
+
+    /// The range of heights in the state history, oldest first.
+    pub fn state_hist_range(&self) -> Result<(BlockHeight, BlockHeight)> {
+        let state = self.committed_state()?;
+        Ok((state.oldest_state_height, state.state_height()))
+    }
+
     /// Set the last committed state.
     fn set_committed_state(&self, mut state: AppState) -> Result<()> {
+        let state_hist_size = self.state_hist_size();
         self.db
             .with_write(|tx| {
                 // Insert latest state history point at the `block_height + 1`,
@@ -273,8 +321,8 @@ where
                     .put(tx, &state_height, &state.state_params)?;
 
                 // Prune state history.
-                if self.state_hist_size > 0 && state_height >= self.state_hist_size {
-                    let prune_height = state_height.saturating_sub(self.state_hist_size);
+                if state_hist_size > 0 && state_height >= state_hist_size {
+                    let prune_height = state_height.saturating_sub(state_hist_size);
                     while state.oldest_state_height <= prune_height {
                         self.state_hist.delete(tx, &state.oldest_state_height)?;
                         state.oldest_state_height += 1;
@@ -289,6 +337,50 @@ where
             .context("commit failed")
     }
 
+    /// State roots in the last `count` heights of the retained history, which have to be kept in the blockstore.
+    ///
+    /// Pass the size of the state history to get all the retained roots.
+    /// The root of a state which is being committed is included as well.
+    pub fn recent_state_roots(&self, count: u64) -> Result<Vec<Cid>> {
+        self.pending_state_root.with_roots(|| {
+            let state = self.committed_state()?;
+            let tx = self.db.read();
+
+            let state_height = state.state_height();
+            let from_height = state
+                .oldest_state_height
+                .max(state_height.saturating_sub(count));
+
+            let mut roots = vec![state.state_params.state_root];
+            for height in from_height..=state_height {
+                if let Some(params) = self
+                    .state_hist
+                    .get(&tx, &height)
+                    .context("error looking up history")?
+                {
+                    roots.push(params.state_root);
+                }
+            }
+
+            Ok(roots)
+        })
+    }
+
+    /// Make the state imported from a snapshot the last committed state,
+    /// so that the application carries on from the height of the snapshot.
+    pub fn import_snapshot_state(
+        &self,
+        block_height: BlockHeight,
+        state_params: FvmStateParams,
+    ) -> Result<()> {
+        let mut state = self.committed_state()?;
+
+        // The height reflects that it was produced in `commit`.
+        state.block_height = block_height;
+        state.state_params = state_params;
+        self.set_committed_state(state)
+    }
+
     /// Diff our current consensus params with new values, and return Some with the final params
     /// if they differ (and therefore a consensus layer update is necessary).
     fn maybe_update_app_state(
@@ -453,6 +545,93 @@ where
     }
 }
 
+impl<DB, SS, S, I> App<DB, SS, S, I>
+where
+    S: KVStore
+        + Codec<AppState>
+        + Encode<AppStoreKey>
+        + Encode<BlockHeight>
+        + Codec<FvmStateParams>,
+    DB: KVWritable<S> + KVReadable<S> + 'static + Clone,
+    SS: Blockstore + 'static + Clone,
+    I: QueryInterpreter<State = FvmQueryState<SS>, Query = FvmQuery, Output = FvmQueryRet>,
+{
+    /// Sample the state of an actor at each height of the range which is still in the state history.
+    ///
+    /// The interpreter only sees the state at a single height, so this runs an `ActorState` query
+    /// against each of the historical states in turn.
+    async fn query_actor_state_range(
+        &self,
+        address: Address,
+        range: HeightRange,
+    ) -> Result<response::Query> {
+        if range.len() > MAX_HEIGHT_RANGE_SAMPLES {
+            return Ok(invalid_query(
+                AppError::InvalidQuery,
+                format!(
+                    "The range contains {} heights; at most {MAX_HEIGHT_RANGE_SAMPLES} can be sampled.",
+                    range.len()
+                ),
+            ));
+        }
+
+        let mut samples = Vec::new();
+
+        for height in range.heights() {
+            let state_params = {
+                let tx = self.db.read();
+                self.state_hist
+                    .get(&tx, &height)
+                    .context("error looking up history")?
+            };
+
+            // Pruned heights and ones that haven't been reached yet are left out.
+            let Some(state_params) = state_params else {
+                continue;
+            };
+
+            if !Self::can_query_state(height, &state_params) {
+                continue;
+            }
+
+            let state = FvmQueryState::new(
+                self.state_store_clone(),
+                self.multi_engine.clone(),
+                height.try_into()?,
+                state_params,
+                self.check_state.clone(),
+                false,
+            )
+            .context("error creating query state")?;
+
+            let (_, result) = self
+                .interpreter
+                .query(state, FvmQuery::ActorState(address))
+                .await
+                .context("error running query")?;
+
+            let state = match result {
+                FvmQueryRet::ActorState(state) => state.map(|s| *s),
+                _ => bail!("unexpected result for an actor state query"),
+            };
+
+            samples.push(ActorStateSample { height, state });
+        }
+
+        tracing::debug!(
+            addr = address.to_string(),
+            from = range.from,
+            to = range.to,
+            samples = samples.len(),
+            "query actor state range"
+        );
+
+        let block_height = self.committed_state()?.block_height;
+
+        to_query(FvmQueryRet::ActorStateRange(samples), block_height)
+    }
+}
+
 // NOTE: The `Application` interface doesn't allow failures at the moment. The protobuf
 // of `Response` actually has an `Exception` type, so in theory we could use that, and
 // Tendermint would break up the connection. However, before the response could reach it,
@@ -485,11 +664,7 @@ where
         Message = Vec<u8>,
         Output = BytesMessageCheckRes,
     >,
-    I: QueryInterpreter<
-        State = FvmQueryState<SS>,
-        Query = BytesMessageQuery,
-        Output = BytesMessageQueryRes,
-    >,
+    I: QueryInterpreter<State = FvmQueryState<SS>, Query = FvmQuery, Output = FvmQueryRet>,
 {
     /// Provide information about the ABCI application.
     async fn info(&self, _request: request::Info) -> AbciResult<response::Info> {
@@ -567,6 +742,36 @@ where
     /// Query the application for data at the current or past height.
     #[instrument(skip(self))]
     async fn query(&self, request: request::Query) -> AbciResult<response::Query> {
+        // Node-local queries which don't depend on the state tree.
+        if request.path == EQUIVOCATIONS_QUERY_PATH {
+            let evidence =
+                atomically(|| self.chain_env.parent_finality_equivocations.evidence()).await;
+            let block_height = self.committed_state()?.block_height;
+            return Ok(to_node_query(&evidence, block_height)?);
+        }
+
+        let qry = match decode_query(&request.path, &request.data) {
+            Err(e) => return Ok(invalid_query(AppError::InvalidEncoding, e.description)),
+            Ok(qry) => qry,
+        };
+
+        match qry {
+            // Range queries need more than one state, which the interpreter doesn't have access to.
+            FvmQuery::ActorStateRange { address, range } => {
+                return Ok(self.query_actor_state_range(address, range).await?);
+            }
+            FvmQuery::CallBatch(ref msgs) if msgs.len() > MAX_CALL_BATCH_SIZE => {
+                return Ok(invalid_query(
+                    AppError::InvalidQuery,
+                    format!(
+                        "The batch contains {} messages; at most {MAX_CALL_BATCH_SIZE} can be executed.",
+                        msgs.len()
+                    ),
+                ));
+            }
+            _ => {}
+        }
+
         let db = self.state_store_clone();
         let height = FvmQueryHeight::from(request.height.value());
         let (state_params, block_height) = self.state_params_at_height(height)?;
@@ -596,19 +801,13 @@ where
         )
         .context("error creating query state")?;
 
-        let qry = (request.path, request.data.to_vec());
-
         let (_, result) = self
             .interpreter
             .query(state, qry)
             .await
             .context("error running query")?;
 
-        let response = match result {
-            Err(e) => invalid_query(AppError::InvalidEncoding, e.description),
-            Ok(result) => to_query(result, block_height)?,
-        };
-        Ok(response)
+        Ok(to_query(result, block_height)?)
     }
 
     /// Check the given transaction before putting it into the local mempool.
@@ -896,6 +1095,7 @@ where
         state.block_height = exec_state.block_height().try_into()?;
         state.state_params.timestamp = exec_state.timestamp();
 
+        // Garbage collection has to see the new root until it's in the committed state.
         let (
             state_root,
             FvmUpdatableParams {
@@ -904,8 +1104,10 @@ where
                 circ_supply,
                 power_scale,
             },
-            _,
-        ) = exec_state.commit().context("failed to commit FVM")?;
+        ) = self.pending_state_root.flush(|| {
+            let (state_root, params, _) = exec_state.commit().context("failed to commit FVM")?;
+            Ok((state_root, params))
+        })?;
 
         state.state_params.state_root = state_root;
         state.state_params.app_version = app_version;
@@ -917,10 +1119,11 @@ where
         let block_height = state.block_height;
 
         // Tell CometBFT how much of the block history it can forget.
-        let retain_height = if self.state_hist_size == 0 {
+        let state_hist_size = self.state_hist_size();
+        let retain_height = if state_hist_size == 0 {
             Default::default()
         } else {
-            block_height.saturating_sub(self.state_hist_size)
+            block_height.saturating_sub(state_hist_size)
         };
 
         tracing::debug!(
@@ -966,6 +1169,7 @@ where
 
         // Commit app state to the datastore.
         self.set_committed_state(state)?;
+        self.pending_state_root.clear();
 
         // Reset check state.
         let mut guard = self.check_state.lock().await;
@@ -985,7 +1189,9 @@ where
     /// List the snapshots available on this node to be served to remote peers.
     async fn list_snapshots(&self) -> AbciResult<response::ListSnapshots> {
         if let Some(ref client) = self.snapshots {
-            let snapshots = atomically(|| client.list_snapshots()).await;
+            let mut snapshots = atomically(|| client.list_snapshots()).await;
+            // Deltas are useless to peers who don't have their base yet.
+            snapshots.retain(|s| !s.manifest.is_delta());
             tracing::info!(snapshot_count = snapshots.len(), "listing snaphots");
             Ok(to_snapshots(snapshots)?)
         } else {
@@ -1035,6 +1241,7 @@ where
             match from_snapshot(request).context("failed to parse snapshot") {
                 Ok(manifest) => {
                     tracing::info!(?manifest, "received snapshot offer");
+
                     // We can look at the version but currently there's only one.
                     match atomically_or_err(|| client.offer_snapshot(manifest.clone())).await {
                         Ok(path) => {
@@ -1045,6 +1252,11 @@ where
                                 chunks = manifest.chunks,
                                 "downloading snapshot"
                             );
+                            // Don't rely on the hash in the offer alone; check it against a verified
+                            // header while the chunks are downloaded, before the snapshot is imported.
+                            client
+                                .verify_download(to_app_hash(&manifest.state_params))
+                                .await;
                             return Ok(response::OfferSnapshot::Accept);
                         }
                         Err(SnapshotError::IncompatibleVersion(version)) => {
@@ -1088,6 +1300,17 @@ where
                             "received all snapshot chunks",
                         );
 
+                        if let Err(e) = atomically_or_err(|| client.download_verified()).await {
+                            tracing::warn!(
+                                height = snapshot.manifest.block_height,
+                                "rejecting snapshot: {e}"
+                            );
+                            return Ok(response::ApplySnapshotChunk {
+                                result: response::ApplySnapshotChunkResult::RejectSnapshot,
+                                ..default
+                            });
+                        }
+
                         // Ideally we would import into some isolated store then validate,
                         // but for now let's trust that all is well.
                         if let Err(e) = snapshot.import(self.state_store_clone(), true).await {
@@ -1104,12 +1327,10 @@ where
                         );
 
                         // Now insert the new state into the history.
-                        let mut state = self.committed_state()?;
-
-                        // The height reflects that it was produced in `commit`.
-                        state.block_height = snapshot.manifest.block_height;
-                        state.state_params = snapshot.manifest.state_params;
-                        self.set_committed_state(state)?;
+                        self.import_snapshot_state(
+                            snapshot.manifest.block_height,
+                            snapshot.manifest.state_params,
+                        )?;
 
                         // TODO: We can remove the `current_download` from the STM
                         // state here which would cause it to get dropped from /tmp,
@@ -7,6 +7,8 @@ use fendermint_app_options::debug::{
     DebugArgs, DebugCommands, DebugExportTopDownEventsArgs, DebugIpcCommands,
 };
 use fendermint_vm_topdown::proxy::IPCProviderProxy;
+use fvm_shared::address::Address;
+use ipc_api::subnet_id::SubnetID;
 use ipc_provider::{
     config::subnet::{EVMSubnet, SubnetConfig},
     IpcProvider,
@@ -14,10 +16,20 @@ use ipc_provider::{
 
 use crate::cmd;
 
+mod db;
+mod export;
+mod replay;
+mod resolver;
+mod state;
+
 cmd! {
   DebugArgs(self) {
     match &self.command {
         DebugCommands::Ipc { command } => command.exec(()).await,
+        DebugCommands::State { command } => command.exec(()).await,
+        DebugCommands::Replay(args) => replay::replay(args).await,
+        DebugCommands::Db { command } => command.exec(()).await,
+        DebugCommands::Resolver(args) => resolver::print_resolver_info(args).await,
     }
   }
 }
@@ -34,28 +46,14 @@ cmd! {
 }
 
 async fn export_topdown_events(args: &DebugExportTopDownEventsArgs) -> anyhow::Result<()> {
-    // Configuration for the child subnet on the parent network,
-    // based on how it's done in `run.rs` and the `genesis ipc from-parent` command.
-    let parent_provider = IpcProvider::new_with_subnet(
-        None,
-        ipc_provider::config::Subnet {
-            id: args
-                .subnet_id
-                .parent()
-                .ok_or_else(|| anyhow!("subnet is not a child"))?,
-            config: SubnetConfig::Fevm(EVMSubnet {
-                provider_http: args.parent_endpoint.clone(),
-                provider_timeout: None,
-                auth_token: args.parent_auth_token.clone(),
-                registry_addr: args.parent_registry,
-                gateway_addr: args.parent_gateway,
-            }),
-        },
+    let parent_proxy = parent_proxy(
+        &args.subnet_id,
+        &args.parent_endpoint,
+        args.parent_auth_token.clone(),
+        args.parent_gateway,
+        args.parent_registry,
     )?;
 
-    let parent_proxy = IPCProviderProxy::new(parent_provider, args.subnet_id.clone())
-        .context("failed to create provider proxy")?;
-
     let events = fendermint_vm_topdown::sync::fetch_topdown_events(
         &parent_proxy,
         args.start_block_height,
@@ -69,3 +67,33 @@ async fn export_topdown_events(args: &DebugExportTopDownEventsArgs) -> anyhow::R
 
     Ok(())
 }
+
+/// Proxy to query the parent about a child subnet.
+fn parent_proxy(
+    subnet_id: &SubnetID,
+    parent_endpoint: &url::Url,
+    parent_auth_token: Option<String>,
+    parent_gateway: Address,
+    parent_registry: Address,
+) -> anyhow::Result<IPCProviderProxy> {
+    // Configuration for the child subnet on the parent network,
+    // based on how it's done in `run.rs` and the `genesis ipc from-parent` command.
+    let parent_provider = IpcProvider::new_with_subnet(
+        None,
+        ipc_provider::config::Subnet {
+            id: subnet_id
+                .parent()
+                .ok_or_else(|| anyhow!("subnet is not a child"))?,
+            config: SubnetConfig::Fevm(EVMSubnet {
+                provider_http: parent_endpoint.clone(),
+                provider_timeout: None,
+                auth_token: parent_auth_token,
+                registry_addr: parent_registry,
+                gateway_addr: parent_gateway,
+            }),
+        },
+    )?;
+
+    IPCProviderProxy::new(parent_provider, subnet_id.clone())
+        .context("failed to create provider proxy")
+}
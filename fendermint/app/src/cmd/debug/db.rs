@@ -0,0 +1,665 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Integrity check of the blocks in the database of a node, their repair, and disk usage statistics.
+//!
+//! Blocks are written to RocksDB without the write-ahead log, so after an unclean shutdown
+//! the state committed in the application store can refer to blocks which never made it to disk.
+
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use fendermint_app::ipc::AppVote;
+use fendermint_app::BitswapBlockstore;
+use fendermint_app_options::debug::{DebugDbCheckArgs, DebugDbCommands, DebugDbStatsArgs};
+use fendermint_rocksdb::blockstore::NamespaceBlockstore;
+use fendermint_rocksdb::{ReadOnlyRocksDb, RocksDb, RocksDbConfig};
+use fendermint_vm_snapshot::{import_chain, read_archive};
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::{from_slice, DAG_CBOR};
+use fvm_shared::IDENTITY_HASH;
+use ipc_ipld_resolver::{
+    AddressBookConfig, Client, Config, ConnectionConfig, ContentConfig, DiscoveryConfig,
+    ErasureConfig, MembershipConfig, NatConfig, NetworkConfig, Resolver, ScoringConfig,
+};
+use libipld::Ipld;
+use libp2p::identity::Keypair;
+use serde_json::json;
+
+use super::state::{ensure_rocksdb, StateDb};
+use crate::cmd;
+use crate::cmd::Namespaces;
+
+/// Time to wait between attempts to resolve a block from peers.
+const RESOLVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// RocksDB properties reported for each column family.
+const CF_PROPERTIES: [(&str, &str); 3] = [
+    ("sst_files_size", "rocksdb.total-sst-files-size"),
+    ("live_data_size", "rocksdb.estimate-live-data-size"),
+    ("num_keys", "rocksdb.estimate-num-keys"),
+];
+
+cmd! {
+  DebugDbCommands(self) {
+    match self {
+        DebugDbCommands::Check(args) => check(args).await,
+        DebugDbCommands::Stats(args) => stats(args),
+    }
+  }
+}
+
+/// Outcome of walking the DAGs under some roots.
+#[derive(Debug, Default)]
+struct DagCheck {
+    /// Number of distinct blocks found.
+    blocks: usize,
+    /// Blocks referenced but not in the store.
+    missing: Vec<Cid>,
+    /// Blocks whose contents don't match the hash in their CID.
+    corrupt: Vec<Cid>,
+}
+
+impl DagCheck {
+    fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let cids = |cids: &[Cid]| cids.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        json!({
+            "blocks": self.blocks,
+            "missing": cids(&self.missing),
+            "corrupt": cids(&self.corrupt),
+        })
+    }
+}
+
+async fn check(args: &DebugDbCheckArgs) -> anyhow::Result<()> {
+    // Release the read-only handle before the database is opened for writing.
+    let (roots, before) = {
+        let db = StateDb::open(&args.db.db_dir, args.db.cold_dir.as_deref())?;
+
+        let roots = if args.all {
+            db.retained_state_roots()?
+        } else {
+            let (height, params) = db.state_params(None)?;
+            vec![(height, params.state_root)]
+        };
+
+        let before = check_dags(&db.store, roots.iter().map(|(_, root)| *root))?;
+
+        (roots, before)
+    };
+
+    let mut report = json!({
+        "roots": roots
+            .iter()
+            .map(|(height, root)| json!({"height": height, "state_root": root.to_string()}))
+            .collect::<Vec<_>>(),
+        "check": before.to_json(),
+    });
+
+    let repair = args.repair_from_snapshot.is_some() || !args.repair_from_peer.is_empty();
+
+    let outcome = if repair && !before.is_ok() {
+        let store = open_store(&args.db.db_dir, args.db.cold_dir.as_deref())?;
+
+        // Corrupt blocks would be considered present, so they wouldn't be fetched again.
+        for cid in before.corrupt.iter() {
+            store
+                .delete(cid)
+                .with_context(|| format!("failed to delete corrupt block {cid}"))?;
+        }
+
+        let restored = if let Some(ref archive) = args.repair_from_snapshot {
+            repair_from_snapshot(archive, store.clone()).await?
+        } else {
+            let wanted = before
+                .missing
+                .iter()
+                .chain(before.corrupt.iter())
+                .cloned()
+                .collect::<Vec<_>>();
+
+            repair_from_peers(args, store.clone(), wanted).await?
+        };
+
+        let after = check_dags(&store, roots.iter().map(|(_, root)| *root))?;
+
+        report["repair"] = json!({
+            "restored": restored,
+            "check": after.to_json(),
+        });
+
+        after
+    } else {
+        before
+    };
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if !outcome.is_ok() {
+        bail!(
+            "the database has {} missing and {} corrupt blocks",
+            outcome.missing.len(),
+            outcome.corrupt.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn stats(args: &DebugDbStatsArgs) -> anyhow::Result<()> {
+    let db = StateDb::open(&args.db.db_dir, args.db.cold_dir.as_deref())?;
+
+    let mut column_families = serde_json::Map::new();
+    for ns in Namespaces::default().values() {
+        column_families.insert(ns.to_owned(), cf_stats(&db.db, ns)?);
+    }
+
+    let mut report = json!({ "column_families": column_families });
+
+    if let Some(ref cold_dir) = args.db.cold_dir {
+        let cold = ReadOnlyRocksDb::open(cold_dir, &RocksDbConfig::default())
+            .context("error opening cold store")?;
+        report["cold_store"] = cf_stats(&cold, "default")?;
+    }
+
+    let roots = db.retained_state_roots()?;
+    let sample = &roots[roots.len().saturating_sub(args.sample_heights.max(1))..];
+    let growth = state_growth(&db.store, sample)?;
+
+    let projections = args
+        .retention
+        .iter()
+        .map(|size| {
+            let (blocks, bytes) = growth.project(*size);
+            json!({ "state_hist_size": size, "blocks": blocks, "bytes": bytes })
+        })
+        .collect::<Vec<_>>();
+
+    report["retained"] = json!({
+        "oldest_height": roots.first().map(|(h, _)| h),
+        "latest_height": roots.last().map(|(h, _)| h),
+        "states": roots.len(),
+    });
+    report["sample"] = growth.to_json();
+    report["projections"] = json!(projections);
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+fn cf_stats(db: &ReadOnlyRocksDb, name: &str) -> anyhow::Result<serde_json::Value> {
+    let mut stats = serde_json::Map::new();
+    for (key, property) in CF_PROPERTIES {
+        let value = db
+            .property_int_value_cf(name, property)
+            .with_context(|| format!("failed to read {property} of {name}"))?;
+        stats.insert(key.to_owned(), json!(value));
+    }
+    Ok(stats.into())
+}
+
+/// Number and size of the blocks in the states of consecutive retained heights.
+#[derive(Debug, Default)]
+struct StateGrowth {
+    /// Blocks of the oldest state in the sample.
+    base_blocks: u64,
+    base_bytes: u64,
+    /// Blocks which were not in any of the states before, by height.
+    heights: Vec<(u64, u64, u64)>,
+}
+
+impl StateGrowth {
+    /// Number of heights the sample spans after the first one.
+    fn span(&self) -> u64 {
+        match (self.heights.first(), self.heights.last()) {
+            (Some((first, _, _)), Some((last, _, _))) if last > first => last - first,
+            _ => 0,
+        }
+    }
+
+    /// Average number of new blocks and bytes per height.
+    fn per_height(&self) -> (f64, f64) {
+        let span = self.span();
+        if span == 0 {
+            return (0.0, 0.0);
+        }
+        let (blocks, bytes) = self
+            .heights
+            .iter()
+            .skip(1)
+            .fold((0, 0), |(a, b), (_, blocks, bytes)| (a + blocks, b + bytes));
+        (blocks as f64 / span as f64, bytes as f64 / span as f64)
+    }
+
+    /// Estimated number of unique blocks and their bytes when `size` heights are retained.
+    ///
+    /// Every retained height keeps the blocks it added alive, on top of a full state.
+    /// Zero means unlimited history, which can't be projected.
+    fn project(&self, size: u64) -> (Option<u64>, Option<u64>) {
+        if size == 0 {
+            return (None, None);
+        }
+        let (blocks, bytes) = self.per_height();
+        let extra = (size - 1) as f64;
+        (
+            Some(self.base_blocks + (blocks * extra).round() as u64),
+            Some(self.base_bytes + (bytes * extra).round() as u64),
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let (blocks, bytes) = self.per_height();
+        json!({
+            "base_blocks": self.base_blocks,
+            "base_bytes": self.base_bytes,
+            "new_blocks_per_height": blocks,
+            "new_bytes_per_height": bytes,
+            "heights": self
+                .heights
+                .iter()
+                .skip(1)
+                .map(|(height, blocks, bytes)| json!({
+                    "height": height,
+                    "new_blocks": blocks,
+                    "new_bytes": bytes,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Walk the states oldest first, counting the blocks each one adds to the ones before it.
+fn state_growth<BS: Blockstore>(store: &BS, roots: &[(u64, Cid)]) -> anyhow::Result<StateGrowth> {
+    let mut growth = StateGrowth::default();
+    let mut visited = HashSet::new();
+
+    for (i, (height, root)) in roots.iter().enumerate() {
+        let (blocks, bytes) = walk_new_blocks(store, *root, &mut visited)?;
+        if i == 0 {
+            growth.base_blocks = blocks;
+            growth.base_bytes = bytes;
+        }
+        growth.heights.push((*height, blocks, bytes));
+    }
+
+    Ok(growth)
+}
+
+/// Count the blocks under a root which are not in the visited set yet, and their total size.
+///
+/// Missing blocks are skipped; `check` is the command to find those.
+fn walk_new_blocks<BS: Blockstore>(
+    store: &BS,
+    root: Cid,
+    visited: &mut HashSet<Cid>,
+) -> anyhow::Result<(u64, u64)> {
+    let mut blocks = 0;
+    let mut bytes = 0;
+
+    walk_dags(store, [root], visited, |_, data| match data {
+        Some(data) => {
+            blocks += 1;
+            bytes += data.len() as u64;
+            true
+        }
+        None => false,
+    })?;
+
+    Ok((blocks, bytes))
+}
+
+/// Walk the DAGs under the roots, checking that every block is present and matches its CID.
+///
+/// Blocks shared between the roots are only visited once. The links of a corrupt block are not followed.
+fn check_dags<BS: Blockstore>(
+    store: &BS,
+    roots: impl IntoIterator<Item = Cid>,
+) -> anyhow::Result<DagCheck> {
+    let mut result = DagCheck::default();
+
+    walk_dags(store, roots, &mut HashSet::new(), |cid, bytes| {
+        let Some(bytes) = bytes else {
+            result.missing.push(cid);
+            return false;
+        };
+
+        result.blocks += 1;
+
+        // Hashes we can't compute are taken at face value.
+        if let Ok(code) = Code::try_from(cid.hash().code()) {
+            if code.digest(bytes) != *cid.hash() {
+                result.corrupt.push(cid);
+                return false;
+            }
+        }
+
+        true
+    })?;
+
+    Ok(result)
+}
+
+/// Walk the DAGs under the roots breadth-first, visiting each block which isn't in `visited` yet.
+///
+/// The visitor gets the contents of the block, or `None` if it's missing, and returns whether
+/// to follow its links. Only DAG-CBOR blocks have links; identity CIDs are skipped.
+fn walk_dags<BS, F>(
+    store: &BS,
+    roots: impl IntoIterator<Item = Cid>,
+    visited: &mut HashSet<Cid>,
+    mut visit: F,
+) -> anyhow::Result<()>
+where
+    BS: Blockstore,
+    F: FnMut(Cid, Option<&[u8]>) -> bool,
+{
+    let mut queue = roots.into_iter().collect::<VecDeque<_>>();
+
+    while let Some(cid) = queue.pop_front() {
+        if cid.hash().code() == IDENTITY_HASH || !visited.insert(cid) {
+            continue;
+        }
+
+        let data = store.get(&cid)?;
+
+        if !visit(cid, data.as_deref()) {
+            continue;
+        }
+
+        if let Some(data) = data {
+            if cid.codec() == DAG_CBOR {
+                let ipld = from_slice::<Ipld>(&data)
+                    .with_context(|| format!("failed to decode block {cid}"))?;
+                push_links(ipld, &mut queue);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn push_links(ipld: Ipld, queue: &mut VecDeque<Cid>) {
+    match ipld {
+        Ipld::List(v) => {
+            for i in v {
+                push_links(i, queue);
+            }
+        }
+        Ipld::Map(map) => {
+            for v in map.into_values() {
+                push_links(v, queue);
+            }
+        }
+        Ipld::Link(cid) => queue.push_back(cid),
+        _ => {}
+    }
+}
+
+/// Open the state store for writing; this fails if the node is running.
+fn open_store(db_dir: &Path, cold_dir: Option<&Path>) -> anyhow::Result<NamespaceBlockstore> {
+    ensure_rocksdb(db_dir)?;
+
+    let ns = Namespaces::default();
+    let config = RocksDbConfig::default();
+
+    let db = RocksDb::open_cf(db_dir, &config, ns.values().iter())
+        .context("error opening DB for writing; is the node still running?")?;
+
+    let store =
+        NamespaceBlockstore::new(db, ns.state_store.clone()).context("error creating state DB")?;
+
+    match cold_dir {
+        Some(cold_dir) => {
+            let cold = RocksDb::open(cold_dir, &config).context("error opening cold store")?;
+            Ok(store.with_cold_store(cold))
+        }
+        None => Ok(store),
+    }
+}
+
+/// A [Blockstore] which only writes the blocks which are not already in the wrapped store,
+/// counting how many it restored.
+#[derive(Clone)]
+struct RepairBlockstore<BS> {
+    inner: BS,
+    restored: Arc<AtomicUsize>,
+}
+
+impl<BS: Blockstore> Blockstore for RepairBlockstore<BS> {
+    fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.get(k)
+    }
+
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+        if self.inner.has(k)? {
+            return Ok(());
+        }
+        self.restored.fetch_add(1, Ordering::Relaxed);
+        self.inner.put_keyed(k, block)
+    }
+}
+
+/// Import every block of a snapshot archive which is not in the store.
+///
+/// Returns the number of blocks restored.
+async fn repair_from_snapshot(archive: &Path, store: NamespaceBlockstore) -> anyhow::Result<usize> {
+    let unpack_dir = tempfile::tempdir().context("failed to create temp dir")?;
+    let chain = read_archive(archive, unpack_dir.path())?;
+
+    let store = RepairBlockstore {
+        inner: store,
+        restored: Default::default(),
+    };
+
+    // The state roots being repaired are checked afterwards, not the snapshot's.
+    import_chain(&chain, store.clone(), false)
+        .await
+        .context("failed to import snapshots")?;
+
+    Ok(store.restored.load(Ordering::Relaxed))
+}
+
+/// Resolve the DAGs under the wanted blocks from peers, using a transient IPLD Resolver.
+///
+/// Returns the number of roots which were resolved.
+async fn repair_from_peers(
+    args: &DebugDbCheckArgs,
+    store: NamespaceBlockstore,
+    wanted: Vec<Cid>,
+) -> anyhow::Result<usize> {
+    let subnet_id = args
+        .subnet_id
+        .clone()
+        .ok_or_else(|| anyhow!("the subnet ID is needed to resolve blocks from peers"))?;
+
+    let config = Config {
+        connection: ConnectionConfig {
+            listen_addr: "/ip4/0.0.0.0/tcp/0".parse()?,
+            quic_listen_addr: None,
+            external_addresses: Vec::new(),
+            max_incoming: 10,
+            expected_peer_count: 1000,
+            max_peers_per_query: 5,
+            event_buffer_capacity: 100,
+        },
+        network: NetworkConfig {
+            local_key: Keypair::generate_secp256k1(),
+            network_name: format!(
+                "ipld-resolver-{}-{}",
+                subnet_id.root_id(),
+                args.network_name
+            ),
+        },
+        discovery: DiscoveryConfig {
+            static_addresses: args.repair_from_peer.clone(),
+            target_connections: args.repair_from_peer.len(),
+            enable_kademlia: false,
+        },
+        membership: MembershipConfig {
+            static_subnets: Vec::new(),
+            max_subnets: 10,
+            publish_interval: Duration::from_secs(60),
+            min_time_between_publish: Duration::from_secs(5),
+            max_provider_age: Duration::from_secs(300),
+        },
+        content: ContentConfig {
+            rate_limit_bytes: 0,
+            rate_limit_period: Duration::from_secs(0),
+            graph_max_response_bytes: 10 << 20,
+            // Nothing is provided while repairing.
+            max_provided_bytes: 0,
+            max_provided_cids: 0,
+            max_provide_ttl: Duration::from_secs(0),
+            max_resolved_bytes: 0,
+            egress_bytes: 0,
+            egress_period: Duration::from_secs(0),
+            subnet_egress_bytes: Default::default(),
+            priority_subnets: vec![],
+        },
+        // The peers were picked by the operator, so never ban them.
+        scoring: ScoringConfig {
+            ban_threshold: f64::MIN,
+            ban_duration: Duration::from_secs(0),
+            decay_half_life: Duration::from_secs(0),
+        },
+        // Allow the peers to be given with relayed addresses, in case they are behind NAT.
+        nat: NatConfig {
+            enable_autonat: false,
+            enable_relay_server: false,
+            enable_relay_client: true,
+            relay_addresses: vec![],
+            enable_hole_punching: false,
+            relay_max_circuit_duration: Duration::from_secs(0),
+            relay_max_circuit_bytes: 0,
+        },
+        // The repair is a one-off, there's nothing worth remembering.
+        address_book: AddressBookConfig {
+            path: None,
+            max_age: Duration::from_secs(0),
+            save_interval: Duration::from_secs(60),
+        },
+        erasure: ErasureConfig {
+            enabled: false,
+            data_chunks: 0,
+            parity_chunks: 0,
+            max_bytes: 0,
+            chunk_ttl: Duration::from_secs(0),
+        },
+    };
+
+    // Bitswap writes the resolved blocks straight into the state store.
+    let bitswap_store = BitswapBlockstore::new(store.clone(), store);
+
+    let service =
+        ipc_ipld_resolver::Service::<libipld::DefaultParams, AppVote>::new(config, bitswap_store)
+            .context("error creating IPLD Resolver Service")?;
+
+    let client: Client<AppVote> = service.client();
+    let service = tokio::spawn(async move { service.run().await });
+
+    let deadline = Instant::now() + Duration::from_secs(args.repair_timeout);
+    let mut resolved = 0;
+
+    // Peers only become providers of the subnet once they published their membership,
+    // so failures are retried until the deadline.
+    for cid in wanted {
+        loop {
+            match client.resolve(cid, subnet_id.clone()).await? {
+                Ok(()) => {
+                    resolved += 1;
+                    break;
+                }
+                Err(e) if Instant::now() + RESOLVE_RETRY_DELAY < deadline => {
+                    tracing::debug!(%cid, error = e.to_string(), "failed to resolve block; retrying");
+                    tokio::time::sleep(RESOLVE_RETRY_DELAY).await;
+                }
+                Err(e) => {
+                    tracing::warn!(%cid, error = e.to_string(), "failed to resolve block");
+                    break;
+                }
+            }
+        }
+    }
+
+    service.abort();
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fvm_ipld_blockstore::{Blockstore, MemoryBlockstore};
+    use fvm_ipld_encoding::{to_vec, DAG_CBOR, IPLD_RAW};
+    use libipld::Ipld;
+
+    use super::{check_dags, state_growth};
+
+    fn put_ipld(store: &impl Blockstore, ipld: &Ipld) -> Cid {
+        let bytes = to_vec(ipld).unwrap();
+        let cid = Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(&bytes));
+        store.put_keyed(&cid, &bytes).unwrap();
+        cid
+    }
+
+    #[test]
+    fn check_finds_missing_and_corrupt_blocks() {
+        let store = MemoryBlockstore::new();
+
+        let missing = Cid::new_v1(IPLD_RAW, Code::Blake2b256.digest(b"missing"));
+        let corrupt = Cid::new_v1(IPLD_RAW, Code::Blake2b256.digest(b"corrupt"));
+        store.put_keyed(&corrupt, b"tampered").unwrap();
+
+        let leaf = put_ipld(&store, &Ipld::List(vec![Ipld::Link(missing)]));
+        let root1 = put_ipld(
+            &store,
+            &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(corrupt)]),
+        );
+        let root2 = put_ipld(&store, &Ipld::List(vec![Ipld::Link(leaf)]));
+
+        let check = check_dags(&store, [root1, root2]).unwrap();
+
+        assert_eq!(check.blocks, 4);
+        assert_eq!(check.missing, vec![missing]);
+        assert_eq!(check.corrupt, vec![corrupt]);
+
+        store.put_keyed(&missing, b"missing").unwrap();
+        store.put_keyed(&corrupt, b"corrupt").unwrap();
+
+        assert!(check_dags(&store, [root1, root2]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn growth_counts_new_blocks_per_height() {
+        let store = MemoryBlockstore::new();
+
+        let shared = put_ipld(&store, &Ipld::Integer(1));
+        let root1 = put_ipld(&store, &Ipld::List(vec![Ipld::Link(shared)]));
+        let changed = put_ipld(&store, &Ipld::Integer(2));
+        let root2 = put_ipld(
+            &store,
+            &Ipld::List(vec![Ipld::Link(shared), Ipld::Link(changed)]),
+        );
+
+        let growth = state_growth(&store, &[(10, root1), (12, root2)]).unwrap();
+
+        assert_eq!(growth.base_blocks, 2);
+        // The second state adds two blocks over two heights.
+        assert_eq!(growth.per_height().0, 1.0);
+        assert_eq!(growth.project(1).0, Some(2));
+        assert_eq!(growth.project(11).0, Some(12));
+        assert_eq!(growth.project(0), (None, None));
+    }
+}
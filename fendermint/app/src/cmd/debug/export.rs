@@ -0,0 +1,472 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Export the actors of a state into files for offline analysis.
+//!
+//! The state tree is walked one actor at a time and every record is written out as it's produced,
+//! so the export works on states which don't fit in memory.
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use fendermint_app_options::debug::{DebugStateExportArgs, ExportFormat};
+use fendermint_vm_interpreter::fvm::state::inspect::{u256_to_hex, ActorRecord};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use serde_json::json;
+
+use super::state::StateDb;
+
+/// Number of rows buffered before they are handed to the Parquet writer as a batch.
+const PARQUET_BATCH_ROWS: usize = 8192;
+
+/// Maximum number of rows in a Parquet row group, which the writer keeps in memory until it's full.
+const PARQUET_ROW_GROUP_ROWS: usize = 128 * 1024;
+
+/// Name of the table for actors whose kind is not known.
+const UNKNOWN_KIND: &str = "unknown";
+
+/// A storage slot of an EVM contract.
+#[derive(Serialize, Debug, Clone)]
+struct StorageRecord {
+    id: u64,
+    slot: String,
+    value: String,
+}
+
+/// Destination of the rows of a table.
+trait RowSink<R> {
+    fn write(&mut self, row: R) -> anyhow::Result<()>;
+
+    /// Flush the rest of the rows and close the file, returning the number of rows written.
+    fn finish(self: Box<Self>) -> anyhow::Result<usize>;
+}
+
+/// Conversion of records to Arrow columns.
+trait ArrowRows: Sized {
+    fn schema() -> SchemaRef;
+    fn to_batch(rows: &[Self]) -> anyhow::Result<RecordBatch>;
+}
+
+impl ArrowRows for ActorRecord {
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt64, false),
+            Field::new("kind", DataType::Utf8, true),
+            Field::new("ipc_contract", DataType::Utf8, true),
+            Field::new("code", DataType::Utf8, false),
+            Field::new("state", DataType::Utf8, false),
+            Field::new("sequence", DataType::UInt64, false),
+            Field::new("balance", DataType::Utf8, false),
+            Field::new("delegated_address", DataType::Utf8, true),
+            Field::new("address", DataType::Utf8, true),
+            Field::new("bytecode_hash", DataType::Utf8, true),
+            Field::new("storage_slots", DataType::UInt64, true),
+        ]))
+    }
+
+    fn to_batch(rows: &[Self]) -> anyhow::Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.id))),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.kind.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.ipc_contract.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.code))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.state))),
+            Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|r| r.sequence),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| &r.balance),
+            )),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.delegated_address.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.address.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.bytecode_hash.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.storage_slots)
+                    .collect::<UInt64Array>(),
+            ),
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns)?)
+    }
+}
+
+impl ArrowRows for StorageRecord {
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt64, false),
+            Field::new("slot", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, false),
+        ]))
+    }
+
+    fn to_batch(rows: &[Self]) -> anyhow::Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.id))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.slot))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.value))),
+        ];
+        Ok(RecordBatch::try_new(Self::schema(), columns)?)
+    }
+}
+
+struct JsonlSink {
+    writer: BufWriter<File>,
+    rows: usize,
+}
+
+impl<R: Serialize> RowSink<R> for JsonlSink {
+    fn write(&mut self, row: R) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, &row)?;
+        self.writer.write_all(b"\n")?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<usize> {
+        self.writer.flush()?;
+        Ok(self.rows)
+    }
+}
+
+struct ParquetSink<R> {
+    writer: ArrowWriter<File>,
+    buffer: Vec<R>,
+    rows: usize,
+}
+
+impl<R: ArrowRows> ParquetSink<R> {
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if !self.buffer.is_empty() {
+            let batch = R::to_batch(&self.buffer)?;
+            self.writer.write(&batch)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<R: ArrowRows> RowSink<R> for ParquetSink<R> {
+    fn write(&mut self, row: R) -> anyhow::Result<()> {
+        self.buffer.push(row);
+        self.rows += 1;
+        if self.buffer.len() >= PARQUET_BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<usize> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(self.rows)
+    }
+}
+
+/// Create the file of a table.
+fn open_sink<R>(
+    format: ExportFormat,
+    dir: &Path,
+    table: &str,
+) -> anyhow::Result<Box<dyn RowSink<R>>>
+where
+    R: ArrowRows + Serialize + 'static,
+{
+    let ext = match format {
+        ExportFormat::Jsonl => "jsonl",
+        ExportFormat::Parquet => "parquet",
+    };
+    let path = dir.join(format!("{table}.{ext}"));
+    let file = File::create(&path)
+        .with_context(|| format!("failed to create {}", path.to_string_lossy()))?;
+
+    match format {
+        ExportFormat::Jsonl => Ok(Box::new(JsonlSink {
+            writer: BufWriter::new(file),
+            rows: 0,
+        })),
+        ExportFormat::Parquet => {
+            let props = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .set_max_row_group_size(PARQUET_ROW_GROUP_ROWS)
+                .build();
+            let writer = ArrowWriter::try_new(file, R::schema(), Some(props))?;
+            Ok(Box::new(ParquetSink {
+                writer,
+                buffer: Vec::new(),
+                rows: 0,
+            }))
+        }
+    }
+}
+
+/// The sink of a table, creating its file when the first row is written to it.
+fn table_sink<'a, R>(
+    sinks: &'a mut BTreeMap<String, Box<dyn RowSink<R>>>,
+    table: String,
+    args: &DebugStateExportArgs,
+) -> anyhow::Result<&'a mut Box<dyn RowSink<R>>>
+where
+    R: ArrowRows + Serialize + 'static,
+{
+    match sinks.entry(table) {
+        Entry::Occupied(e) => Ok(e.into_mut()),
+        Entry::Vacant(e) => {
+            let sink = open_sink(args.format, &args.output_dir, e.key())?;
+            Ok(e.insert(sink))
+        }
+    }
+}
+
+/// Write every actor to the table of its kind, and the storage of the IPC contracts to tables of their own.
+pub(super) fn export_state(args: &DebugStateExportArgs) -> anyhow::Result<()> {
+    let db = StateDb::open(&args.db.db_dir, args.db.cold_dir.as_deref())?;
+    let (height, params) = db.state_params(args.height)?;
+    let inspector = db.inspector(&params.state_root)?;
+
+    fs::create_dir_all(&args.output_dir).context("failed to create output directory")?;
+
+    let mut actor_sinks: BTreeMap<String, Box<dyn RowSink<ActorRecord>>> = BTreeMap::new();
+    let mut storage_sinks: BTreeMap<String, Box<dyn RowSink<StorageRecord>>> = BTreeMap::new();
+
+    inspector.for_each_actor(|id, actor| {
+        let record = inspector
+            .actor_record(id, actor)
+            .with_context(|| format!("failed to export actor {id}"))?;
+
+        if let Some(ref contract) = record.ipc_contract {
+            let table = format!("{contract}_storage");
+            let sink = table_sink(&mut storage_sinks, table, args)?;
+            inspector.for_each_evm_storage(actor, |slot, value| {
+                sink.write(StorageRecord {
+                    id,
+                    slot: u256_to_hex(slot),
+                    value: u256_to_hex(value),
+                })
+            })?;
+        }
+
+        let table = record
+            .ipc_contract
+            .clone()
+            .or_else(|| record.kind.clone())
+            .unwrap_or_else(|| UNKNOWN_KIND.to_owned());
+
+        table_sink(&mut actor_sinks, table, args)?.write(record)
+    })?;
+
+    let mut tables = serde_json::Map::new();
+    for (table, sink) in actor_sinks {
+        tables.insert(table, json!(sink.finish()?));
+    }
+    for (table, sink) in storage_sinks {
+        tables.insert(table, json!(sink.finish()?));
+    }
+
+    let json = json!({
+        "height": height,
+        "state_root": params.state_root.to_string(),
+        "tables": tables,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    use arrow_array::{Array, RecordBatch, StringArray, UInt64Array};
+    use fendermint_app_options::debug::{DebugStateDbArgs, DebugStateExportArgs, ExportFormat};
+    use fendermint_vm_interpreter::fvm::state::inspect::ActorRecord;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::json;
+
+    use super::{export_state, open_sink, StorageRecord, PARQUET_BATCH_ROWS};
+
+    fn actor(id: u64, kind: Option<&str>) -> ActorRecord {
+        ActorRecord {
+            id,
+            kind: kind.map(|k| k.to_owned()),
+            ipc_contract: None,
+            code: format!("code-{id}"),
+            state: format!("state-{id}"),
+            sequence: id * 10,
+            balance: "1000".to_owned(),
+            delegated_address: None,
+            address: kind.map(|_| format!("f0{id}")),
+            bytecode_hash: None,
+            storage_slots: kind.map(|_| id),
+        }
+    }
+
+    fn storage(id: u64, i: usize) -> StorageRecord {
+        StorageRecord {
+            id,
+            slot: format!("0x{i:064x}"),
+            value: format!("0x{:064x}", i * 2),
+        }
+    }
+
+    fn read_parquet(path: PathBuf) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<T>()
+            .unwrap()
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = vec![actor(1, Some("account")), actor(2, None)];
+
+        let mut sink = open_sink::<ActorRecord>(ExportFormat::Jsonl, dir.path(), "actors").unwrap();
+        for record in records.iter().cloned() {
+            sink.write(record).unwrap();
+        }
+        assert_eq!(sink.finish().unwrap(), 2);
+
+        let contents = std::fs::read_to_string(dir.path().join("actors.jsonl")).unwrap();
+        let rows = contents
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        let expected = records
+            .iter()
+            .map(|r| serde_json::to_value(r).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(rows, expected);
+        assert_eq!(rows[1]["kind"], json!(null));
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let records = vec![actor(1, Some("account")), actor(2, None)];
+        let mut sink =
+            open_sink::<ActorRecord>(ExportFormat::Parquet, dir.path(), "actors").unwrap();
+        for record in records.iter().cloned() {
+            sink.write(record).unwrap();
+        }
+        assert_eq!(sink.finish().unwrap(), 2);
+
+        let batches = read_parquet(dir.path().join("actors.parquet"));
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+
+        let ids = column::<UInt64Array>(batch, "id");
+        let kinds = column::<StringArray>(batch, "kind");
+        let codes = column::<StringArray>(batch, "code");
+        let slots = column::<UInt64Array>(batch, "storage_slots");
+
+        assert_eq!(ids.values().to_vec(), vec![1, 2]);
+        assert_eq!(kinds.value(0), "account");
+        assert!(kinds.is_null(1));
+        assert_eq!(codes.value(1), "code-2");
+        assert_eq!(slots.value(0), 1);
+        assert!(slots.is_null(1));
+
+        // More rows than fit in a batch are flushed in several and still all read back.
+        let count = PARQUET_BATCH_ROWS + 10;
+        let mut sink =
+            open_sink::<StorageRecord>(ExportFormat::Parquet, dir.path(), "storage").unwrap();
+        for i in 0..count {
+            sink.write(storage(3, i)).unwrap();
+        }
+        assert_eq!(sink.finish().unwrap(), count);
+
+        let batches = read_parquet(dir.path().join("storage.parquet"));
+        let rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        assert_eq!(rows, count);
+
+        let last = batches.last().unwrap();
+        let values = column::<StringArray>(last, "value");
+        assert_eq!(values.value(values.len() - 1), storage(3, count - 1).value);
+    }
+
+    #[test]
+    fn open_sink_fails_without_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        for format in [ExportFormat::Jsonl, ExportFormat::Parquet] {
+            let err = open_sink::<StorageRecord>(format, &missing, "storage")
+                .err()
+                .expect("should fail to create the file");
+            assert!(err.to_string().contains("failed to create"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn export_fails_without_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let redb = dir.path().join("fendermint.redb");
+        std::fs::write(&redb, b"").unwrap();
+
+        let args = |db_dir: PathBuf| DebugStateExportArgs {
+            db: DebugStateDbArgs {
+                db_dir,
+                cold_dir: None,
+            },
+            height: None,
+            output_dir: dir.path().join("export"),
+            format: ExportFormat::Jsonl,
+        };
+
+        let err = export_state(&args(dir.path().join("rocksdb"))).unwrap_err();
+        assert!(err.to_string().contains("error opening DB"), "{err:#}");
+
+        let err = export_state(&args(redb)).unwrap_err();
+        assert!(err.to_string().contains("redb"), "{err:#}");
+
+        // Nothing is written if the state can't be read.
+        assert!(!dir.path().join("export").exists());
+    }
+}
@@ -0,0 +1,461 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Re-execute a historical block to find out why a node ended up with a different application hash.
+//!
+//! The block is executed through the same [App] as on a live node, but on top of an in-memory
+//! overlay of the pre-state, so the database is never modified.
+//!
+//! Blocks which commit parent finality can only be replayed with access to the parent,
+//! to fetch the top-down messages and validator changes which were executed with it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use async_stm::atomically;
+use cid::Cid;
+use fendermint_abci::Application;
+use fendermint_app::ipc::AppParentFinalityQuery;
+use fendermint_app::{to_app_hash, App, AppConfig, AppState, AppStore, AppStoreKey};
+use fendermint_app_options::debug::DebugReplayArgs;
+use fendermint_storage::{im::InMemoryBackend, KVRead, KVReadable};
+use fendermint_vm_interpreter::{
+    bytes::{BytesMessageInterpreter, ProposalPrepareMode},
+    chain::{ChainEnv, ChainMessageInterpreter, CheckpointPool, TopDownFinalityProvider},
+    fvm::{
+        state::{
+            inspect::{diff_states, StateInspector},
+            snapshot::Snapshot,
+            FvmStateParams,
+        },
+        store::{memory::MemoryBlockstore, overlay::OverlayBlockstore},
+        upgrades::UpgradeScheduler,
+        FvmMessageInterpreter,
+    },
+    signed::SignedMessageInterpreter,
+};
+use fendermint_vm_message::chain::ChainMessage;
+use fendermint_vm_message::ipc::{IpcMessage, ParentFinality};
+use fendermint_vm_snapshot::{import_chain, read_archive};
+use fendermint_vm_topdown::proxy::IPCProviderProxyWithLatency;
+use fendermint_vm_topdown::sync::ParentFinalityStateQuery;
+use fendermint_vm_topdown::{
+    equivocation::EquivocationDetector, voting::VoteTally, CachedFinalityProvider, Config, Toggle,
+};
+use fvm_ipld_blockstore::Blockstore;
+use serde_json::{json, Value};
+use tendermint::abci::{request, types::CommitInfo};
+use tendermint::block::Height;
+use tendermint_rpc::{Client, HttpClient};
+use tracing::Instrument;
+
+use super::state::StateDb;
+use crate::cmd::Namespaces;
+
+/// The outcome of executing the block on the network.
+struct Committed {
+    /// Results of the transactions, as stored by CometBFT.
+    tx_results: Vec<tendermint::abci::types::ExecTxResult>,
+    /// Application hash in the header of the next block, if it has been produced yet.
+    app_hash: Option<tendermint::hash::AppHash>,
+    /// State root the local node arrived at, if it has the state in its history.
+    state_root: Option<Cid>,
+}
+
+pub async fn replay(args: &DebugReplayArgs) -> anyhow::Result<()> {
+    if args.height == 0 {
+        bail!("there is no block at height 0");
+    }
+
+    let client =
+        HttpClient::new(args.rpc_url.as_str()).context("failed to create CometBFT client")?;
+
+    let parent_finality_provider = parent_finality_provider(args).await?;
+
+    let report = match (&args.db_dir, &args.snapshot) {
+        (Some(db_dir), _) => {
+            let db = StateDb::open(db_dir, args.cold_dir.as_deref())?;
+            let (_, pre_state) = db.state_params(Some(args.height))?;
+            let state_root = db
+                .state_params(Some(args.height + 1))
+                .ok()
+                .map(|(_, params)| params.state_root);
+
+            replay_block(
+                &client,
+                parent_finality_provider,
+                db.store.clone(),
+                args.height,
+                pre_state,
+                state_root,
+                args.diff,
+            )
+            .await?
+        }
+        (None, Some(archive)) => {
+            let unpack_dir = tempfile::tempdir().context("failed to create temp dir")?;
+            let chain = read_archive(archive, unpack_dir.path())?;
+            let store = MemoryBlockstore::new();
+
+            let Snapshot::V1(snapshot) = import_chain(&chain, store.clone(), true)
+                .await
+                .context("failed to load snapshots")?;
+
+            if snapshot.block_height() + 1 != args.height {
+                bail!(
+                    "the snapshot was taken at height {}; it can only be used to replay block {}",
+                    snapshot.block_height(),
+                    snapshot.block_height() + 1
+                );
+            }
+
+            replay_block(
+                &client,
+                parent_finality_provider,
+                store,
+                args.height,
+                snapshot.state_params().clone(),
+                None,
+                false,
+            )
+            .await?
+        }
+        (None, None) => bail!("either a database or a snapshot is needed for the pre-state"),
+    };
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+/// Build a provider which fetches what the parent finality in a block executes from the parent,
+/// if the parent is configured; otherwise blocks with parent finality cannot be replayed.
+async fn parent_finality_provider(
+    args: &DebugReplayArgs,
+) -> anyhow::Result<TopDownFinalityProvider> {
+    let (Some(subnet_id), Some(endpoint), Some(gateway), Some(registry)) = (
+        &args.subnet_id,
+        &args.parent_endpoint,
+        args.parent_gateway,
+        args.parent_registry,
+    ) else {
+        return Ok(Arc::new(Toggle::disabled()));
+    };
+
+    let proxy = super::parent_proxy(
+        subnet_id,
+        endpoint,
+        args.parent_auth_token.clone(),
+        gateway,
+        registry,
+    )?;
+    let proxy = Arc::new(IPCProviderProxyWithLatency::new(proxy));
+
+    // Nothing is synced from the parent, so only the retries matter.
+    let config = Config::new(0, Duration::from_secs(1), Duration::from_secs(1), 5);
+
+    let provider = CachedFinalityProvider::uninitialized(config, proxy)
+        .await
+        .context("failed to query the parent")?;
+
+    Ok(Arc::new(Toggle::enabled(provider)))
+}
+
+/// The parent finality committed in a block, if any.
+fn parent_finality(txs: &[Vec<u8>]) -> Option<ParentFinality> {
+    txs.iter().find_map(
+        |tx| match fvm_ipld_encoding::from_slice::<ChainMessage>(tx) {
+            Ok(ChainMessage::Ipc(IpcMessage::TopDownExec(p))) => Some(p),
+            _ => None,
+        },
+    )
+}
+
+/// Execute the block at the given height on top of the pre-state, and compare the results
+/// with what the network committed.
+async fn replay_block<DB>(
+    client: &HttpClient,
+    parent_finality_provider: TopDownFinalityProvider,
+    base: DB,
+    height: u64,
+    pre_state: FvmStateParams,
+    expected_state_root: Option<Cid>,
+    with_diff: bool,
+) -> anyhow::Result<Value>
+where
+    DB: Blockstore + Clone + Send + Sync + 'static,
+{
+    let tm_height = Height::try_from(height).context("invalid block height")?;
+
+    let block = client
+        .block(tm_height)
+        .await
+        .with_context(|| format!("failed to fetch block {height}"))?;
+
+    if let Some(p) = parent_finality(&block.block.data) {
+        if !parent_finality_provider.is_enabled() {
+            bail!(
+                "block {height} commits parent finality at parent height {}; \
+                 the subnet ID and the parent endpoint, gateway and registry are needed to replay it",
+                p.height
+            );
+        }
+    }
+
+    // If this doesn't match, the divergence happened at an earlier height.
+    let pre_state_matches = block.block.header.app_hash == to_app_hash(&pre_state);
+    if !pre_state_matches {
+        tracing::warn!(
+            height,
+            "the pre-state doesn't match the application hash in the header of the block"
+        );
+    }
+
+    let committed = Committed {
+        tx_results: client
+            .block_results(tm_height)
+            .await
+            .with_context(|| format!("failed to fetch the results of block {height}"))?
+            .txs_results
+            .unwrap_or_default(),
+        app_hash: client
+            .commit(tm_height.increment())
+            .await
+            .ok()
+            .map(|c| c.signed_header.header.app_hash),
+        state_root: expected_state_root,
+    };
+
+    let store = OverlayBlockstore::new(base);
+    let ns = Namespaces::default();
+    let db = InMemoryBackend::<AppStore>::default();
+    let app = replay_app(
+        client,
+        parent_finality_provider.clone(),
+        db.clone(),
+        store.clone(),
+        &ns,
+    )?;
+
+    // The state at the height is what the previous block produced.
+    app.import_snapshot_state(height - 1, pre_state)
+        .context("failed to set the pre-state")?;
+
+    // Start from the parent finality recorded in the pre-state, like a node does after a restart.
+    if parent_finality_provider.is_enabled() {
+        let finality = AppParentFinalityQuery::new(app.clone())
+            .get_latest_committed_finality()
+            .context("failed to read the parent finality of the pre-state")?;
+
+        if let Some(finality) = finality {
+            atomically(|| parent_finality_provider.reset(finality.clone())).await;
+        }
+    }
+
+    let messages = execute(&app, &block)
+        .instrument(tracing::info_span!("replay", height))
+        .await?;
+
+    let state: AppState = db
+        .read()
+        .get(&ns.app, &AppStoreKey::State)?
+        .ok_or_else(|| anyhow!("app state not found after replay"))?;
+
+    let app_hash = state.app_hash();
+    let state_root = state.state_root();
+
+    let mut diverged_messages = Vec::new();
+    let messages = messages
+        .into_iter()
+        .enumerate()
+        .map(|(i, (replayed, mut json))| {
+            if let Some(committed) = committed.tx_results.get(i) {
+                let diverged = committed.code != replayed.code
+                    || committed.gas_used != replayed.gas_used
+                    || committed.data != replayed.data;
+                json["committed"] = json!({
+                    "code": committed.code.value(),
+                    "gas_used": committed.gas_used,
+                    "data": hex::encode(&committed.data),
+                });
+                json["diverged"] = json!(diverged);
+                if diverged {
+                    diverged_messages.push(i);
+                }
+            }
+            json
+        })
+        .collect::<Vec<_>>();
+
+    let diverged = !diverged_messages.is_empty()
+        || committed.app_hash.as_ref().is_some_and(|h| *h != app_hash)
+        || committed.state_root.is_some_and(|r| r != state_root);
+
+    let diff = match committed.state_root {
+        Some(expected) if with_diff && expected != state_root => {
+            let from = StateInspector::new(store.base().clone(), &expected)?;
+            let to = StateInspector::new(store.clone(), &state_root)?;
+            Some(diff_states(&from, &to, true)?)
+        }
+        _ => None,
+    };
+
+    Ok(json!({
+        "height": height,
+        "pre_state_matches": pre_state_matches,
+        "state_root": state_root.to_string(),
+        "committed_state_root": committed.state_root.map(|r| r.to_string()),
+        "app_hash": app_hash.to_string(),
+        "committed_app_hash": committed.app_hash.map(|h| h.to_string()),
+        "diverged": diverged,
+        "diverged_messages": diverged_messages,
+        "messages": messages,
+        "diff": diff,
+    }))
+}
+
+type ReplayApp<DB> = App<
+    InMemoryBackend<AppStore>,
+    OverlayBlockstore<DB>,
+    AppStore,
+    BytesMessageInterpreter<
+        ChainMessageInterpreter<
+            SignedMessageInterpreter<FvmMessageInterpreter<OverlayBlockstore<DB>, HttpClient>>,
+            OverlayBlockstore<DB>,
+        >,
+    >,
+>;
+
+/// Create an application like the one in `run`, but without a validator context, snapshots
+/// or state history, since only the execution of a single block matters.
+fn replay_app<DB>(
+    client: &HttpClient,
+    parent_finality_provider: TopDownFinalityProvider,
+    db: InMemoryBackend<AppStore>,
+    store: OverlayBlockstore<DB>,
+    ns: &Namespaces,
+) -> anyhow::Result<ReplayApp<DB>>
+where
+    DB: Blockstore + Clone + Send + Sync + 'static,
+{
+    // The gas estimation settings don't affect execution.
+    let interpreter = FvmMessageInterpreter::<OverlayBlockstore<DB>, _>::new(
+        client.clone(),
+        None,
+        1.25,
+        1.25,
+        false,
+        UpgradeScheduler::new(),
+    );
+    let interpreter = SignedMessageInterpreter::new(interpreter);
+    let interpreter = ChainMessageInterpreter::<_, OverlayBlockstore<DB>>::new(interpreter);
+    let interpreter =
+        BytesMessageInterpreter::new(interpreter, ProposalPrepareMode::PrependOnly, false, 1000);
+
+    App::new(
+        AppConfig {
+            app_namespace: ns.app.clone(),
+            state_hist_namespace: ns.state_hist.clone(),
+            state_hist_size: 0,
+            halt_height: 0,
+        },
+        db,
+        store,
+        interpreter,
+        ChainEnv {
+            checkpoint_pool: CheckpointPool::new(),
+            parent_finality_provider,
+            parent_finality_votes: VoteTally::empty(),
+            parent_finality_equivocations: EquivocationDetector::default(),
+        },
+        None,
+    )
+}
+
+/// Run the block through the ABCI methods, returning the result of each transaction.
+async fn execute<DB>(
+    app: &ReplayApp<DB>,
+    block: &tendermint_rpc::endpoint::block::Response,
+) -> anyhow::Result<Vec<(tendermint::abci::response::DeliverTx, Value)>>
+where
+    DB: Blockstore + Clone + Send + Sync + 'static,
+{
+    app.begin_block(request::BeginBlock {
+        hash: block.block_id.hash,
+        header: block.block.header.clone(),
+        last_commit_info: CommitInfo {
+            round: Default::default(),
+            votes: Vec::new(),
+        },
+        byzantine_validators: Vec::new(),
+    })
+    .await
+    .map_err(|e| anyhow!("begin block failed: {e}"))?;
+
+    let mut results = Vec::new();
+    for (i, tx) in block.block.data.iter().enumerate() {
+        let res = app
+            .deliver_tx(request::DeliverTx {
+                tx: tx.clone().into(),
+            })
+            .await
+            .map_err(|e| anyhow!("deliver tx {i} failed: {e}"))?;
+
+        tracing::info!(
+            index = i,
+            code = res.code.value(),
+            gas_used = res.gas_used,
+            "replayed transaction"
+        );
+
+        let json = json!({
+            "index": i,
+            "code": res.code.value(),
+            "info": res.info,
+            "gas_wanted": res.gas_wanted,
+            "gas_used": res.gas_used,
+            "data": hex::encode(&res.data),
+            "events": res.events.len(),
+        });
+
+        results.push((res, json));
+    }
+
+    app.end_block(request::EndBlock {
+        height: block.block.header.height.into(),
+    })
+    .await
+    .map_err(|e| anyhow!("end block failed: {e}"))?;
+
+    app.commit()
+        .await
+        .map_err(|e| anyhow!("commit failed: {e}"))?;
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use fendermint_vm_message::chain::ChainMessage;
+    use fendermint_vm_message::ipc::{IpcMessage, ParentFinality};
+
+    use super::parent_finality;
+
+    #[test]
+    fn finds_parent_finality() {
+        let finality = ParentFinality {
+            height: 100,
+            block_hash: vec![1; 32],
+        };
+        let topdown = fvm_ipld_encoding::to_vec(&ChainMessage::Ipc(IpcMessage::TopDownExec(
+            finality.clone(),
+        )))
+        .unwrap();
+        let garbage = vec![0xff; 10];
+
+        assert_eq!(parent_finality(&[]), None);
+        assert_eq!(parent_finality(&[garbage.clone()]), None);
+        assert_eq!(parent_finality(&[garbage, topdown]), Some(finality));
+    }
+}
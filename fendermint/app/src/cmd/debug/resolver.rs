@@ -0,0 +1,35 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Inspect the IPLD Resolver of a running node through its admin endpoint.
+
+use anyhow::{bail, Context};
+use fendermint_app_options::debug::DebugResolverArgs;
+use ipc_ipld_resolver::ResolverInfo;
+
+/// Fetch the state of the resolver and print it as JSON.
+pub async fn print_resolver_info(args: &DebugResolverArgs) -> anyhow::Result<()> {
+    let url = args
+        .admin_url
+        .join("resolver")
+        .context("invalid admin URL")?;
+
+    let response = reqwest::get(url.clone())
+        .await
+        .with_context(|| format!("failed to reach the admin endpoint at {url}"))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        bail!("admin endpoint returned {status}: {body}");
+    }
+
+    let info = response
+        .json::<ResolverInfo>()
+        .await
+        .context("failed to parse resolver info")?;
+
+    println!("{}", serde_json::to_string_pretty(&info)?);
+
+    Ok(())
+}
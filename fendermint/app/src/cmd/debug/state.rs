@@ -0,0 +1,221 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Offline inspection of the ledger state in the database of a node.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use cid::Cid;
+use fendermint_app::{AppState, AppStore, AppStoreKey};
+use fendermint_app_options::debug::{
+    DebugStateActorArgs, DebugStateActorsArgs, DebugStateCommands, DebugStateDbArgs,
+    DebugStateDiffArgs, StateRef,
+};
+use fendermint_rocksdb::{blockstore::ReadOnlyNamespaceBlockstore, ReadOnlyRocksDb, RocksDbConfig};
+use fendermint_storage::{Decode, Encode};
+use fendermint_vm_interpreter::fvm::state::inspect::{diff_states, u256_to_hex, StateInspector};
+use fendermint_vm_interpreter::fvm::state::FvmStateParams;
+use serde_json::json;
+
+use crate::cmd;
+use crate::cmd::Namespaces;
+
+cmd! {
+  DebugStateCommands(self) {
+    match self {
+        DebugStateCommands::Actors(args) => list_actors(args),
+        DebugStateCommands::Actor(args) => dump_actor(args),
+        DebugStateCommands::Storage(args) => dump_storage(args),
+        DebugStateCommands::Diff(args) => diff(args),
+        DebugStateCommands::Export(args) => super::export::export_state(args),
+    }
+  }
+}
+
+/// Fail with a clear error if the database isn't RocksDB, which is all the debug tools can read.
+///
+/// A `redb` database is a single file rather than a directory.
+pub(super) fn ensure_rocksdb(db_dir: &Path) -> anyhow::Result<()> {
+    if db_dir.is_file() {
+        bail!(
+            "{} is not a RocksDB directory; the debug tools don't support the redb backend",
+            db_dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Read-only access to the parts of the database the state lives in.
+pub(super) struct StateDb {
+    pub db: ReadOnlyRocksDb,
+    pub store: ReadOnlyNamespaceBlockstore,
+    ns: Namespaces,
+}
+
+impl StateDb {
+    pub fn open(db_dir: &Path, cold_dir: Option<&Path>) -> anyhow::Result<Self> {
+        ensure_rocksdb(db_dir)?;
+
+        let ns = Namespaces::default();
+        let config = RocksDbConfig::default();
+
+        let db = ReadOnlyRocksDb::open(db_dir, &config).context("error opening DB")?;
+        let mut store = ReadOnlyNamespaceBlockstore::new(db.clone(), ns.state_store.clone())
+            .context("error creating state DB")?;
+
+        if let Some(cold_dir) = cold_dir {
+            let cold =
+                ReadOnlyRocksDb::open(cold_dir, &config).context("error opening cold store")?;
+            store = store.with_cold_store(cold);
+        }
+
+        Ok(Self { db, store, ns })
+    }
+
+    /// The last committed application state.
+    pub fn app_state(&self) -> anyhow::Result<AppState> {
+        let key = AppStore::to_repr(&AppStoreKey::State)?;
+        let bytes = self
+            .db
+            .read_cf(&self.ns.app, key.as_slice())?
+            .ok_or_else(|| anyhow!("app state not found"))?;
+        Ok(AppStore::from_repr(&bytes)?)
+    }
+
+    /// The state at a height, or the latest one.
+    ///
+    /// Like with queries, the state at a height is the result of executing the block before it,
+    /// and its hash is the application hash in the header at that height.
+    pub fn state_params(&self, height: Option<u64>) -> anyhow::Result<(u64, FvmStateParams)> {
+        let Some(height) = height else {
+            let state = self.app_state()?;
+            return Ok((state.state_height(), state.state_params().clone()));
+        };
+
+        let key = AppStore::to_repr(&height)?;
+        let bytes = self
+            .db
+            .read_cf(&self.ns.state_hist, key.as_slice())?
+            .ok_or_else(|| {
+                anyhow!("no state at height {height}; it may have been pruned from the history")
+            })?;
+
+        Ok((height, AppStore::from_repr(&bytes)?))
+    }
+
+    /// Heights and roots of every state retained in the history, oldest first, ending with the latest.
+    pub fn retained_state_roots(&self) -> anyhow::Result<Vec<(u64, Cid)>> {
+        let state = self.app_state()?;
+        let mut roots = Vec::new();
+
+        for height in state.oldest_state_height()..state.state_height() {
+            let key = AppStore::to_repr(&height)?;
+            // Heights before a snapshot import are not in the history.
+            if let Some(bytes) = self.db.read_cf(&self.ns.state_hist, key.as_slice())? {
+                let params: FvmStateParams = AppStore::from_repr(&bytes)?;
+                roots.push((height, params.state_root));
+            }
+        }
+        roots.push((state.state_height(), state.state_root()));
+
+        Ok(roots)
+    }
+
+    fn state_root(&self, state: &StateRef) -> anyhow::Result<Cid> {
+        match state {
+            StateRef::Height(height) => Ok(self.state_params(Some(*height))?.1.state_root),
+            StateRef::Root(cid) => Ok(*cid),
+        }
+    }
+
+    pub fn inspector(
+        &self,
+        state_root: &Cid,
+    ) -> anyhow::Result<StateInspector<ReadOnlyNamespaceBlockstore>> {
+        StateInspector::new(self.store.clone(), state_root)
+    }
+}
+
+fn open_db(args: &DebugStateDbArgs) -> anyhow::Result<StateDb> {
+    StateDb::open(&args.db_dir, args.cold_dir.as_deref())
+}
+
+fn list_actors(args: &DebugStateActorsArgs) -> anyhow::Result<()> {
+    let db = open_db(&args.db)?;
+    let (height, params) = db.state_params(args.height)?;
+    let inspector = db.inspector(&params.state_root)?;
+
+    let actors = inspector
+        .actors()?
+        .iter()
+        .map(|(id, actor)| inspector.actor_summary(*id, actor))
+        .collect::<Vec<_>>();
+
+    let json = json!({
+        "height": height,
+        "state_root": params.state_root.to_string(),
+        "actors": actors,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+fn dump_actor(args: &DebugStateActorArgs) -> anyhow::Result<()> {
+    let db = open_db(&args.db)?;
+    let (height, params) = db.state_params(args.height)?;
+    let inspector = db.inspector(&params.state_root)?;
+
+    let id = inspector.lookup_id(&args.address)?;
+    let actor = inspector.actor(id)?;
+
+    let json = json!({
+        "height": height,
+        "actor": inspector.actor_summary(id, &actor),
+        "state": inspector.actor_state(id, &actor)?,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+fn dump_storage(args: &DebugStateActorArgs) -> anyhow::Result<()> {
+    let db = open_db(&args.db)?;
+    let (height, params) = db.state_params(args.height)?;
+    let inspector = db.inspector(&params.state_root)?;
+
+    let id = inspector.lookup_id(&args.address)?;
+    let actor = inspector.actor(id)?;
+
+    let storage = inspector
+        .evm_storage(&actor)?
+        .iter()
+        .map(|(k, v)| (u256_to_hex(k), json!(u256_to_hex(v))))
+        .collect::<serde_json::Map<_, _>>();
+
+    let json = json!({
+        "height": height,
+        "id": id,
+        "storage": storage,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+fn diff(args: &DebugStateDiffArgs) -> anyhow::Result<()> {
+    let db = open_db(&args.db)?;
+
+    let from = db.inspector(&db.state_root(&args.from)?)?;
+    let to = db.inspector(&db.state_root(&args.to)?)?;
+
+    let diff = diff_states(&from, &to, args.storage)?;
+
+    println!("{}", serde_json::to_string_pretty(&diff)?);
+
+    Ok(())
+}
@@ -3,25 +3,25 @@
 
 use std::time::Duration;
 
-use anyhow::Context;
-use fendermint_eth_api::HybridClient;
+use anyhow::{bail, Context};
+use fendermint_eth_api::{EthIndex, HybridClient, Indexer};
 use tracing::info;
 
 use crate::{
     cmd,
     options::eth::{EthArgs, EthCommands},
-    settings::eth::EthSettings,
+    settings::Settings,
 };
 
 cmd! {
-  EthArgs(self, settings: EthSettings) {
+  EthArgs(self, settings: Settings) {
     match self.command.clone() {
-      EthCommands::Run { ws_url, http_url, connect_retry_delay } => {
+      EthCommands::Run { ws_url, http_url, connect_retry_delay, reindex } => {
         let (client, driver) = HybridClient::new(http_url, ws_url, Duration::from_secs(connect_retry_delay)).context("failed to create HybridClient")?;
 
         let driver_handle = tokio::spawn(async move { driver.run().await });
 
-        let result = run(settings, client).await;
+        let result = run(settings, client, reindex).await;
 
         // Await the driver's termination to ensure proper connection closure.
         let _ = driver_handle.await;
@@ -32,7 +32,10 @@ cmd! {
 }
 
 /// Run the Ethereum API facade.
-async fn run(settings: EthSettings, client: HybridClient) -> anyhow::Result<()> {
+async fn run(settings: Settings, client: HybridClient, reindex: bool) -> anyhow::Result<()> {
+    let index = start_indexer(&settings, &client, reindex)?;
+    let settings = settings.eth;
+
     if settings.metrics.enabled {
         info!("metrics enabled");
 
@@ -70,6 +73,49 @@ async fn run(settings: EthSettings, client: HybridClient) -> anyhow::Result<()>
         settings.max_nonce_gap,
         gas,
         cors,
+        index,
     )
     .await
 }
+
+/// Open the block index and start following the chain in the background, if the indexer is enabled.
+fn start_indexer(
+    settings: &Settings,
+    client: &HybridClient,
+    reindex: bool,
+) -> anyhow::Result<Option<EthIndex>> {
+    let Some(ref indexer) = settings.eth.indexer else {
+        if reindex {
+            bail!("the indexer is not enabled in the settings");
+        }
+        info!("block indexer disabled");
+        return Ok(None);
+    };
+
+    let path = indexer.db_dir(settings.home_dir());
+
+    if reindex && path.exists() {
+        info!(
+            path = path.to_string_lossy().into_owned(),
+            "deleting the block index to rebuild it"
+        );
+        std::fs::remove_dir_all(&path).context("failed to delete the block index")?;
+    }
+
+    info!(
+        path = path.to_string_lossy().into_owned(),
+        "opening block index"
+    );
+    let index = EthIndex::open(&path)?;
+
+    let indexer = Indexer::new(
+        index.clone(),
+        client.clone(),
+        indexer.poll_interval,
+        indexer.start_height,
+    );
+
+    tokio::spawn(indexer.run());
+
+    Ok(Some(index))
+}
@@ -5,10 +5,13 @@
 
 use crate::{
     options::{Commands, Options},
-    settings::{utils::expand_tilde, Settings},
+    settings::{utils::expand_tilde, DbBackend, Settings},
 };
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
+use fendermint_redb::Redb;
+use fendermint_rocksdb::{blockstore::NamespaceBlockstore, namespaces, RocksDb, RocksDbConfig};
+use std::path::PathBuf;
 
 use ipc_observability::config::TracingSettings;
 use ipc_observability::traces::create_temporary_subscriber;
@@ -23,6 +26,17 @@ pub mod key;
 pub mod materializer;
 pub mod rpc;
 pub mod run;
+pub mod snapshot;
+
+// Database collection names.
+namespaces! {
+    Namespaces {
+        app,
+        state_hist,
+        state_store,
+        bit_store
+    }
+}
 
 #[async_trait]
 pub trait Cmd {
@@ -90,10 +104,14 @@ pub async fn exec(opts: &Options) -> anyhow::Result<()> {
             args.exec(()).await
         }
         Commands::Eth(args) => {
-            let settings = settings(opts)?.eth;
-            let _trace_file_guard = set_global_tracing_subscriber(&settings.tracing);
+            let settings = settings(opts)?;
+            let _trace_file_guard = set_global_tracing_subscriber(&settings.eth.tracing);
             args.exec(settings).await
         }
+        Commands::Snapshot(args) => {
+            let _trace_file_guard = set_global_tracing_subscriber(&TracingSettings::default());
+            args.exec(settings(opts)?).await
+        }
         Commands::Materializer(args) => {
             let _trace_file_guard = set_global_tracing_subscriber(&TracingSettings::default());
             args.exec(()).await
@@ -121,3 +139,67 @@ fn settings(opts: &Options) -> anyhow::Result<Settings> {
 
     Ok(settings)
 }
+
+/// Location of the database of the configured backend.
+fn db_path(settings: &Settings) -> PathBuf {
+    match settings.db.backend {
+        DbBackend::RocksDb => settings.data_dir().join("rocksdb"),
+        DbBackend::Redb => settings.data_dir().join("fendermint.redb"),
+    }
+}
+
+/// Open database with all the namespaces.
+fn open_db(settings: &Settings, ns: &Namespaces) -> anyhow::Result<RocksDb> {
+    let path = db_path(settings);
+    tracing::info!(
+        path = path.to_string_lossy().into_owned(),
+        "opening database"
+    );
+    let config = RocksDbConfig {
+        compaction_style: settings.db.compaction_style.to_string(),
+        ..Default::default()
+    };
+    let db = RocksDb::open_cf(path, &config, ns.values().iter())?;
+    Ok(db)
+}
+
+/// Open the `redb` database with all the namespaces.
+///
+/// The settings are validated not to ask for features which only RocksDB supports.
+fn open_redb(settings: &Settings, ns: &Namespaces) -> anyhow::Result<Redb> {
+    let path = db_path(settings);
+    tracing::info!(
+        path = path.to_string_lossy().into_owned(),
+        "opening database"
+    );
+    let db = Redb::open_ns(path, ns.values().iter())?;
+    Ok(db)
+}
+
+/// Open the state store, with the cold tier in archive mode.
+fn open_state_store(
+    settings: &Settings,
+    db: RocksDb,
+    ns: &Namespaces,
+) -> anyhow::Result<NamespaceBlockstore> {
+    let store =
+        NamespaceBlockstore::new(db, ns.state_store.clone()).context("error creating state DB")?;
+
+    let Some(ref archive) = settings.db.archive else {
+        return Ok(store);
+    };
+
+    let path = archive.cold_dir(settings.home_dir());
+    tracing::info!(
+        path = path.to_string_lossy().into_owned(),
+        "opening cold store"
+    );
+    let config = RocksDbConfig {
+        compaction_style: "level".into(),
+        compression_type: archive.compression_type.clone(),
+        ..Default::default()
+    };
+    let cold = RocksDb::open(path, &config).context("error opening cold store")?;
+
+    Ok(store.with_cold_store(cold))
+}
@@ -17,7 +17,7 @@ use fendermint_rpc::tx::{
 };
 use fendermint_vm_core::chainid;
 use fendermint_vm_message::chain::ChainMessage;
-use fendermint_vm_message::query::FvmQueryHeight;
+use fendermint_vm_message::query::{FvmQueryHeight, HeightRange};
 use fvm_ipld_encoding::RawBytes;
 use fvm_shared::address::Address;
 use fvm_shared::econ::TokenAmount;
@@ -98,6 +98,27 @@ async fn query(
                 }
             }
         }
+        RpcQueryCommands::ActorStateRange {
+            address,
+            from,
+            to,
+            step,
+        } => {
+            let range = HeightRange::new(from, to, step);
+            let res = client.actor_state_range(&address, range).await?;
+            let samples = res
+                .value
+                .into_iter()
+                .map(|s| {
+                    json!({
+                        "height": s.height,
+                        "id": s.state.as_ref().map(|(id, _)| id),
+                        "state": s.state.as_ref().map(|(_, st)| st),
+                    })
+                })
+                .collect::<Vec<_>>();
+            print_json(&json!({ "samples": samples }))?;
+        }
         RpcQueryCommands::StateParams => {
             let res = client.state_params(height).await?;
             let json = json!({ "response": res });
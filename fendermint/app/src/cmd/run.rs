@@ -2,13 +2,17 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 use anyhow::{anyhow, bail, Context};
-use async_stm::atomically_or_err;
+use async_stm::{atomically, atomically_or_err};
 use fendermint_abci::ApplicationService;
+use fendermint_app::gc::run_gc;
 use fendermint_app::ipc::{AppParentFinalityQuery, AppVote};
-use fendermint_app::{App, AppConfig, AppStore, BitswapBlockstore};
-use fendermint_app_settings::AccountKind;
+use fendermint_app::{admin, content_api};
+use fendermint_app::{App, AppConfig, AppStore, BitswapBlockstore, DeletableBlockstore};
+use fendermint_app_settings::{AccountKind, DbBackend};
 use fendermint_crypto::SecretKey;
-use fendermint_rocksdb::{blockstore::NamespaceBlockstore, namespaces, RocksDb, RocksDbConfig};
+use fendermint_redb::blockstore::NamespaceBlockstore as RedbBlockstore;
+use fendermint_rocksdb::blockstore::NamespaceBlockstore;
+use fendermint_storage::{KVReadable, KVWritable};
 use fendermint_vm_actor_interface::eam::EthAddress;
 use fendermint_vm_interpreter::chain::ChainEnv;
 use fendermint_vm_interpreter::fvm::observe::register_metrics as register_interpreter_metrics;
@@ -21,24 +25,31 @@ use fendermint_vm_interpreter::{
 };
 use fendermint_vm_resolver::ipld::IpldResolver;
 use fendermint_vm_snapshot::{SnapshotManager, SnapshotParams};
+use fendermint_vm_topdown::equivocation::{
+    submit_evidence_loop, DirEvidenceSubmitter, EquivocationDetector, SignedVote,
+};
 use fendermint_vm_topdown::observe::register_metrics as register_topdown_metrics;
 use fendermint_vm_topdown::proxy::{IPCProviderProxy, IPCProviderProxyWithLatency};
 use fendermint_vm_topdown::sync::launch_polling_syncer;
 use fendermint_vm_topdown::voting::{publish_vote_loop, Error as VoteError, VoteTally};
 use fendermint_vm_topdown::{CachedFinalityProvider, IPCParentFinality, Toggle};
+use fvm_ipld_blockstore::Blockstore;
 use fvm_shared::address::{current_network, Address, Network};
-use ipc_ipld_resolver::{Event as ResolverEvent, VoteRecord};
+use ipc_ipld_resolver::{Event as ResolverEvent, SignedVoteRecord};
 use ipc_observability::observe::register_metrics as register_default_metrics;
 use ipc_provider::config::subnet::{EVMSubnet, SubnetConfig};
 use ipc_provider::IpcProvider;
 use libp2p::identity::secp256k1;
 use libp2p::identity::Keypair;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast::error::RecvError;
 use tower::ServiceBuilder;
 use tracing::info;
 
 use crate::cmd::key::read_secret_key;
+use crate::cmd::snapshot::app_hash_verifier;
+use crate::cmd::{open_db, open_redb, open_state_store, Namespaces};
 use crate::{cmd, options::run::RunArgs, settings::Settings};
 use fendermint_app::observe::register_metrics as register_consensus_metrics;
 
@@ -48,20 +59,56 @@ cmd! {
   }
 }
 
-// Database collection names.
-namespaces! {
-    Namespaces {
-        app,
-        state_hist,
-        state_store,
-        bit_store
+/// How long to wait before trying to submit equivocation evidence again after a failure.
+const EVIDENCE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Run the Fendermint ABCI Application on the configured database backend.
+async fn run(settings: Settings) -> anyhow::Result<()> {
+    let ns = Namespaces::default();
+
+    match settings.db.backend {
+        DbBackend::RocksDb => {
+            let db = open_db(&settings, &ns).context("error opening DB")?;
+
+            // Blockstore for actors.
+            let state_store = open_state_store(&settings, db.clone(), &ns)?;
+            // Blockstore for Bitswap.
+            let bit_store = NamespaceBlockstore::new(db.clone(), ns.bit_store.clone())
+                .context("error creating bit DB")?;
+
+            let gc_store = state_store.clone();
+            run_app(settings, ns, db, state_store, bit_store, Some(gc_store)).await
+        }
+        DbBackend::Redb => {
+            let db = open_redb(&settings, &ns).context("error opening DB")?;
+
+            let state_store = RedbBlockstore::new(db.clone(), ns.state_store.clone())
+                .context("error creating state DB")?;
+            let bit_store = RedbBlockstore::new(db.clone(), ns.bit_store.clone())
+                .context("error creating bit DB")?;
+
+            run_app(settings, ns, db, state_store, bit_store, None).await
+        }
     }
 }
 
 /// Run the Fendermint ABCI Application.
 ///
 /// This method acts as our composition root.
-async fn run(settings: Settings) -> anyhow::Result<()> {
+///
+/// Garbage collection only runs if a `gc_store` is passed, which is the state store itself.
+async fn run_app<DB, SS>(
+    settings: Settings,
+    ns: Namespaces,
+    db: DB,
+    state_store: SS,
+    bit_store: SS,
+    gc_store: Option<NamespaceBlockstore>,
+) -> anyhow::Result<()>
+where
+    DB: KVWritable<AppStore> + KVReadable<AppStore> + Clone + Send + Sync + 'static,
+    SS: DeletableBlockstore + Clone + Send + Sync + 'static,
+{
     let tendermint_rpc_url = settings.tendermint_rpc_url()?;
     tracing::info!("Connecting to Tendermint at {tendermint_rpc_url}");
 
@@ -138,7 +185,7 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
         other => other,
     };
 
-    let interpreter = FvmMessageInterpreter::<NamespaceBlockstore, _>::new(
+    let interpreter = FvmMessageInterpreter::<SS, _>::new(
         tendermint_client.clone(),
         validator_ctx,
         settings.fvm.gas_overestimation_rate,
@@ -149,7 +196,7 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
     .with_push_chain_meta(testing_settings.map_or(true, |t| t.push_chain_meta));
 
     let interpreter = SignedMessageInterpreter::new(interpreter);
-    let interpreter = ChainMessageInterpreter::<_, NamespaceBlockstore>::new(interpreter);
+    let interpreter = ChainMessageInterpreter::<_, SS>::new(interpreter);
     let interpreter = BytesMessageInterpreter::new(
         interpreter,
         ProposalPrepareMode::PrependOnly,
@@ -157,22 +204,17 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
         settings.abci.block_max_msgs,
     );
 
-    let ns = Namespaces::default();
-    let db = open_db(&settings, &ns).context("error opening DB")?;
-
-    // Blockstore for actors.
-    let state_store =
-        NamespaceBlockstore::new(db.clone(), ns.state_store).context("error creating state DB")?;
-
     let checkpoint_pool = CheckpointPool::new();
     let parent_finality_votes = VoteTally::empty();
+    let parent_finality_equivocations = EquivocationDetector::default();
 
     let topdown_enabled = settings.topdown_enabled();
 
+    let mut resolver_admin: Option<Arc<dyn admin::ResolverAdmin>> = None;
+
     // If enabled, start a resolver that communicates with the application through the resolve pool.
     if settings.resolver_enabled() {
-        let mut service =
-            make_resolver_service(&settings, db.clone(), state_store.clone(), ns.bit_store)?;
+        let mut service = make_resolver_service(&settings, state_store.clone(), bit_store.clone())?;
 
         // Register all metrics from the IPLD resolver stack
         if let Some(ref registry) = metrics_registry {
@@ -182,6 +224,37 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
         }
 
         let client = service.client();
+        resolver_admin = Some(Arc::new(client.clone()));
+
+        if settings.resolver.content_api.enabled {
+            let api = &settings.resolver.content_api;
+            let listen_addr: std::net::SocketAddr = api.listen.clone().try_into()?;
+            let retention = Arc::new(
+                content_api::ContentRetention::load(
+                    settings.data_dir().join("content_retention.json"),
+                )
+                .context("error loading content retention")?,
+            );
+            let api_state = content_api::ContentApiState::new(
+                BitswapBlockstore::new(state_store.clone(), bit_store.clone()),
+                client.clone(),
+                settings.ipc.subnet_id.clone(),
+                api.max_block_size,
+                api.resolve_timeout,
+                retention.clone(),
+                api.retention,
+            );
+            tokio::spawn(async move {
+                if let Err(e) = content_api::listen(listen_addr, api_state).await {
+                    tracing::error!(error = ?e, "content API failed");
+                }
+            });
+            tokio::spawn(content_api::run_content_gc(
+                bit_store,
+                retention,
+                api.gc_interval,
+            ));
+        }
 
         let own_subnet_id = settings.ipc.subnet_id.clone();
 
@@ -223,10 +296,30 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
         tracing::info!("subscribing to gossip...");
         let rx = service.subscribe();
         let parent_finality_votes = parent_finality_votes.clone();
+        let parent_finality_equivocations = parent_finality_equivocations.clone();
         tokio::spawn(async move {
-            dispatch_resolver_events(rx, parent_finality_votes, topdown_enabled).await;
+            dispatch_resolver_events(
+                rx,
+                parent_finality_votes,
+                parent_finality_equivocations,
+                topdown_enabled,
+            )
+            .await;
         });
 
+        if topdown_enabled {
+            let dir = settings.data_dir().join("equivocations");
+            tracing::info!(
+                dir = dir.to_string_lossy().to_string(),
+                "writing equivocation evidence..."
+            );
+            tokio::spawn(submit_evidence_loop(
+                parent_finality_equivocations.clone(),
+                DirEvidenceSubmitter::new(dir),
+                EVIDENCE_RETRY_DELAY,
+            ));
+        }
+
         tracing::info!("starting the IPLD Resolver Service...");
         tokio::spawn(async move {
             if let Err(e) = service.run().await {
@@ -284,10 +377,25 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
                 hist_size: settings.snapshots.hist_size,
                 last_access_hold: settings.snapshots.last_access_hold,
                 sync_poll_interval: settings.snapshots.sync_poll_interval,
+                max_deltas: settings.snapshots.max_deltas,
+                compression_level: settings.snapshots.compression_level,
             },
         )
         .context("failed to create snapshot manager")?;
 
+        let client = match settings.snapshots.light_client {
+            Some(ref lc) => client.with_verifier(
+                app_hash_verifier(
+                    &lc.rpc_url.to_string(),
+                    lc.trust_height,
+                    &lc.trust_hash,
+                    lc.trust_period,
+                )
+                .context("failed to create snapshot verifier")?,
+            ),
+            None => client,
+        };
+
         tracing::info!("starting the SnapshotManager...");
         let tendermint_client = tendermint_client.clone();
         tokio::spawn(async move { manager.run(tendermint_client).await });
@@ -306,16 +414,56 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
             halt_height: settings.halt_height,
         },
         db,
-        state_store,
+        state_store.clone(),
         interpreter,
         ChainEnv {
             checkpoint_pool,
             parent_finality_provider: parent_finality_provider.clone(),
             parent_finality_votes: parent_finality_votes.clone(),
+            parent_finality_equivocations,
         },
-        snapshots,
+        snapshots.clone(),
     )?;
 
+    // In archive mode the whole history is kept, but only the recent states are in the hot store.
+    // Otherwise the size of the history can change at runtime, so it's looked up before every round.
+    // The settings have been validated to keep the whole history with garbage collection in archive mode.
+    let hot_hist_size = settings.db.archive.as_ref().map(|a| a.hot_hist_size);
+
+    match (settings.db.gc_interval, gc_store) {
+        (Some(_), _) if hot_hist_size == Some(0) => {
+            tracing::warn!("blockstore garbage collection disabled with unlimited state history");
+        }
+        (Some(gc_interval), Some(gc_store)) => {
+            tracing::info!(
+                archive = gc_store.is_archive(),
+                "starting blockstore garbage collection..."
+            );
+            if hot_hist_size.is_none() && settings.db.state_hist_size == 0 {
+                tracing::warn!(
+                    "blockstore garbage collection paused while the state history is unlimited"
+                );
+            }
+            let gc_app = app.clone();
+            tokio::spawn(async move {
+                run_gc(
+                    gc_store,
+                    snapshots,
+                    gc_interval,
+                    move || match hot_hist_size.unwrap_or_else(|| gc_app.state_hist_size()) {
+                        0 => Ok(None),
+                        size => gc_app.recent_state_roots(size).map(Some),
+                    },
+                )
+                .await
+            });
+        }
+        (Some(_), None) => {
+            tracing::warn!("blockstore garbage collection is not supported by the database");
+        }
+        (None, _) => {}
+    }
+
     if let Some((agent_proxy, config)) = ipc_tuple {
         let app_parent_finality_query = AppParentFinalityQuery::new(app.clone());
         tokio::spawn(async move {
@@ -335,6 +483,17 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
         });
     }
 
+    if settings.admin.enabled {
+        let listen_addr: std::net::SocketAddr = settings.admin.listen.clone().try_into()?;
+        let admin_app = Arc::new(app.clone());
+        let archive = settings.db.archive.is_some();
+        tokio::spawn(async move {
+            if let Err(e) = admin::listen(listen_addr, admin_app, archive, resolver_admin).await {
+                tracing::error!(error = ?e, "admin endpoint failed");
+            }
+        });
+    }
+
     // Start the metrics on a background thread.
     if let Some(registry) = metrics_registry {
         info!(
@@ -386,30 +545,14 @@ async fn run(settings: Settings) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Open database with all
-fn open_db(settings: &Settings, ns: &Namespaces) -> anyhow::Result<RocksDb> {
-    let path = settings.data_dir().join("rocksdb");
-    info!(
-        path = path.to_string_lossy().into_owned(),
-        "opening database"
-    );
-    let config = RocksDbConfig {
-        compaction_style: settings.db.compaction_style.to_string(),
-        ..Default::default()
-    };
-    let db = RocksDb::open_cf(path, &config, ns.values().iter())?;
-    Ok(db)
-}
-
-fn make_resolver_service(
+fn make_resolver_service<SS>(
     settings: &Settings,
-    db: RocksDb,
-    state_store: NamespaceBlockstore,
-    bit_store_ns: String,
-) -> anyhow::Result<ipc_ipld_resolver::Service<libipld::DefaultParams, AppVote>> {
-    // Blockstore for Bitswap.
-    let bit_store = NamespaceBlockstore::new(db, bit_store_ns).context("error creating bit DB")?;
-
+    state_store: SS,
+    bit_store: SS,
+) -> anyhow::Result<ipc_ipld_resolver::Service<libipld::DefaultParams, AppVote>>
+where
+    SS: Blockstore + Send + Sync + 'static,
+{
     // Blockstore for Bitswap with a fallback on the actor store for reads.
     let bitswap_store = BitswapBlockstore::new(state_store, bit_store);
 
@@ -449,7 +592,8 @@ fn make_ipc_provider_proxy(settings: &Settings) -> anyhow::Result<IPCProviderPro
 
 fn to_resolver_config(settings: &Settings) -> anyhow::Result<ipc_ipld_resolver::Config> {
     use ipc_ipld_resolver::{
-        Config, ConnectionConfig, ContentConfig, DiscoveryConfig, MembershipConfig, NetworkConfig,
+        AddressBookConfig, Config, ConnectionConfig, ContentConfig, DiscoveryConfig, ErasureConfig,
+        MembershipConfig, NatConfig, NetworkConfig, ScoringConfig,
     };
 
     let r = &settings.resolver;
@@ -470,6 +614,7 @@ fn to_resolver_config(settings: &Settings) -> anyhow::Result<ipc_ipld_resolver::
     let config = Config {
         connection: ConnectionConfig {
             listen_addr: r.connection.listen_addr.clone(),
+            quic_listen_addr: r.connection.quic_listen_addr.clone(),
             external_addresses: r.connection.external_addresses.clone(),
             expected_peer_count: r.connection.expected_peer_count,
             max_incoming: r.connection.max_incoming,
@@ -495,6 +640,48 @@ fn to_resolver_config(settings: &Settings) -> anyhow::Result<ipc_ipld_resolver::
         content: ContentConfig {
             rate_limit_bytes: r.content.rate_limit_bytes,
             rate_limit_period: r.content.rate_limit_period,
+            graph_max_response_bytes: r.content.graph_max_response_bytes,
+            max_provided_bytes: r.content.max_provided_bytes,
+            max_provided_cids: r.content.max_provided_cids,
+            max_provide_ttl: r.content.max_provide_ttl,
+            max_resolved_bytes: r.content.max_resolved_bytes,
+            egress_bytes: r.content.egress_bytes,
+            egress_period: r.content.egress_period,
+            subnet_egress_bytes: r.content.subnet_egress_bytes.clone(),
+            // Checkpoints of our own subnet must be resolvable by the parent no matter what.
+            priority_subnets: std::iter::once(settings.ipc.subnet_id.clone())
+                .chain(r.content.priority_subnets.iter().cloned())
+                .collect(),
+        },
+        scoring: ScoringConfig {
+            ban_threshold: r.scoring.ban_threshold,
+            ban_duration: r.scoring.ban_duration,
+            decay_half_life: r.scoring.decay_half_life,
+        },
+        nat: NatConfig {
+            enable_autonat: r.nat.enable_autonat,
+            enable_relay_server: r.nat.enable_relay_server,
+            enable_relay_client: r.nat.enable_relay_client,
+            relay_addresses: r.nat.relay_addresses.clone(),
+            enable_hole_punching: r.nat.enable_hole_punching,
+            relay_max_circuit_duration: r.nat.relay_max_circuit_duration,
+            relay_max_circuit_bytes: r.nat.relay_max_circuit_bytes,
+        },
+        address_book: AddressBookConfig {
+            path: if r.address_book.enabled {
+                Some(r.address_book.path(settings.home_dir()))
+            } else {
+                None
+            },
+            max_age: r.address_book.max_age,
+            save_interval: r.address_book.save_interval,
+        },
+        erasure: ErasureConfig {
+            enabled: r.erasure.enabled,
+            data_chunks: r.erasure.data_chunks,
+            parity_chunks: r.erasure.parity_chunks,
+            max_bytes: r.erasure.max_bytes,
+            chunk_ttl: r.erasure.chunk_ttl,
         },
     };
 
@@ -512,6 +699,7 @@ fn to_address(sk: &SecretKey, kind: &AccountKind) -> anyhow::Result<Address> {
 async fn dispatch_resolver_events(
     mut rx: tokio::sync::broadcast::Receiver<ResolverEvent<AppVote>>,
     parent_finality_votes: VoteTally,
+    parent_finality_equivocations: EquivocationDetector,
     topdown_enabled: bool,
 ) {
     loop {
@@ -519,7 +707,13 @@ async fn dispatch_resolver_events(
             Ok(event) => match event {
                 ResolverEvent::ReceivedPreemptive(_, _) => {}
                 ResolverEvent::ReceivedVote(vote) => {
-                    dispatch_vote(*vote, &parent_finality_votes, topdown_enabled).await;
+                    dispatch_vote(
+                        *vote,
+                        &parent_finality_votes,
+                        &parent_finality_equivocations,
+                        topdown_enabled,
+                    )
+                    .await;
                 }
             },
             Err(RecvError::Lagged(n)) => {
@@ -534,11 +728,13 @@ async fn dispatch_resolver_events(
 }
 
 async fn dispatch_vote(
-    vote: VoteRecord<AppVote>,
+    signed_vote: SignedVoteRecord<AppVote>,
     parent_finality_votes: &VoteTally,
+    parent_finality_equivocations: &EquivocationDetector,
     topdown_enabled: bool,
 ) {
-    match vote.content {
+    let vote = signed_vote.record();
+    match &vote.content {
         AppVote::ParentFinality(f) => {
             if !topdown_enabled {
                 tracing::debug!("ignoring vote; topdown disabled");
@@ -553,6 +749,27 @@ async fn dispatch_vote(
             })
             .await;
 
+            // Keep the signatures of new votes from empowered validators, so that if they
+            // equivocate later, or already did, we have the evidence to prove it.
+            if matches!(res, Ok(true) | Err(VoteError::Equivocation(_, _, _, _))) {
+                let signed = SignedVote::new(&signed_vote, f.block_hash.clone());
+                let evidence = atomically(|| {
+                    let finalized_height = parent_finality_votes.last_finalized_height()?;
+                    parent_finality_equivocations.prune(finalized_height)?;
+                    parent_finality_equivocations.observe(
+                        vote.public_key.clone(),
+                        vote.subnet_id.clone(),
+                        f.height,
+                        signed.clone(),
+                    )
+                })
+                .await;
+
+                if let Some(evidence) = evidence {
+                    evidence.emit();
+                }
+            }
+
             match res {
                 Err(e @ VoteError::Equivocation(_, _, _, _)) => {
                     tracing::warn!(error = e.to_string(), "failed to handle vote");
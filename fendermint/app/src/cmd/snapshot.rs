@@ -0,0 +1,441 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use fendermint_app::{to_app_hash, App, AppConfig, AppStore};
+use fendermint_app_options::snapshot::{
+    LightClientArgs, S3Args, SnapshotArgs, SnapshotCommands, SnapshotExportArgs,
+    SnapshotImportArgs, SnapshotVerifyArgs,
+};
+use fendermint_redb::blockstore::NamespaceBlockstore as RedbBlockstore;
+use fendermint_storage::{KVReadable, KVWritable};
+use fendermint_vm_interpreter::chain::{ChainEnv, CheckpointPool};
+use fendermint_vm_interpreter::fvm::state::snapshot::Snapshot;
+use fendermint_vm_interpreter::fvm::store::memory::MemoryBlockstore;
+use fendermint_vm_snapshot::{
+    import_chain, install_snapshots, list_manifests, read_archive, snapshot_chain, write_archive,
+    AppHashCheck, AppHashVerifier, SnapshotItem, TrustOptions,
+};
+use fendermint_vm_topdown::equivocation::EquivocationDetector;
+use fendermint_vm_topdown::voting::VoteTally;
+use fendermint_vm_topdown::Toggle;
+use futures::StreamExt;
+use fvm_ipld_blockstore::Blockstore;
+use object_store::{aws::AmazonS3Builder, path::Path as ObjectPath, ObjectStore, WriteMultipart};
+use serde_json::json;
+use tendermint::hash::{Algorithm, Hash};
+use tendermint_rpc::HttpClient;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::cmd::{db_path, open_db, open_redb, open_state_store, Namespaces};
+use crate::{
+    cmd,
+    settings::{DbBackend, Settings},
+};
+
+/// Size of the parts uploaded to object storage.
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Maximum number of parts being uploaded at the same time.
+const UPLOAD_CONCURRENCY: usize = 4;
+
+cmd! {
+  SnapshotArgs(self, settings) {
+    match &self.command {
+        SnapshotCommands::List => list_snapshots(&settings),
+        SnapshotCommands::Export(args) => export_archive(&settings, args).await,
+        SnapshotCommands::Import(args) => import_archive(&settings, args).await,
+        SnapshotCommands::Verify(args) => verify_archive(args).await,
+    }
+  }
+}
+
+fn list_snapshots(settings: &Settings) -> anyhow::Result<()> {
+    let snapshots = list_manifests(settings.snapshots_dir())?;
+
+    let json = snapshots
+        .into_iter()
+        .map(|s| {
+            json!({
+                "snapshot_dir": s.snapshot_dir,
+                "manifest": s.manifest,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+async fn export_archive(settings: &Settings, args: &SnapshotExportArgs) -> anyhow::Result<()> {
+    let snapshots = list_manifests(settings.snapshots_dir())?;
+
+    let item = match args.height {
+        Some(height) => snapshots
+            .iter()
+            .find(|s| s.manifest.block_height == height)
+            .ok_or_else(|| anyhow!("there is no snapshot at height {height}"))?,
+        None => snapshots
+            .last()
+            .ok_or_else(|| anyhow!("there are no snapshots to export"))?,
+    };
+
+    let chain = snapshot_chain(&snapshots.clone().into(), item)?;
+    let archive = write_archive(&chain, &args.archive).context("failed to write archive")?;
+
+    if let Some(s3_url) = &args.s3.s3_url {
+        upload(&args.s3, &args.archive)
+            .await
+            .with_context(|| format!("failed to upload archive to {s3_url}"))?;
+    }
+
+    println!("{}", serde_json::to_string_pretty(&archive)?);
+
+    Ok(())
+}
+
+async fn import_archive(settings: &Settings, args: &SnapshotImportArgs) -> anyhow::Result<()> {
+    // Importing into an existing database could leave it in an inconsistent state.
+    let db_path = db_path(settings);
+    if db_path.exists() {
+        bail!(
+            "the database already exists at {}; snapshots can only be imported into a fresh node",
+            db_path.to_string_lossy()
+        );
+    }
+
+    if args.s3.s3_url.is_some() {
+        download(&args.s3, &args.archive)
+            .await
+            .context("failed to download archive")?;
+    }
+
+    let unpack_dir = tempfile::tempdir().context("failed to create temp dir")?;
+    let chain = read_archive(&args.archive, unpack_dir.path())?;
+
+    if let Some(verifier) = light_client(&args.light_client)? {
+        verify_app_hashes(&verifier, &chain).await?;
+    }
+
+    let latest = chain
+        .last()
+        .expect("archives are not empty")
+        .manifest
+        .clone();
+
+    let ns = Namespaces::default();
+    match settings.db.backend {
+        DbBackend::RocksDb => {
+            let db = open_db(settings, &ns).context("error opening DB")?;
+            let state_store = open_state_store(settings, db.clone(), &ns)?;
+            import_state(settings, ns, db, state_store, &chain).await?;
+        }
+        DbBackend::Redb => {
+            let db = open_redb(settings, &ns).context("error opening DB")?;
+            let state_store = RedbBlockstore::new(db.clone(), ns.state_store.clone())
+                .context("error creating state DB")?;
+            import_state(settings, ns, db, state_store, &chain).await?;
+        }
+    }
+
+    if args.install {
+        install_snapshots(&chain, settings.snapshots_dir())
+            .context("failed to install snapshots")?;
+    }
+
+    tracing::info!(
+        block_height = latest.block_height,
+        "imported snapshot archive; CometBFT has to be bootstrapped at the same height, e.g. with `cometbft bootstrap-state`"
+    );
+
+    println!("{}", serde_json::to_string_pretty(&latest)?);
+
+    Ok(())
+}
+
+/// Load the blocks of the snapshot chain into the state store and make the last one the committed state.
+async fn import_state<DB, SS>(
+    settings: &Settings,
+    ns: Namespaces,
+    db: DB,
+    state_store: SS,
+    chain: &[SnapshotItem],
+) -> anyhow::Result<()>
+where
+    DB: KVWritable<AppStore> + KVReadable<AppStore> + Clone + 'static,
+    SS: Blockstore + Clone + Send + Sync + 'static,
+{
+    let Snapshot::V1(snapshot) = import_chain(chain, state_store.clone(), true)
+        .await
+        .context("failed to import snapshots")?;
+
+    // The rest of the chain environment is not used for setting the state.
+    let app: App<_, _, AppStore, ()> = App::new(
+        AppConfig {
+            app_namespace: ns.app,
+            state_hist_namespace: ns.state_hist,
+            state_hist_size: settings.db.state_hist_size,
+            halt_height: settings.halt_height,
+        },
+        db,
+        state_store,
+        (),
+        ChainEnv {
+            checkpoint_pool: CheckpointPool::new(),
+            parent_finality_provider: Arc::new(Toggle::disabled()),
+            parent_finality_votes: VoteTally::empty(),
+            parent_finality_equivocations: EquivocationDetector::default(),
+        },
+        None,
+    )?;
+
+    app.import_snapshot_state(snapshot.block_height(), snapshot.state_params().clone())
+        .context("failed to set the application state")?;
+
+    Ok(())
+}
+
+async fn verify_archive(args: &SnapshotVerifyArgs) -> anyhow::Result<()> {
+    if args.s3.s3_url.is_some() {
+        download(&args.s3, &args.archive)
+            .await
+            .context("failed to download archive")?;
+    }
+
+    let unpack_dir = tempfile::tempdir().context("failed to create temp dir")?;
+    let chain = read_archive(&args.archive, unpack_dir.path())?;
+
+    if let Some(verifier) = light_client(&args.light_client)? {
+        verify_app_hashes(&verifier, &chain).await?;
+    }
+
+    if args.load {
+        import_chain(&chain, MemoryBlockstore::new(), true)
+            .await
+            .context("failed to load snapshots")?;
+    }
+
+    let manifests = chain.into_iter().map(|s| s.manifest).collect::<Vec<_>>();
+
+    println!("{}", serde_json::to_string_pretty(&manifests)?);
+
+    Ok(())
+}
+
+/// Create a light client to check the application hash of snapshots against the network.
+pub fn app_hash_verifier(
+    rpc_url: &str,
+    trust_height: u64,
+    trust_hash: &str,
+    trust_period: Duration,
+) -> anyhow::Result<AppHashVerifier<HttpClient>> {
+    let client = HttpClient::new(rpc_url).context("failed to create CometBFT client")?;
+
+    let trust_hash = Hash::from_hex_upper(Algorithm::Sha256, &trust_hash.to_uppercase())
+        .context("invalid trust hash")?;
+
+    Ok(AppHashVerifier::new(
+        client,
+        TrustOptions {
+            trust_height,
+            trust_hash,
+            trust_period,
+        },
+    ))
+}
+
+fn light_client(args: &LightClientArgs) -> anyhow::Result<Option<AppHashVerifier<HttpClient>>> {
+    match (&args.trust_rpc_url, args.trust_height, &args.trust_hash) {
+        (Some(url), Some(height), Some(hash)) => app_hash_verifier(
+            url.as_str(),
+            height,
+            hash,
+            Duration::from_secs(args.trust_period),
+        )
+        .map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Check that every snapshot in the chain has the application hash the network committed to.
+async fn verify_app_hashes(
+    verifier: &AppHashVerifier<HttpClient>,
+    chain: &[SnapshotItem],
+) -> anyhow::Result<()> {
+    for item in chain {
+        let app_hash = to_app_hash(&item.manifest.state_params);
+        verifier
+            .verify_app_hash(item.manifest.block_height, &app_hash)
+            .await
+            .context("failed to verify snapshot against the network")?;
+    }
+    Ok(())
+}
+
+/// Connect to the object storage and return the location of the archive in it.
+fn s3_object(args: &S3Args) -> anyhow::Result<(impl ObjectStore, ObjectPath)> {
+    let url = args
+        .s3_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("missing S3 URL"))?;
+
+    let (bucket, location) = s3_location(url)?;
+
+    let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+
+    if let Some(endpoint) = &args.s3_endpoint {
+        builder = builder
+            .with_endpoint(endpoint.as_str().trim_end_matches('/'))
+            .with_allow_http(endpoint.scheme() == "http");
+    }
+
+    let store = builder.build().context("failed to create S3 client")?;
+
+    Ok((store, location))
+}
+
+/// Parse an `s3://<bucket>/<key>` URL into the bucket name and the object key.
+fn s3_location(url: &Url) -> anyhow::Result<(&str, ObjectPath)> {
+    if url.scheme() != "s3" {
+        bail!("expected an s3://<bucket>/<key> URL; got {url}");
+    }
+
+    let bucket = url
+        .host_str()
+        .ok_or_else(|| anyhow!("missing bucket name in {url}"))?;
+
+    let key = url.path().trim_start_matches('/');
+    if key.is_empty() {
+        bail!("missing object key in {url}");
+    }
+
+    Ok((bucket, ObjectPath::from(key)))
+}
+
+/// Upload a local file to object storage in parts.
+async fn upload(args: &S3Args, path: &Path) -> anyhow::Result<()> {
+    let (store, location) = s3_object(args)?;
+
+    let upload = store.put_multipart(&location).await?;
+    let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_CHUNK_SIZE);
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.wait_for_capacity(UPLOAD_CONCURRENCY).await?;
+        writer.write(&buf[..n]);
+    }
+
+    writer.finish().await?;
+
+    tracing::info!(location = location.to_string(), "uploaded archive");
+
+    Ok(())
+}
+
+/// Download a file from object storage into a local file.
+async fn download(args: &S3Args, path: &Path) -> anyhow::Result<()> {
+    let (store, location) = s3_object(args)?;
+
+    let mut stream = store.get(&location).await?.into_stream();
+    let mut file = tokio::fs::File::create(path).await?;
+
+    while let Some(bytes) = stream.next().await {
+        file.write_all(&bytes?).await?;
+    }
+
+    file.flush().await?;
+
+    tracing::info!(location = location.to_string(), "downloaded archive");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fendermint_app_options::snapshot::S3Args;
+    use url::Url;
+
+    use super::{download, s3_location, upload};
+
+    fn parse(url: &str) -> anyhow::Result<(String, String)> {
+        let url = Url::parse(url).unwrap();
+        s3_location(&url).map(|(bucket, key)| (bucket.to_owned(), key.to_string()))
+    }
+
+    #[test]
+    fn s3_url_parsing() {
+        assert_eq!(
+            parse("s3://snapshots/archive.tar").unwrap(),
+            ("snapshots".to_owned(), "archive.tar".to_owned())
+        );
+        assert_eq!(
+            parse("s3://snapshots/calibration/2024/archive.tar").unwrap(),
+            (
+                "snapshots".to_owned(),
+                "calibration/2024/archive.tar".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn s3_url_parsing_errors() {
+        for (url, error) in [
+            ("https://snapshots/archive.tar", "expected an s3://"),
+            ("s3://snapshots", "missing object key"),
+            ("s3://snapshots/", "missing object key"),
+            ("s3:archive.tar", "missing bucket name"),
+        ] {
+            let err = parse(url).unwrap_err();
+            assert!(err.to_string().contains(error), "{url}: {err}");
+        }
+    }
+
+    /// Upload an archive to a local MinIO and download it again.
+    ///
+    /// Start MinIO and create the bucket, then run the test with the credentials:
+    ///
+    /// ```text
+    /// docker run -d -p 9000:9000 minio/minio server /data
+    /// mc alias set local http://127.0.0.1:9000 minioadmin minioadmin && mc mb local/fendermint-test
+    /// AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin AWS_REGION=us-east-1 \
+    ///   cargo test -p fendermint_app s3_round_trip -- --ignored
+    /// ```
+    #[tokio::test]
+    #[ignore = "needs a local MinIO"]
+    async fn s3_round_trip() {
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| "http://127.0.0.1:9000".to_owned());
+
+        let args = S3Args {
+            s3_url: Some(Url::parse("s3://fendermint-test/round-trip/archive.tar").unwrap()),
+            s3_endpoint: Some(Url::parse(&endpoint).unwrap()),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("upload.tar");
+        let dst = dir.path().join("download.tar");
+
+        // Larger than a part, so the multipart upload has more than one.
+        let contents = (0..super::UPLOAD_CHUNK_SIZE + 1024)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&src, &contents).unwrap();
+
+        upload(&args, &src).await.expect("failed to upload");
+        download(&args, &dst).await.expect("failed to download");
+
+        assert_eq!(std::fs::read(&dst).unwrap(), contents);
+    }
+}
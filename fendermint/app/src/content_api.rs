@@ -0,0 +1,626 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! JSON-RPC endpoint for applications, such as FEVM dapps, to use the validator network
+//! as a content-addressed data availability layer through the IPLD Resolver.
+//!
+//! It has no authentication, so it should only listen on a local interface,
+//! or behind a proxy that decides who can use it.
+//!
+//! * `ipld_putBlock(data, codec)` stores a hex encoded block with the `raw` or `dag-cbor` codec and returns its CID.
+//! * `ipld_getBlock(cid)` returns the hex encoded block, or `null` if it's not in the store.
+//! * `ipld_provide(cid, ttl)` announces the DAG under the CID to the network for `ttl` seconds.
+//! * `ipld_unprovide(cid)` stops announcing the DAG.
+//! * `ipld_resolve(cid)` fetches the DAG from its providers into the store, up to the size limit of the resolver.
+//! * `ipld_publishErasureCoded(cid)` gossips erasure coded chunks of the DAG to the validators of
+//!   this subnet and its parent, so they can reconstruct it even if this subnet goes offline.
+//!
+//! Content stored or resolved through the API is kept for the configured retention period,
+//! or until its provider TTL expires, whichever is later; [run_content_gc] deletes it afterwards.
+//! The store is shared with the resolver, so only the blocks the API itself added are ever deleted.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use axum::routing::post;
+use axum::{Json, Router};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use fendermint_vm_core::Timestamp;
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::{DAG_CBOR, IPLD_RAW};
+use ipc_api::subnet_id::SubnetID;
+use ipc_ipld_resolver::{Client, ProgressCallback, ResolveProgress};
+use jsonrpc_v2::{Data, MapRouter, Params, RequestObject, ResponseObjects, Server};
+use libipld::{Block, DefaultParams};
+use serde::{Deserialize, Serialize};
+
+use crate::store::DeletableBlockstore;
+
+type ApiResult<T> = Result<T, jsonrpc_v2::Error>;
+
+/// Passed to every method handler.
+pub struct ContentApiState<BS, V> {
+    /// Store the resolver reads from and writes to.
+    store: BS,
+    client: Client<V>,
+    /// The subnet of the node, whose topic erasure coded chunks are published on.
+    subnet_id: SubnetID,
+    max_block_size: usize,
+    resolve_timeout: Duration,
+    /// The content stored or resolved through the API, which the garbage collection must not delete yet.
+    retention: Arc<ContentRetention>,
+    retention_period: Duration,
+}
+
+impl<BS, V> ContentApiState<BS, V> {
+    pub fn new(
+        store: BS,
+        client: Client<V>,
+        subnet_id: SubnetID,
+        max_block_size: usize,
+        resolve_timeout: Duration,
+        retention: Arc<ContentRetention>,
+        retention_period: Duration,
+    ) -> Self {
+        Self {
+            store,
+            client,
+            subnet_id,
+            max_block_size,
+            resolve_timeout,
+            retention,
+            retention_period,
+        }
+    }
+
+    /// Keep the DAG under a CID for at least the retention period.
+    async fn retain(&self, cid: Cid) {
+        self.retention.retain(cid, self.retention_period).await
+    }
+}
+
+/// The content stored or resolved through the API, and the blocks the API added to the store for it.
+///
+/// The lock is held while the garbage collection runs, and the content is retained
+/// before it's written, so blocks can't be deleted between being written and retained.
+#[derive(Default)]
+pub struct ContentRetention {
+    retained: tokio::sync::Mutex<Retained>,
+    /// File the retention is saved to after every collection, so content stored before a restart gets deleted too.
+    path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Retained {
+    /// The roots of the content, with the time until they have to be kept.
+    roots: HashMap<Cid, Timestamp>,
+    /// The blocks which weren't in the store before the API stored or resolved them.
+    ///
+    /// The rest of the blocks under the roots were put there by the resolver, for example
+    /// when it fetched checkpoint content, so the garbage collection must not delete them.
+    blocks: HashSet<Cid>,
+}
+
+/// The format the retention is saved in.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedRetention {
+    roots: HashMap<String, u64>,
+    blocks: Vec<String>,
+}
+
+impl ContentRetention {
+    /// Load the retention saved by a previous run, if any, and save it to the same file from now on.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let retained = if path.exists() {
+            let json =
+                std::fs::read_to_string(&path).context("failed to read content retention")?;
+            let saved: SavedRetention =
+                serde_json::from_str(&json).context("failed to parse content retention")?;
+            Retained {
+                roots: saved
+                    .roots
+                    .into_iter()
+                    .map(|(cid, expiry)| Ok((Cid::from_str(&cid)?, Timestamp(expiry))))
+                    .collect::<anyhow::Result<_>>()?,
+                blocks: saved
+                    .blocks
+                    .iter()
+                    .map(|cid| Ok(Cid::from_str(cid)?))
+                    .collect::<anyhow::Result<_>>()?,
+            }
+        } else {
+            Retained::default()
+        };
+        Ok(Self {
+            retained: tokio::sync::Mutex::new(retained),
+            path: Some(path),
+        })
+    }
+
+    /// Keep the DAG under a CID for at least the given time.
+    pub async fn retain(&self, cid: Cid, ttl: Duration) {
+        self.retained.lock().await.retain(cid, ttl)
+    }
+
+    /// Let the DAG under a CID be deleted by the next collection.
+    pub async fn release(&self, cid: &Cid) {
+        if let Some(expiry) = self.retained.lock().await.roots.get_mut(cid) {
+            *expiry = Timestamp::current();
+        }
+    }
+
+    /// Record blocks added to the store by the API, which the garbage collection may delete.
+    async fn add_blocks(&self, blocks: impl IntoIterator<Item = Cid>) {
+        self.retained.lock().await.blocks.extend(blocks)
+    }
+
+    fn save(&self, retained: &Retained) -> anyhow::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let saved = SavedRetention {
+            roots: retained
+                .roots
+                .iter()
+                .map(|(cid, expiry)| (cid.to_string(), expiry.0))
+                .collect(),
+            blocks: retained.blocks.iter().map(|cid| cid.to_string()).collect(),
+        };
+        let part = path.with_extension("part");
+        std::fs::write(&part, serde_json::to_string(&saved)?)?;
+        std::fs::rename(&part, path)?;
+        Ok(())
+    }
+}
+
+impl Retained {
+    fn retain(&mut self, cid: Cid, ttl: Duration) {
+        let until = Timestamp(Timestamp::current().0.saturating_add(ttl.as_secs()));
+        let expiry = self.roots.entry(cid).or_insert(until);
+        expiry.0 = std::cmp::max(expiry.0, until.0);
+    }
+}
+
+/// Summary of a successful resolution.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ResolveSummary {
+    /// Number of peers found to provide the content.
+    pub providers: usize,
+    /// Number of blocks fetched from the providers; 0 if everything was already in the store.
+    pub blocks: usize,
+}
+
+/// Serve the JSON-RPC endpoint until the process is stopped.
+pub async fn listen<BS, V>(
+    listen_addr: SocketAddr,
+    state: ContentApiState<BS, V>,
+) -> anyhow::Result<()>
+where
+    BS: Blockstore + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    let server = Server::new()
+        .with_data(Data(Arc::new(state)))
+        .with_method("ipld_putBlock", put_block::<BS, V>)
+        .with_method("ipld_getBlock", get_block::<BS, V>)
+        .with_method("ipld_provide", provide::<BS, V>)
+        .with_method("ipld_unprovide", unprovide::<BS, V>)
+        .with_method("ipld_resolve", resolve::<BS, V>)
+        .with_method("ipld_publishErasureCoded", publish_erasure_coded::<BS, V>)
+        .finish();
+
+    let router = Router::new().route("/", post(handle)).with_state(server);
+    let server = axum::Server::try_bind(&listen_addr)?.serve(router.into_make_service());
+    tracing::info!(?listen_addr, "bound content API");
+    server.await?;
+    Ok(())
+}
+
+async fn handle(
+    axum::extract::State(server): axum::extract::State<Arc<Server<MapRouter>>>,
+    Json(request): Json<RequestObject>,
+) -> Json<ResponseObjects> {
+    Json(server.handle(request).await)
+}
+
+/// Store a block and return its CID.
+async fn put_block<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((block, codec)): Params<(String, String)>,
+) -> ApiResult<String>
+where
+    BS: Blockstore,
+{
+    let codec = match codec.as_str() {
+        "raw" => IPLD_RAW,
+        "dag-cbor" => DAG_CBOR,
+        other => return Err(api_error(anyhow!("unsupported codec: {other}"))),
+    };
+    let block = decode_hex(&block)?;
+
+    if block.len() > data.max_block_size {
+        return Err(api_error(anyhow!(
+            "block is larger than the limit of {} bytes",
+            data.max_block_size
+        )));
+    }
+
+    let block = to_block(codec, block).map_err(api_error)?;
+    let cid = *block.cid();
+
+    {
+        let mut retained = data.retention.retained.lock().await;
+        retained.retain(cid, data.retention_period);
+        if !data.store.has(&cid).map_err(api_error)? {
+            retained.blocks.insert(cid);
+        }
+    }
+
+    data.store
+        .put_keyed(block.cid(), block.data())
+        .map_err(api_error)?;
+
+    Ok(block.cid().to_string())
+}
+
+/// Check that a block can be decoded with its codec, so its links can be followed
+/// when the DAG is provided or resolved.
+fn to_block(codec: u64, data: Vec<u8>) -> anyhow::Result<Block<DefaultParams>> {
+    let cid = Cid::new_v1(codec, Code::Blake2b256.digest(&data));
+    let block = Block::<DefaultParams>::new(cid, data)?;
+    block
+        .references(&mut Vec::<Cid>::new())
+        .context("failed to decode block")?;
+    Ok(block)
+}
+
+/// Return a block from the store, if it's there.
+async fn get_block<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((cid,)): Params<(String,)>,
+) -> ApiResult<Option<String>>
+where
+    BS: Blockstore,
+{
+    let cid = parse_cid(&cid)?;
+    let block = data.store.get(&cid).map_err(api_error)?;
+    Ok(block.map(hex::encode))
+}
+
+/// Announce the DAG under a CID to the network for a number of seconds.
+async fn provide<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((cid, ttl)): Params<(String, u64)>,
+) -> ApiResult<()>
+where
+    V: Sync + Send + 'static,
+{
+    let cid = parse_cid(&cid)?;
+    let ttl = Duration::from_secs(ttl);
+    data.client
+        .provide_content(cid, ttl)
+        .await
+        .map_err(api_error)?;
+    data.retention.retain(cid, ttl).await;
+    Ok(())
+}
+
+/// Stop announcing the DAG under a CID.
+async fn unprovide<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((cid,)): Params<(String,)>,
+) -> ApiResult<()> {
+    let cid = parse_cid(&cid)?;
+    data.client.unprovide_content(cid).map_err(api_error)?;
+    data.retention.release(&cid).await;
+    Ok(())
+}
+
+/// Fetch the DAG under a CID from its providers.
+async fn resolve<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((cid,)): Params<(String,)>,
+) -> ApiResult<ResolveSummary>
+where
+    BS: Blockstore,
+    V: Sync + Send + 'static,
+{
+    let cid = parse_cid(&cid)?;
+    let summary = Arc::new(Mutex::new(ResolveSummary::default()));
+
+    // Whatever is fetched is kept, even if the resolution fails or times out.
+    data.retain(cid).await;
+
+    // Only the blocks which weren't there already can be attributed to the API.
+    let existing = dag_blocks(&data.store, [cid]).map_err(api_error)?;
+
+    let progress: ProgressCallback = {
+        let summary = summary.clone();
+        Box::new(move |progress: ResolveProgress| {
+            let mut summary = summary.lock().expect("summary lock poisoned");
+            match progress {
+                ResolveProgress::Providers(n) => summary.providers = n,
+                ResolveProgress::Received { blocks, .. } => summary.blocks += blocks,
+                ResolveProgress::Missing(_) => summary.blocks += 1,
+            }
+        })
+    };
+
+    let res = tokio::time::timeout(
+        data.resolve_timeout,
+        data.client.resolve_content(cid, Some(progress)),
+    )
+    .await;
+
+    // Blocks arriving after a timeout aren't recorded, so they are never deleted.
+    let fetched = dag_blocks(&data.store, [cid]).map_err(api_error)?;
+    data.retention
+        .add_blocks(fetched.difference(&existing).copied())
+        .await;
+
+    res.map_err(|_| api_error(anyhow!("timeout resolving {cid}")))?
+        .and_then(|res| res)
+        .map_err(api_error)?;
+
+    let summary = summary.lock().expect("summary lock poisoned").clone();
+    Ok(summary)
+}
+
+/// Erasure code the DAG under a CID and publish the chunks, returning their number.
+async fn publish_erasure_coded<BS, V>(
+    data: Data<ContentApiState<BS, V>>,
+    Params((cid,)): Params<(String,)>,
+) -> ApiResult<usize>
+where
+    V: Sync + Send + 'static,
+{
+    let cid = parse_cid(&cid)?;
+    data.client
+        .publish_erasure_coded(data.subnet_id.clone(), cid)
+        .await
+        .map_err(api_error)
+}
+
+/// Periodically delete the content whose retention expired from the store the API writes to.
+///
+/// Blocks of an expired DAG which are also part of content still retained are kept,
+/// and so are the blocks which the API didn't add to the store itself.
+pub async fn run_content_gc<BS>(store: BS, retention: Arc<ContentRetention>, interval: Duration)
+where
+    BS: DeletableBlockstore + Clone + Send + 'static,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let store = store.clone();
+        let retention = retention.clone();
+
+        let res = tokio::task::spawn_blocking(move || {
+            let mut retained = retention.retained.blocking_lock();
+            let deleted = collect_expired(&store, &mut retained, Timestamp::current())?;
+            retention.save(&retained)?;
+            Ok::<_, anyhow::Error>(deleted)
+        })
+        .await;
+
+        match res {
+            Ok(Ok(0)) => {}
+            Ok(Ok(deleted)) => tracing::info!(deleted, "deleted expired content"),
+            Ok(Err(e)) => tracing::error!(error = ?e, "failed to delete expired content"),
+            Err(e) => tracing::error!(error = ?e, "content garbage collection panicked"),
+        }
+    }
+}
+
+/// Forget the roots which expired and delete the blocks the API added for them,
+/// returning the number of blocks deleted.
+fn collect_expired<BS: DeletableBlockstore>(
+    store: &BS,
+    retained: &mut Retained,
+    now: Timestamp,
+) -> anyhow::Result<usize> {
+    let (expired, kept): (Vec<_>, Vec<_>) = retained.roots.drain().partition(|(_, e)| e.0 <= now.0);
+    retained.roots.extend(kept);
+
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let keep = dag_blocks(store, retained.roots.keys().copied())?;
+    let mut deleted = 0;
+
+    for cid in dag_blocks(store, expired.into_iter().map(|(cid, _)| cid))? {
+        if !keep.contains(&cid) && retained.blocks.remove(&cid) {
+            store.delete(&cid)?;
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Collect the CIDs of the blocks reachable from the roots which are in the store.
+///
+/// Unlike the walk over the state, which only follows DAG-CBOR links, this follows the links
+/// of any codec the content can be put with. The content comes from users, so blocks which
+/// can't be decoded are treated as leaves rather than failing the garbage collection.
+fn dag_blocks<BS: Blockstore>(
+    store: &BS,
+    roots: impl IntoIterator<Item = Cid>,
+) -> anyhow::Result<HashSet<Cid>> {
+    let mut blocks = HashSet::new();
+    let mut queue = Vec::from_iter(roots);
+
+    while let Some(cid) = queue.pop() {
+        if blocks.contains(&cid) {
+            continue;
+        }
+        let Some(data) = store.get(&cid)? else {
+            continue;
+        };
+        blocks.insert(cid);
+
+        let block = Block::<DefaultParams>::new_unchecked(cid, data);
+        if let Err(e) = block.references(&mut queue) {
+            tracing::debug!(error = ?e, %cid, "failed to decode content block");
+        }
+    }
+
+    Ok(blocks)
+}
+
+fn parse_cid(cid: &str) -> ApiResult<Cid> {
+    Cid::from_str(cid)
+        .context("failed to parse CID")
+        .map_err(api_error)
+}
+
+fn decode_hex(data: &str) -> ApiResult<Vec<u8>> {
+    let data = data.strip_prefix("0x").unwrap_or(data);
+    if data.is_empty() {
+        return Err(api_error(anyhow!("empty block")));
+    }
+    hex::decode(data)
+        .context("failed to decode data as hex")
+        .map_err(api_error)
+}
+
+fn api_error(e: impl Into<anyhow::Error>) -> jsonrpc_v2::Error {
+    jsonrpc_v2::Error::Full {
+        code: 0,
+        message: format!("{:#}", e.into()),
+        data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fendermint_redb::blockstore::NamespaceBlockstore;
+    use fendermint_redb::Redb;
+    use fendermint_vm_core::Timestamp;
+    use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::{to_vec, DAG_CBOR, IPLD_RAW};
+    use libipld::Ipld;
+
+    use super::{collect_expired, to_block, ContentRetention, Retained};
+
+    fn open_store(dir: &tempfile::TempDir) -> NamespaceBlockstore {
+        let db = Redb::open_ns(dir.path().join("test.redb"), ["bit"].iter()).unwrap();
+        NamespaceBlockstore::new(db, "bit".into()).unwrap()
+    }
+
+    fn put(store: &NamespaceBlockstore, codec: u64, data: Vec<u8>) -> Cid {
+        let block = to_block(codec, data).unwrap();
+        store.put_keyed(block.cid(), block.data()).unwrap();
+        *block.cid()
+    }
+
+    fn put_links(store: &NamespaceBlockstore, links: &[Cid]) -> Cid {
+        let ipld = Ipld::List(links.iter().copied().map(Ipld::Link).collect());
+        put(store, DAG_CBOR, to_vec(&ipld).unwrap())
+    }
+
+    #[test]
+    fn blocks_must_decode() {
+        assert!(to_block(IPLD_RAW, b"anything".to_vec()).is_ok());
+        assert!(to_block(DAG_CBOR, to_vec(&Ipld::String("foo".into())).unwrap()).is_ok());
+        assert!(to_block(DAG_CBOR, vec![0xff, 0x00]).is_err());
+    }
+
+    #[test]
+    fn collect_expired_keeps_retained_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+
+        let shared = put(&store, IPLD_RAW, b"shared".to_vec());
+        let only_expired = put(&store, IPLD_RAW, b"only expired".to_vec());
+        let expired = put_links(&store, &[shared, only_expired]);
+        let retained = put_links(&store, &[shared]);
+
+        let mut state = Retained {
+            roots: HashMap::from([(expired, Timestamp(10)), (retained, Timestamp(30))]),
+            blocks: HashSet::from([shared, only_expired, expired, retained]),
+        };
+
+        let deleted = collect_expired(&store, &mut state, Timestamp(20)).unwrap();
+
+        assert_eq!(deleted, 2);
+        assert!(!store.has(&expired).unwrap());
+        assert!(!store.has(&only_expired).unwrap());
+        assert!(store.has(&shared).unwrap());
+        assert!(store.has(&retained).unwrap());
+        assert_eq!(state.roots.keys().collect::<Vec<_>>(), vec![&retained]);
+
+        // Nothing else expired yet.
+        assert_eq!(
+            collect_expired(&store, &mut state, Timestamp(20)).unwrap(),
+            0
+        );
+        assert_eq!(
+            collect_expired(&store, &mut state, Timestamp(30)).unwrap(),
+            2
+        );
+        assert!(state.roots.is_empty());
+        assert!(state.blocks.is_empty());
+    }
+
+    #[test]
+    fn collect_expired_keeps_blocks_not_added_by_the_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+
+        // A block the resolver fetched for a checkpoint, which the API content happens to link to.
+        let checkpoint = put(&store, IPLD_RAW, b"checkpoint".to_vec());
+        let added = put(&store, IPLD_RAW, b"added".to_vec());
+        let root = put_links(&store, &[checkpoint, added]);
+
+        let mut state = Retained {
+            roots: HashMap::from([(root, Timestamp(10))]),
+            blocks: HashSet::from([root, added]),
+        };
+
+        let deleted = collect_expired(&store, &mut state, Timestamp(20)).unwrap();
+
+        assert_eq!(deleted, 2);
+        assert!(!store.has(&root).unwrap());
+        assert!(!store.has(&added).unwrap());
+        assert!(store.has(&checkpoint).unwrap());
+    }
+
+    #[tokio::test]
+    async fn retention_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("retention.json");
+        let cid = Cid::new_v1(IPLD_RAW, Code::Blake2b256.digest(b"foo"));
+
+        let retention = ContentRetention::load(path.clone()).unwrap();
+        retention.retain(cid, Duration::from_secs(60)).await;
+        retention.add_blocks([cid]).await;
+        retention.save(&*retention.retained.lock().await).unwrap();
+
+        let retention = ContentRetention::load(path).unwrap();
+        let retained = retention.retained.lock().await;
+        assert!(retained.roots.get(&cid).unwrap().0 >= Timestamp::current().0 + 59);
+        assert!(retained.blocks.contains(&cid));
+
+        // Releasing makes the content expire.
+        drop(retained);
+        retention.release(&cid).await;
+        let retained = retention.retained.lock().await;
+        assert!(retained.roots.get(&cid).unwrap().0 <= Timestamp::current().0);
+    }
+}
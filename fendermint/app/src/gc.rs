@@ -0,0 +1,265 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_stm::atomically;
+use cid::Cid;
+use fendermint_rocksdb::blockstore::{GcStats, NamespaceBlockstore};
+use fendermint_vm_snapshot::SnapshotClient;
+use ipc_observability::emit;
+
+use crate::observe::BlockstoreGcCompleted;
+
+/// The root of a state which has been flushed to the blockstore but isn't committed yet.
+///
+/// Committing a block first flushes the execution state, then records its root in the
+/// state history. Garbage collection has to see the root in one of the two places, so
+/// the flush and the lookup of the roots are not allowed to overlap.
+#[derive(Clone, Default)]
+pub struct PendingStateRoot(Arc<Mutex<Option<Cid>>>);
+
+impl PendingStateRoot {
+    /// Flush a state and remember its root until [PendingStateRoot::clear] is called.
+    pub fn flush<T>(
+        &self,
+        f: impl FnOnce() -> anyhow::Result<(Cid, T)>,
+    ) -> anyhow::Result<(Cid, T)> {
+        let mut guard = self.0.lock().expect("pending state root poisoned");
+        let (root, value) = f()?;
+        *guard = Some(root);
+        Ok((root, value))
+    }
+
+    /// Forget the root once the state has been committed.
+    pub fn clear(&self) {
+        *self.0.lock().expect("pending state root poisoned") = None;
+    }
+
+    /// Look up the committed roots while no state is being flushed, adding the pending root.
+    pub fn with_roots(
+        &self,
+        f: impl FnOnce() -> anyhow::Result<Vec<Cid>>,
+    ) -> anyhow::Result<Vec<Cid>> {
+        let guard = self.0.lock().expect("pending state root poisoned");
+        let mut roots = f()?;
+        roots.extend(guard.as_ref());
+        Ok(roots)
+    }
+}
+
+/// Periodically delete the blocks from the state store which are no longer reachable
+/// from the retained state history or the snapshots.
+///
+/// If the store has a cold tier, the blocks are moved there instead of being deleted.
+///
+/// The `retained_roots` are expected to come from [crate::App::recent_state_roots],
+/// which includes the state being committed; returning `None` skips a round,
+/// e.g. while the state history is unlimited.
+/// The collection runs on a blocking thread, so block execution carries on in the meantime.
+pub async fn run_gc<F>(
+    store: NamespaceBlockstore,
+    snapshots: Option<SnapshotClient>,
+    interval: Duration,
+    retained_roots: F,
+) where
+    F: Fn() -> anyhow::Result<Option<Vec<Cid>>>,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately; give the node a chance to start first.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if let Some(stats) = gc_round(&store, snapshots.as_ref(), &retained_roots).await {
+            emit(BlockstoreGcCompleted {
+                reachable: stats.reachable,
+                deleted: stats.deleted,
+                archived: stats.archived,
+                deleted_bytes: stats.deleted_bytes,
+                reclaimed_bytes: stats.reclaimed_bytes,
+                duration_ms: stats.duration.as_millis(),
+            })
+        }
+    }
+}
+
+/// Run one garbage collection, unless it's skipped or fails.
+///
+/// Writes are recorded from before the roots are looked up, so a state flushed
+/// in the meantime is kept even if its root isn't among them.
+async fn gc_round<F>(
+    store: &NamespaceBlockstore,
+    snapshots: Option<&SnapshotClient>,
+    retained_roots: &F,
+) -> Option<GcStats>
+where
+    F: Fn() -> anyhow::Result<Option<Vec<Cid>>>,
+{
+    let run = match store.start_gc() {
+        Ok(run) => run,
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to start blockstore garbage collection");
+            return None;
+        }
+    };
+
+    let mut roots = match retained_roots() {
+        Ok(Some(roots)) => roots,
+        Ok(None) => {
+            tracing::debug!("skipping blockstore garbage collection");
+            return None;
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to collect retained state roots");
+            return None;
+        }
+    };
+
+    if let Some(client) = snapshots {
+        roots.extend(atomically(|| client.state_roots()).await);
+    }
+
+    tracing::info!(
+        roots = roots.len(),
+        "starting blockstore garbage collection"
+    );
+
+    match tokio::task::spawn_blocking(move || run.collect(roots)).await {
+        Ok(Ok(stats)) => Some(stats),
+        Ok(Err(e)) => {
+            tracing::error!(error = ?e, "blockstore garbage collection failed");
+            None
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "blockstore garbage collection panicked");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fendermint_rocksdb::blockstore::NamespaceBlockstore;
+    use fendermint_rocksdb::{RocksDb, RocksDbConfig};
+    use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::{to_vec, DAG_CBOR};
+    use libipld::Ipld;
+
+    use super::{gc_round, PendingStateRoot};
+
+    fn put_ipld(store: &impl Blockstore, ipld: &Ipld) -> Cid {
+        let bytes = to_vec(ipld).unwrap();
+        let cid = Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(&bytes));
+        store.put_keyed(&cid, &bytes).unwrap();
+        cid
+    }
+
+    fn open_store(dir: &tempfile::TempDir) -> NamespaceBlockstore {
+        let db = RocksDb::open_cf(dir.path(), &RocksDbConfig::default(), ["state"].iter()).unwrap();
+        NamespaceBlockstore::new(db, "state".to_owned()).unwrap()
+    }
+
+    /// Flush a new state with a leaf, the way the execution state is flushed in `commit`.
+    fn flush_state(
+        store: &NamespaceBlockstore,
+        pending: &PendingStateRoot,
+        name: &str,
+    ) -> (Cid, Cid) {
+        let (root, leaf) = pending
+            .flush(|| {
+                let leaf = put_ipld(store, &Ipld::String(name.into()));
+                let root = put_ipld(store, &Ipld::List(vec![Ipld::Link(leaf)]));
+                Ok((root, leaf))
+            })
+            .unwrap();
+        (root, leaf)
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_state_committed_while_reading_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let pending = PendingStateRoot::default();
+
+        let (old_root, _) = flush_state(&store, &pending, "old");
+        pending.clear();
+        let committed = Mutex::new(old_root);
+        let orphan = put_ipld(&store, &Ipld::String("orphan".into()));
+
+        // The whole commit happens right after the committed root has been read,
+        // as if it didn't have to wait for the lookup to finish.
+        let new_state = Mutex::new(None);
+        let retained_roots = || {
+            pending
+                .with_roots(|| {
+                    let roots = vec![*committed.lock().unwrap()];
+                    let state = flush_state(&store, &PendingStateRoot::default(), "new");
+                    *committed.lock().unwrap() = state.0;
+                    *new_state.lock().unwrap() = Some(state);
+                    Ok(roots)
+                })
+                .map(Some)
+        };
+
+        let stats = gc_round(&store, None, &retained_roots).await.unwrap();
+        let (new_root, new_leaf) = new_state.lock().unwrap().unwrap();
+
+        assert_eq!(stats.deleted, 1);
+        assert!(!store.has(&orphan).unwrap());
+        for cid in [old_root, new_root, new_leaf] {
+            assert!(store.has(&cid).unwrap(), "{cid} should be kept");
+        }
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_state_flushed_but_not_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let pending = PendingStateRoot::default();
+
+        let (old_root, _) = flush_state(&store, &pending, "old");
+        pending.clear();
+
+        // The new state was flushed before the collection started, but it isn't committed yet.
+        let (new_root, new_leaf) = flush_state(&store, &pending, "new");
+
+        let retained_roots = || pending.with_roots(|| Ok(vec![old_root])).map(Some);
+        let stats = gc_round(&store, None, &retained_roots).await.unwrap();
+
+        assert_eq!(stats.deleted, 0);
+        assert_eq!(stats.reachable, 4);
+        assert!(store.has(&new_root).unwrap());
+        assert!(store.has(&new_leaf).unwrap());
+
+        // Once committed, the root is expected to be among the retained ones.
+        pending.clear();
+        let retained_roots = || pending.with_roots(|| Ok(vec![new_root])).map(Some);
+        let stats = gc_round(&store, None, &retained_roots).await.unwrap();
+        assert_eq!(stats.deleted, 2);
+        assert!(!store.has(&old_root).unwrap());
+    }
+
+    #[tokio::test]
+    async fn gc_round_can_be_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let orphan = put_ipld(&store, &Ipld::String("orphan".into()));
+
+        let retained_roots = || Ok(None);
+        assert!(gc_round(&store, None, &retained_roots).await.is_none());
+        assert!(store.has(&orphan).unwrap());
+
+        // The skipped round doesn't keep the next one from starting.
+        let retained_roots = || Ok(Some(vec![]));
+        let stats = gc_round(&store, None, &retained_roots).await.unwrap();
+        assert_eq!(stats.deleted, 1);
+    }
+}
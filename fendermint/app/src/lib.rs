@@ -2,7 +2,10 @@ extern crate core;
 
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: Apache-2.0, MIT
+pub mod admin;
 mod app;
+pub mod content_api;
+pub mod gc;
 pub mod ipc;
 pub mod metrics;
 pub mod observe;
@@ -10,8 +13,9 @@ mod store;
 mod tmconv;
 mod validators;
 
-pub use app::{App, AppConfig};
-pub use store::{AppStore, BitswapBlockstore};
+pub use app::{App, AppConfig, AppState, AppStoreKey};
+pub use store::{AppStore, BitswapBlockstore, DeletableBlockstore};
+pub use tmconv::to_app_hash;
 
 // Different type from `ChainEpoch` just because we might use epoch in a more traditional sense for checkpointing.
 pub type BlockHeight = u64;
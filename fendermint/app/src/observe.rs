@@ -9,7 +9,10 @@ use ipc_observability::{
     impl_traceable, impl_traceables, lazy_static, register_metrics, serde::HexEncodableBlockHash,
     Recordable, TraceLevel, Traceable,
 };
-use prometheus::{register_counter_vec, register_int_gauge, CounterVec, IntGauge, Registry};
+use prometheus::{
+    register_counter_vec, register_int_counter, register_int_gauge, CounterVec, IntCounter,
+    IntGauge, Registry,
+};
 use tendermint::account::Id;
 
 register_metrics! {
@@ -24,6 +27,14 @@ register_metrics! {
     CONSENSUS_BLOCK_COMMITTED: IntGauge
         = register_int_gauge!("consensus_block_committed_height", "Block committed (last height)");
     MPOOL_RECEIVED: CounterVec = register_counter_vec!("mpool_received", "Message received in mpool", &["accept"]);
+    BLOCKSTORE_GC_DELETED_BLOCKS: IntCounter
+        = register_int_counter!("blockstore_gc_deleted_blocks_total", "Blocks deleted by the blockstore garbage collection");
+    BLOCKSTORE_GC_RECLAIMED_BYTES: IntCounter
+        = register_int_counter!("blockstore_gc_reclaimed_bytes_total", "Disk space reclaimed by the blockstore garbage collection");
+    BLOCKSTORE_GC_ARCHIVED_BLOCKS: IntCounter
+        = register_int_counter!("blockstore_gc_archived_blocks_total", "Blocks moved to cold storage by the blockstore garbage collection");
+    BLOCKSTORE_GC_REACHABLE_BLOCKS: IntGauge
+        = register_int_gauge!("blockstore_gc_reachable_blocks", "Blocks reachable from the retained state in the last garbage collection");
 }
 
 impl_traceables!(
@@ -37,6 +48,8 @@ impl_traceables!(
 
 impl_traceables!(TraceLevel::Info, "Mpool", MpoolReceived);
 
+impl_traceables!(TraceLevel::Info, "Storage", BlockstoreGcCompleted);
+
 pub type BlockHeight = u64;
 
 #[derive(Debug)]
@@ -101,6 +114,25 @@ impl Recordable for BlockCommitted {
     }
 }
 
+#[derive(Debug)]
+pub struct BlockstoreGcCompleted {
+    pub reachable: usize,
+    pub deleted: usize,
+    pub archived: usize,
+    pub deleted_bytes: u64,
+    pub reclaimed_bytes: u64,
+    pub duration_ms: u128,
+}
+
+impl Recordable for BlockstoreGcCompleted {
+    fn record_metrics(&self) {
+        BLOCKSTORE_GC_DELETED_BLOCKS.inc_by(self.deleted as u64);
+        BLOCKSTORE_GC_ARCHIVED_BLOCKS.inc_by(self.archived as u64);
+        BLOCKSTORE_GC_RECLAIMED_BYTES.inc_by(self.reclaimed_bytes);
+        BLOCKSTORE_GC_REACHABLE_BLOCKS.set(self.reachable as i64);
+    }
+}
+
 #[derive(Debug)]
 pub struct Message {
     pub from: Address,
@@ -189,5 +221,14 @@ mod tests {
             height: 1,
             app_hash: HexEncodableBlockHash(vec![0x01, 0x02, 0x03]),
         });
+
+        emit(BlockstoreGcCompleted {
+            reachable: 1000,
+            deleted: 100,
+            archived: 0,
+            deleted_bytes: 10000,
+            reclaimed_bytes: 8000,
+            duration_ms: 500,
+        });
     }
 }
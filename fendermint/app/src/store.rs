@@ -5,6 +5,7 @@ use ipc_ipld_resolver::missing_blocks::missing_blocks;
 use libp2p_bitswap::BitswapStore;
 use std::borrow::Cow;
 
+use fendermint_redb::blockstore::NamespaceBlockstore as RedbBlockstore;
 use fendermint_rocksdb::blockstore::NamespaceBlockstore;
 use fendermint_storage::{Codec, Decode, Encode, KVError, KVResult, KVStore};
 use fvm_ipld_blockstore::Blockstore;
@@ -43,20 +44,37 @@ where
     }
 }
 
+/// A [`Blockstore`] which blocks can be removed from, e.g. content which is no longer needed.
+pub trait DeletableBlockstore: Blockstore {
+    fn delete(&self, k: &Cid) -> anyhow::Result<()>;
+}
+
+impl DeletableBlockstore for NamespaceBlockstore {
+    fn delete(&self, k: &Cid) -> anyhow::Result<()> {
+        NamespaceBlockstore::delete(self, k)
+    }
+}
+
+impl DeletableBlockstore for RedbBlockstore {
+    fn delete(&self, k: &Cid) -> anyhow::Result<()> {
+        RedbBlockstore::delete(self, k)
+    }
+}
+
 /// A `Blockstore` and `BitswapStore` implementation we can pass to the IPLD Resolver.
-pub struct BitswapBlockstore {
+pub struct BitswapBlockstore<BS = NamespaceBlockstore> {
     /// The `Blockstore` implementation where we the FVM actors store their data.
     ///
     /// This must not be written to by Bitswap operations, because that could result
     /// in some nodes having some data that others don't, which would lead to a
     /// consensu failure. We can use read data from it, but not write to it.
-    state_store: NamespaceBlockstore,
+    state_store: BS,
     /// The `Blockstore` implementation where Bitswap operations can write to.
-    bit_store: NamespaceBlockstore,
+    bit_store: BS,
 }
 
-impl BitswapBlockstore {
-    pub fn new(state_store: NamespaceBlockstore, bit_store: NamespaceBlockstore) -> Self {
+impl<BS> BitswapBlockstore<BS> {
+    pub fn new(state_store: BS, bit_store: BS) -> Self {
         Self {
             state_store,
             bit_store,
@@ -64,7 +82,7 @@ impl BitswapBlockstore {
     }
 }
 
-impl Blockstore for BitswapBlockstore {
+impl<BS: Blockstore> Blockstore for BitswapBlockstore<BS> {
     fn has(&self, k: &cid::Cid) -> anyhow::Result<bool> {
         if self.bit_store.has(k)? {
             Ok(true)
@@ -95,7 +113,10 @@ impl Blockstore for BitswapBlockstore {
     }
 }
 
-impl BitswapStore for BitswapBlockstore {
+impl<BS> BitswapStore for BitswapBlockstore<BS>
+where
+    BS: Blockstore + Send + Sync + 'static,
+{
     type Params = libipld::DefaultParams;
 
     fn contains(&mut self, cid: &Cid) -> anyhow::Result<bool> {
@@ -9,7 +9,7 @@ use fendermint_vm_interpreter::fvm::{
     FvmApplyRet, FvmCheckRet, FvmQueryRet,
 };
 use fendermint_vm_message::signed::DomainHash;
-use fendermint_vm_snapshot::{SnapshotItem, SnapshotManifest};
+use fendermint_vm_snapshot::{ChunkCompression, SnapshotItem, SnapshotManifest};
 use fvm_shared::{address::Address, error::ExitCode, event::StampedEvent, ActorID};
 use prost::Message;
 use serde::{Deserialize, Serialize};
@@ -241,6 +241,21 @@ pub fn to_message_event(from: Address, to: Address) -> Event {
     )
 }
 
+/// Respond to a query about the local state of the node, rather than the ledger.
+pub fn to_node_query<T: Serialize>(
+    value: &T,
+    block_height: BlockHeight,
+) -> anyhow::Result<response::Query> {
+    let value = fvm_ipld_encoding::to_vec(value).context("failed to encode node query result")?;
+    let height = tendermint::block::Height::try_from(block_height).context("height too big")?;
+
+    Ok(response::Query {
+        value: value.into(),
+        height,
+        ..Default::default()
+    })
+}
+
 /// Map to query results.
 pub fn to_query(ret: FvmQueryRet, block_height: BlockHeight) -> anyhow::Result<response::Query> {
     let exit_code = match ret {
@@ -251,6 +266,9 @@ pub fn to_query(ret: FvmQueryRet, block_height: BlockHeight) -> anyhow::Result<r
         FvmQueryRet::Call(_) | FvmQueryRet::EstimateGas(_) => ExitCode::OK,
         FvmQueryRet::StateParams(_) => ExitCode::OK,
         FvmQueryRet::BuiltinActors(_) => ExitCode::OK,
+        // Missing actors are indicated in the individual samples.
+        FvmQueryRet::ActorStateRange(_) => ExitCode::OK,
+        FvmQueryRet::CallBatch(_) => ExitCode::OK,
     };
 
     // The return value has a `key` field which is supposed to be set to the data matched.
@@ -270,11 +288,7 @@ pub fn to_query(ret: FvmQueryRet, block_height: BlockHeight) -> anyhow::Result<r
             // Send back an entire Tendermint deliver_tx response, encoded as IPLD.
             // This is so there is a single representation of a call result, instead
             // of a normal delivery being one way and a query exposing `FvmApplyRet`.
-            let dtx = to_deliver_tx(*ret, None, None);
-            let dtx = tendermint_proto::abci::ResponseDeliverTx::from(dtx);
-            let mut buf = bytes::BytesMut::new();
-            dtx.encode(&mut buf)?;
-            let bz = buf.to_vec();
+            let bz = to_deliver_tx_proto(*ret)?;
             // So the value is an IPLD encoded Protobuf byte vector.
             let v = ipld_encode!(bz);
             (Vec::new(), v)
@@ -291,6 +305,19 @@ pub fn to_query(ret: FvmQueryRet, block_height: BlockHeight) -> anyhow::Result<r
             let v = ipld_encode!(ba);
             (Vec::new(), v)
         }
+        FvmQueryRet::ActorStateRange(samples) => {
+            let v = ipld_encode!(samples);
+            (Vec::new(), v)
+        }
+        FvmQueryRet::CallBatch(rets) => {
+            // Same as a single call, but a list of them.
+            let bzs = rets
+                .into_iter()
+                .map(to_deliver_tx_proto)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let v = ipld_encode!(bzs);
+            (Vec::new(), v)
+        }
     };
 
     // The height here is the height of the block that was committed, not in which the app hash appeared.
@@ -308,6 +335,15 @@ pub fn to_query(ret: FvmQueryRet, block_height: BlockHeight) -> anyhow::Result<r
     Ok(res)
 }
 
+/// Encode the result of a read-only call as a Protobuf `ResponseDeliverTx`.
+fn to_deliver_tx_proto(ret: FvmApplyRet) -> anyhow::Result<Vec<u8>> {
+    let dtx = to_deliver_tx(ret, None, None);
+    let dtx = tendermint_proto::abci::ResponseDeliverTx::from(dtx);
+    let mut buf = bytes::BytesMut::new();
+    dtx.encode(&mut buf)?;
+    Ok(buf.to_vec())
+}
+
 /// Project Genesis validators to Tendermint.
 /// TODO: the import is quite strange, `Validator` and `Power` are imported from `genesis` crate,
 /// TODO: which should be from a `type` or `validator` crate.
@@ -392,7 +428,7 @@ pub fn to_snapshot(snapshot: SnapshotItem) -> anyhow::Result<tendermint::abci::t
             .block_height
             .try_into()
             .expect("height is valid"),
-        format: snapshot.manifest.version,
+        format: snapshot.manifest.format(),
         chunks: snapshot.manifest.chunks,
         hash: snapshot.manifest.checksum.into(),
         metadata: fvm_ipld_encoding::to_vec(&metadata)?.into(),
@@ -419,13 +455,18 @@ pub fn from_snapshot(
     let checksum = tendermint::hash::Hash::try_from(offer.snapshot.hash)
         .context("failed to parse checksum")?;
 
+    let (version, compression) = ChunkCompression::from_format(offer.snapshot.format);
+
     let manifest = SnapshotManifest {
         block_height: offer.snapshot.height.value(),
         size: metadata.size,
         chunks: offer.snapshot.chunks,
         checksum,
         state_params: metadata.state_params,
-        version: offer.snapshot.format,
+        version,
+        // Only full snapshots are offered over state sync.
+        base: None,
+        compression,
     };
 
     Ok(manifest)
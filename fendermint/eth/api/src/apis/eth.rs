@@ -30,7 +30,7 @@ use fvm_shared::{chainid::ChainID, error::ExitCode};
 use jsonrpc_v2::Params;
 use rand::Rng;
 use tendermint::block::Height;
-use tendermint_rpc::endpoint::{self, status};
+use tendermint_rpc::endpoint::status;
 use tendermint_rpc::SubscriptionClient;
 use tendermint_rpc::{
     endpoint::{block, block_results, broadcast::tx_sync, consensus_params, header},
@@ -41,11 +41,12 @@ use crate::conv::from_eth::{self, derive_origin_kind, to_fvm_message};
 use crate::conv::from_tm::{self, msg_hash, to_chain_message, to_cumulative, to_eth_block_zero};
 use crate::error::{error_with_revert, OutOfSequence};
 use crate::filters::{matches_topics, FilterId, FilterKind, FilterRecords};
+use crate::state::to_json_block;
 use crate::{
     conv::{
         from_eth::to_fvm_address,
         from_fvm::to_eth_tokens,
-        from_tm::{to_eth_receipt, to_eth_transaction_response},
+        from_tm::{to_eth_block_receipts, to_eth_receipt, to_eth_transaction_response},
     },
     error, JsonRpcData, JsonRpcResult,
 };
@@ -338,6 +339,10 @@ pub async fn get_block_by_hash<C>(
 where
     C: Client + Sync + Send,
 {
+    if let Some(block) = data.lookup_index(|index| index.block_by_hash(&block_hash))? {
+        return to_json_block(block, full_tx).map(Some);
+    }
+
     match data.block_by_hash_opt(block_hash).await? {
         Some(block) if from_tm::is_block_zero(&block) => Ok(Some(to_eth_block_zero(block)?)),
         Some(block) => data.enrich_block(block, full_tx).await.map(Some),
@@ -353,6 +358,12 @@ pub async fn get_block_by_number<C>(
 where
     C: Client + Sync + Send,
 {
+    if let BlockNumber::Number(height) = block_number {
+        if let Some(block) = data.lookup_index(|index| index.block_by_height(height.as_u64()))? {
+            return to_json_block(block, full_tx).map(Some);
+        }
+    }
+
     match data.block_by_height(block_number).await? {
         block if block.header().height.value() > 0 => {
             data.enrich_block(block, full_tx).await.map(Some)
@@ -429,6 +440,8 @@ where
     if let Some((tx, sig)) = data.tx_cache.get(&tx_hash) {
         let tx = from_eth::to_eth_transaction_response(&tx, sig)?;
         Ok(Some(tx))
+    } else if let Some(tx) = data.lookup_index(|index| index.transaction(&tx_hash))? {
+        Ok(Some(tx))
     } else if let Some(res) = data.tx_by_hash(tx_hash).await? {
         let msg = to_chain_message(&res.tx)?;
 
@@ -487,6 +500,10 @@ pub async fn get_transaction_receipt<C>(
 where
     C: Client + Sync + Send,
 {
+    if let Some(receipt) = data.lookup_index(|index| index.receipt(&tx_hash))? {
+        return Ok(Some(receipt));
+    }
+
     let Some(tx_res) = data.tx_by_hash(tx_hash).await? else {
         return Ok(None);
     };
@@ -531,6 +548,12 @@ pub async fn get_block_receipts<C>(
 where
     C: Client + Sync + Send,
 {
+    if let BlockNumber::Number(height) = block_number {
+        if let Some(receipts) = data.lookup_index(|index| index.block_receipts(height.as_u64()))? {
+            return Ok(receipts);
+        }
+    }
+
     let block = data.block_by_height(block_number).await?;
     if from_tm::is_block_zero(&block) {
         return Ok(Vec::new());
@@ -541,37 +564,10 @@ where
         .state_params(FvmQueryHeight::Height(height.value()))
         .await?;
     let block_results: block_results::Response = data.tm().block_results(height).await?;
-    let cumulative = to_cumulative(&block_results);
-    let mut receipts = Vec::new();
-
-    for (index, (tx, tx_result)) in block
-        .data
-        .into_iter()
-        .zip(block_results.txs_results.unwrap_or_default())
-        .enumerate()
-    {
-        let msg = to_chain_message(&tx)?;
-        if let ChainMessage::Signed(msg) = msg {
-            let result = endpoint::tx::Response {
-                hash: Default::default(), // Shouldn't use this anyway.
-                height,
-                index: index as u32,
-                tx_result,
-                tx,
-                proof: None,
-            };
+    let receipts = to_eth_block_receipts(&block, &block_results, &state_params.value.base_fee)
+        .await
+        .context("failed to convert to receipts")?;
 
-            let receipt = to_eth_receipt(
-                &msg,
-                &result,
-                &cumulative,
-                &block.header,
-                &state_params.value.base_fee,
-            )
-            .await?;
-            receipts.push(receipt)
-        }
-    }
     Ok(receipts)
 }
 
@@ -240,6 +240,37 @@ pub fn to_cumulative(block_results: &endpoint::block_results::Response) -> Vec<(
     records
 }
 
+/// Receipts of all the signed transactions in a block.
+pub async fn to_eth_block_receipts(
+    block: &tendermint::Block,
+    block_results: &endpoint::block_results::Response,
+    base_fee: &TokenAmount,
+) -> anyhow::Result<Vec<et::TransactionReceipt>> {
+    let cumulative = to_cumulative(block_results);
+    let tx_results = block_results.txs_results.as_deref().unwrap_or_default();
+    let mut receipts = Vec::new();
+
+    for (index, (tx, tx_result)) in block.data.iter().zip(tx_results).enumerate() {
+        let msg = to_chain_message(tx)?;
+        if let ChainMessage::Signed(msg) = msg {
+            let result = endpoint::tx::Response {
+                hash: Default::default(), // Shouldn't use this anyway.
+                height: block.header.height,
+                index: index as u32,
+                tx_result: tx_result.clone(),
+                tx: tx.clone(),
+                proof: None,
+            };
+
+            let receipt =
+                to_eth_receipt(&msg, &result, &cumulative, &block.header, base_fee).await?;
+
+            receipts.push(receipt)
+        }
+    }
+    Ok(receipts)
+}
+
 // https://github.com/filecoin-project/lotus/blob/6cc506f5cf751215be6badc94a960251c6453202/node/impl/full/eth.go#L2174
 // https://github.com/evmos/ethermint/blob/07cf2bd2b1ce9bdb2e44ec42a39e7239292a14af/rpc/backend/tx_info.go#L147
 pub async fn to_eth_receipt(
@@ -0,0 +1,348 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Optional index of Ethereum-shaped blocks, transactions and receipts.
+//!
+//! Without it every request rebuilds them from the CometBFT `block` and `block_results` RPCs,
+//! which is slow and stops working once CometBFT prunes the blocks. The [Indexer] follows the
+//! chain and persists the converted data, which the API serves directly when it's there.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use ethers_core::types as et;
+use fendermint_rocksdb::{RocksDb, RocksDbConfig};
+use fendermint_rpc::client::FendermintClient;
+use fendermint_rpc::query::QueryClient;
+use fendermint_storage::{
+    Codec, Decode, Encode, KVCollection, KVError, KVReadable, KVResult, KVStore, KVTransaction,
+    KVWritable,
+};
+use fendermint_vm_message::query::FvmQueryHeight;
+use fvm_shared::chainid::ChainID;
+use serde::{de::DeserializeOwned, Serialize};
+use tendermint::block::Height;
+use tendermint_rpc::endpoint::{block, block_results, status};
+use tendermint_rpc::Client;
+
+use crate::conv::from_tm::{to_eth_block, to_eth_block_receipts};
+
+/// Key of the last indexed height in the metadata namespace.
+const LAST_HEIGHT_KEY: &str = "last_height";
+
+/// Key-value store of the index, with the values kept as JSON, the same as the API returns them.
+#[derive(Clone)]
+pub struct IndexStore;
+
+impl KVStore for IndexStore {
+    type Namespace = String;
+    type Repr = Vec<u8>;
+}
+
+impl<T: Serialize> Encode<T> for IndexStore {
+    fn to_repr(value: &T) -> KVResult<Cow<Self::Repr>> {
+        serde_json::to_vec(value)
+            .map_err(|e| KVError::Codec(Box::new(e)))
+            .map(Cow::Owned)
+    }
+}
+
+impl<T: DeserializeOwned> Decode<T> for IndexStore {
+    fn from_repr(repr: &Self::Repr) -> KVResult<T> {
+        serde_json::from_slice(repr).map_err(|e| KVError::Codec(Box::new(e)))
+    }
+}
+
+impl<T> Codec<T> for IndexStore where IndexStore: Encode<T> + Decode<T> {}
+
+/// The index database, shared between the [Indexer] writing it and the API reading it.
+#[derive(Clone)]
+pub struct EthIndex {
+    db: RocksDb,
+    /// Blocks by height, with full transactions.
+    blocks: KVCollection<IndexStore, u64, et::Block<et::Transaction>>,
+    /// Block heights by hash.
+    block_heights: KVCollection<IndexStore, et::H256, u64>,
+    /// Heights of the blocks transactions are included in, by transaction hash.
+    tx_heights: KVCollection<IndexStore, et::H256, u64>,
+    /// Receipts by transaction hash.
+    receipts: KVCollection<IndexStore, et::H256, et::TransactionReceipt>,
+    meta: KVCollection<IndexStore, String, u64>,
+}
+
+impl EthIndex {
+    const NAMESPACES: [&'static str; 5] =
+        ["blocks", "block_heights", "tx_heights", "receipts", "meta"];
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = RocksDb::open_cf(path, &RocksDbConfig::default(), Self::NAMESPACES.iter())
+            .context("error opening index DB")?;
+
+        let [blocks, block_heights, tx_heights, receipts, meta] =
+            Self::NAMESPACES.map(|ns| ns.to_owned());
+
+        Ok(Self {
+            db,
+            blocks: KVCollection::new(blocks),
+            block_heights: KVCollection::new(block_heights),
+            tx_heights: KVCollection::new(tx_heights),
+            receipts: KVCollection::new(receipts),
+            meta: KVCollection::new(meta),
+        })
+    }
+
+    /// The height of the last block in the index, if there is any.
+    pub fn last_height(&self) -> anyhow::Result<Option<u64>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        Ok(self.meta.get(&tx, &LAST_HEIGHT_KEY.to_owned())?)
+    }
+
+    pub fn block_by_height(
+        &self,
+        height: u64,
+    ) -> anyhow::Result<Option<et::Block<et::Transaction>>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        Ok(self.blocks.get(&tx, &height)?)
+    }
+
+    pub fn block_by_hash(
+        &self,
+        hash: &et::H256,
+    ) -> anyhow::Result<Option<et::Block<et::Transaction>>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        match self.block_heights.get(&tx, hash)? {
+            Some(height) => Ok(self.blocks.get(&tx, &height)?),
+            None => Ok(None),
+        }
+    }
+
+    pub fn transaction(&self, hash: &et::H256) -> anyhow::Result<Option<et::Transaction>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        let Some(height) = self.tx_heights.get(&tx, hash)? else {
+            return Ok(None);
+        };
+        let Some(block) = self.blocks.get(&tx, &height)? else {
+            return Ok(None);
+        };
+        Ok(block.transactions.into_iter().find(|t| t.hash == *hash))
+    }
+
+    pub fn receipt(&self, hash: &et::H256) -> anyhow::Result<Option<et::TransactionReceipt>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        Ok(self.receipts.get(&tx, hash)?)
+    }
+
+    /// Receipts of all transactions in a block, if the block is indexed.
+    pub fn block_receipts(
+        &self,
+        height: u64,
+    ) -> anyhow::Result<Option<Vec<et::TransactionReceipt>>> {
+        let tx = KVReadable::<IndexStore>::read(&self.db);
+        let Some(block) = self.blocks.get(&tx, &height)? else {
+            return Ok(None);
+        };
+        let mut receipts = Vec::new();
+        for t in block.transactions {
+            if let Some(receipt) = self.receipts.get(&tx, &t.hash)? {
+                receipts.push(receipt);
+            }
+        }
+        Ok(Some(receipts))
+    }
+
+    /// Add a block with the receipts of its transactions, and make it the last indexed height.
+    pub fn put_block(
+        &self,
+        block: &et::Block<et::Transaction>,
+        receipts: &[et::TransactionReceipt],
+    ) -> anyhow::Result<()> {
+        let height = block.number.context("block has no number")?.as_u64();
+        let hash = block.hash.context("block has no hash")?;
+
+        let mut tx = KVWritable::<IndexStore>::write(&self.db);
+
+        self.blocks.put(&mut tx, &height, block)?;
+        self.block_heights.put(&mut tx, &hash, &height)?;
+        for t in block.transactions.iter() {
+            self.tx_heights.put(&mut tx, &t.hash, &height)?;
+        }
+        for r in receipts {
+            self.receipts.put(&mut tx, &r.transaction_hash, r)?;
+        }
+        self.meta
+            .put(&mut tx, &LAST_HEIGHT_KEY.to_owned(), &height)?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+/// Follows CometBFT and adds every committed block to the index.
+pub struct Indexer<C> {
+    index: EthIndex,
+    client: FendermintClient<C>,
+    poll_interval: Duration,
+    /// Height to start from when the index is empty, instead of the earliest block CometBFT has.
+    start_height: Option<u64>,
+}
+
+impl<C> Indexer<C>
+where
+    C: Client + Sync + Send,
+{
+    pub fn new(
+        index: EthIndex,
+        client: C,
+        poll_interval: Duration,
+        start_height: Option<u64>,
+    ) -> Self {
+        Self {
+            index,
+            client: FendermintClient::new(client),
+            poll_interval,
+            start_height,
+        }
+    }
+
+    /// Keep indexing new blocks until the process is stopped.
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = self.catch_up().await {
+                tracing::error!(error = e.to_string(), "failed to index blocks");
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Index every block between the last indexed one and the latest one with results.
+    async fn catch_up(&self) -> anyhow::Result<()> {
+        let status: status::Response = self.client.underlying().status().await?;
+
+        // Same as the API, stay one block behind, so the results are surely available.
+        let latest = status
+            .sync_info
+            .latest_block_height
+            .value()
+            .saturating_sub(1);
+
+        let earliest = status.sync_info.earliest_block_height.value().max(1);
+
+        let mut next = match self.index.last_height()? {
+            Some(height) => height + 1,
+            None => self.start_height.unwrap_or(earliest).max(1),
+        };
+
+        if next < earliest {
+            tracing::warn!(
+                next,
+                earliest,
+                "CometBFT has pruned the blocks the index is missing; skipping them"
+            );
+            next = earliest;
+        }
+
+        while next <= latest {
+            self.index_block(next)
+                .await
+                .with_context(|| format!("failed to index block {next}"))?;
+
+            if next % 1000 == 0 {
+                tracing::info!(height = next, latest, "indexed blocks");
+            }
+
+            next += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn index_block(&self, height: u64) -> anyhow::Result<()> {
+        let height = Height::try_from(height)?;
+
+        let block: block::Response = self.client.underlying().block(height).await?;
+        let block = block.block;
+
+        // This needs the state history at the height of the block. If that has been pruned,
+        // retrying won't bring it back, so the block is indexed with the current base fee,
+        // rather than holding up every block after it.
+        let state_params = match self
+            .client
+            .state_params(FvmQueryHeight::Height(height.value()))
+            .await
+        {
+            Ok(state_params) => state_params,
+            Err(e) => {
+                tracing::warn!(
+                    height = height.value(),
+                    error = e.to_string(),
+                    "state history not available; indexing with the current base fee"
+                );
+                self.client
+                    .state_params(FvmQueryHeight::default())
+                    .await
+                    .context("failed to get the current state params")?
+            }
+        };
+
+        let base_fee = state_params.value.base_fee;
+        let chain_id = ChainID::from(state_params.value.chain_id);
+
+        let block_results: block_results::Response =
+            self.client.underlying().block_results(height).await?;
+
+        let receipts = to_eth_block_receipts(&block, &block_results, &base_fee)
+            .await
+            .context("failed to convert to receipts")?;
+
+        let block = to_eth_block(&block, block_results, base_fee, chain_id)
+            .context("failed to convert to eth block")?;
+
+        self.index.put_block(&block, &receipts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers_core::types as et;
+
+    use super::EthIndex;
+
+    #[test]
+    fn index_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = EthIndex::open(dir.path()).unwrap();
+
+        assert_eq!(index.last_height().unwrap(), None);
+
+        let tx = et::Transaction {
+            hash: et::H256::repeat_byte(1),
+            ..Default::default()
+        };
+        let receipt = et::TransactionReceipt {
+            transaction_hash: tx.hash,
+            ..Default::default()
+        };
+        let block = et::Block {
+            hash: Some(et::H256::repeat_byte(2)),
+            number: Some(et::U64::from(10)),
+            transactions: vec![tx.clone()],
+            ..Default::default()
+        };
+
+        index.put_block(&block, &[receipt.clone()]).unwrap();
+
+        assert_eq!(index.last_height().unwrap(), Some(10));
+        assert_eq!(index.block_by_height(10).unwrap(), Some(block.clone()));
+        assert_eq!(
+            index.block_by_hash(&et::H256::repeat_byte(2)).unwrap(),
+            Some(block)
+        );
+        assert_eq!(index.transaction(&tx.hash).unwrap(), Some(tx.clone()));
+        assert_eq!(index.receipt(&tx.hash).unwrap(), Some(receipt.clone()));
+        assert_eq!(index.block_receipts(10).unwrap(), Some(vec![receipt]));
+        assert_eq!(index.block_receipts(11).unwrap(), None);
+    }
+}
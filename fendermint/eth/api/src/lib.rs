@@ -16,10 +16,12 @@ mod error;
 mod filters;
 mod gas;
 mod handlers;
+mod indexer;
 mod mpool;
 mod state;
 
 pub use client::{HybridClient, HybridClientDriver};
+pub use indexer::{EthIndex, Indexer};
 
 use error::{error, JsonRpcError};
 use state::{JsonRpcState, Nonce};
@@ -59,6 +61,7 @@ pub async fn listen<A: ToSocketAddrs>(
     max_nonce_gap: Nonce,
     gas_opt: GasOpt,
     cors_opt: CorsOpt,
+    index: Option<EthIndex>,
 ) -> anyhow::Result<()> {
     if let Some(listen_addr) = listen_addr.to_socket_addrs()?.next() {
         let rpc_state = Arc::new(JsonRpcState::new(
@@ -67,6 +70,7 @@ pub async fn listen<A: ToSocketAddrs>(
             cache_capacity,
             max_nonce_gap,
             gas_opt,
+            index,
         ));
 
         // Start the transaction cache pruning subscription.
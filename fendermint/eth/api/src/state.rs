@@ -37,6 +37,7 @@ use crate::filters::{
     FilterRecords,
 };
 use crate::handlers::ws::MethodNotification;
+use crate::indexer::EthIndex;
 use crate::mpool::{TransactionBuffer, TransactionCache};
 use crate::GasOpt;
 use crate::{
@@ -70,6 +71,8 @@ pub struct JsonRpcState<C> {
     web_sockets: RwLock<HashMap<WebSocketId, WebSocketSender>>,
     pub max_nonce_gap: Nonce,
     pub gas_opt: GasOpt,
+    /// Blocks and receipts indexed ahead of time, if the indexer is enabled.
+    index: Option<EthIndex>,
 }
 
 impl<C> JsonRpcState<C>
@@ -82,6 +85,7 @@ where
         cache_capacity: usize,
         max_nonce_gap: Nonce,
         gas_opt: GasOpt,
+        index: Option<EthIndex>,
     ) -> Self {
         let client = FendermintClient::new(client);
         let addr_cache = AddressCache::new(client.clone(), cache_capacity);
@@ -101,6 +105,7 @@ where
             web_sockets: Default::default(),
             gas_opt,
             max_nonce_gap,
+            index,
         }
     }
 }
@@ -111,6 +116,20 @@ impl<C> JsonRpcState<C> {
         self.client.underlying()
     }
 
+    /// Look something up in the block index, if it's enabled.
+    ///
+    /// Returns `None` if the index is disabled or doesn't have the item, in which case
+    /// the caller should fall back to reconstructing it from CometBFT.
+    pub fn lookup_index<T>(
+        &self,
+        f: impl FnOnce(&EthIndex) -> anyhow::Result<Option<T>>,
+    ) -> JsonRpcResult<Option<T>> {
+        match self.index {
+            Some(ref index) => Ok(f(index)?),
+            None => Ok(None),
+        }
+    }
+
     /// Register the sender of a web socket.
     pub async fn add_web_socket(&self, tx: WebSocketSender) -> WebSocketId {
         let next_id = self.next_web_socket_id.fetch_add(1, Ordering::Relaxed);
@@ -326,14 +345,7 @@ where
     {
         let block = enrich_block(&self.client, &block).await?;
 
-        let block = if full_tx {
-            map_rpc_block_txs(block, serde_json::to_value).context("failed to convert to JSON")?
-        } else {
-            map_rpc_block_txs(block, |h| serde_json::to_value(h.hash))
-                .context("failed to convert hash to JSON")?
-        };
-
-        Ok(block)
+        to_json_block(block, full_tx)
     }
 
     /// Get a transaction from a block by index.
@@ -630,6 +642,21 @@ impl<C> JsonRpcState<C> {
     }
 }
 
+/// Render the transactions of a block either in full or as hashes.
+pub fn to_json_block(
+    block: et::Block<et::Transaction>,
+    full_tx: bool,
+) -> JsonRpcResult<et::Block<serde_json::Value>> {
+    let block = if full_tx {
+        map_rpc_block_txs(block, serde_json::to_value).context("failed to convert to JSON")?
+    } else {
+        map_rpc_block_txs(block, |h| serde_json::to_value(h.hash))
+            .context("failed to convert hash to JSON")?
+    };
+
+    Ok(block)
+}
+
 pub async fn enrich_block<C>(
     client: &FendermintClient<C>,
     block: &tendermint::Block,
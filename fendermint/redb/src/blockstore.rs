@@ -0,0 +1,115 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+use redb::{Database, ReadableTable};
+
+use crate::db::table;
+use crate::Redb;
+
+/// A [`Blockstore`] implementation that writes to a specific table of the database.
+///
+/// Every write is its own transaction. KV write transactions only lock the database
+/// while they commit, so the [`Blockstore`] can be written to while one is open.
+#[derive(Clone)]
+pub struct NamespaceBlockstore {
+    db: Arc<Database>,
+    ns: String,
+}
+
+impl NamespaceBlockstore {
+    pub fn new(db: Redb, ns: String) -> anyhow::Result<Self> {
+        // All namespaces are pre-created during open.
+        if !db.has_table(&ns)? {
+            Err(anyhow!("namespace {ns} does not exist!"))
+        } else {
+            Ok(Self { db: db.db, ns })
+        }
+    }
+
+    /// Remove a block from the namespace, for example content which is no longer needed.
+    pub fn delete(&self, k: &Cid) -> anyhow::Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut tbl = tx.open_table(table(&self.ns))?;
+            tbl.remove(k.to_bytes().as_slice())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl Blockstore for NamespaceBlockstore {
+    fn has(&self, k: &Cid) -> anyhow::Result<bool> {
+        let tx = self.db.begin_read()?;
+        let tbl = tx.open_table(table(&self.ns))?;
+        Ok(tbl.get(k.to_bytes().as_slice())?.is_some())
+    }
+
+    fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let tx = self.db.begin_read()?;
+        let tbl = tx.open_table(table(&self.ns))?;
+        let res = tbl.get(k.to_bytes().as_slice())?;
+        Ok(res.map(|v| v.value().to_vec()))
+    }
+
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+        self.put_many_keyed([(*k, block)])
+    }
+
+    // Called by the BufferedBlockstore during flush.
+    fn put_many_keyed<D, I>(&self, blocks: I) -> anyhow::Result<()>
+    where
+        Self: Sized,
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (Cid, D)>,
+    {
+        let tx = self.db.begin_write()?;
+        {
+            let mut tbl = tx.open_table(table(&self.ns))?;
+            for (cid, v) in blocks.into_iter() {
+                tbl.insert(cid.to_bytes().as_slice(), v.as_ref())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+    use fvm_ipld_blockstore::Blockstore;
+
+    use crate::Redb;
+
+    use super::NamespaceBlockstore;
+
+    #[test]
+    fn namespaces_are_separate() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Redb::open_ns(dir.path().join("test.redb"), ["foo", "bar"].iter()).unwrap();
+
+        let foo = NamespaceBlockstore::new(db.clone(), "foo".into()).unwrap();
+        let bar = NamespaceBlockstore::new(db.clone(), "bar".into()).unwrap();
+        assert!(NamespaceBlockstore::new(db, "baz".into()).is_err());
+
+        let data = b"hello".to_vec();
+        let cid = Cid::new_v1(0x55, Code::Blake2b256.digest(&data));
+
+        foo.put_keyed(&cid, &data).unwrap();
+
+        assert_eq!(foo.get(&cid).unwrap(), Some(data));
+        assert!(foo.has(&cid).unwrap());
+        assert!(!bar.has(&cid).unwrap());
+
+        foo.delete(&cid).unwrap();
+        assert!(!foo.has(&cid).unwrap());
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+use std::{path::Path, sync::Arc};
+
+use redb::{Database, TableDefinition, TableError};
+
+/// Every namespace is a table mapping binary keys to binary values.
+pub(crate) type Table<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+pub(crate) fn table(name: &str) -> Table<'_> {
+    TableDefinition::new(name)
+}
+
+/// `Redb` is a pure Rust, memory-mapped alternative to RocksDB.
+///
+/// Namespaces are tables, all stored in a single file. Reads see a consistent snapshot
+/// without blocking writes, but `redb` write transactions are serialized: starting one
+/// blocks until the previous has been committed or rolled back. Hence the KV store only
+/// opens one to apply its changes on commit.
+///
+/// Usage:
+/// ```no_run
+/// use fendermint_redb::Redb;
+///
+/// let db = Redb::open_ns("test.redb", ["foo", "bar"].iter()).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Redb {
+    pub db: Arc<Database>,
+}
+
+impl Redb {
+    /// Open the database file, creating it if it doesn't exist.
+    pub fn open<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let nss: Vec<String> = Vec::new();
+        Self::open_ns(path, nss.iter())
+    }
+
+    /// Open the database file and create any of the namespaces which don't exist yet.
+    pub fn open_ns<P, I, N>(path: P, nss: I) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+        I: Iterator<Item = N>,
+        N: AsRef<str>,
+    {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let db = Self {
+            db: Arc::new(Database::create(path)?),
+        };
+
+        let tx = db.db.begin_write()?;
+        for ns in nss {
+            tx.open_table(table(ns.as_ref()))?;
+        }
+        tx.commit()?;
+
+        Ok(db)
+    }
+
+    /// Check whether a namespace has been created.
+    pub fn has_table(&self, name: &str) -> anyhow::Result<bool> {
+        let tx = self.db.begin_read()?;
+        match tx.open_table(table(name)) {
+            Ok(_) => Ok(true),
+            Err(TableError::TableDoesNotExist(_)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
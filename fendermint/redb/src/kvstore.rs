@@ -0,0 +1,416 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use fendermint_storage::Decode;
+use fendermint_storage::Encode;
+use fendermint_storage::KVResult;
+use fendermint_storage::KVTransaction;
+use fendermint_storage::KVWritable;
+use fendermint_storage::KVWrite;
+use fendermint_storage::{KVError, KVRead, KVReadable, KVStore};
+use redb::{Database, ReadTransaction, ReadableTable};
+
+use crate::db::table;
+use crate::Redb;
+
+/// Raw key-value pairs read from a table.
+type Items = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Changes of a write transaction to a table; `None` is a deletion.
+type Changes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// For reads, the transaction sees a snapshot of the database as of its start.
+pub struct RedbReadTx {
+    tx: ReadTransaction,
+}
+
+/// For writes, changes are only visible to others after the commit.
+///
+/// `redb` only allows one write transaction at a time, so to let writers work side by side
+/// the way they do with RocksDB, the changes are buffered in memory on top of a snapshot,
+/// and only applied in a `redb` write transaction on commit. Like with RocksDB, the commit
+/// fails with a conflict if a key that was changed has been changed by someone else since
+/// the transaction started. Dropping the transaction without a commit rolls it back.
+///
+/// Because nothing is locked until the commit, the blockstores of the same database
+/// can be written to while a transaction is open, even on the same thread.
+pub struct RedbWriteTx {
+    db: Arc<Database>,
+    snapshot: ReadTransaction,
+    changes: BTreeMap<String, Changes>,
+}
+
+impl<S> KVReadable<S> for Redb
+where
+    S: KVStore<Repr = Vec<u8>>,
+    S::Namespace: AsRef<str>,
+{
+    type Tx<'a>
+        = RedbReadTx
+    where
+        Self: 'a;
+
+    fn read(&self) -> Self::Tx<'_> {
+        let tx = self
+            .db
+            .begin_read()
+            .expect("failed to begin read transaction");
+        RedbReadTx { tx }
+    }
+}
+
+impl<S> KVWritable<S> for Redb
+where
+    S: KVStore<Repr = Vec<u8>>,
+    S::Namespace: AsRef<str>,
+{
+    type Tx<'a>
+        = RedbWriteTx
+    where
+        Self: 'a;
+
+    fn write(&self) -> Self::Tx<'_> {
+        let snapshot = self
+            .db
+            .begin_read()
+            .expect("failed to begin write transaction");
+        RedbWriteTx {
+            db: self.db.clone(),
+            snapshot,
+            changes: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S> KVRead<S> for RedbReadTx
+where
+    S: KVStore<Repr = Vec<u8>>,
+    S::Namespace: AsRef<str>,
+{
+    fn get<K, V>(&self, ns: &S::Namespace, k: &K) -> KVResult<Option<V>>
+    where
+        S: Encode<K> + Decode<V>,
+    {
+        let key = S::to_repr(k)?;
+        let tbl = self
+            .tx
+            .open_table(table(ns.as_ref()))
+            .map_err(to_kv_error)?;
+        let res = tbl.get(key.as_slice()).map_err(to_kv_error)?;
+
+        match res {
+            Some(v) => Ok(Some(S::from_repr(&v.value().to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn iterate<K, V>(&self, ns: &S::Namespace) -> impl Iterator<Item = KVResult<(K, V)>>
+    where
+        S: Decode<K> + Decode<V>,
+        <S as KVStore>::Repr: Ord + 'static,
+    {
+        let items = self
+            .tx
+            .open_table(table(ns.as_ref()))
+            .map_err(to_kv_error)
+            .and_then(|tbl| read_items(&tbl));
+
+        decode_items::<S, K, V>(items)
+    }
+}
+
+impl<S> KVRead<S> for RedbWriteTx
+where
+    S: KVStore<Repr = Vec<u8>>,
+    S::Namespace: AsRef<str>,
+{
+    fn get<K, V>(&self, ns: &S::Namespace, k: &K) -> KVResult<Option<V>>
+    where
+        S: Encode<K> + Decode<V>,
+    {
+        let key = S::to_repr(k)?;
+
+        if let Some(change) = self
+            .changes
+            .get(ns.as_ref())
+            .and_then(|changes| changes.get(key.as_ref()))
+        {
+            return match change {
+                Some(v) => Ok(Some(S::from_repr(v)?)),
+                None => Ok(None),
+            };
+        }
+
+        let tbl = self
+            .snapshot
+            .open_table(table(ns.as_ref()))
+            .map_err(to_kv_error)?;
+        let res = tbl.get(key.as_slice()).map_err(to_kv_error)?;
+
+        match res {
+            Some(v) => Ok(Some(S::from_repr(&v.value().to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn iterate<K, V>(&self, ns: &S::Namespace) -> impl Iterator<Item = KVResult<(K, V)>>
+    where
+        S: Decode<K> + Decode<V>,
+        <S as KVStore>::Repr: Ord + 'static,
+    {
+        let items = self
+            .snapshot
+            .open_table(table(ns.as_ref()))
+            .map_err(to_kv_error)
+            .and_then(|tbl| read_items(&tbl))
+            .map(|items| match self.changes.get(ns.as_ref()) {
+                None => items,
+                Some(changes) => {
+                    let mut items = BTreeMap::from_iter(items);
+                    for (k, v) in changes {
+                        match v {
+                            Some(v) => items.insert(k.clone(), v.clone()),
+                            None => items.remove(k),
+                        };
+                    }
+                    items.into_iter().collect()
+                }
+            });
+
+        decode_items::<S, K, V>(items)
+    }
+}
+
+impl<S> KVWrite<S> for RedbWriteTx
+where
+    S: KVStore<Repr = Vec<u8>>,
+    S::Namespace: AsRef<str>,
+{
+    fn put<K, V>(&mut self, ns: &S::Namespace, k: &K, v: &V) -> KVResult<()>
+    where
+        S: Encode<K> + Encode<V>,
+    {
+        let k = S::to_repr(k)?;
+        let v = S::to_repr(v)?;
+
+        self.changes
+            .entry(ns.as_ref().to_owned())
+            .or_default()
+            .insert(k.into_owned(), Some(v.into_owned()));
+
+        Ok(())
+    }
+
+    fn delete<K>(&mut self, ns: &S::Namespace, k: &K) -> KVResult<()>
+    where
+        S: Encode<K>,
+    {
+        let k = S::to_repr(k)?;
+
+        self.changes
+            .entry(ns.as_ref().to_owned())
+            .or_default()
+            .insert(k.into_owned(), None);
+
+        Ok(())
+    }
+}
+
+impl KVTransaction for RedbWriteTx {
+    fn commit(self) -> KVResult<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+
+        // This waits for any other commit to finish.
+        let tx = self.db.begin_write().map_err(to_kv_error)?;
+
+        for (ns, changes) in self.changes.iter() {
+            let seen = self.snapshot.open_table(table(ns)).map_err(to_kv_error)?;
+            let mut tbl = tx.open_table(table(ns)).map_err(to_kv_error)?;
+
+            for (k, v) in changes {
+                let before = seen.get(k.as_slice()).map_err(to_kv_error)?;
+                let current = tbl.get(k.as_slice()).map_err(to_kv_error)?;
+                if before.map(|v| v.value().to_vec()) != current.map(|v| v.value().to_vec()) {
+                    return Err(KVError::Conflict);
+                }
+                match v {
+                    Some(v) => tbl.insert(k.as_slice(), v.as_slice()),
+                    None => tbl.remove(k.as_slice()),
+                }
+                .map_err(to_kv_error)?;
+            }
+        }
+
+        tx.commit().map_err(to_kv_error)
+    }
+
+    fn rollback(self) -> KVResult<()> {
+        Ok(())
+    }
+}
+
+/// Read all items of a table.
+///
+/// The table borrows the transaction, so rather than returning an iterator over it
+/// the items are collected up front; namespaces in the KV store are expected to be small.
+fn read_items(tbl: &impl ReadableTable<&'static [u8], &'static [u8]>) -> KVResult<Items> {
+    let mut items = Vec::new();
+    for item in tbl.iter().map_err(to_kv_error)? {
+        let (k, v) = item.map_err(to_kv_error)?;
+        items.push((k.value().to_vec(), v.value().to_vec()));
+    }
+    Ok(items)
+}
+
+fn decode_items<S, K, V>(items: KVResult<Items>) -> impl Iterator<Item = KVResult<(K, V)>>
+where
+    S: KVStore<Repr = Vec<u8>> + Decode<K> + Decode<V>,
+{
+    let (items, err) = match items {
+        Ok(items) => (items, None),
+        Err(e) => (Vec::new(), Some(Err(e))),
+    };
+
+    err.into_iter().chain(items.into_iter().map(|(k, v)| {
+        let k: K = S::from_repr(&k)?;
+        let v: V = S::from_repr(&v)?;
+        Ok((k, v))
+    }))
+}
+
+fn to_kv_error<E: Into<redb::Error>>(e: E) -> KVError {
+    KVError::Unexpected(Box::new(e.into()))
+}
+
+#[cfg(all(feature = "kvstore", test))]
+mod tests {
+    use std::borrow::Cow;
+
+    use quickcheck::{QuickCheck, Testable};
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use fendermint_storage::{
+        testing::*, Codec, Decode, Encode, KVError, KVRead, KVReadable, KVResult, KVStore,
+        KVTransaction, KVWritable, KVWrite,
+    };
+
+    use crate::db::table;
+    use crate::Redb;
+
+    const TEST_COUNT: u64 = 20;
+
+    #[derive(Clone)]
+    struct TestKVStore;
+
+    impl KVStore for TestKVStore {
+        type Namespace = TestNamespace;
+        type Repr = Vec<u8>;
+    }
+
+    impl<T: Serialize> Encode<T> for TestKVStore {
+        fn to_repr(value: &T) -> KVResult<Cow<Self::Repr>> {
+            fvm_ipld_encoding::to_vec(value)
+                .map_err(|e| KVError::Codec(Box::new(e)))
+                .map(Cow::Owned)
+        }
+    }
+    impl<T: DeserializeOwned> Decode<T> for TestKVStore {
+        fn from_repr(repr: &Self::Repr) -> KVResult<T> {
+            fvm_ipld_encoding::from_slice(repr).map_err(|e| KVError::Codec(Box::new(e)))
+        }
+    }
+
+    impl<T> Codec<T> for TestKVStore where TestKVStore: Encode<T> + Decode<T> {}
+
+    fn new_backend() -> Redb {
+        let dir = tempfile::Builder::new()
+            .tempdir()
+            .expect("error creating temporary path for db");
+        let path = dir.into_path().join("test.redb");
+
+        // Create the tables the test will use.
+        Redb::open_ns(path, test_namespaces().iter()).expect("error creating redb")
+    }
+
+    fn run_quickcheck<F: Testable>(f: F) {
+        QuickCheck::new().tests(TEST_COUNT).quickcheck(f)
+    }
+
+    #[test]
+    fn writable() {
+        run_quickcheck(
+            (|data| {
+                let backend = new_backend();
+                check_writable::<TestKVStore>(&backend, data)
+            }) as fn(TestData) -> bool,
+        )
+    }
+
+    #[test]
+    fn write_isolation() {
+        run_quickcheck(
+            (|data| {
+                let backend = new_backend();
+                check_write_isolation::<TestKVStore>(&backend, data)
+            }) as fn(TestDataMulti<2>) -> bool,
+        )
+    }
+
+    #[test]
+    fn direct_writes_during_transaction() {
+        let backend = new_backend();
+        let ns = test_namespaces()[0];
+
+        let mut tx = KVWritable::<TestKVStore>::write(&backend);
+        KVWrite::<TestKVStore>::put(&mut tx, &ns, &"foo".to_string(), &1u8).unwrap();
+
+        // This is what the blockstores do; it would wait forever if the transaction held the lock.
+        let direct = backend.db.begin_write().unwrap();
+        direct
+            .open_table(table(ns))
+            .unwrap()
+            .insert(b"bar".as_slice(), b"baz".as_slice())
+            .unwrap();
+        direct.commit().unwrap();
+
+        tx.commit().unwrap();
+
+        let tx = KVReadable::<TestKVStore>::read(&backend);
+        let foo: Option<u8> = KVRead::<TestKVStore>::get(&tx, &ns, &"foo".to_string()).unwrap();
+        assert_eq!(foo, Some(1));
+    }
+
+    #[test]
+    fn write_isolation_concurrent() {
+        run_quickcheck(
+            (|data1, data2| {
+                let backend = new_backend();
+                check_write_isolation_concurrent::<TestKVStore, _>(&backend, data1, data2)
+            }) as fn(TestData, TestData) -> bool,
+        )
+    }
+
+    #[test]
+    fn write_serialization_concurrent() {
+        run_quickcheck(
+            (|data1, data2| {
+                let backend = new_backend();
+                check_write_serialization_concurrent::<TestKVStore, _>(&backend, data1, data2)
+            }) as fn(TestData, TestData) -> bool,
+        )
+    }
+
+    #[test]
+    fn read_isolation() {
+        run_quickcheck(
+            (|data| {
+                let backend = new_backend();
+                check_read_isolation::<TestKVStore, _>(&backend, data)
+            }) as fn(TestData) -> bool,
+        )
+    }
+}
@@ -0,0 +1,10 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+mod db;
+
+#[cfg(feature = "blockstore")]
+pub mod blockstore;
+#[cfg(feature = "kvstore")]
+mod kvstore;
+
+pub use db::Redb;
@@ -0,0 +1,434 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Mark-and-sweep garbage collection for blocks in a [NamespaceBlockstore].
+//!
+//! Marking walks the DAG from the roots to keep, sweeping deletes every other block in the namespace,
+//! or moves it to the cold tier, if the store has one.
+//! Writes carry on while the collection is running: the store records the blocks written
+//! in the meantime, and any of those which got swept are put back, since they are likely
+//! to be part of a newer state which wasn't among the roots.
+//!
+//! Recording has to start before the roots are looked up, otherwise a state flushed between
+//! reading the roots and starting the collection would be swept; see [NamespaceBlockstore::start_gc].
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use cid::Cid;
+use fvm_ipld_encoding::{from_slice, DAG_CBOR};
+use fvm_shared::IDENTITY_HASH;
+use libipld::Ipld;
+use rocksdb::{IteratorMode, WriteBatchWithTransaction};
+
+use super::NamespaceBlockstore;
+
+/// Number of blocks deleted in one batch.
+const SWEEP_BATCH_SIZE: usize = 10_000;
+
+/// Property with the size of the files the namespace takes up on disk.
+const SST_FILES_SIZE: &str = "rocksdb.total-sst-files-size";
+
+/// Outcome of a garbage collection.
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    /// Number of blocks reachable from the roots.
+    pub reachable: usize,
+    /// Number of blocks deleted.
+    pub deleted: usize,
+    /// Number of the deleted blocks which were moved to the cold tier.
+    pub archived: usize,
+    /// Size of the keys and values deleted.
+    pub deleted_bytes: u64,
+    /// Change in the size of the files on disk, after compaction.
+    pub reclaimed_bytes: u64,
+    /// How long the collection took.
+    pub duration: Duration,
+}
+
+/// Keeps track of the blocks written while a collection is in progress.
+#[derive(Default)]
+pub(crate) struct WriteBarrier(Mutex<Option<HashSet<Cid>>>);
+
+impl WriteBarrier {
+    /// Remember the keys if a collection is running.
+    ///
+    /// Has to be called *before* the blocks are written to the database.
+    pub fn record<'a>(&self, cids: impl IntoIterator<Item = &'a Cid>) {
+        let mut guard = self.0.lock().expect("write barrier poisoned");
+        if let Some(written) = guard.as_mut() {
+            written.extend(cids);
+        }
+    }
+
+    fn start(&self) -> anyhow::Result<()> {
+        let mut guard = self.0.lock().expect("write barrier poisoned");
+        if guard.is_some() {
+            bail!("garbage collection is already running");
+        }
+        *guard = Some(HashSet::new());
+        Ok(())
+    }
+
+    fn stop(&self) {
+        *self.0.lock().expect("write barrier poisoned") = None;
+    }
+
+    /// Check which of the given keys have been written since the collection started.
+    fn written(&self, cids: impl Iterator<Item = Cid>) -> Vec<Cid> {
+        let guard = self.0.lock().expect("write barrier poisoned");
+        match guard.as_ref() {
+            Some(written) => cids.filter(|cid| written.contains(cid)).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A garbage collection which has started recording writes, waiting for its roots.
+///
+/// Dropping it without calling [GcRun::collect] stops the recording.
+pub struct GcRun {
+    store: NamespaceBlockstore,
+    started: Instant,
+}
+
+impl GcRun {
+    /// Delete all blocks which aren't reachable from any of the roots, or move them to the cold tier.
+    ///
+    /// The roots have to be looked up after the run was started.
+    ///
+    /// This is a blocking operation which can take a long time, but it doesn't stop other
+    /// clones of the store from reading and writing.
+    pub fn collect(self, roots: impl IntoIterator<Item = Cid>) -> anyhow::Result<GcStats> {
+        self.store.collect_started(roots, self.started)
+    }
+}
+
+impl Drop for GcRun {
+    fn drop(&mut self) {
+        self.store.barrier.stop()
+    }
+}
+
+impl NamespaceBlockstore {
+    /// Start recording writes for a garbage collection, before its roots are looked up.
+    ///
+    /// Only one collection can run at a time.
+    pub fn start_gc(&self) -> anyhow::Result<GcRun> {
+        let started = Instant::now();
+        self.barrier.start()?;
+        Ok(GcRun {
+            store: self.clone(),
+            started,
+        })
+    }
+
+    /// Delete all blocks which aren't reachable from any of the roots, or move them to the cold tier.
+    ///
+    /// Only safe if no state is flushed between looking up the roots and calling this;
+    /// otherwise use [NamespaceBlockstore::start_gc].
+    pub fn collect_garbage(&self, roots: impl IntoIterator<Item = Cid>) -> anyhow::Result<GcStats> {
+        self.start_gc()?.collect(roots)
+    }
+
+    fn collect_started(
+        &self,
+        roots: impl IntoIterator<Item = Cid>,
+        started: Instant,
+    ) -> anyhow::Result<GcStats> {
+        let cf = self.cf()?;
+        let size_before = self.db.property_int_value_cf(&cf, SST_FILES_SIZE)?;
+
+        let reachable = self.mark(roots)?;
+
+        let mut stats = GcStats {
+            reachable: reachable.len(),
+            ..Default::default()
+        };
+
+        let mut batch = Vec::new();
+        for entry in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            let (key, value) = entry?;
+
+            // Leave alone anything that isn't a CID; it wasn't put here by the `Blockstore`.
+            let Ok(cid) = Cid::try_from(key.as_ref()) else {
+                continue;
+            };
+
+            if reachable.contains(&cid) {
+                continue;
+            }
+
+            batch.push((cid, key, value));
+
+            if batch.len() >= SWEEP_BATCH_SIZE {
+                self.sweep(std::mem::take(&mut batch), &mut stats)?;
+            }
+        }
+        self.sweep(batch, &mut stats)?;
+
+        if stats.deleted > 0 {
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        }
+
+        let size_after = self.db.property_int_value_cf(&cf, SST_FILES_SIZE)?;
+
+        if let (Some(before), Some(after)) = (size_before, size_after) {
+            stats.reclaimed_bytes = before.saturating_sub(after);
+        }
+
+        stats.duration = started.elapsed();
+
+        Ok(stats)
+    }
+
+    /// Collect all the CIDs reachable from the roots.
+    fn mark(&self, roots: impl IntoIterator<Item = Cid>) -> anyhow::Result<HashSet<Cid>> {
+        let cf = self.cf()?;
+        let mut reachable = HashSet::new();
+        let mut queue = VecDeque::from_iter(roots);
+
+        while let Some(cid) = queue.pop_front() {
+            if !reachable.insert(cid) {
+                continue;
+            }
+            // Only DAG-CBOR can have links; raw blocks such as Wasm bytecode are just kept.
+            if cid.codec() != DAG_CBOR || cid.hash().code() == IDENTITY_HASH {
+                continue;
+            }
+            // Blocks which have already been moved to the cold tier can still link to hot ones.
+            let bytes = match self.db.get_pinned_cf(&cf, cid.to_bytes())? {
+                Some(bytes) => Some(bytes.to_vec()),
+                None => match self.cold {
+                    Some(ref cold) => cold.read(cid.to_bytes())?,
+                    None => None,
+                },
+            };
+            if let Some(bytes) = bytes {
+                let ipld = from_slice::<Ipld>(&bytes)
+                    .map_err(|e| anyhow!("failed to decode block {cid}: {e}"))?;
+                push_links(ipld, &mut queue);
+            }
+        }
+
+        Ok(reachable)
+    }
+
+    /// Delete a batch of unreachable blocks, then restore any of them which were written in the meantime.
+    fn sweep(
+        &self,
+        blocks: Vec<(Cid, Box<[u8]>, Box<[u8]>)>,
+        stats: &mut GcStats,
+    ) -> anyhow::Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        // Archive first; if we crash before deleting, the blocks will be in both tiers.
+        if let Some(ref cold) = self.cold {
+            let mut batch = WriteBatchWithTransaction::<true>::default();
+            for (_, key, value) in blocks.iter() {
+                batch.put(key, value);
+            }
+            cold.db.write(batch)?;
+            stats.archived += blocks.len();
+        }
+
+        let cf = self.cf()?;
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        for (_, key, value) in blocks.iter() {
+            batch.delete_cf(&cf, key);
+            stats.deleted_bytes += (key.len() + value.len()) as u64;
+        }
+        self.db.write(batch)?;
+        stats.deleted += blocks.len();
+
+        // A block written before the delete would be lost; one written after it is still there,
+        // but we can't tell which, and putting it back again does no harm.
+        let rewritten = self.barrier.written(blocks.iter().map(|(cid, _, _)| *cid));
+
+        if !rewritten.is_empty() {
+            let mut batch = WriteBatchWithTransaction::<true>::default();
+            for (cid, key, value) in blocks.iter() {
+                if rewritten.contains(cid) {
+                    batch.put_cf(&cf, key, value);
+                    stats.deleted_bytes -= (key.len() + value.len()) as u64;
+                }
+            }
+            self.db.write(batch)?;
+            stats.deleted -= rewritten.len();
+            if self.cold.is_some() {
+                stats.archived -= rewritten.len();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn push_links(ipld: Ipld, queue: &mut VecDeque<Cid>) {
+    match ipld {
+        Ipld::List(v) => {
+            for i in v {
+                push_links(i, queue);
+            }
+        }
+        Ipld::Map(map) => {
+            for v in map.into_values() {
+                push_links(v, queue);
+            }
+        }
+        Ipld::Link(cid) => queue.push_back(cid),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::{to_vec, IPLD_RAW};
+    use libipld::Ipld;
+
+    use crate::{blockstore::NamespaceBlockstore, RocksDb, RocksDbConfig};
+
+    fn put_ipld(store: &impl Blockstore, ipld: &Ipld) -> Cid {
+        let bytes = to_vec(ipld).unwrap();
+        let cid = Cid::new_v1(fvm_ipld_encoding::DAG_CBOR, Code::Blake2b256.digest(&bytes));
+        store.put_keyed(&cid, &bytes).unwrap();
+        cid
+    }
+
+    fn put_raw(store: &impl Blockstore, bytes: &[u8]) -> Cid {
+        let cid = Cid::new_v1(IPLD_RAW, Code::Blake2b256.digest(bytes));
+        store.put_keyed(&cid, bytes).unwrap();
+        cid
+    }
+
+    #[test]
+    fn gc_keeps_reachable_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RocksDb::open_cf(dir.path(), &RocksDbConfig::default(), ["state"].iter()).unwrap();
+        let store = NamespaceBlockstore::new(db, "state".to_owned()).unwrap();
+
+        // A root linking to a leaf and a raw block, and an old root sharing the leaf.
+        let leaf = put_ipld(&store, &Ipld::String("leaf".into()));
+        let code = put_raw(&store, b"wasm");
+        let root = put_ipld(
+            &store,
+            &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(code)]),
+        );
+        let orphan = put_ipld(&store, &Ipld::String("orphan".into()));
+        let old_root = put_ipld(
+            &store,
+            &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(orphan)]),
+        );
+
+        let stats = store.collect_garbage([root]).unwrap();
+
+        assert_eq!(stats.reachable, 3);
+        assert_eq!(stats.deleted, 2);
+
+        for cid in [root, leaf, code] {
+            assert!(store.has(&cid).unwrap(), "{cid} should be kept");
+        }
+        for cid in [old_root, orphan] {
+            assert!(!store.has(&cid).unwrap(), "{cid} should be deleted");
+        }
+    }
+
+    #[test]
+    fn gc_moves_blocks_to_cold_tier() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RocksDb::open_cf(
+            dir.path().join("hot"),
+            &RocksDbConfig::default(),
+            ["state"].iter(),
+        )
+        .unwrap();
+        let cold = RocksDb::open(dir.path().join("cold"), &RocksDbConfig::default()).unwrap();
+        let store = NamespaceBlockstore::new(db, "state".to_owned())
+            .unwrap()
+            .with_cold_store(cold.clone());
+
+        let leaf = put_ipld(&store, &Ipld::String("leaf".into()));
+        let old_root = put_ipld(&store, &Ipld::List(vec![Ipld::Link(leaf)]));
+        let root = put_ipld(&store, &Ipld::String("root".into()));
+
+        let stats = store.collect_garbage([root]).unwrap();
+
+        assert_eq!(stats.deleted, 2);
+        assert_eq!(stats.archived, 2);
+
+        // The old blocks are only in the cold tier, but they can still be read.
+        for cid in [old_root, leaf] {
+            assert!(cold.exists(cid.to_bytes()).unwrap());
+            assert!(store.has(&cid).unwrap(), "{cid} should be readable");
+        }
+
+        // A new root referring to an archived block keeps the hot blocks it links to.
+        let hot_leaf = put_ipld(&store, &Ipld::String("hot leaf".into()));
+        let new_root = put_ipld(
+            &store,
+            &Ipld::List(vec![Ipld::Link(old_root), Ipld::Link(hot_leaf)]),
+        );
+
+        let stats = store.collect_garbage([new_root]).unwrap();
+        assert_eq!(stats.reachable, 4);
+        assert_eq!(stats.archived, 1);
+        assert!(store.has(&hot_leaf).unwrap());
+    }
+
+    #[test]
+    fn gc_keeps_blocks_written_during_collection() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RocksDb::open_cf(dir.path(), &RocksDbConfig::default(), ["state"].iter()).unwrap();
+        let store = NamespaceBlockstore::new(db, "state".to_owned()).unwrap();
+
+        let orphan = put_ipld(&store, &Ipld::String("orphan".into()));
+
+        // Simulate the block being written again by a clone while the sweep is going on.
+        store.barrier.start().unwrap();
+        store
+            .clone()
+            .put_keyed(&orphan, &store.get(&orphan).unwrap().unwrap())
+            .unwrap();
+
+        let mut stats = Default::default();
+        let key = orphan.to_bytes().into_boxed_slice();
+        let value = store.get(&orphan).unwrap().unwrap().into_boxed_slice();
+        store.sweep(vec![(orphan, key, value)], &mut stats).unwrap();
+        store.barrier.stop();
+
+        assert_eq!(stats.deleted, 0);
+        assert!(store.has(&orphan).unwrap());
+    }
+
+    #[test]
+    fn gc_keeps_blocks_flushed_before_roots_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RocksDb::open_cf(dir.path(), &RocksDbConfig::default(), ["state"].iter()).unwrap();
+        let store = NamespaceBlockstore::new(db, "state".to_owned()).unwrap();
+
+        let old_root = put_ipld(&store, &Ipld::String("old root".into()));
+
+        // A new state is flushed after the run started, but the roots were read before it was committed.
+        let run = store.start_gc().unwrap();
+        let new_leaf = put_ipld(&store, &Ipld::String("new leaf".into()));
+        let new_root = put_ipld(&store, &Ipld::List(vec![Ipld::Link(new_leaf)]));
+        let stats = run.collect([old_root]).unwrap();
+
+        assert_eq!(stats.deleted, 0);
+        for cid in [old_root, new_leaf, new_root] {
+            assert!(store.has(&cid).unwrap(), "{cid} should be kept");
+        }
+
+        // Dropping a run lets the next one start.
+        drop(store.start_gc().unwrap());
+        let stats = store.collect_garbage([old_root]).unwrap();
+        assert_eq!(stats.deleted, 2);
+    }
+}
@@ -7,7 +7,12 @@ use cid::Cid;
 use fvm_ipld_blockstore::Blockstore;
 use rocksdb::{BoundColumnFamily, OptimisticTransactionDB, WriteBatchWithTransaction};
 
-use crate::RocksDb;
+use crate::{ReadOnlyRocksDb, RocksDb};
+
+mod gc;
+
+pub use gc::{GcRun, GcStats};
+use gc::WriteBarrier;
 
 impl Blockstore for RocksDb {
     fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
@@ -43,10 +48,18 @@ impl Blockstore for RocksDb {
 }
 
 /// A [`Blockstore`] implementation that writes to a specific namespace, not the default like above.
+///
+/// Clones share the [WriteBarrier], so garbage can be collected on one while others keep writing;
+/// instances created separately with [NamespaceBlockstore::new] don't see each other's writes.
+///
+/// Optionally it can have a cold tier: reads fall through to it when a block isn't in the namespace,
+/// and garbage collection moves blocks there instead of deleting them.
 #[derive(Clone)]
 pub struct NamespaceBlockstore {
     db: Arc<OptimisticTransactionDB>,
     ns: String,
+    barrier: Arc<WriteBarrier>,
+    cold: Option<RocksDb>,
 }
 
 impl NamespaceBlockstore {
@@ -55,10 +68,34 @@ impl NamespaceBlockstore {
         if !db.has_cf_handle(&ns) {
             Err(anyhow!("namespace {ns} does not exist!"))
         } else {
-            Ok(Self { db: db.db, ns })
+            Ok(Self {
+                db: db.db,
+                ns,
+                barrier: Default::default(),
+                cold: None,
+            })
         }
     }
 
+    /// Use a separate database as the cold tier, where blocks no longer reachable
+    /// from recent state are archived rather than deleted.
+    pub fn with_cold_store(mut self, cold: RocksDb) -> Self {
+        self.cold = Some(cold);
+        self
+    }
+
+    /// Check whether there is a cold tier.
+    pub fn is_archive(&self) -> bool {
+        self.cold.is_some()
+    }
+
+    /// Remove a block from the namespace, for example a corrupt copy which should be fetched again.
+    ///
+    /// The cold tier, if any, is left alone.
+    pub fn delete(&self, k: &Cid) -> anyhow::Result<()> {
+        Ok(self.db.delete_cf(&self.cf()?, k.to_bytes())?)
+    }
+
     // Unfortunately there doesn't seem to be a way to avoid having to
     // clone another instance for each operation :(
     fn cf(&self) -> anyhow::Result<Arc<BoundColumnFamily>> {
@@ -70,10 +107,18 @@ impl NamespaceBlockstore {
 
 impl Blockstore for NamespaceBlockstore {
     fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
-        Ok(self.db.get_cf(&self.cf()?, k.to_bytes())?)
+        let key = k.to_bytes();
+        match self.db.get_cf(&self.cf()?, &key)? {
+            None => match self.cold {
+                Some(ref cold) => Ok(cold.read(&key)?),
+                None => Ok(None),
+            },
+            found => Ok(found),
+        }
     }
 
     fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+        self.barrier.record([k]);
         Ok(self.db.put_cf(&self.cf()?, k.to_bytes(), block)?)
     }
 
@@ -86,11 +131,55 @@ impl Blockstore for NamespaceBlockstore {
     {
         let cf = self.cf()?;
         let mut batch = WriteBatchWithTransaction::<true>::default();
+        let mut cids = Vec::new();
         for (cid, v) in blocks.into_iter() {
             let k = cid.to_bytes();
             let v = v.as_ref();
             batch.put_cf(&cf, k, v);
+            cids.push(cid);
         }
+        self.barrier.record(&cids);
         Ok(self.db.write(batch)?)
     }
 }
+
+/// A [`Blockstore`] over a namespace of a database opened in read-only mode,
+/// with the same fall through to the cold tier as [NamespaceBlockstore].
+#[derive(Clone)]
+pub struct ReadOnlyNamespaceBlockstore {
+    db: ReadOnlyRocksDb,
+    ns: String,
+    cold: Option<ReadOnlyRocksDb>,
+}
+
+impl ReadOnlyNamespaceBlockstore {
+    pub fn new(db: ReadOnlyRocksDb, ns: String) -> anyhow::Result<Self> {
+        if !db.has_cf_handle(&ns) {
+            Err(anyhow!("namespace {ns} does not exist!"))
+        } else {
+            Ok(Self { db, ns, cold: None })
+        }
+    }
+
+    pub fn with_cold_store(mut self, cold: ReadOnlyRocksDb) -> Self {
+        self.cold = Some(cold);
+        self
+    }
+}
+
+impl Blockstore for ReadOnlyNamespaceBlockstore {
+    fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = k.to_bytes();
+        match self.db.read_cf(&self.ns, &key)? {
+            None => match self.cold {
+                Some(ref cold) => Ok(cold.read(&key)?),
+                None => Ok(None),
+            },
+            found => Ok(found),
+        }
+    }
+
+    fn put_keyed(&self, _k: &Cid, _block: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow!("the blockstore is read-only"))
+    }
+}
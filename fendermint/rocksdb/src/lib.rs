@@ -9,4 +9,4 @@ mod kvstore;
 
 pub mod namespaces;
 
-pub use rocks::{Error as RocksDbError, RocksDb, RocksDbConfig};
+pub use rocks::{Error as RocksDbError, ReadOnlyRocksDb, RocksDb, RocksDbConfig};
@@ -9,9 +9,11 @@ use std::{path::Path, sync::Arc};
 
 mod config;
 mod error;
+mod readonly;
 
 pub use config::RocksDbConfig;
 pub use error::Error;
+pub use readonly::ReadOnlyRocksDb;
 
 #[derive(Clone)]
 pub struct RocksDb {
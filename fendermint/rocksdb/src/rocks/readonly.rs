@@ -0,0 +1,100 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use rocksdb::{DBWithThreadMode, MultiThreaded};
+use std::{path::Path, sync::Arc};
+
+use super::{Error, RocksDb, RocksDbConfig};
+
+/// Read-only handle to a database, for inspecting the data of a node offline.
+///
+/// It doesn't take the lock on the database, so it can be opened while a node is running,
+/// although it only sees what had been flushed at the time of opening.
+#[derive(Clone)]
+pub struct ReadOnlyRocksDb {
+    db: Arc<DBWithThreadMode<MultiThreaded>>,
+}
+
+impl ReadOnlyRocksDb {
+    /// Open all existing column families.
+    pub fn open<P>(path: P, config: &RocksDbConfig) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if !path.as_ref().exists() {
+            return Err(Error::Other(format!(
+                "database {} does not exist",
+                path.as_ref().to_string_lossy()
+            )));
+        }
+
+        let db_opts: rocksdb::Options = config.into();
+        let cfs = RocksDb::list_cf(&path, config)?;
+        let db =
+            DBWithThreadMode::<MultiThreaded>::open_cf_for_read_only(&db_opts, path, cfs, false)?;
+
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Check if a column family exists
+    pub fn has_cf_handle(&self, name: &str) -> bool {
+        self.db.cf_handle(name).is_some()
+    }
+
+    pub fn read<K>(&self, key: K) -> Result<Option<Vec<u8>>, Error>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.db.get(key).map_err(Error::from)
+    }
+
+    /// Read a key from a column family.
+    pub fn read_cf<K>(&self, name: &str, key: K) -> Result<Option<Vec<u8>>, Error>
+    where
+        K: AsRef<[u8]>,
+    {
+        let cf = self
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| Error::Other(format!("column family '{name}' does not exist")))?;
+
+        self.db.get_cf(&cf, key).map_err(Error::from)
+    }
+
+    /// Read an integer property of a column family, e.g. `rocksdb.total-sst-files-size`.
+    pub fn property_int_value_cf(&self, name: &str, property: &str) -> Result<Option<u64>, Error> {
+        let cf = self
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| Error::Other(format!("column family '{name}' does not exist")))?;
+
+        self.db
+            .property_int_value_cf(&cf, property)
+            .map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadOnlyRocksDb;
+    use crate::{RocksDb, RocksDbConfig};
+
+    #[test]
+    fn read_while_open_for_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RocksDbConfig::default();
+
+        let db = RocksDb::open_cf(dir.path(), &config, ["foo"].iter()).unwrap();
+        db.write(b"key", b"value").unwrap();
+        db.flush().unwrap();
+
+        let ro = ReadOnlyRocksDb::open(dir.path(), &config).unwrap();
+        assert!(ro.has_cf_handle("foo"));
+        assert_eq!(ro.read(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(ro.read_cf("bar", b"key").is_err());
+        assert!(ro
+            .property_int_value_cf("foo", "rocksdb.estimate-num-keys")
+            .unwrap()
+            .is_some());
+    }
+}
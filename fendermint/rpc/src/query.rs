@@ -13,10 +13,11 @@ use tendermint_rpc::endpoint::abci_query::AbciQuery;
 
 use cid::Cid;
 use fvm_shared::ActorID;
-use fvm_shared::{address::Address, error::ExitCode};
+use fvm_shared::{address::Address, econ::TokenAmount, error::ExitCode};
 
 use fendermint_vm_message::query::{
-    ActorState, BuiltinActors, FvmQuery, FvmQueryHeight, GasEstimate, StateParams,
+    ActorState, ActorStateSample, BuiltinActors, FvmQuery, FvmQueryHeight, GasEstimate,
+    HeightRange, StateParams,
 };
 
 use crate::response::encode_data;
@@ -128,6 +129,71 @@ pub trait QueryClient: Sync {
         Ok(QueryResponse { height, value })
     }
 
+    /// Query the state of an actor at the heights sampled from a range.
+    ///
+    /// Heights which are no longer in the state history of the node are left out.
+    async fn actor_state_range(
+        &self,
+        address: &Address,
+        range: HeightRange,
+    ) -> anyhow::Result<QueryResponse<Vec<ActorStateSample>>> {
+        let res = self
+            .perform(
+                FvmQuery::ActorStateRange {
+                    address: *address,
+                    range,
+                },
+                FvmQueryHeight::Committed,
+            )
+            .await
+            .context("actor state range query failed")?;
+        let height = res.height;
+        let value = extract(res, |res| {
+            fvm_ipld_encoding::from_slice(&res.value)
+                .context("failed to decode ActorStateSample list from query")
+        })?;
+        Ok(QueryResponse { height, value })
+    }
+
+    /// Query the balance of an actor at the heights sampled from a range.
+    ///
+    /// The balance is `None` at heights where the actor didn't exist.
+    async fn balance_history(
+        &self,
+        address: &Address,
+        range: HeightRange,
+    ) -> anyhow::Result<QueryResponse<Vec<(u64, Option<TokenAmount>)>>> {
+        let res = self.actor_state_range(address, range).await?;
+        let value = res
+            .value
+            .into_iter()
+            .map(|s| (s.height, s.state.map(|(_, st)| st.balance)))
+            .collect();
+        Ok(QueryResponse {
+            height: res.height,
+            value,
+        })
+    }
+
+    /// Run multiple messages in a read-only fashion against the same state.
+    ///
+    /// The messages don't see each others' effects.
+    /// At most [`MAX_CALL_BATCH_SIZE`](fendermint_vm_message::query::MAX_CALL_BATCH_SIZE)
+    /// messages can be sent in one batch.
+    async fn call_batch(
+        &self,
+        messages: Vec<Message>,
+        height: FvmQueryHeight,
+    ) -> anyhow::Result<QueryResponse<Vec<response::DeliverTx>>> {
+        let res = self
+            .perform(FvmQuery::CallBatch(messages), height)
+            .await
+            .context("call batch query failed")?;
+        let height = res.height;
+        let value = extract(res, parse_deliver_txs)?;
+        Ok(QueryResponse { height, value })
+    }
+
     /// Run an ABCI query.
     async fn perform(&self, query: FvmQuery, height: FvmQueryHeight) -> anyhow::Result<AbciQuery>;
 }
@@ -178,7 +244,18 @@ fn parse_deliver_tx(res: AbciQuery) -> anyhow::Result<DeliverTx> {
     let bz: Vec<u8> =
         fvm_ipld_encoding::from_slice(&res.value).context("failed to decode IPLD as bytes")?;
 
-    let deliver_tx = tendermint_proto::abci::ResponseDeliverTx::decode(bz.as_ref())
+    decode_deliver_tx(&bz)
+}
+
+fn parse_deliver_txs(res: AbciQuery) -> anyhow::Result<Vec<DeliverTx>> {
+    let bzs: Vec<Vec<u8>> = fvm_ipld_encoding::from_slice(&res.value)
+        .context("failed to decode IPLD as a list of bytes")?;
+
+    bzs.iter().map(|bz| decode_deliver_tx(bz)).collect()
+}
+
+fn decode_deliver_tx(bz: &[u8]) -> anyhow::Result<DeliverTx> {
+    let deliver_tx = tendermint_proto::abci::ResponseDeliverTx::decode(bz)
         .context("failed to deserialize ResponseDeliverTx from proto bytes")?;
 
     let mut deliver_tx = tendermint::abci::response::DeliverTx::try_from(deliver_tx)
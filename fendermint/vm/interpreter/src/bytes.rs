@@ -14,10 +14,22 @@ use crate::{
 
 pub type BytesMessageApplyRes = Result<ChainMessageApplyRet, IpldError>;
 pub type BytesMessageCheckRes = Result<ChainMessageCheckRes, IpldError>;
-pub type BytesMessageQueryRes = Result<FvmQueryRet, IpldError>;
 
-/// Close to what the ABCI sends: (Path, Bytes).
-pub type BytesMessageQuery = (String, Vec<u8>);
+/// Decode a query close to what the ABCI sends: (Path, Bytes).
+///
+/// The application needs to look at some queries before they get to the interpreter,
+/// so it decodes them itself rather than leaving it to [BytesMessageInterpreter].
+pub fn decode_query(path: &str, bz: &[u8]) -> Result<FvmQuery, IpldError> {
+    if path == "/store" {
+        // According to the docstrings, the application MUST interpret `/store` as a query on the underlying KV store.
+        fvm_ipld_encoding::from_slice::<Cid>(bz).map(FvmQuery::Ipld)
+    } else {
+        // Otherwise ignore the path for now. The docs also say that the query bytes can be used in lieu of the path,
+        // so it's okay to have two ways to send IPLD queries: either by using the `/store` path and sending a CID,
+        // or by sending the appropriate `FvmQuery`.
+        fvm_ipld_encoding::from_slice::<FvmQuery>(bz)
+    }
+}
 
 /// Behavour of proposal preparation. It's an optimisation to cut down needless serialization
 /// when we know we aren't doing anything with the messages.
@@ -245,33 +257,42 @@ where
     I: QueryInterpreter<Query = FvmQuery, Output = FvmQueryRet>,
 {
     type State = I::State;
-    type Query = BytesMessageQuery;
-    type Output = BytesMessageQueryRes;
+    type Query = FvmQuery;
+    type Output = FvmQueryRet;
 
+    /// Pass on queries which have already been decoded with [decode_query].
     async fn query(
         &self,
         state: Self::State,
         qry: Self::Query,
     ) -> anyhow::Result<(Self::State, Self::Output)> {
-        let (path, bz) = qry;
-        let qry = if path.as_str() == "/store" {
-            // According to the docstrings, the application MUST interpret `/store` as a query on the underlying KV store.
-            match fvm_ipld_encoding::from_slice::<Cid>(&bz) {
-                Err(e) => return Ok((state, Err(e))),
-                Ok(cid) => FvmQuery::Ipld(cid),
-            }
-        } else {
-            // Otherwise ignore the path for now. The docs also say that the query bytes can be used in lieu of the path,
-            // so it's okay to have two ways to send IPLD queries: either by using the `/store` path and sending a CID,
-            // or by sending the appropriate `FvmQuery`.
-            match fvm_ipld_encoding::from_slice::<FvmQuery>(&bz) {
-                Err(e) => return Ok((state, Err(e))),
-                Ok(qry) => qry,
-            }
-        };
+        self.inner.query(state, qry).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::Cid;
+    use fendermint_vm_message::query::FvmQuery;
+    use cid::multihash::{Code, MultihashDigest};
+
+    use super::decode_query;
+
+    #[test]
+    fn decode_store_query() {
+        let cid = Cid::new_v1(fvm_ipld_encoding::DAG_CBOR, Code::Blake2b256.digest(b"foo"));
+        let bz = fvm_ipld_encoding::to_vec(&cid).unwrap();
+
+        assert!(matches!(decode_query("/store", &bz), Ok(FvmQuery::Ipld(c)) if c == cid));
+        // Other paths expect an `FvmQuery`.
+        assert!(decode_query("", &bz).is_err());
+    }
 
-        let (state, ret) = self.inner.query(state, qry).await?;
+    #[test]
+    fn decode_fvm_query() {
+        let bz = fvm_ipld_encoding::to_vec(&FvmQuery::CallBatch(Vec::new())).unwrap();
 
-        Ok((state, Ok(ret)))
+        assert!(matches!(decode_query("", &bz), Ok(FvmQuery::CallBatch(msgs)) if msgs.is_empty()));
+        assert!(decode_query("/store", &bz).is_err());
     }
 }
@@ -22,6 +22,7 @@ use fendermint_vm_message::{
     ipc::{BottomUpCheckpoint, CertifiedMessage, IpcMessage, SignedRelayedMessage},
 };
 use fendermint_vm_resolver::pool::{ResolveKey, ResolvePool};
+use fendermint_vm_topdown::equivocation::EquivocationDetector;
 use fendermint_vm_topdown::proxy::IPCProviderProxyWithLatency;
 use fendermint_vm_topdown::voting::{ValidatorKey, VoteTally};
 use fendermint_vm_topdown::{
@@ -47,6 +48,8 @@ pub struct ChainEnv {
     /// The parent finality provider for top down checkpoint
     pub parent_finality_provider: TopDownFinalityProvider,
     pub parent_finality_votes: VoteTally,
+    /// Evidence of validators equivocating in their parent finality votes.
+    pub parent_finality_equivocations: EquivocationDetector,
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -2,9 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 use std::time::Instant;
 
+use anyhow::bail;
 use async_trait::async_trait;
 use cid::Cid;
-use fendermint_vm_message::query::{ActorState, FvmQuery, GasEstimate, StateParams};
+use fendermint_vm_message::query::{
+    ActorState, ActorStateSample, FvmQuery, GasEstimate, StateParams, MAX_CALL_BATCH_SIZE,
+};
 use fvm_ipld_blockstore::Blockstore;
 use fvm_ipld_encoding::RawBytes;
 use fvm_shared::{
@@ -35,6 +38,10 @@ pub enum FvmQueryRet {
     StateParams(StateParams),
     /// Builtin actors known by the system.
     BuiltinActors(Vec<(String, Cid)>),
+    /// The state of an actor sampled at multiple heights.
+    ActorStateRange(Vec<ActorStateSample>),
+    /// The results of multiple read-only message applications, in the order they were sent.
+    CallBatch(Vec<FvmApplyRet>),
 }
 
 #[async_trait]
@@ -78,36 +85,8 @@ where
                 Ok((state, out))
             }
             FvmQuery::Call(msg) => {
-                let from = msg.from;
-                let to = msg.to;
-                let method_num = msg.method_num;
-                let gas_limit = msg.gas_limit;
-
-                let start = Instant::now();
-                // Do not stack effects
-                let (state, (apply_ret, emitters)) = state.call(*msg.clone()).await?;
-                let latency = start.elapsed().as_secs_f64();
-                let exit_code = apply_ret.msg_receipt.exit_code.value();
-
-                emit(MsgExec {
-                    purpose: MsgExecPurpose::Call,
-                    height: state.block_height(),
-                    message: *msg,
-                    duration: latency,
-                    exit_code,
-                });
-
-                let ret = FvmApplyRet {
-                    apply_ret,
-                    from,
-                    to,
-                    method_num,
-                    gas_limit,
-                    emitters,
-                };
-
-                let out = FvmQueryRet::Call(Box::new(ret));
-                Ok((state, out))
+                let (state, ret) = self.call(state, *msg).await?;
+                Ok((state, FvmQueryRet::Call(Box::new(ret))))
             }
             FvmQuery::EstimateGas(mut msg) => {
                 tracing::info!(
@@ -154,6 +133,33 @@ where
                 let (state, ret) = state.builtin_actors().await?;
                 Ok((state, FvmQueryRet::BuiltinActors(ret)))
             }
+            FvmQuery::ActorStateRange { .. } => {
+                // The query state is fixed to a single height; going through the history
+                // is up to the application, which can run `ActorState` queries at each of them.
+                bail!("range queries have to be resolved by the application")
+            }
+            FvmQuery::CallBatch(msgs) => {
+                if msgs.len() > MAX_CALL_BATCH_SIZE {
+                    bail!(
+                        "the batch contains {} messages; at most {MAX_CALL_BATCH_SIZE} can be executed",
+                        msgs.len()
+                    )
+                }
+                tracing::info!(
+                    height = state.block_height(),
+                    pending = state.pending(),
+                    count = msgs.len(),
+                    "query call batch"
+                );
+                let mut state = state;
+                let mut rets = Vec::with_capacity(msgs.len());
+                for msg in msgs {
+                    let (st, ret) = self.call(state, msg).await?;
+                    state = st;
+                    rets.push(ret);
+                }
+                Ok((state, FvmQueryRet::CallBatch(rets)))
+            }
         }
     }
 }
@@ -162,6 +168,43 @@ impl<DB, TC> FvmMessageInterpreter<DB, TC>
 where
     DB: Blockstore + 'static + Send + Sync + Clone,
 {
+    /// Execute a read-only message.
+    async fn call(
+        &self,
+        state: FvmQueryState<DB>,
+        msg: Message,
+    ) -> anyhow::Result<(FvmQueryState<DB>, FvmApplyRet)> {
+        let from = msg.from;
+        let to = msg.to;
+        let method_num = msg.method_num;
+        let gas_limit = msg.gas_limit;
+
+        let start = Instant::now();
+        // Do not stack effects
+        let (state, (apply_ret, emitters)) = state.call(msg.clone()).await?;
+        let latency = start.elapsed().as_secs_f64();
+        let exit_code = apply_ret.msg_receipt.exit_code.value();
+
+        emit(MsgExec {
+            purpose: MsgExecPurpose::Call,
+            height: state.block_height(),
+            message: msg,
+            duration: latency,
+            exit_code,
+        });
+
+        let ret = FvmApplyRet {
+            apply_ret,
+            from,
+            to,
+            method_num,
+            gas_limit,
+            emitters,
+        };
+
+        Ok((state, ret))
+    }
+
     async fn estimate_gassed_msg(
         &self,
         state: FvmQueryState<DB>,
@@ -0,0 +1,721 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Look into a state tree without executing anything, to investigate what a node has
+//! in its ledger, and to compare the states of nodes which ended up with different hashes.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Context};
+use cid::Cid;
+use fendermint_vm_actor_interface::{
+    account, activity, chainmetadata, cron, evm, gas_market, init, ipc, multisig, system,
+};
+use fvm::state_tree::{ActorState, StateTree};
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_encoding::CborStore;
+use fvm_ipld_kamt::{AsHashedKey, Config as KamtConfig, HashedKey, Kamt};
+use fvm_shared::{address::Address, ActorID};
+use libipld::Ipld;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use evm::uints::U256;
+
+/// Position of the storage root in the tuple of the EVM actor state.
+const EVM_CONTRACT_STATE_INDEX: usize = 2;
+
+/// Storage slots of an EVM contract.
+pub type EvmStorage = BTreeMap<U256, U256>;
+
+/// Read-only view of the actors in a state tree.
+pub struct StateInspector<DB: Blockstore> {
+    state_tree: StateTree<DB>,
+    /// Names of the built-in actors by code, as registered in the system actor.
+    code_names: HashMap<Cid, String>,
+}
+
+impl<DB> StateInspector<DB>
+where
+    DB: Blockstore,
+{
+    pub fn new(store: DB, state_root: &Cid) -> anyhow::Result<Self> {
+        let state_tree = StateTree::new_from_root(store, state_root)
+            .with_context(|| format!("failed to load state tree {state_root}"))?;
+
+        let mut inspector = Self {
+            state_tree,
+            code_names: HashMap::new(),
+        };
+
+        inspector.code_names = inspector.load_code_names()?;
+
+        Ok(inspector)
+    }
+
+    /// Load the registry of built-in actors from the system actor, if there is one.
+    fn load_code_names(&self) -> anyhow::Result<HashMap<Cid, String>> {
+        let Some(sys) = self.state_tree.get_actor(system::SYSTEM_ACTOR_ID)? else {
+            return Ok(HashMap::new());
+        };
+
+        let store = self.state_tree.store();
+
+        let state: system::State = store
+            .get_cbor(&sys.state)
+            .context("failed to get system actor state")?
+            .ok_or_else(|| anyhow!("system actor state not found"))?;
+
+        let registry: Vec<(String, Cid)> = store
+            .get_cbor(&state.builtin_actors)
+            .context("failed to get builtin actors registry")?
+            .ok_or_else(|| anyhow!("builtin actors registry not found"))?;
+
+        Ok(registry
+            .into_iter()
+            .map(|(name, code)| (code, name))
+            .collect())
+    }
+
+    pub fn store(&self) -> &DB {
+        self.state_tree.store()
+    }
+
+    /// All actors in the tree, in the order of their IDs.
+    pub fn actors(&self) -> anyhow::Result<BTreeMap<ActorID, ActorState>> {
+        let mut actors = BTreeMap::new();
+        self.for_each_actor(|id, state| {
+            actors.insert(id, state.clone());
+            Ok(())
+        })?;
+        Ok(actors)
+    }
+
+    /// Visit every actor in the tree, loading the nodes of the tree as they are reached,
+    /// so that the whole tree doesn't have to fit in memory.
+    pub fn for_each_actor(
+        &self,
+        mut f: impl FnMut(ActorID, &ActorState) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.state_tree.for_each(|addr, state| {
+            let id = addr
+                .id()
+                .context("state tree keys should be ID addresses")?;
+            f(id, state)
+        })
+    }
+
+    /// Resolve an address to an actor ID through the init actor.
+    pub fn lookup_id(&self, addr: &Address) -> anyhow::Result<ActorID> {
+        self.state_tree
+            .lookup_id(addr)?
+            .ok_or_else(|| anyhow!("actor {addr} not found"))
+    }
+
+    pub fn actor(&self, id: ActorID) -> anyhow::Result<ActorState> {
+        self.state_tree
+            .get_actor(id)?
+            .ok_or_else(|| anyhow!("actor {id} not found"))
+    }
+
+    /// Name of the kind of actor, based on its code, or failing that, its well-known ID.
+    pub fn actor_kind(&self, id: ActorID, actor: &ActorState) -> Option<&str> {
+        if let Some(name) = self.code_names.get(&actor.code) {
+            return Some(name);
+        }
+        match id {
+            chainmetadata::CHAINMETADATA_ACTOR_ID => Some("chainmetadata"),
+            gas_market::GAS_MARKET_ACTOR_ID => Some("gas_market"),
+            activity::ACTIVITY_TRACKER_ACTOR_ID => Some("activity_tracker"),
+            _ => None,
+        }
+    }
+
+    fn is_evm(&self, actor: &ActorState) -> bool {
+        self.code_names
+            .get(&actor.code)
+            .is_some_and(|name| name == "evm")
+    }
+
+    /// Summary of the actor, as it appears in the state tree.
+    pub fn actor_summary(&self, id: ActorID, actor: &ActorState) -> Value {
+        json!({
+            "id": id,
+            "kind": self.actor_kind(id, actor),
+            "code": actor.code.to_string(),
+            "state": actor.state.to_string(),
+            "sequence": actor.sequence,
+            "balance": actor.balance.atto().to_string(),
+            "delegated_address": actor.delegated_address.map(|a| a.to_string()),
+        })
+    }
+
+    /// Flat summary of an actor for exporting, with the most commonly needed fields of its state.
+    ///
+    /// Counting the storage slots of EVM contracts means walking their storage.
+    pub fn actor_record(&self, id: ActorID, actor: &ActorState) -> anyhow::Result<ActorRecord> {
+        let kind = self.actor_kind(id, actor);
+
+        let mut record = ActorRecord {
+            id,
+            kind: kind.map(|k| k.to_owned()),
+            ipc_contract: None,
+            code: actor.code.to_string(),
+            state: actor.state.to_string(),
+            sequence: actor.sequence,
+            balance: actor.balance.atto().to_string(),
+            delegated_address: actor.delegated_address.map(|a| a.to_string()),
+            address: None,
+            bytecode_hash: None,
+            storage_slots: None,
+        };
+
+        match kind {
+            Some("account") => {
+                let state: account::State = get_cbor(self.store(), &actor.state)?;
+                record.address = Some(state.address.to_string());
+            }
+            Some("evm") => {
+                let fields = evm_state_fields(self.store(), &actor.state)?;
+                if let Ipld::Bytes(ref hash) = fields[1] {
+                    record.bytecode_hash = Some(format!("0x{}", hex::encode(hash)));
+                }
+                let mut slots = 0;
+                self.for_each_evm_storage(actor, |_, _| {
+                    slots += 1;
+                    Ok(())
+                })?;
+                record.ipc_contract = ipc_contract_name(id).map(|c| c.to_owned());
+                record.storage_slots = Some(slots);
+            }
+            _ => {}
+        }
+
+        Ok(record)
+    }
+
+    /// The state of an actor as JSON.
+    ///
+    /// The states of actors with a known layout are decoded into named fields,
+    /// the rest are rendered as generic IPLD, where links point at further blocks.
+    pub fn actor_state(&self, id: ActorID, actor: &ActorState) -> anyhow::Result<Value> {
+        let store = self.store();
+        let kind = self.actor_kind(id, actor).unwrap_or_default();
+        let cid = &actor.state;
+
+        let state = match kind {
+            "system" => {
+                let state: system::State = get_cbor(store, cid)?;
+                let registry: Vec<(String, Cid)> = get_cbor(store, &state.builtin_actors)?;
+                json!({
+                    "builtin_actors": registry
+                        .into_iter()
+                        .map(|(name, code)| (name, Value::String(code.to_string())))
+                        .collect::<serde_json::Map<_, _>>()
+                })
+            }
+            "init" => {
+                let state: init::State = get_cbor(store, cid)?;
+                json!({
+                    "address_map": state.address_map.to_string(),
+                    "next_id": state.next_id,
+                    "network_name": state.network_name,
+                })
+            }
+            "account" => {
+                let state: account::State = get_cbor(store, cid)?;
+                json!({ "address": state.address.to_string() })
+            }
+            "cron" => {
+                let state: cron::State = get_cbor(store, cid)?;
+                json!({
+                    "entries": state.entries.into_iter().map(|e| json!({
+                        "receiver": e.receiver.to_string(),
+                        "method_num": e.method_num,
+                    })).collect::<Vec<_>>()
+                })
+            }
+            "multisig" => {
+                let state: multisig::State = get_cbor(store, cid)?;
+                json!({
+                    "signers": state.signers.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+                    "num_approvals_threshold": state.num_approvals_threshold,
+                    "next_tx_id": state.next_tx_id.0,
+                    "initial_balance": state.initial_balance.atto().to_string(),
+                    "start_epoch": state.start_epoch,
+                    "unlock_duration": state.unlock_duration,
+                    "pending_txs": state.pending_txs.to_string(),
+                })
+            }
+            "chainmetadata" => {
+                let state: fendermint_actor_chainmetadata::State = get_cbor(store, cid)?;
+                json!({
+                    "blockhashes": state.blockhashes.to_string(),
+                    "lookback_len": state.lookback_len,
+                })
+            }
+            "gas_market" => {
+                let state: fendermint_actor_gas_market_eip1559::State = get_cbor(store, cid)?;
+                let c = state.constants;
+                json!({
+                    "base_fee": state.base_fee.atto().to_string(),
+                    "constants": {
+                        "block_gas_limit": c.block_gas_limit,
+                        "minimal_base_fee": c.minimal_base_fee.atto().to_string(),
+                        "elasticity_multiplier": c.elasticity_multiplier,
+                        "base_fee_max_change_denominator": c.base_fee_max_change_denominator,
+                    }
+                })
+            }
+            "activity_tracker" => {
+                let state: fendermint_actor_activity_tracker::State = get_cbor(store, cid)?;
+                json!({
+                    "tracking_since": state.tracking_since,
+                    "consensus": state.consensus.to_string(),
+                })
+            }
+            "evm" => {
+                let fields = evm_state_fields(store, cid)?;
+                let mut state = json!({
+                    "bytecode": ipld_to_json(&fields[0]),
+                    "bytecode_hash": ipld_to_json(&fields[1]),
+                    "contract_state": ipld_to_json(&fields[EVM_CONTRACT_STATE_INDEX]),
+                    // The fields after the storage differ between actor versions.
+                    "rest": fields[EVM_CONTRACT_STATE_INDEX + 1..].iter().map(ipld_to_json).collect::<Vec<_>>(),
+                });
+                if let Some(contract) = ipc_contract_name(id) {
+                    state["ipc_contract"] = json!(contract);
+                }
+                state
+            }
+            _ => ipld_to_json(&get_cbor::<Ipld>(store, cid)?),
+        };
+
+        Ok(state)
+    }
+
+    /// Read all storage slots of an EVM contract.
+    pub fn evm_storage(&self, actor: &ActorState) -> anyhow::Result<EvmStorage> {
+        let mut storage = BTreeMap::new();
+        self.for_each_evm_storage(actor, |k, v| {
+            storage.insert(*k, *v);
+            Ok(())
+        })?;
+        Ok(storage)
+    }
+
+    /// Visit the storage slots of an EVM contract, in the order of their hashed keys.
+    pub fn for_each_evm_storage(
+        &self,
+        actor: &ActorState,
+        mut f: impl FnMut(&U256, &U256) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if !self.is_evm(actor) {
+            return Err(anyhow!(
+                "actor with code {} is not an EVM actor",
+                actor.code
+            ));
+        }
+
+        let fields = evm_state_fields(self.store(), &actor.state)?;
+
+        let Ipld::Link(root) = fields[EVM_CONTRACT_STATE_INDEX] else {
+            return Err(anyhow!("expected the EVM contract state to be a link"));
+        };
+
+        let kamt = Kamt::<_, U256, U256, StorageKeyHasher>::load_with_config(
+            &root,
+            self.store(),
+            evm_storage_config(),
+        )
+        .context("failed to load EVM storage")?;
+
+        kamt.for_each(|k, v| f(k, v))
+            .context("failed to iterate EVM storage")
+    }
+}
+
+/// An actor as a flat record, with fields which only apply to some kinds of actors left empty.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ActorRecord {
+    pub id: ActorID,
+    /// Name of the built-in actor, or the custom actor at a well-known ID.
+    pub kind: Option<String>,
+    /// Name of the IPC contract, if the actor is one of those deployed at genesis.
+    pub ipc_contract: Option<String>,
+    pub code: String,
+    /// Root of the actor state.
+    pub state: String,
+    /// The nonce of accounts.
+    pub sequence: u64,
+    /// Balance in atto.
+    pub balance: String,
+    pub delegated_address: Option<String>,
+    /// Key address of accounts.
+    pub address: Option<String>,
+    /// Keccak hash of the bytecode of EVM contracts.
+    pub bytecode_hash: Option<String>,
+    /// Number of storage slots of EVM contracts.
+    pub storage_slots: Option<u64>,
+}
+
+/// Name of the IPC contract deployed at a well-known ID.
+fn ipc_contract_name(id: ActorID) -> Option<&'static str> {
+    match id {
+        ipc::GATEWAY_ACTOR_ID => Some("gateway"),
+        ipc::SUBNETREGISTRY_ACTOR_ID => Some("subnet_registry"),
+        _ => None,
+    }
+}
+
+/// The EVM actor state is a tuple; we only rely on the leading fields, which are the same in all versions.
+fn evm_state_fields<DB: Blockstore>(store: &DB, cid: &Cid) -> anyhow::Result<Vec<Ipld>> {
+    match get_cbor::<Ipld>(store, cid)? {
+        Ipld::List(fields) if fields.len() > EVM_CONTRACT_STATE_INDEX => Ok(fields),
+        _ => Err(anyhow!("unexpected EVM actor state layout")),
+    }
+}
+
+/// The same parameters the EVM actor uses for its storage.
+fn evm_storage_config() -> KamtConfig {
+    KamtConfig {
+        min_data_depth: 0,
+        bit_width: 5,
+        max_array_width: 1,
+    }
+}
+
+/// The EVM actor uses the big-endian storage key as the hash.
+#[derive(Debug)]
+struct StorageKeyHasher;
+
+impl AsHashedKey<U256, 32> for StorageKeyHasher {
+    fn as_hashed_key(key: &U256) -> Cow<HashedKey<32>> {
+        let mut bytes = [0u8; 32];
+        key.to_big_endian(&mut bytes);
+        Cow::Owned(bytes)
+    }
+}
+
+fn get_cbor<T: serde::de::DeserializeOwned>(
+    store: &impl Blockstore,
+    cid: &Cid,
+) -> anyhow::Result<T> {
+    store
+        .get_cbor(cid)
+        .with_context(|| format!("failed to decode {cid}"))?
+        .ok_or_else(|| anyhow!("block {cid} not found"))
+}
+
+/// Render IPLD as JSON, using the DAG-JSON convention for links, and hex for bytes.
+pub fn ipld_to_json(ipld: &Ipld) -> Value {
+    match ipld {
+        Ipld::Null => Value::Null,
+        Ipld::Bool(b) => Value::Bool(*b),
+        Ipld::Integer(i) => match i64::try_from(*i) {
+            Ok(i) => json!(i),
+            Err(_) => json!(i.to_string()),
+        },
+        Ipld::Float(f) => json!(f),
+        Ipld::String(s) => json!(s),
+        Ipld::Bytes(b) => json!(format!("0x{}", hex::encode(b))),
+        Ipld::List(items) => Value::Array(items.iter().map(ipld_to_json).collect()),
+        Ipld::Map(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), ipld_to_json(v)))
+                .collect(),
+        ),
+        Ipld::Link(cid) => json!({ "/": cid.to_string() }),
+    }
+}
+
+/// Render a storage slot key or value as 32 bytes of hex.
+pub fn u256_to_hex(value: &U256) -> String {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// Difference between two values of a field.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Change<T> {
+    pub from: T,
+    pub to: T,
+}
+
+/// Differences between the storage of two versions of an EVM contract, keyed by slot.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct StorageDiff {
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, Change<String>>,
+}
+
+impl StorageDiff {
+    pub fn new(from: &EvmStorage, to: &EvmStorage) -> Self {
+        let mut diff = Self::default();
+        for (k, v) in from {
+            match to.get(k) {
+                None => {
+                    diff.removed.insert(u256_to_hex(k), u256_to_hex(v));
+                }
+                Some(w) if w != v => {
+                    diff.changed.insert(
+                        u256_to_hex(k),
+                        Change {
+                            from: u256_to_hex(v),
+                            to: u256_to_hex(w),
+                        },
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, v) in to {
+            if !from.contains_key(k) {
+                diff.added.insert(u256_to_hex(k), u256_to_hex(v));
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Changes to an actor present in both states.
+#[derive(Serialize, Debug, Clone)]
+pub struct ActorDiff {
+    pub id: ActorID,
+    pub kind: Option<String>,
+    /// Fields of the actor record in the state tree which differ, e.g. `balance` or `state`.
+    pub fields: BTreeMap<&'static str, Change<Value>>,
+    /// Slot level changes of EVM contracts, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageDiff>,
+}
+
+/// Structural difference between two state trees.
+#[derive(Serialize, Debug, Clone)]
+pub struct StateDiff {
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    pub changed: Vec<ActorDiff>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare the actors in two states, optionally comparing the storage of EVM contracts as well.
+pub fn diff_states<DB1, DB2>(
+    from: &StateInspector<DB1>,
+    to: &StateInspector<DB2>,
+    with_storage: bool,
+) -> anyhow::Result<StateDiff>
+where
+    DB1: Blockstore,
+    DB2: Blockstore,
+{
+    let from_actors = from.actors()?;
+    let to_actors = to.actors()?;
+
+    let mut diff = StateDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+    };
+
+    for (id, a) in &from_actors {
+        let Some(b) = to_actors.get(id) else {
+            diff.removed.push(from.actor_summary(*id, a));
+            continue;
+        };
+        if a == b {
+            continue;
+        }
+
+        let sa = from.actor_summary(*id, a);
+        let sb = to.actor_summary(*id, b);
+
+        let fields = ["code", "state", "sequence", "balance", "delegated_address"]
+            .into_iter()
+            .filter(|f| sa[f] != sb[f])
+            .map(|f| {
+                (
+                    f,
+                    Change {
+                        from: sa[f].clone(),
+                        to: sb[f].clone(),
+                    },
+                )
+            })
+            .collect();
+
+        let storage = if with_storage && a.state != b.state && from.is_evm(a) && to.is_evm(b) {
+            let storage = StorageDiff::new(&from.evm_storage(a)?, &to.evm_storage(b)?);
+            Some(storage).filter(|s| !s.is_empty())
+        } else {
+            None
+        };
+
+        diff.changed.push(ActorDiff {
+            id: *id,
+            kind: to.actor_kind(*id, b).map(|k| k.to_owned()),
+            fields,
+            storage,
+        });
+    }
+
+    for (id, b) in &to_actors {
+        if !from_actors.contains_key(id) {
+            diff.added.push(to.actor_summary(*id, b));
+        }
+    }
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fendermint_vm_actor_interface::system;
+    use fvm::state_tree::{ActorState, StateTree};
+    use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::{CborStore, DAG_CBOR};
+    use fvm_ipld_kamt::Kamt;
+    use fvm_shared::econ::TokenAmount;
+
+    use super::{diff_states, evm_storage_config, StateInspector, StorageKeyHasher, U256};
+    use crate::fvm::state::empty_state_tree;
+    use crate::fvm::store::memory::MemoryBlockstore;
+
+    const EVM_ACTOR_ID: u64 = 1000;
+
+    fn fake_code(name: &str) -> Cid {
+        Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(name.as_bytes()))
+    }
+
+    fn put_actor<S: Blockstore>(
+        tree: &mut StateTree<S>,
+        id: u64,
+        code: Cid,
+        state: &impl serde::Serialize,
+    ) {
+        let state = tree
+            .store()
+            .put_cbor(state, Code::Blake2b256)
+            .expect("failed to put state");
+        tree.set_actor(
+            id,
+            ActorState::new(code, state, TokenAmount::from_atto(id), 0, None),
+        );
+    }
+
+    fn put_storage<S: Blockstore>(store: &S, slots: &[(u64, u64)]) -> Cid {
+        let mut kamt =
+            Kamt::<_, U256, U256, StorageKeyHasher>::new_with_config(store, evm_storage_config());
+        for (k, v) in slots {
+            kamt.set(U256::from(*k), U256::from(*v)).unwrap();
+        }
+        kamt.flush().unwrap()
+    }
+
+    /// Create a state with a system actor, a few generic actors and an EVM contract.
+    fn make_state(store: MemoryBlockstore, actors: &[u64], slots: &[(u64, u64)]) -> Cid {
+        let mut tree = empty_state_tree(store).unwrap();
+
+        let registry = vec![
+            ("system".to_owned(), fake_code("system")),
+            ("evm".to_owned(), fake_code("evm")),
+        ];
+        let builtin_actors = tree.store().put_cbor(&registry, Code::Blake2b256).unwrap();
+        put_actor(
+            &mut tree,
+            system::SYSTEM_ACTOR_ID,
+            fake_code("system"),
+            &system::State { builtin_actors },
+        );
+
+        for id in actors {
+            put_actor(&mut tree, *id, fake_code("other"), &format!("actor-{id}"));
+        }
+
+        let storage = put_storage(tree.store(), slots);
+        let bytecode = fake_code("bytecode");
+        put_actor(
+            &mut tree,
+            EVM_ACTOR_ID,
+            fake_code("evm"),
+            &(bytecode, [0u8; 32].to_vec(), storage, 0u64),
+        );
+
+        tree.flush().unwrap()
+    }
+
+    #[test]
+    fn inspect_evm_storage() {
+        let store = MemoryBlockstore::new();
+        let root = make_state(store.clone(), &[100], &[(1, 10), (2, 20)]);
+        let inspector = StateInspector::new(store, &root).unwrap();
+
+        let evm = inspector.actor(EVM_ACTOR_ID).unwrap();
+        assert_eq!(inspector.actor_kind(EVM_ACTOR_ID, &evm), Some("evm"));
+
+        let storage = inspector.evm_storage(&evm).unwrap();
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage.get(&U256::from(2)), Some(&U256::from(20)));
+
+        let record = inspector.actor_record(EVM_ACTOR_ID, &evm).unwrap();
+        assert_eq!(record.storage_slots, Some(2));
+        assert_eq!(record.bytecode_hash, Some(format!("0x{}", "00".repeat(32))));
+
+        let mut ids = Vec::new();
+        inspector
+            .for_each_actor(|id, _| {
+                ids.push(id);
+                Ok(())
+            })
+            .unwrap();
+        ids.sort();
+        assert_eq!(ids, vec![system::SYSTEM_ACTOR_ID, 100, EVM_ACTOR_ID]);
+
+        let other = inspector.actor(100).unwrap();
+        assert!(inspector.evm_storage(&other).is_err());
+        assert_eq!(inspector.actor_state(100, &other).unwrap(), "actor-100");
+    }
+
+    #[test]
+    fn diff_actors_and_storage() {
+        let store = MemoryBlockstore::new();
+        let root1 = make_state(store.clone(), &[100, 101], &[(1, 10), (2, 20)]);
+        let root2 = make_state(store.clone(), &[101, 102], &[(2, 21), (3, 30)]);
+
+        let from = StateInspector::new(store.clone(), &root1).unwrap();
+        let to = StateInspector::new(store, &root2).unwrap();
+
+        let diff = diff_states(&from, &from, true).unwrap();
+        assert!(diff.is_empty());
+
+        let diff = diff_states(&from, &to, true).unwrap();
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0]["id"], 100);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0]["id"], 102);
+        assert_eq!(diff.changed.len(), 1);
+
+        let changed = &diff.changed[0];
+        assert_eq!(changed.id, EVM_ACTOR_ID);
+        assert!(changed.fields.contains_key("state"));
+
+        let storage = changed.storage.as_ref().expect("storage diff");
+        assert_eq!(storage.removed.len(), 1);
+        assert_eq!(storage.added.len(), 1);
+        assert_eq!(storage.changed.len(), 1);
+    }
+}
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0, MIT
 
 pub mod fevm;
+pub mod inspect;
 pub mod ipc;
 pub mod snapshot;
 
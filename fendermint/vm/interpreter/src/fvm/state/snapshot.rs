@@ -11,11 +11,13 @@ use fvm::state_tree::StateTree;
 use fvm_ipld_blockstore::Blockstore;
 use fvm_ipld_car::{load_car, load_car_unchecked, CarHeader};
 use fvm_ipld_encoding::{from_slice, CborStore, DAG_CBOR};
+use fvm_shared::IDENTITY_HASH;
 use libipld::Ipld;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use tokio_stream::StreamExt;
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
@@ -38,6 +40,12 @@ struct SnapshotMetadata {
 /// The streamer that streams the snapshot into (Cid, Vec<u8>) for car file.
 type SnapshotStreamer = Box<dyn Send + Unpin + Stream<Item = (Cid, Vec<u8>)>>;
 
+/// The error which stopped a [StateTreeStreamer], if any.
+///
+/// The CAR writer only takes a stream of blocks, so the streamer can only end early;
+/// the error has to be checked once the stream is finished.
+type StreamerError = Arc<Mutex<Option<anyhow::Error>>>;
+
 impl<BS> Snapshot<BS>
 where
     BS: Blockstore + 'static + Send + Clone,
@@ -54,6 +62,24 @@ where
         )?))
     }
 
+    /// Create a delta snapshot, which only contains the blocks reachable from the current
+    /// state root that aren't also reachable from the state root of a base snapshot.
+    ///
+    /// The base state has to be present in the store, as it is traversed during the export.
+    /// The CIDs of every block reachable from the base are collected into memory first,
+    /// so the export needs in the order of 100 bytes per block in the base state on top
+    /// of what a full snapshot uses.
+    pub fn new_delta(
+        store: BS,
+        state_params: FvmStateParams,
+        block_height: BlockHeight,
+        base_state_root: Cid,
+    ) -> anyhow::Result<Self> {
+        let mut snapshot = V1Snapshot::new(store, state_params, block_height)?;
+        snapshot.base_state_root = Some(base_state_root);
+        Ok(Self::V1(snapshot))
+    }
+
     pub fn version(&self) -> SnapshotVersion {
         match self {
             Snapshot::V1(_) => 1,
@@ -103,7 +129,7 @@ where
         let file = tokio::fs::File::create(path).await?;
 
         // derive the metadata for the car file, so that the snapshot version can be recorded.
-        let (metadata, snapshot_streamer) = self.into_streamer()?;
+        let (metadata, snapshot_streamer, streamer_error) = self.into_streamer()?;
         let (metadata_cid, metadata_bytes) = derive_cid(&metadata)?;
 
         // create the target car header with the metadata cid as the only root
@@ -121,19 +147,29 @@ where
 
         write_task.await??;
 
+        // A snapshot with missing blocks could not be imported.
+        if let Some(e) = streamer_error
+            .lock()
+            .expect("streamer error poisoned")
+            .take()
+        {
+            return Err(e.context("failed to export the state"));
+        }
+
         Ok(())
     }
 
-    fn into_streamer(self) -> anyhow::Result<(SnapshotMetadata, SnapshotStreamer)> {
+    fn into_streamer(self) -> anyhow::Result<(SnapshotMetadata, SnapshotStreamer, StreamerError)> {
         match self {
             Snapshot::V1(inner) => {
-                let (data_root_cid, streamer) = inner.into_streamer()?;
+                let (data_root_cid, streamer, error) = inner.into_streamer()?;
                 Ok((
                     SnapshotMetadata {
                         version: 1,
                         data_root_cid,
                     },
                     streamer,
+                    error,
                 ))
             }
         }
@@ -145,6 +181,8 @@ pub struct V1Snapshot<BS> {
     state_tree: StateTree<ReadOnlyBlockstore<BS>>,
     state_params: FvmStateParams,
     block_height: BlockHeight,
+    /// State root of the snapshot this one is a delta to, if any.
+    base_state_root: Option<Cid>,
 }
 
 pub type BlockStateParams = (FvmStateParams, BlockHeight);
@@ -166,6 +204,7 @@ where
             state_tree,
             state_params,
             block_height,
+            base_state_root: None,
         })
     }
 
@@ -179,6 +218,7 @@ where
                 )?,
                 state_params,
                 block_height,
+                base_state_root: None,
             })
         } else {
             Err(anyhow!(
@@ -188,19 +228,29 @@ where
         }
     }
 
-    fn into_streamer(self) -> anyhow::Result<(Cid, SnapshotStreamer)> {
+    fn into_streamer(self) -> anyhow::Result<(Cid, SnapshotStreamer, StreamerError)> {
         let state_tree_root = self.state_params.state_root;
 
         let block_state_params = (self.state_params, self.block_height);
         let bytes = fvm_ipld_encoding::to_vec(&block_state_params)?;
         let root_cid = Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(&bytes));
 
-        let state_tree_streamer =
-            StateTreeStreamer::new(state_tree_root, self.state_tree.into_store());
+        let store = self.state_tree.into_store();
+
+        // Everything reachable from the base is already present wherever the delta is going to be applied.
+        // This holds the whole set of base CIDs in memory for the duration of the export;
+        // we can't tell whether a block is shared with the base without visiting all of it.
+        let skip = match self.base_state_root {
+            Some(base_state_root) => reachable_cids(&store, base_state_root, false)?,
+            None => HashSet::new(),
+        };
+
+        let state_tree_streamer = StateTreeStreamer::new(state_tree_root, store).with_skip(skip);
+        let error = state_tree_streamer.error();
         let root_streamer = tokio_stream::iter(vec![(root_cid, bytes)]);
         let streamer: SnapshotStreamer = Box::new(state_tree_streamer.merge(root_streamer));
 
-        Ok((root_cid, streamer))
+        Ok((root_cid, streamer, error))
     }
 
     pub fn block_height(&self) -> BlockHeight {
@@ -210,6 +260,12 @@ where
     pub fn state_params(&self) -> &FvmStateParams {
         &self.state_params
     }
+
+    /// Check that every block reachable from the state root is present in the store,
+    /// which is not guaranteed after importing a delta on top of the wrong base.
+    pub fn check_complete(&self) -> anyhow::Result<()> {
+        reachable_cids(self.state_tree.store(), self.state_params.state_root, true).map(|_| ())
+    }
 }
 
 #[pin_project::pin_project]
@@ -219,13 +275,32 @@ pub(crate) struct StateTreeStreamer<BS> {
     dfs: VecDeque<Cid>,
     /// The block store
     bs: BS,
+    /// CIDs which should not be streamed, along with anything they link to.
+    skip: HashSet<Cid>,
+    /// Set when a block is missing or cannot be read, which ends the stream.
+    error: StreamerError,
 }
 
 impl<BS> StateTreeStreamer<BS> {
     pub fn new(state_root_cid: Cid, bs: BS) -> Self {
         let mut dfs = VecDeque::new();
         dfs.push_back(state_root_cid);
-        Self { dfs, bs }
+        Self {
+            dfs,
+            bs,
+            skip: HashSet::new(),
+            error: Default::default(),
+        }
+    }
+
+    pub fn with_skip(mut self, skip: HashSet<Cid>) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// Handle to the error which stopped the stream, if any.
+    pub fn error(&self) -> StreamerError {
+        self.error.clone()
     }
 }
 
@@ -242,6 +317,10 @@ impl<BS: Blockstore> Stream for StateTreeStreamer<BS> {
                 return Poll::Ready(None);
             };
 
+            if this.skip.contains(&cid) {
+                continue;
+            }
+
             match this.bs.get(&cid) {
                 Ok(Some(bytes)) => {
                     // Not all data in the blockstore is traversable, e.g.
@@ -255,13 +334,17 @@ impl<BS: Blockstore> Stream for StateTreeStreamer<BS> {
                     }
                     return Poll::Ready(Some((cid, bytes)));
                 }
+                // Identity CIDs carry their own data, they don't need to be in the store.
+                Ok(None) if cid.hash().code() == IDENTITY_HASH => continue,
                 Ok(None) => {
-                    tracing::debug!("cid: {cid:?} has no value in block store, skip");
-                    continue;
+                    tracing::error!("cid: {cid:?} has no value in block store");
+                    *this.error.lock().expect("streamer error poisoned") =
+                        Some(anyhow!("block not found in the store: {cid}"));
+                    return Poll::Ready(None);
                 }
                 Err(e) => {
                     tracing::error!("cannot get from block store: {}", e.to_string());
-                    // TODO: consider returning Result, but it won't work with `car.write_stream_async`.
+                    *this.error.lock().expect("streamer error poisoned") = Some(e);
                     return Poll::Ready(None);
                 }
             }
@@ -286,6 +369,42 @@ fn walk_ipld_cids(ipld: Ipld, dfs: &mut VecDeque<Cid>) {
     }
 }
 
+/// Collect all the CIDs reachable from a root.
+///
+/// The result has an entry for every block in the DAG, so memory use grows with the size of the state.
+///
+/// If `strict` is enabled, it's an error if a block cannot be found in the store,
+/// otherwise missing blocks are ignored.
+fn reachable_cids<BS: Blockstore>(
+    store: &BS,
+    root: Cid,
+    strict: bool,
+) -> anyhow::Result<HashSet<Cid>> {
+    let mut visited = HashSet::new();
+    let mut dfs = VecDeque::from(vec![root]);
+
+    while let Some(cid) = dfs.pop_front() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        match store.get(&cid)? {
+            Some(bytes) => {
+                if cid.codec() == DAG_CBOR {
+                    let ipld = from_slice::<Ipld>(&bytes)?;
+                    walk_ipld_cids(ipld, &mut dfs);
+                }
+            }
+            // Identity CIDs carry their own data, they don't need to be in the store.
+            None if strict && cid.hash().code() != IDENTITY_HASH => {
+                return Err(anyhow!("block not found in the store: {cid}"));
+            }
+            None => {}
+        }
+    }
+
+    Ok(visited)
+}
+
 pub(crate) fn derive_cid<T: Serialize>(t: &T) -> anyhow::Result<(Cid, Vec<u8>)> {
     let bytes = fvm_ipld_encoding::to_vec(&t)?;
     let cid = Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(&bytes));
@@ -298,15 +417,17 @@ mod tests {
     use crate::fvm::state::FvmStateParams;
     use crate::fvm::store::memory::MemoryBlockstore;
     use crate::fvm::store::ReadOnlyBlockstore;
+    use cid::multihash::Code;
     use cid::Cid;
     use fendermint_vm_core::Timestamp;
     use futures_util::StreamExt;
     use fvm::state_tree::{ActorState, StateTree};
     use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::CborStore;
     use fvm_shared::state::StateTreeVersion;
     use fvm_shared::version::NetworkVersion;
     use quickcheck::{Arbitrary, Gen};
-    use std::collections::VecDeque;
+    use std::collections::{HashSet, VecDeque};
 
     fn prepare_state_tree(items: u64) -> (Cid, StateTree<MemoryBlockstore>) {
         let store = MemoryBlockstore::new();
@@ -314,13 +435,25 @@ mod tests {
         let mut gen = Gen::new(16);
 
         for i in 1..=items {
-            let state = ActorState::arbitrary(&mut gen);
+            let state = arbitrary_actor(state_tree.store(), &mut gen);
             state_tree.set_actor(i, state);
         }
         let root_cid = state_tree.flush().unwrap();
         (root_cid, state_tree)
     }
 
+    /// An actor whose code and state are in the store, so it can be exported.
+    fn arbitrary_actor(store: &MemoryBlockstore, gen: &mut Gen) -> ActorState {
+        let mut state = ActorState::arbitrary(gen);
+        state.code = store
+            .put_cbor(&u64::arbitrary(gen), Code::Blake2b256)
+            .unwrap();
+        state.state = store
+            .put_cbor(&Vec::<u64>::arbitrary(gen), Code::Blake2b256)
+            .unwrap();
+        state
+    }
+
     fn assert_tree2_contains_tree1<Store1: Blockstore, Store2: Blockstore>(
         tree1: &StateTree<Store1>,
         tree2: &StateTree<Store2>,
@@ -349,6 +482,8 @@ mod tests {
         let mut stream = StateTreeStreamer {
             dfs: VecDeque::from(vec![root_cid]),
             bs: bs.clone(),
+            skip: Default::default(),
+            error: Default::default(),
         };
 
         let new_bs = MemoryBlockstore::new();
@@ -363,10 +498,8 @@ mod tests {
         assert_tree2_contains_tree1(&new_state_tree, &old_state_tree);
     }
 
-    #[tokio::test]
-    async fn test_car() {
-        let (state_root, state_tree) = prepare_state_tree(100);
-        let state_params = FvmStateParams {
+    fn test_state_params(state_root: Cid) -> FvmStateParams {
+        FvmStateParams {
             state_root,
             timestamp: Timestamp(100),
             network_version: NetworkVersion::V1,
@@ -376,7 +509,13 @@ mod tests {
             power_scale: 0,
             app_version: 0,
             consensus_params: None,
-        };
+        }
+    }
+
+    #[tokio::test]
+    async fn test_car() {
+        let (state_root, state_tree) = prepare_state_tree(100);
+        let state_params = test_state_params(state_root);
         let block_height = 2048;
 
         let bs = state_tree.into_store();
@@ -399,4 +538,85 @@ mod tests {
             &loaded_snapshot.state_tree,
         );
     }
+
+    #[tokio::test]
+    async fn test_car_missing_block() {
+        let (state_root, state_tree) = prepare_state_tree(100);
+        let bs = state_tree.into_store();
+
+        // Copy everything but the last block streamed, which is one of the leaves.
+        let mut stream = StateTreeStreamer::new(state_root, bs);
+        let mut blocks = Vec::new();
+        while let Some(block) = stream.next().await {
+            blocks.push(block);
+        }
+        assert!(stream.error().lock().unwrap().is_none());
+        blocks.pop();
+
+        let incomplete = MemoryBlockstore::new();
+        for (cid, bytes) in blocks {
+            incomplete.put_keyed(&cid, &bytes).unwrap();
+        }
+
+        let snapshot = Snapshot::new(incomplete, test_state_params(state_root), 1).unwrap();
+        let tmp_file = tempfile::NamedTempFile::new().unwrap();
+        let err = snapshot.write_car(tmp_file.path()).await.unwrap_err();
+        assert!(format!("{err:#}").contains("block not found"));
+    }
+
+    #[tokio::test]
+    async fn test_delta_car() {
+        let (base_root, mut state_tree) = prepare_state_tree(100);
+
+        // Change some actors and add some new ones.
+        let mut gen = Gen::new(16);
+        for i in 50..=150 {
+            let state = arbitrary_actor(state_tree.store(), &mut gen);
+            state_tree.set_actor(i, state);
+        }
+        let state_root = state_tree.flush().unwrap();
+        let bs = state_tree.into_store();
+
+        let full_file = tempfile::NamedTempFile::new().unwrap();
+        let delta_file = tempfile::NamedTempFile::new().unwrap();
+
+        Snapshot::new(bs.clone(), test_state_params(base_root), 1)
+            .unwrap()
+            .write_car(full_file.path())
+            .await
+            .unwrap();
+
+        Snapshot::new_delta(bs.clone(), test_state_params(state_root), 2, base_root)
+            .unwrap()
+            .write_car(delta_file.path())
+            .await
+            .unwrap();
+
+        let full_size = full_file.as_file().metadata().unwrap().len();
+        let delta_size = delta_file.as_file().metadata().unwrap().len();
+        assert!(delta_size < full_size, "delta should be smaller");
+
+        // The delta on its own is incomplete.
+        let new_store = MemoryBlockstore::new();
+        let Snapshot::V1(loaded_delta) =
+            Snapshot::read_car(delta_file.path(), new_store.clone(), true)
+                .await
+                .unwrap();
+        assert!(loaded_delta.check_complete().is_err());
+
+        // Applied on top of its base it is complete.
+        Snapshot::read_car(full_file.path(), new_store.clone(), true)
+            .await
+            .unwrap();
+        let Snapshot::V1(loaded_delta) = Snapshot::read_car(delta_file.path(), new_store, true)
+            .await
+            .unwrap();
+
+        loaded_delta.check_complete().unwrap();
+        assert_eq!(loaded_delta.block_height, 2);
+        assert_tree2_contains_tree1(
+            &StateTree::new_from_root(bs, &state_root).unwrap(),
+            &loaded_delta.state_tree,
+        );
+    }
 }
@@ -0,0 +1,92 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use anyhow::Result;
+use cid::Cid;
+use fvm_ipld_blockstore::Blockstore;
+
+use super::memory::MemoryBlockstore;
+
+/// A blockstore which reads through to another one, but keeps all writes in memory,
+/// so blocks can be executed on top of an existing state without changing it.
+#[derive(Clone)]
+pub struct OverlayBlockstore<DB> {
+    base: DB,
+    overlay: MemoryBlockstore,
+}
+
+impl<DB> OverlayBlockstore<DB> {
+    pub fn new(base: DB) -> Self {
+        Self {
+            base,
+            overlay: MemoryBlockstore::new(),
+        }
+    }
+
+    /// The underlying store, without the writes made through the overlay.
+    pub fn base(&self) -> &DB {
+        &self.base
+    }
+}
+
+impl<DB> Blockstore for OverlayBlockstore<DB>
+where
+    DB: Blockstore,
+{
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        match self.overlay.get(k)? {
+            None => self.base.get(k),
+            found => Ok(found),
+        }
+    }
+
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.overlay.put_keyed(k, block)
+    }
+
+    fn has(&self, k: &Cid) -> Result<bool> {
+        Ok(self.overlay.has(k)? || self.base.has(k)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use fvm_ipld_blockstore::Blockstore;
+    use fvm_ipld_encoding::DAG_CBOR;
+
+    use super::OverlayBlockstore;
+    use crate::fvm::store::memory::MemoryBlockstore;
+
+    fn put(store: &impl Blockstore, data: &[u8]) -> Cid {
+        let cid = Cid::new_v1(DAG_CBOR, Code::Blake2b256.digest(data));
+        store.put_keyed(&cid, data).unwrap();
+        cid
+    }
+
+    #[test]
+    fn reads_through_to_base() {
+        let base = MemoryBlockstore::new();
+        let cid = put(&base, b"base");
+        let store = OverlayBlockstore::new(base);
+
+        assert!(store.has(&cid).unwrap());
+        assert_eq!(store.get(&cid).unwrap(), Some(b"base".to_vec()));
+    }
+
+    #[test]
+    fn writes_stay_in_overlay() {
+        let base = MemoryBlockstore::new();
+        let store = OverlayBlockstore::new(base.clone());
+        let cid = put(&store, b"overlay");
+
+        assert!(store.has(&cid).unwrap());
+        assert_eq!(store.get(&cid).unwrap(), Some(b"overlay".to_vec()));
+        assert!(!base.has(&cid).unwrap());
+        assert!(!store.base().has(&cid).unwrap());
+
+        // Clones share the writes, like the ones given to the interpreter.
+        assert!(store.clone().has(&cid).unwrap());
+    }
+}
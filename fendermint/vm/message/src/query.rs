@@ -4,7 +4,7 @@ use cid::Cid;
 use fvm_ipld_encoding::RawBytes;
 use fvm_shared::{
     address::Address, econ::TokenAmount, error::ExitCode, message::Message as FvmMessage,
-    version::NetworkVersion,
+    version::NetworkVersion, ActorID,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
@@ -76,6 +76,62 @@ pub enum FvmQuery {
     StateParams,
     /// Query the built-in actors known by the System actor.
     BuiltinActors,
+    /// Query the state of an actor at every height sampled from a range.
+    ///
+    /// The height of the query itself is ignored. Heights which are no longer
+    /// (or not yet) in the state history are left out of the response.
+    ///
+    /// The response is IPLD encoded `Vec<ActorStateSample>`.
+    ActorStateRange {
+        address: Address,
+        range: HeightRange,
+    },
+    /// Execute multiple FVM messages against the same state, without adding them to the blockchain.
+    ///
+    /// Each message is executed in isolation, as if it was a separate [`Call`];
+    /// the effects of one are not visible to the next.
+    CallBatch(Vec<FvmMessage>),
+}
+
+/// The maximum number of heights a single range query can sample.
+pub const MAX_HEIGHT_RANGE_SAMPLES: u64 = 1000;
+
+/// The maximum number of messages a single [`FvmQuery::CallBatch`] can execute.
+pub const MAX_CALL_BATCH_SIZE: usize = 1000;
+
+/// Heights to sample in a range query: `from`, `from + step`, and so on, up to and including `to`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeightRange {
+    pub from: u64,
+    pub to: u64,
+    /// Distance between samples; zero is treated as one.
+    pub step: u64,
+}
+
+impl HeightRange {
+    pub fn new(from: u64, to: u64, step: u64) -> Self {
+        Self { from, to, step }
+    }
+
+    /// Number of heights in the sample.
+    pub fn len(&self) -> u64 {
+        if self.from > self.to {
+            0
+        } else {
+            ((self.to - self.from) / self.step.max(1)).saturating_add(1)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate the sampled heights in ascending order.
+    pub fn heights(&self) -> impl Iterator<Item = u64> {
+        let step = self.step.max(1);
+        let from = self.from;
+        (0..self.len()).map(move |i| from + i * step)
+    }
 }
 
 /// State of all actor implementations.
@@ -144,12 +200,37 @@ pub struct StateParams {
     pub network_version: NetworkVersion,
 }
 
+/// The state of an actor at one of the heights sampled by a range query.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct ActorStateSample {
+    pub height: u64,
+    /// The ID and state of the actor, or `None` if it didn't exist at this height.
+    pub state: Option<(ActorID, ActorState)>,
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct BuiltinActors {
     /// Registry of built-in actors known by the system.
     pub registry: Vec<(String, Cid)>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::HeightRange;
+
+    #[test]
+    fn height_range_samples() {
+        let heights = |r: HeightRange| r.heights().collect::<Vec<_>>();
+
+        assert_eq!(heights(HeightRange::new(10, 20, 5)), vec![10, 15, 20]);
+        assert_eq!(heights(HeightRange::new(10, 22, 5)), vec![10, 15, 20]);
+        assert_eq!(heights(HeightRange::new(3, 5, 0)), vec![3, 4, 5]);
+        assert_eq!(heights(HeightRange::new(7, 7, 100)), vec![7]);
+        assert!(HeightRange::new(8, 7, 1).is_empty());
+        assert_eq!(HeightRange::new(0, u64::MAX, 1).len(), u64::MAX);
+    }
+}
+
 #[cfg(feature = "arb")]
 mod arb {
     use fendermint_testing::arb::{ArbAddress, ArbCid, ArbTokenAmount};
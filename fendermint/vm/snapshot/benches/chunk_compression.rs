@@ -0,0 +1,136 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Compare the size of the chunks transferred over state sync and the time it takes to restore
+//! them, with and without compression, using a genesis state with the actor bundles and contracts.
+//!
+//! Run it with `cargo bench -p fendermint_vm_snapshot --bench chunk_compression`.
+
+use std::time::{Duration, Instant};
+
+use async_stm::{atomically, retry};
+use fendermint_vm_genesis::Genesis;
+use fendermint_vm_interpreter::fvm::{
+    bundle::{bundle_path, contracts_path, custom_actors_bundle_path},
+    state::FvmStateParams,
+    store::memory::MemoryBlockstore,
+};
+use fendermint_vm_interpreter::genesis::create_test_genesis_state;
+use fendermint_vm_snapshot::{SnapshotItem, SnapshotManager, SnapshotParams};
+use quickcheck::Arbitrary;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[tokio::main]
+async fn main() {
+    let (state_params, store) = init_genesis().await;
+
+    println!(
+        "{:<12} {:>8} {:>14} {:>14} {:>12} {:>12}",
+        "compression", "chunks", "car bytes", "sent bytes", "export", "restore"
+    );
+
+    for level in [None, Some(1), Some(3), Some(9), Some(19)] {
+        let started = Instant::now();
+        let (snapshot, _dir) = export(store.clone(), state_params.clone(), level).await;
+        let export_time = started.elapsed();
+
+        let sent_bytes = (0..snapshot.manifest.chunks)
+            .map(|i| snapshot.load_chunk(i).expect("chunk exists").len())
+            .sum::<usize>();
+
+        let started = Instant::now();
+        snapshot
+            .import(MemoryBlockstore::new(), true)
+            .await
+            .expect("failed to import snapshot");
+        let restore_time = started.elapsed();
+
+        println!(
+            "{:<12} {:>8} {:>14} {:>14} {:>12?} {:>12?}",
+            level.map_or("none".to_owned(), |l| format!("zstd-{l}")),
+            snapshot.manifest.chunks,
+            snapshot.manifest.size,
+            sent_bytes,
+            export_time,
+            restore_time
+        );
+    }
+}
+
+/// Export a snapshot of the state with a manager, returning it along with the directory it's in.
+async fn export(
+    store: MemoryBlockstore,
+    state_params: FvmStateParams,
+    compression_level: Option<i32>,
+) -> (SnapshotItem, tempfile::TempDir) {
+    let snapshots_dir = tempfile::tempdir().expect("failed to create tmp dir");
+    let download_dir = tempfile::tempdir().expect("failed to create tmp dir");
+
+    let (manager, client) = SnapshotManager::new(
+        store,
+        SnapshotParams {
+            snapshots_dir: snapshots_dir.path().into(),
+            download_dir: download_dir.path().into(),
+            block_interval: 1,
+            chunk_size: CHUNK_SIZE,
+            hist_size: 0,
+            last_access_hold: Duration::ZERO,
+            sync_poll_interval: Duration::ZERO,
+            max_deltas: 0,
+            compression_level,
+        },
+    )
+    .expect("failed to create snapshot manager");
+
+    let mock_client =
+        tendermint_rpc::MockClient::new(tendermint_rpc::MockRequestMethodMatcher::default()).0;
+
+    let handle = tokio::spawn(async move { manager.run(mock_client).await });
+
+    atomically(|| client.notify(0, state_params.clone())).await;
+
+    let snapshot = atomically(|| match client.list_snapshots()?.head() {
+        Some(snapshot) => Ok(snapshot.clone()),
+        None => retry(),
+    })
+    .await;
+
+    handle.abort();
+
+    (snapshot, snapshots_dir)
+}
+
+async fn init_genesis() -> (FvmStateParams, MemoryBlockstore) {
+    let mut g = quickcheck::Gen::new(50);
+    let genesis = Genesis::arbitrary(&mut g);
+
+    let (state, out) = create_test_genesis_state(
+        bundle_path(),
+        custom_actors_bundle_path(),
+        contracts_path(),
+        genesis,
+    )
+    .await
+    .expect("cannot create genesis state");
+
+    let store = state.store().clone();
+    let state = state
+        .into_exec_state()
+        .unwrap_or_else(|_| panic!("cannot create exec state"));
+    let (state_root, _, _) = state.commit().expect("failed to commit");
+
+    let state_params = FvmStateParams {
+        state_root,
+        timestamp: out.timestamp,
+        network_version: out.network_version,
+        base_fee: out.base_fee,
+        circ_supply: out.circ_supply,
+        chain_id: out.chain_id.into(),
+        power_scale: out.power_scale,
+        app_version: 0,
+        consensus_params: None,
+    };
+
+    (state_params, store)
+}
@@ -0,0 +1,330 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Package snapshots into a single file that can be moved around outside state sync,
+//! e.g. through object storage, and used to bootstrap a node.
+//!
+//! The archive is a TAR file with an index of the manifests it contains, followed by
+//! the snapshot directories: a full snapshot and all the deltas building on it, if any.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    manifest::{self, write_manifest, SnapshotManifest},
+    SnapshotItem, MANIFEST_FILE_NAME, PARTS_DIR_NAME,
+};
+
+/// The file name of the index at the beginning of an archive.
+const ARCHIVE_INDEX_FILE_NAME: &str = "archive.json";
+
+/// Index of the snapshots in an archive, in the order they have to be imported.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SnapshotArchive {
+    pub snapshots: Vec<SnapshotManifest>,
+}
+
+impl SnapshotArchive {
+    /// The snapshot the archive restores the state to.
+    pub fn latest(&self) -> Option<&SnapshotManifest> {
+        self.snapshots.last()
+    }
+}
+
+/// Name of the directory of a snapshot within the archive.
+fn snapshot_dir_name(manifest: &SnapshotManifest) -> String {
+    format!("snapshot-{}", manifest.block_height)
+}
+
+/// Write a chain of snapshots into an archive file.
+pub fn write_archive(
+    chain: &[SnapshotItem],
+    archive_path: impl AsRef<Path>,
+) -> anyhow::Result<SnapshotArchive> {
+    check_chain(chain.iter().map(|s| &s.manifest))?;
+
+    for item in chain {
+        verify_snapshot(item)?;
+    }
+
+    let archive = SnapshotArchive {
+        snapshots: chain.iter().map(|s| s.manifest.clone()).collect(),
+    };
+
+    let index = serde_json::to_vec_pretty(&archive).context("failed to convert index to JSON")?;
+
+    let file = fs::File::create(archive_path.as_ref()).context("failed to create archive")?;
+    let mut builder = tar::Builder::new(file);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(index.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, ARCHIVE_INDEX_FILE_NAME, index.as_slice())
+        .context("failed to add index to archive")?;
+
+    for item in chain {
+        let dir_name = snapshot_dir_name(&item.manifest);
+        builder
+            .append_path_with_name(
+                item.snapshot_dir.join(MANIFEST_FILE_NAME),
+                Path::new(&dir_name).join(MANIFEST_FILE_NAME),
+            )
+            .context("failed to add manifest to archive")?;
+
+        for part in manifest::list_parts(item.snapshot_dir.join(PARTS_DIR_NAME))? {
+            let part_name = part
+                .file_name()
+                .ok_or_else(|| anyhow!("part without a file name"))?;
+
+            builder
+                .append_path_with_name(
+                    &part,
+                    Path::new(&dir_name).join(PARTS_DIR_NAME).join(part_name),
+                )
+                .with_context(|| format!("failed to add {} to archive", part.to_string_lossy()))?;
+        }
+    }
+
+    builder
+        .into_inner()
+        .context("failed to finish archive")?
+        .sync_all()
+        .context("failed to flush archive")?;
+
+    Ok(archive)
+}
+
+/// Unpack an archive into a directory and verify the contents.
+///
+/// Returns the snapshots in the order they have to be imported.
+pub fn read_archive(
+    archive_path: impl AsRef<Path>,
+    target_dir: impl AsRef<Path>,
+) -> anyhow::Result<Vec<SnapshotItem>> {
+    let target_dir = target_dir.as_ref();
+    let file = fs::File::open(archive_path.as_ref()).context("failed to open archive")?;
+
+    // Unpacking ignores entries which would end up outside the target directory.
+    tar::Archive::new(file)
+        .unpack(target_dir)
+        .context("failed to unpack archive")?;
+
+    let index = fs::read(target_dir.join(ARCHIVE_INDEX_FILE_NAME))
+        .context("failed to read archive index")?;
+
+    let archive: SnapshotArchive =
+        serde_json::from_slice(&index).context("failed to parse archive index")?;
+
+    check_chain(archive.snapshots.iter())?;
+
+    let mut items = Vec::new();
+    for manifest in archive.snapshots {
+        let snapshot_dir: PathBuf = target_dir.join(snapshot_dir_name(&manifest));
+
+        let json = fs::read_to_string(snapshot_dir.join(MANIFEST_FILE_NAME))
+            .context("failed to read manifest")?;
+
+        let dir_manifest: SnapshotManifest =
+            serde_json::from_str(&json).context("failed to parse manifest")?;
+
+        if dir_manifest != manifest {
+            bail!(
+                "the manifest of the snapshot at height {} does not match the index",
+                manifest.block_height
+            );
+        }
+
+        let item = SnapshotItem::new(snapshot_dir, manifest);
+        verify_snapshot(&item)?;
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// Check that all the parts of a snapshot are present and match the checksum in the manifest.
+pub fn verify_snapshot(item: &SnapshotItem) -> anyhow::Result<()> {
+    let parts_dir = item.snapshot_dir.join(PARTS_DIR_NAME);
+    let parts = manifest::list_parts(&parts_dir)?;
+    let height = item.manifest.block_height;
+
+    if parts.len() != item.manifest.chunks as usize {
+        bail!(
+            "snapshot at height {height} should have {} parts, found {}",
+            item.manifest.chunks,
+            parts.len()
+        );
+    }
+
+    let checksum =
+        manifest::parts_checksum(&parts_dir, item.manifest.compression, item.manifest.size)?;
+
+    if checksum != item.manifest.checksum {
+        bail!(
+            "wrong checksum for snapshot at height {height}; expected {}, got {checksum}",
+            item.manifest.checksum
+        );
+    }
+
+    Ok(())
+}
+
+/// Copy the snapshots into a directory where the snapshot manager can find them.
+///
+/// Snapshots which already exist in the directory are left alone.
+pub fn install_snapshots(
+    items: &[SnapshotItem],
+    snapshots_dir: impl AsRef<Path>,
+) -> anyhow::Result<Vec<SnapshotItem>> {
+    let mut installed = Vec::new();
+    for item in items {
+        let snapshot_dir = snapshots_dir
+            .as_ref()
+            .join(snapshot_dir_name(&item.manifest));
+
+        if !snapshot_dir.exists() {
+            let parts_dir = snapshot_dir.join(PARTS_DIR_NAME);
+            fs::create_dir_all(&parts_dir).context("failed to create snapshot directory")?;
+            for part in manifest::list_parts(item.snapshot_dir.join(PARTS_DIR_NAME))? {
+                if let Some(name) = part.file_name() {
+                    fs::copy(&part, parts_dir.join(name)).context("failed to copy part")?;
+                }
+            }
+            write_manifest(&snapshot_dir, &item.manifest)?;
+        }
+
+        installed.push(SnapshotItem::new(snapshot_dir, item.manifest.clone()));
+    }
+    Ok(installed)
+}
+
+/// Check that the manifests start with a full snapshot, followed by deltas building on each other.
+fn check_chain<'a>(
+    mut manifests: impl Iterator<Item = &'a SnapshotManifest>,
+) -> anyhow::Result<()> {
+    let Some(mut prev) = manifests.next() else {
+        bail!("there are no snapshots in the archive");
+    };
+    if prev.is_delta() {
+        bail!(
+            "the first snapshot in the archive has to be a full one; height {} is a delta",
+            prev.block_height
+        );
+    }
+    for next in manifests {
+        if !prev.is_base_of(next) {
+            bail!(
+                "the snapshot at height {} does not build on the one at height {}",
+                next.block_height,
+                prev.block_height
+            );
+        }
+        prev = next;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use fs_err as fs;
+    use quickcheck::Arbitrary;
+
+    use super::{read_archive, write_archive};
+    use crate::{
+        manifest::{self, write_manifest, SnapshotBase},
+        ChunkCompression, SnapshotItem, SnapshotManifest, PARTS_DIR_NAME,
+    };
+
+    /// Create a snapshot directory with some random parts and a matching manifest.
+    fn make_snapshot(
+        dir: &std::path::Path,
+        height: u64,
+        base: Option<&SnapshotManifest>,
+    ) -> SnapshotItem {
+        let mut g = quickcheck::Gen::new(10);
+        let snapshot_dir = dir.join(format!("snapshot-{height}"));
+        let parts_dir = snapshot_dir.join(PARTS_DIR_NAME);
+        fs::create_dir_all(&parts_dir).unwrap();
+
+        let mut size = 0;
+        for i in 0..3 {
+            let mut file = fs::File::create(parts_dir.join(format!("{i}.part"))).unwrap();
+            let bytes = Vec::<u8>::arbitrary(&mut g);
+            file.write_all(&bytes).unwrap();
+            size += bytes.len() as u64;
+        }
+
+        let mut manifest = SnapshotManifest::arbitrary(&mut g);
+        manifest.block_height = height;
+        manifest.chunks = 3;
+        manifest.size = size;
+        manifest.compression = ChunkCompression::None;
+        manifest.checksum =
+            manifest::parts_checksum(&parts_dir, manifest.compression, manifest.size).unwrap();
+        manifest.base = base.map(SnapshotBase::from);
+
+        write_manifest(&snapshot_dir, &manifest).unwrap();
+
+        SnapshotItem::new(snapshot_dir, manifest)
+    }
+
+    #[test]
+    fn archive_roundtrip() {
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let full = make_snapshot(snapshots_dir.path(), 10, None);
+        let delta = make_snapshot(snapshots_dir.path(), 20, Some(&full.manifest));
+
+        let archive_path = snapshots_dir.path().join("archive.tar");
+        let archive = write_archive(&[full.clone(), delta.clone()], &archive_path).unwrap();
+        assert_eq!(archive.latest(), Some(&delta.manifest));
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let items = read_archive(&archive_path, target_dir.path()).unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].manifest, full.manifest);
+        assert_eq!(items[1].manifest, delta.manifest);
+    }
+
+    #[test]
+    fn archive_rejects_broken_chain() {
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let full = make_snapshot(snapshots_dir.path(), 10, None);
+        let other = make_snapshot(snapshots_dir.path(), 15, None);
+        let delta = make_snapshot(snapshots_dir.path(), 20, Some(&other.manifest));
+
+        let archive_path = snapshots_dir.path().join("archive.tar");
+        assert!(write_archive(&[delta.clone()], &archive_path).is_err());
+        assert!(write_archive(&[full, delta], &archive_path).is_err());
+    }
+
+    #[test]
+    fn archive_rejects_corrupted_parts() {
+        let snapshots_dir = tempfile::tempdir().unwrap();
+        let full = make_snapshot(snapshots_dir.path(), 10, None);
+
+        let archive_path = snapshots_dir.path().join("archive.tar");
+        write_archive(&[full.clone()], &archive_path).unwrap();
+
+        let target_dir = tempfile::tempdir().unwrap();
+        read_archive(&archive_path, target_dir.path()).unwrap();
+
+        // Tamper with a part after unpacking, then check it again.
+        let part = target_dir
+            .path()
+            .join("snapshot-10")
+            .join(PARTS_DIR_NAME)
+            .join("0.part");
+        fs::write(&part, b"tampered").unwrap();
+
+        let item = SnapshotItem::new(target_dir.path().join("snapshot-10"), full.manifest);
+        assert!(super::verify_snapshot(&item).is_err());
+    }
+}
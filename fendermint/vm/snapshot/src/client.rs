@@ -3,16 +3,16 @@
 
 use std::{path::PathBuf, sync::Arc, time::SystemTime};
 
-use async_stm::{abort, Stm, StmResult, TVar};
-use fendermint_vm_interpreter::fvm::state::{
-    snapshot::{BlockHeight, SnapshotVersion},
-    FvmStateParams,
-};
+use async_stm::{abort, atomically, retry, Stm, StmResult, TVar};
+use cid::Cid;
+use fendermint_vm_interpreter::fvm::state::{snapshot::BlockHeight, FvmStateParams};
 use fs_err as fs;
+use tendermint::hash::AppHash;
 
 use crate::{
     manifest,
     state::{SnapshotDownload, SnapshotState},
+    verifier::AppHashCheck,
     SnapshotError, SnapshotItem, SnapshotManifest, MANIFEST_FILE_NAME,
 };
 
@@ -23,6 +23,8 @@ pub struct SnapshotClient {
     /// The client will only notify the manager of snapshottable heights.
     snapshot_interval: BlockHeight,
     state: SnapshotState,
+    /// Light client to check offered snapshots against the network, if configured.
+    verifier: Option<Arc<dyn AppHashCheck>>,
 }
 
 impl SnapshotClient {
@@ -35,8 +37,55 @@ impl SnapshotClient {
             download_dir,
             snapshot_interval,
             state,
+            verifier: None,
         }
     }
+
+    /// Verify the application hash of offered snapshots with a light client before accepting them.
+    pub fn with_verifier(mut self, verifier: impl AppHashCheck + 'static) -> Self {
+        self.verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Check the application hash of the current download against the network in the background,
+    /// so that the chunks can be downloaded in the meantime.
+    ///
+    /// Without a verifier the download is considered verified as soon as it's offered.
+    pub async fn verify_download(&self, app_hash: AppHash) {
+        let Some(verifier) = self.verifier.clone() else {
+            return;
+        };
+        let Some(download) = atomically(|| self.state.current_download.read_clone()).await else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            let block_height = download.manifest.block_height;
+            let result = verifier
+                .verify_app_hash(block_height, &app_hash)
+                .await
+                .map_err(|e| format!("{e:#}"));
+
+            if let Err(ref e) = result {
+                tracing::warn!(height = block_height, "snapshot failed verification: {e}");
+            }
+
+            atomically(|| download.verified.write(Some(result.clone()))).await;
+        });
+    }
+
+    /// Wait until the current download has been checked against the network.
+    pub fn download_verified(&self) -> StmResult<(), SnapshotError> {
+        match self.state.current_download.read()?.as_ref() {
+            None => abort(SnapshotError::NoDownload),
+            Some(cd) => match cd.verified.read()?.as_ref() {
+                None => retry()?,
+                Some(Ok(())) => Ok(()),
+                Some(Err(e)) => abort(SnapshotError::FailedVerification(e.clone())),
+            },
+        }
+    }
+
     /// Set the latest block state parameters and notify the manager.
     ///
     /// Call this with the block height where the `app_hash` in the block reflects the
@@ -55,18 +104,43 @@ impl SnapshotClient {
         self.state.snapshots.read_clone()
     }
 
+    /// State roots of the completed snapshots, the one being exported and the one which might be exported next.
+    ///
+    /// Deltas are computed against the blocks reachable from their base, which have to be kept around.
+    pub fn state_roots(&self) -> Stm<Vec<Cid>> {
+        let mut roots = self
+            .state
+            .snapshots
+            .read()?
+            .iter()
+            .map(|s| s.manifest.state_params.state_root)
+            .collect::<Vec<_>>();
+
+        if let Some((params, _)) = self.state.exporting.read()?.as_ref() {
+            roots.push(params.state_root);
+        }
+
+        if let Some((params, _)) = self.state.latest_params.read()?.as_ref() {
+            roots.push(params.state_root);
+        }
+
+        Ok(roots)
+    }
+
     /// Try to find a snapshot, if it still exists.
     ///
     /// If found, mark it as accessed, so that it doesn't get purged while likely to be requested or read from disk.
+    ///
+    /// The format is what was advertised over state sync; see [SnapshotManifest::format].
     pub fn access_snapshot(
         &self,
         block_height: BlockHeight,
-        version: SnapshotVersion,
+        format: u32,
     ) -> Stm<Option<SnapshotItem>> {
         let mut snapshots = self.state.snapshots.read_clone()?;
         let mut snapshot = None;
         for s in snapshots.iter_mut() {
-            if s.manifest.block_height == block_height && s.manifest.version == version {
+            if s.manifest.block_height == block_height && s.manifest.format() == format {
                 s.last_access = SystemTime::now();
                 snapshot = Some(s.clone());
                 break;
@@ -100,6 +174,7 @@ impl SnapshotClient {
                         manifest,
                         download_dir: Arc::new(dir),
                         next_index: TVar::new(0),
+                        verified: TVar::new(self.verifier.is_none().then_some(Ok(()))),
                     };
 
                     // Create a `parts` sub-directory for the chunks.
@@ -145,8 +220,12 @@ impl SnapshotClient {
                         cd.next_index.write(next_index)?;
 
                         if next_index == cd.manifest.chunks {
-                            // Verify the checksum then load the snapshot and remove the current download from memory.
-                            match manifest::parts_checksum(cd.parts_dir()) {
+                            // Verify the checksum of the decompressed contents, then load the snapshot and remove the current download from memory.
+                            match manifest::parts_checksum(
+                                cd.parts_dir(),
+                                cd.manifest.compression,
+                                cd.manifest.size,
+                            ) {
                                 Ok(checksum) => {
                                     if checksum == cd.manifest.checksum {
                                         let item = SnapshotItem::new(
@@ -0,0 +1,145 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use fendermint_vm_interpreter::fvm::state::snapshot::SnapshotVersion;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+/// Bit set in the snapshot format advertised over state sync when the chunks are compressed.
+///
+/// Nodes which don't know about compression will see it as an unknown version and reject it.
+const ZSTD_FORMAT_FLAG: u32 = 1 << 16;
+
+/// Compression applied to the individual chunk files of a snapshot.
+///
+/// The checksum in the manifest is always over the uncompressed contents.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkCompression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl ChunkCompression {
+    pub fn is_none(&self) -> bool {
+        *self == ChunkCompression::None
+    }
+
+    /// Combine the snapshot version with the compression into the format used by state sync.
+    pub fn to_format(&self, version: SnapshotVersion) -> u32 {
+        match self {
+            ChunkCompression::None => version,
+            ChunkCompression::Zstd => version | ZSTD_FORMAT_FLAG,
+        }
+    }
+
+    /// Split a state sync format into the snapshot version and the compression.
+    pub fn from_format(format: u32) -> (SnapshotVersion, Self) {
+        if format & ZSTD_FORMAT_FLAG != 0 {
+            (format & !ZSTD_FORMAT_FLAG, ChunkCompression::Zstd)
+        } else {
+            (format, ChunkCompression::None)
+        }
+    }
+
+    /// Wrap a reader of a chunk so that it returns the uncompressed contents.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        match self {
+            ChunkCompression::None => Ok(Box::new(reader)),
+            ChunkCompression::Zstd => Ok(Box::new(zstd::stream::Decoder::new(reader)?)),
+        }
+    }
+
+    /// Copy the uncompressed contents of a chunk into a writer.
+    ///
+    /// Fails if the contents are larger than `limit`, so that a small chunk sent by
+    /// a peer can't be inflated into an arbitrary amount of data.
+    pub fn decode_into<R: Read, W: Write>(
+        &self,
+        reader: R,
+        writer: &mut W,
+        limit: u64,
+    ) -> io::Result<u64> {
+        let decoder = self.decoder(reader)?;
+        let size = io::copy(&mut decoder.take(limit.saturating_add(1)), writer)?;
+        if size > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decoded chunk is larger than the expected {limit} bytes"),
+            ));
+        }
+        Ok(size)
+    }
+}
+
+/// Compress a chunk file in place with zstd.
+pub fn compress_file(path: &Path, level: i32) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("zst");
+    {
+        let input = fs::File::open(path).context("failed to open chunk")?;
+        let output = fs::File::create(&tmp_path).context("failed to create compressed chunk")?;
+        zstd::stream::copy_encode(input, output, level).context("failed to compress chunk")?;
+    }
+    fs::rename(&tmp_path, path).context("failed to replace chunk")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use fs_err as fs;
+    use tempfile::NamedTempFile;
+
+    use super::{compress_file, ChunkCompression};
+
+    #[test]
+    fn format_roundtrip() {
+        for compression in [ChunkCompression::None, ChunkCompression::Zstd] {
+            let format = compression.to_format(1);
+            assert_eq!(ChunkCompression::from_format(format), (1, compression));
+        }
+        // Old nodes only accept version 1.
+        assert_ne!(ChunkCompression::Zstd.to_format(1), 1);
+    }
+
+    #[test]
+    fn compress_decompress_file() {
+        let content = b"Hello Compression! ".repeat(100);
+
+        let mut file = NamedTempFile::new().expect("new temp file");
+        file.write_all(&content).expect("write contents");
+        let path = file.into_temp_path();
+
+        compress_file(&path, 3).expect("compress");
+        assert!(fs::metadata(&path).unwrap().len() < content.len() as u64);
+
+        let mut decoded = Vec::new();
+        ChunkCompression::Zstd
+            .decode_into(
+                fs::File::open(&path).unwrap(),
+                &mut decoded,
+                content.len() as u64,
+            )
+            .expect("decompress");
+
+        assert_eq!(decoded, content);
+
+        // Anything beyond the expected size is rejected.
+        let mut decoded = Vec::new();
+        let err = ChunkCompression::Zstd
+            .decode_into(
+                fs::File::open(&path).unwrap(),
+                &mut decoded,
+                content.len() as u64 - 1,
+            )
+            .expect_err("should not decompress more than the limit");
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -16,4 +16,6 @@ pub enum SnapshotError {
     UnexpectedChunk(u32, u32),
     #[error("wrong checksum; expected {0}, got {1}")]
     WrongChecksum(tendermint::Hash, tendermint::Hash),
+    #[error("the snapshot failed verification: {0}")]
+    FailedVerification(String),
 }
@@ -1,11 +1,14 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: Apache-2.0, MIT
+mod archive;
 mod car;
 mod client;
+mod compression;
 mod error;
 mod manager;
 mod manifest;
 mod state;
+mod verifier;
 
 /// The file name to export the CAR to.
 const SNAPSHOT_FILE_NAME: &str = "snapshot.car";
@@ -16,8 +19,13 @@ const MANIFEST_FILE_NAME: &str = "manifest.json";
 /// Name of the subdirectory where `{idx}.part` files are stored within a snapshot.
 const PARTS_DIR_NAME: &str = "parts";
 
+pub use archive::{
+    install_snapshots, read_archive, verify_snapshot, write_archive, SnapshotArchive,
+};
 pub use client::SnapshotClient;
+pub use compression::ChunkCompression;
 pub use error::SnapshotError;
 pub use manager::{SnapshotManager, SnapshotParams};
-pub use manifest::SnapshotManifest;
-pub use state::SnapshotItem;
+pub use manifest::{list_manifests, SnapshotBase, SnapshotManifest};
+pub use state::{import_chain, snapshot_chain, SnapshotItem, SnapshotState};
+pub use verifier::{AppHashCheck, AppHashVerifier, LightBlockProvider, TrustOptions};
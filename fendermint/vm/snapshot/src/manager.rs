@@ -4,8 +4,11 @@
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::manifest::{file_checksum, list_manifests, write_manifest, SnapshotManifest};
-use crate::state::SnapshotState;
+use crate::compression::{self, ChunkCompression};
+use crate::manifest::{
+    self, file_checksum, list_manifests, write_manifest, SnapshotBase, SnapshotManifest,
+};
+use crate::state::{snapshot_chain, SnapshotState};
 use crate::{car, SnapshotClient, SnapshotItem, PARTS_DIR_NAME, SNAPSHOT_FILE_NAME};
 use anyhow::Context;
 use async_stm::{atomically, retry, TVar};
@@ -32,6 +35,13 @@ pub struct SnapshotParams {
     pub last_access_hold: Duration,
     /// How often to check CometBFT whether it has finished syncing.
     pub sync_poll_interval: Duration,
+    /// Maximum number of delta snapshots to export on top of a full one,
+    /// before exporting the full state again.
+    ///
+    /// 0 means every snapshot is a full one.
+    pub max_deltas: usize,
+    /// Level of zstd compression to apply to the chunks, if any.
+    pub compression_level: Option<i32>,
 }
 
 /// Create snapshots at regular block intervals.
@@ -42,6 +52,8 @@ pub struct SnapshotManager<BS> {
     hist_size: usize,
     last_access_hold: Duration,
     sync_poll_interval: Duration,
+    max_deltas: usize,
+    compression_level: Option<i32>,
     /// Shared state of snapshots.
     state: SnapshotState,
     /// Indicate whether CometBFT has finished syncing with the chain,
@@ -71,6 +83,8 @@ where
             hist_size: params.hist_size,
             last_access_hold: params.last_access_hold,
             sync_poll_interval: params.sync_poll_interval,
+            max_deltas: params.max_deltas,
+            compression_level: params.compression_level,
             state: state.clone(),
             // Assume we are syncing until we can determine otherwise.
             is_syncing: TVar::new(true),
@@ -111,16 +125,24 @@ where
                     retry()?;
                 }
 
-                match self.state.latest_params.read()?.as_ref() {
+                let new_params = match self.state.latest_params.read()?.as_ref() {
                     None => retry()?,
                     unchanged if *unchanged == last_params => retry()?,
-                    Some(new_params) => Ok(new_params.clone()),
-                }
+                    Some(new_params) => new_params.clone(),
+                };
+
+                // Keep the state being exported out of the reach of the garbage collection,
+                // even if newer parameters replace the latest ones in the meantime.
+                self.state.exporting.write(Some(new_params.clone()))?;
+
+                Ok(new_params)
             })
             .await;
 
+            let base = self.delta_base().await;
+
             match self
-                .create_snapshot(block_height, state_params.clone())
+                .create_snapshot(block_height, state_params.clone(), base)
                 .await
             {
                 Ok(item) => {
@@ -129,10 +151,12 @@ where
                         block_height,
                         chunks_count = item.manifest.chunks,
                         snapshot_size = item.manifest.size,
+                        is_delta = item.manifest.is_delta(),
                         "exported snapshot"
                     );
                     // Add the snapshot to the in-memory records.
                     atomically(|| {
+                        self.state.exporting.write(None)?;
                         self.state
                             .snapshots
                             .modify_mut(|items| items.push_back(item.clone()))
@@ -140,6 +164,7 @@ where
                     .await;
                 }
                 Err(e) => {
+                    atomically(|| self.state.exporting.write(None)).await;
                     tracing::warn!(error =? e, block_height, "failed to create snapshot");
                 }
             }
@@ -151,7 +176,31 @@ where
         }
     }
 
+    /// Find the latest snapshot the next one can be a delta to, if the chain it belongs to
+    /// is shorter than the maximum allowed number of deltas.
+    async fn delta_base(&self) -> Option<SnapshotItem> {
+        if self.max_deltas == 0 {
+            return None;
+        }
+
+        let snapshots = atomically(|| self.state.snapshots.read_clone()).await;
+        let latest = snapshots.last()?;
+
+        match snapshot_chain(&snapshots, latest) {
+            // The chain includes the full snapshot as well.
+            Ok(chain) if chain.len() <= self.max_deltas => Some(latest.clone()),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!(error =? e, "broken snapshot chain; exporting full snapshot");
+                None
+            }
+        }
+    }
+
     /// Remove snapshot directories if we have more than the desired history size.
+    ///
+    /// A full snapshot is only removed together with all the deltas building on it,
+    /// and only if that still leaves at least the desired number of snapshots.
     async fn prune_history(&self) {
         if self.hist_size == 0 {
             return;
@@ -161,19 +210,31 @@ where
             self.state.snapshots.modify_mut(|snapshots| {
                 let mut removables = Vec::new();
                 while snapshots.len() > self.hist_size {
-                    // Stop at the first snapshot that was accessed recently.
-                    if let Some(last_access) =
-                        snapshots.head().and_then(|s| s.last_access.elapsed().ok())
-                    {
-                        if last_access <= self.last_access_hold {
-                            break;
-                        }
+                    // The oldest snapshot is either full, or its base has been removed.
+                    // Either way everything after it up to the next full snapshot goes with it.
+                    let chain_len = snapshots
+                        .iter()
+                        .skip(1)
+                        .position(|s| !s.manifest.is_delta())
+                        .map(|i| i + 1)
+                        .unwrap_or(snapshots.len());
+
+                    if snapshots.len() - chain_len < self.hist_size {
+                        break;
                     }
-                    if let Some(snapshot) = snapshots.pop_front() {
-                        removables.push(snapshot);
-                    } else {
+                    // Stop at the first chain that was accessed recently.
+                    if snapshots.iter().take(chain_len).any(|s| {
+                        s.last_access
+                            .elapsed()
+                            .map_or(false, |e| e <= self.last_access_hold)
+                    }) {
                         break;
                     }
+                    for _ in 0..chain_len {
+                        if let Some(snapshot) = snapshots.pop_front() {
+                            removables.push(snapshot);
+                        }
+                    }
                 }
                 removables
             })
@@ -191,13 +252,24 @@ where
     }
 
     /// Export a snapshot to a temporary file, then copy it to the snapshot directory.
+    ///
+    /// If a base is given, only the blocks which aren't reachable from its state root are exported.
     async fn create_snapshot(
         &self,
         block_height: BlockHeight,
         state_params: FvmStateParams,
+        base: Option<SnapshotItem>,
     ) -> anyhow::Result<SnapshotItem> {
-        let snapshot = Snapshot::new(self.store.clone(), state_params.clone(), block_height)
-            .context("failed to create snapshot")?;
+        let snapshot = match base {
+            Some(ref base) => Snapshot::new_delta(
+                self.store.clone(),
+                state_params.clone(),
+                block_height,
+                base.manifest.state_params.state_root,
+            ),
+            None => Snapshot::new(self.store.clone(), state_params.clone(), block_height),
+        }
+        .context("failed to create snapshot")?;
 
         let snapshot_version = snapshot.version();
         let snapshot_name = format!("snapshot-{block_height}");
@@ -247,6 +319,18 @@ where
         .await
         .context("failed to split CAR into chunks")?;
 
+        // Compress the chunks individually, so they can be served as they are.
+        // The checksum stays the one over the uncompressed CAR file.
+        let chunk_compression = match self.compression_level {
+            Some(level) => {
+                for part in manifest::list_parts(&parts_path).context("failed to list parts")? {
+                    compression::compress_file(&part, level)?;
+                }
+                ChunkCompression::Zstd
+            }
+            None => ChunkCompression::None,
+        };
+
         // Create and export a manifest that we can easily look up.
         let manifest = SnapshotManifest {
             block_height,
@@ -255,6 +339,8 @@ where
             checksum: checksum_bytes,
             state_params,
             version: snapshot_version,
+            base: base.map(|b| SnapshotBase::from(&b.manifest)),
+            compression: chunk_compression,
         };
         let _ = write_manifest(temp_dir.path(), &manifest).context("failed to export manifest")?;
 
@@ -314,7 +400,7 @@ mod tests {
     use super::fs;
     use std::time::Duration;
 
-    use async_stm::{atomically, retry};
+    use async_stm::{atomically, atomically_or_err, retry};
     use fendermint_vm_genesis::Genesis;
     use fendermint_vm_interpreter::fvm::{
         bundle::{bundle_path, contracts_path, custom_actors_bundle_path},
@@ -324,7 +410,10 @@ mod tests {
     use fendermint_vm_interpreter::genesis::create_test_genesis_state;
     use quickcheck::Arbitrary;
 
-    use crate::{manager::SnapshotParams, manifest, PARTS_DIR_NAME};
+    use crate::{
+        manager::SnapshotParams, manifest, state::SnapshotState, ChunkCompression, SnapshotClient,
+        PARTS_DIR_NAME,
+    };
 
     use super::SnapshotManager;
 
@@ -364,6 +453,8 @@ mod tests {
                 hist_size: 1,
                 last_access_hold: Duration::ZERO,
                 sync_poll_interval: never_poll_sync,
+                max_deltas: 0,
+                compression_level: None,
             },
         )
         .expect("failed to create snapshot manager");
@@ -412,9 +503,12 @@ mod tests {
         assert_eq!(snapshots.len(), 1, "can list manifests");
         assert_eq!(snapshots[0], snapshot);
 
-        let checksum =
-            manifest::parts_checksum(snapshot.snapshot_dir.as_path().join(PARTS_DIR_NAME))
-                .expect("parts checksum can be calculated");
+        let checksum = manifest::parts_checksum(
+            snapshot.snapshot_dir.as_path().join(PARTS_DIR_NAME),
+            snapshot.manifest.compression,
+            snapshot.manifest.size,
+        )
+        .expect("parts checksum can be calculated");
 
         assert_eq!(
             checksum, snapshot.manifest.checksum,
@@ -432,6 +526,8 @@ mod tests {
                 hist_size: 1,
                 last_access_hold: Duration::ZERO,
                 sync_poll_interval: never_poll_sync,
+                max_deltas: 0,
+                compression_level: None,
             },
         )
         .expect("failed to create snapshot manager");
@@ -440,6 +536,169 @@ mod tests {
         assert!(!snapshots.is_empty(), "loads manifests on start");
     }
 
+    // Export a full snapshot followed by a delta, with compression, then import the chain into an empty store.
+    #[tokio::test]
+    async fn create_delta_snapshot_with_manager() {
+        let (state_params, store) = init_genesis().await;
+
+        let snapshots_dir = tempfile::tempdir().expect("failed to create tmp dir");
+        let download_dir = tempfile::tempdir().expect("failed to create tmp dir");
+
+        let (snapshot_manager, snapshot_client) = SnapshotManager::new(
+            store.clone(),
+            SnapshotParams {
+                snapshots_dir: snapshots_dir.path().into(),
+                download_dir: download_dir.path().into(),
+                block_interval: 1,
+                chunk_size: 10000,
+                hist_size: 1,
+                last_access_hold: Duration::ZERO,
+                sync_poll_interval: Duration::ZERO,
+                max_deltas: 1,
+                compression_level: Some(3),
+            },
+        )
+        .expect("failed to create snapshot manager");
+
+        tokio::spawn(async move { snapshot_manager.run(mock_client()).await });
+
+        let wait_for_snapshots = |count: usize| {
+            let snapshot_client = snapshot_client.clone();
+            async move {
+                tokio::time::timeout(
+                    Duration::from_secs(10),
+                    atomically(|| {
+                        let snapshots = snapshot_client.list_snapshots()?;
+                        if snapshots.len() < count {
+                            retry()
+                        } else {
+                            Ok(snapshots)
+                        }
+                    }),
+                )
+                .await
+                .expect("failed to export snapshot")
+            }
+        };
+
+        atomically(|| snapshot_client.notify(0, state_params.clone())).await;
+        wait_for_snapshots(1).await;
+
+        atomically(|| snapshot_client.notify(1, state_params.clone())).await;
+        let snapshots = wait_for_snapshots(2).await;
+
+        // The delta doesn't get pruned while its base is needed.
+        assert_eq!(snapshots.len(), 2);
+
+        let full = snapshots[0].clone();
+        let delta = snapshots[1].clone();
+        assert!(!full.manifest.is_delta());
+        assert_eq!(full.manifest.compression, ChunkCompression::Zstd);
+        assert!(full.manifest.is_base_of(&delta.manifest));
+        // Nothing changed, so the delta only has the metadata.
+        assert!(delta.manifest.size < full.manifest.size);
+
+        let state = SnapshotState::new(manifest::list_manifests(snapshots_dir.path()).unwrap());
+
+        // The delta on its own doesn't even have the state root.
+        assert!(delta.import(MemoryBlockstore::new(), true).await.is_err());
+
+        let imported = state
+            .import(&delta, MemoryBlockstore::new(), true)
+            .await
+            .expect("failed to import snapshot chain");
+
+        let Snapshot::V1(imported) = imported;
+        assert_eq!(imported.block_height(), 1);
+        assert_eq!(*imported.state_params(), state_params);
+    }
+
+    // Export a compressed snapshot, then download it chunk by chunk the way state sync would, and import it.
+    #[tokio::test]
+    async fn save_compressed_chunks() {
+        let (state_params, store) = init_genesis().await;
+
+        let snapshots_dir = tempfile::tempdir().expect("failed to create tmp dir");
+        let download_dir = tempfile::tempdir().expect("failed to create tmp dir");
+
+        let (snapshot_manager, snapshot_client) = SnapshotManager::new(
+            store,
+            SnapshotParams {
+                snapshots_dir: snapshots_dir.path().into(),
+                download_dir: download_dir.path().into(),
+                block_interval: 1,
+                chunk_size: 10000,
+                hist_size: 1,
+                last_access_hold: Duration::ZERO,
+                sync_poll_interval: Duration::ZERO,
+                max_deltas: 0,
+                compression_level: Some(3),
+            },
+        )
+        .expect("failed to create snapshot manager");
+
+        tokio::spawn(async move { snapshot_manager.run(mock_client()).await });
+
+        atomically(|| snapshot_client.notify(0, state_params.clone())).await;
+
+        let snapshot = tokio::time::timeout(
+            Duration::from_secs(10),
+            atomically(|| match snapshot_client.list_snapshots()?.pop_front() {
+                Some(snapshot) => Ok(snapshot),
+                None => retry(),
+            }),
+        )
+        .await
+        .expect("failed to export snapshot");
+
+        assert_eq!(snapshot.manifest.compression, ChunkCompression::Zstd);
+        assert!(snapshot.manifest.chunks > 1);
+
+        // A separate client with nothing in it, receiving the chunks from the exporter.
+        let receive_dir = tempfile::tempdir().expect("failed to create tmp dir");
+        let receiver =
+            SnapshotClient::new(receive_dir.path().into(), 1, SnapshotState::new(vec![]));
+
+        let save_chunks = |manifest: manifest::SnapshotManifest| {
+            let receiver = receiver.clone();
+            let snapshot = snapshot.clone();
+            async move {
+                atomically_or_err(|| receiver.offer_snapshot(manifest.clone()))
+                    .await
+                    .expect("failed to offer snapshot");
+
+                let mut received = None;
+                for index in 0..manifest.chunks {
+                    assert!(received.is_none(), "only done after the last chunk");
+                    let chunk = snapshot.load_chunk(index).expect("failed to load chunk");
+                    received =
+                        atomically_or_err(|| receiver.save_chunk(index, chunk.clone())).await?;
+                }
+                Ok::<_, crate::SnapshotError>(received)
+            }
+        };
+
+        let received = save_chunks(snapshot.manifest.clone())
+            .await
+            .expect("failed to save chunks")
+            .expect("all chunks received");
+
+        assert_eq!(received.manifest, snapshot.manifest);
+
+        let Snapshot::V1(imported) = received
+            .import(MemoryBlockstore::new(), true)
+            .await
+            .expect("failed to import snapshot");
+
+        assert_eq!(*imported.state_params(), state_params);
+
+        // Chunks that decompress to more than the manifest says are rejected.
+        let mut understated = snapshot.manifest.clone();
+        understated.size -= 1;
+
+        assert!(save_chunks(understated).await.is_err());
+    }
+
     async fn init_genesis() -> (FvmStateParams, MemoryBlockstore) {
         let mut g = quickcheck::Gen::new(5);
         let genesis = Genesis::arbitrary(&mut g);
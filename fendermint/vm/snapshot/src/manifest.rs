@@ -12,7 +12,7 @@ use fs_err as fs;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::{SnapshotItem, MANIFEST_FILE_NAME};
+use crate::{compression::ChunkCompression, SnapshotItem, MANIFEST_FILE_NAME};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct SnapshotManifest {
@@ -32,6 +32,50 @@ pub struct SnapshotManifest {
     pub state_params: FvmStateParams,
     /// Snapshot format version
     pub version: SnapshotVersion,
+    /// The snapshot this one is a delta to, if it's not a full snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<SnapshotBase>,
+    /// Compression applied to the chunks.
+    #[serde(default, skip_serializing_if = "ChunkCompression::is_none")]
+    pub compression: ChunkCompression,
+}
+
+impl SnapshotManifest {
+    /// The format of the snapshot as advertised over state sync,
+    /// which reflects both the version and the compression of the chunks.
+    pub fn format(&self) -> u32 {
+        self.compression.to_format(self.version)
+    }
+
+    /// Check whether this is a delta snapshot which can only be imported on top of its base.
+    pub fn is_delta(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// Check whether this snapshot is the one referred to by a delta.
+    pub fn is_base_of(&self, other: &SnapshotManifest) -> bool {
+        other.base.as_ref().map_or(false, |base| {
+            base.block_height == self.block_height && base.checksum == self.checksum
+        })
+    }
+}
+
+/// Reference to the snapshot a delta snapshot was taken relative to.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SnapshotBase {
+    /// Block height of the base snapshot.
+    pub block_height: BlockHeight,
+    /// Checksum of the base snapshot contents.
+    pub checksum: tendermint::Hash,
+}
+
+impl From<&SnapshotManifest> for SnapshotBase {
+    fn from(value: &SnapshotManifest) -> Self {
+        Self {
+            block_height: value.block_height,
+            checksum: value.checksum,
+        }
+    }
 }
 
 /// Save a manifest along with the other snapshot files into a snapshot specific directory.
@@ -110,15 +154,24 @@ pub fn file_checksum(path: impl AsRef<Path>) -> anyhow::Result<tendermint::Hash>
     Ok(tendermint::Hash::Sha256(hash))
 }
 
-/// Calculate the Sha256 checksum of all `{idx}.part` files in a directory.
-pub fn parts_checksum(path: impl AsRef<Path>) -> anyhow::Result<tendermint::Hash> {
+/// Calculate the Sha256 checksum of the uncompressed contents of all `{idx}.part` files in a directory.
+///
+/// Fails if the uncompressed contents are larger than the expected `size`.
+pub fn parts_checksum(
+    path: impl AsRef<Path>,
+    compression: ChunkCompression,
+    size: u64,
+) -> anyhow::Result<tendermint::Hash> {
     let mut hasher = Sha256::new();
+    let mut remaining = size;
 
     let chunks = list_parts(path)?;
 
     for path in chunks {
-        let mut file = fs::File::open(path).context("failed to open part")?;
-        let _ = std::io::copy(&mut file, &mut hasher)?;
+        let file = fs::File::open(path).context("failed to open part")?;
+        remaining -= compression
+            .decode_into(file, &mut hasher, remaining)
+            .context("failed to decode part")?;
     }
 
     let hash = hasher.finalize().into();
@@ -167,6 +220,7 @@ mod arb {
     use quickcheck::Arbitrary;
 
     use super::SnapshotManifest;
+    use crate::compression::ChunkCompression;
 
     impl quickcheck::Arbitrary for SnapshotManifest {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
@@ -194,7 +248,13 @@ mod arb {
                     app_version: 0,
                     consensus_params: None,
                 },
-                version: Arbitrary::arbitrary(g),
+                // Leave room for the flags that signal compression over state sync.
+                version: u32::arbitrary(g) & 0xFFFF,
+                // Deltas aren't offered over state sync.
+                base: None,
+                compression: *g
+                    .choose(&[ChunkCompression::None, ChunkCompression::Zstd])
+                    .unwrap(),
             }
         }
     }
@@ -1,10 +1,10 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use std::{fs::File, io, path::PathBuf, sync::Arc, time::SystemTime};
+use std::{fs::File, path::PathBuf, sync::Arc, time::SystemTime};
 
 use anyhow::{bail, Context};
-use async_stm::TVar;
+use async_stm::{atomically, TVar};
 use fendermint_vm_interpreter::fvm::state::snapshot::{BlockStateParams, Snapshot};
 use fs_err as fs;
 use fvm_ipld_blockstore::Blockstore;
@@ -25,6 +25,10 @@ pub struct SnapshotState {
     pub latest_params: TVar<Option<BlockStateParams>>,
     /// The latest snapshot offered, which CometBFT is downloading and feeding to us.
     pub current_download: TVar<Option<SnapshotDownload>>,
+    /// The state parameters of the snapshot being exported, if any.
+    ///
+    /// Unlike the latest parameters, these don't change until the export is finished.
+    pub exporting: TVar<Option<BlockStateParams>>,
 }
 
 impl SnapshotState {
@@ -35,8 +39,107 @@ impl SnapshotState {
             // We could also look back to find the latest height we should have snapshotted.
             latest_params: TVar::new(None),
             current_download: TVar::new(None),
+            exporting: TVar::new(None),
+        }
+    }
+
+    /// Import a snapshot into the blockstore.
+    ///
+    /// If it's a delta, then the full snapshot it builds on and all the deltas in between
+    /// are imported first, in the order they were created, which all have to be available.
+    pub async fn import<BS>(
+        &self,
+        item: &SnapshotItem,
+        store: BS,
+        validate: bool,
+    ) -> anyhow::Result<Snapshot<BS>>
+    where
+        BS: Blockstore + Send + Clone + 'static,
+    {
+        let snapshots = atomically(|| self.snapshots.read_clone()).await;
+        let chain = snapshot_chain(&snapshots, item)?;
+        import_chain(&chain, store, validate).await
+    }
+}
+
+/// Collect the chain of snapshots from the full snapshot up to the given item.
+pub fn snapshot_chain(
+    snapshots: &im::Vector<SnapshotItem>,
+    item: &SnapshotItem,
+) -> anyhow::Result<Vec<SnapshotItem>> {
+    let mut chain = vec![item.clone()];
+    let mut current = item;
+    while let Some(ref base) = current.manifest.base {
+        let Some(prev) = snapshots
+            .iter()
+            .find(|s| s.manifest.is_base_of(&current.manifest))
+        else {
+            bail!(
+                "base snapshot at height {} with checksum {} not found",
+                base.block_height,
+                base.checksum
+            );
+        };
+        chain.push(prev.clone());
+        current = prev;
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Import a full snapshot followed by deltas applied on top of each other.
+///
+/// Returns the last imported snapshot.
+pub async fn import_chain<BS>(
+    chain: &[SnapshotItem],
+    store: BS,
+    validate: bool,
+) -> anyhow::Result<Snapshot<BS>>
+where
+    BS: Blockstore + Send + Clone + 'static,
+{
+    let Some((full, deltas)) = chain.split_first() else {
+        bail!("empty snapshot chain");
+    };
+
+    if full.manifest.is_delta() {
+        bail!(
+            "snapshot chain has to start with a full snapshot; height {} is a delta",
+            full.manifest.block_height
+        );
+    }
+
+    let mut snapshot = full.import(store.clone(), validate).await?;
+
+    for (prev, delta) in chain.iter().zip(deltas) {
+        if !prev.manifest.is_base_of(&delta.manifest) {
+            bail!(
+                "delta snapshot at height {} does not build on the snapshot at height {}",
+                delta.manifest.block_height,
+                prev.manifest.block_height
+            );
+        }
+        snapshot = delta
+            .import(store.clone(), validate)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to import delta snapshot at height {}",
+                    delta.manifest.block_height
+                )
+            })?;
+    }
+
+    // Deltas can be individually valid but still leave gaps if the chain was wrong.
+    if validate && !deltas.is_empty() {
+        match snapshot {
+            Snapshot::V1(ref snapshot) => snapshot
+                .check_complete()
+                .context("imported snapshot chain is incomplete")?,
         }
     }
+
+    Ok(snapshot)
 }
 
 /// A snapshot directory and its manifest.
@@ -81,7 +184,9 @@ impl SnapshotItem {
         Ok(content)
     }
 
-    /// Import a snapshot into the blockstore.
+    /// Import the contents of this snapshot alone into the blockstore.
+    ///
+    /// A delta snapshot needs its base to be imported first; see [SnapshotState::import].
     pub async fn import<BS>(&self, store: BS, validate: bool) -> anyhow::Result<Snapshot<BS>>
     where
         BS: Blockstore + Send + Clone + 'static,
@@ -92,13 +197,20 @@ impl SnapshotItem {
         // 1. Restore the snapshots into a complete `snapshot.car` file.
         let car_path = self.snapshot_dir.join(SNAPSHOT_FILE_NAME);
         let mut car_file = File::create(&car_path).context("failed to create CAR file")?;
+        let mut remaining = self.manifest.size;
 
         for part in parts {
-            let mut part_file = File::open(&part).with_context(|| {
+            let part_file = File::open(&part).with_context(|| {
                 format!("failed to open snapshot part {}", part.to_string_lossy())
             })?;
 
-            io::copy(&mut part_file, &mut car_file)?;
+            remaining -= self
+                .manifest
+                .compression
+                .decode_into(part_file, &mut car_file, remaining)
+                .with_context(|| {
+                    format!("failed to decode snapshot part {}", part.to_string_lossy())
+                })?;
         }
 
         // 2. Import the contents.
@@ -170,6 +282,8 @@ pub struct SnapshotDownload {
     pub download_dir: Arc<TempDir>,
     // Next expected chunk index.
     pub next_index: TVar<u32>,
+    // Outcome of checking the application hash against the network; `None` while in progress.
+    pub verified: TVar<Option<Result<(), String>>>,
 }
 
 impl SnapshotDownload {
@@ -0,0 +1,467 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Check snapshots against the application hash committed by the network.
+//!
+//! The manifest of a snapshot contains the `FvmStateParams`, and its CID is the application hash.
+//! The hash appears in the header of the block *after* the one the snapshot was taken at.
+//! That header is verified with the CometBFT light client algorithm, starting from a header
+//! the operator trusts, so that a peer or a storage bucket can't talk us into restoring
+//! a state that the validators never agreed on.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use fendermint_vm_interpreter::fvm::state::snapshot::BlockHeight;
+use tendermint::{
+    block::{signed_header::SignedHeader, Height},
+    hash::AppHash,
+    node, validator, Hash, Time,
+};
+use tendermint_light_client_verifier::{
+    options::Options,
+    types::{LightBlock, TrustThreshold},
+    ProdVerifier, Verdict, Verifier,
+};
+use tendermint_rpc::{Client, Paging};
+use tokio::sync::Mutex;
+
+/// The header the light client starts from, and the conditions to trust further headers.
+#[derive(Debug, Clone)]
+pub struct TrustOptions {
+    /// Height of a block the operator knows to be on the correct chain.
+    pub trust_height: BlockHeight,
+    /// Hash of the block at the trusted height.
+    pub trust_hash: Hash,
+    /// How long the validators of a header can be trusted after the block was produced;
+    /// should be shorter than the unbonding period.
+    pub trust_period: Duration,
+}
+
+/// Check the application hash of a snapshot before it's restored.
+#[async_trait]
+pub trait AppHashCheck: Send + Sync {
+    /// Check that the application hash of the state produced by the block at the given height
+    /// matches what the network committed to in the next block.
+    async fn verify_app_hash(
+        &self,
+        block_height: BlockHeight,
+        app_hash: &AppHash,
+    ) -> anyhow::Result<()>;
+}
+
+/// Source of the headers and validator sets the light client verifies.
+#[async_trait]
+pub trait LightBlockProvider: Send + Sync {
+    /// The header at the given height, with the commit signed by the validators.
+    async fn signed_header(&self, height: BlockHeight) -> anyhow::Result<SignedHeader>;
+
+    /// The validator set at the given height.
+    async fn validators(&self, height: BlockHeight) -> anyhow::Result<validator::Set>;
+}
+
+#[async_trait]
+impl<C> LightBlockProvider for C
+where
+    C: Client + Send + Sync,
+{
+    async fn signed_header(&self, height: BlockHeight) -> anyhow::Result<SignedHeader> {
+        let response = self
+            .commit(to_height(height)?)
+            .await
+            .with_context(|| format!("failed to fetch commit at height {height}"))?;
+
+        Ok(response.signed_header)
+    }
+
+    async fn validators(&self, height: BlockHeight) -> anyhow::Result<validator::Set> {
+        let response = Client::validators(self, to_height(height)?, Paging::All)
+            .await
+            .with_context(|| format!("failed to fetch validators at height {height}"))?;
+
+        Ok(validator::Set::without_proposer(response.validators))
+    }
+}
+
+/// Fetches block headers from a provider, normally over RPC, and verifies them with the light client algorithm
+/// to find out the application hash at a given height.
+pub struct AppHashVerifier<C> {
+    client: C,
+    options: TrustOptions,
+    verifier: ProdVerifier,
+    /// The highest block verified so far, which is where we start from the next time.
+    trusted: Mutex<Option<LightBlock>>,
+}
+
+#[async_trait]
+impl<C> AppHashCheck for AppHashVerifier<C>
+where
+    C: LightBlockProvider,
+{
+    async fn verify_app_hash(
+        &self,
+        block_height: BlockHeight,
+        app_hash: &AppHash,
+    ) -> anyhow::Result<()> {
+        let verified = self.verified_app_hash(block_height).await?;
+        if verified != *app_hash {
+            bail!(
+                "the application hash of the snapshot at height {block_height} does not match the network; expected {verified}, got {app_hash}"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<C> AppHashVerifier<C>
+where
+    C: LightBlockProvider,
+{
+    pub fn new(client: C, options: TrustOptions) -> Self {
+        Self {
+            client,
+            options,
+            verifier: ProdVerifier::default(),
+            trusted: Mutex::new(None),
+        }
+    }
+
+    /// Application hash reflecting the state after executing the block at the given height,
+    /// taken from a verified header of the next block.
+    pub async fn verified_app_hash(&self, block_height: BlockHeight) -> anyhow::Result<AppHash> {
+        let target_height = block_height + 1;
+        let mut guard = self.trusted.lock().await;
+
+        let mut trusted = match guard.take() {
+            Some(trusted) => trusted,
+            None => self.fetch_trusted_block().await?,
+        };
+
+        let header = if target_height >= trusted.height().value() {
+            self.verify_forward(&mut trusted, target_height)
+                .await
+                .map(|()| trusted.signed_header.header.clone())
+        } else {
+            self.verify_backward(&trusted, target_height).await
+        };
+
+        *guard = Some(trusted);
+
+        Ok(header?.app_hash)
+    }
+
+    /// Fetch the block at the trusted height and check that it's the one the operator expects.
+    async fn fetch_trusted_block(&self) -> anyhow::Result<LightBlock> {
+        let block = self.fetch_light_block(self.options.trust_height).await?;
+        let hash = block.signed_header.header.hash();
+
+        if hash != self.options.trust_hash {
+            bail!(
+                "the header at the trusted height {} has hash {hash}; expected {}",
+                self.options.trust_height,
+                self.options.trust_hash
+            );
+        }
+
+        if block.signed_header.header.validators_hash != block.validators.hash()
+            || block.signed_header.header.next_validators_hash != block.next_validators.hash()
+        {
+            bail!(
+                "the validators returned for the trusted height {} do not match the header",
+                self.options.trust_height
+            );
+        }
+
+        Ok(block)
+    }
+
+    /// Verify the header at the target height by skipping ahead from the trusted block,
+    /// bisecting the range whenever the trusted validators don't have enough power in the target.
+    ///
+    /// The trusted block is moved forward as headers are verified, even if the target can't be reached.
+    async fn verify_forward(
+        &self,
+        trusted: &mut LightBlock,
+        target_height: BlockHeight,
+    ) -> anyhow::Result<()> {
+        let options = Options {
+            trust_threshold: TrustThreshold::ONE_THIRD,
+            trusting_period: self.options.trust_period,
+            clock_drift: Duration::from_secs(10),
+        };
+
+        let mut cache = BTreeMap::new();
+        let mut pivot_height = target_height;
+
+        while trusted.height().value() < target_height {
+            let untrusted = match cache.remove(&pivot_height) {
+                Some(block) => block,
+                None => self.fetch_light_block(pivot_height).await?,
+            };
+
+            if untrusted.signed_header.header.chain_id != trusted.signed_header.header.chain_id {
+                bail!("the header at height {pivot_height} is for a different chain");
+            }
+
+            match self.verifier.verify_update_header(
+                untrusted.as_untrusted_state(),
+                trusted.as_trusted_state(),
+                &options,
+                now()?,
+            ) {
+                Verdict::Success => {
+                    *trusted = untrusted;
+                    pivot_height = target_height;
+                }
+                Verdict::NotEnoughTrust(tally) => {
+                    tracing::debug!(
+                        trusted_height = trusted.height().value(),
+                        pivot_height,
+                        ?tally,
+                        "not enough trust; bisecting"
+                    );
+                    cache.insert(pivot_height, untrusted);
+                    // Adjacent headers are verified sequentially, so this always makes progress.
+                    pivot_height = (trusted.height().value() + pivot_height) / 2;
+                }
+                Verdict::Invalid(detail) => {
+                    bail!("invalid header at height {pivot_height}: {detail}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Verify a header below the trusted height by following the hashes linking each header to its parent.
+    async fn verify_backward(
+        &self,
+        trusted: &LightBlock,
+        target_height: BlockHeight,
+    ) -> anyhow::Result<tendermint::block::Header> {
+        let mut header = trusted.signed_header.header.clone();
+
+        while header.height.value() > target_height {
+            let parent_hash = header
+                .last_block_id
+                .as_ref()
+                .map(|id| id.hash)
+                .ok_or_else(|| anyhow!("header at height {} has no parent", header.height))?;
+
+            let parent_height = header.height.value() - 1;
+            let parent = self.client.signed_header(parent_height).await?.header;
+
+            if parent.hash() != parent_hash {
+                bail!("the header at height {parent_height} does not match the hash in its child");
+            }
+
+            header = parent;
+        }
+
+        Ok(header)
+    }
+
+    /// Fetch the signed header and the validator sets needed to verify it.
+    async fn fetch_light_block(&self, height: BlockHeight) -> anyhow::Result<LightBlock> {
+        let signed_header = self.client.signed_header(height).await?;
+        let validators = self.client.validators(height).await?;
+        let next_validators = self.client.validators(height + 1).await?;
+
+        Ok(LightBlock::new(
+            signed_header,
+            validators,
+            next_validators,
+            node::Id::new([0; 20]),
+        ))
+    }
+}
+
+fn to_height(height: BlockHeight) -> anyhow::Result<Height> {
+    Height::try_from(height).context("invalid block height")
+}
+
+fn now() -> anyhow::Result<Time> {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time before epoch")?;
+
+    Time::from_unix_timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos())
+        .context("invalid system time")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use fendermint_vm_interpreter::fvm::state::snapshot::BlockHeight;
+    use tendermint::{block::signed_header::SignedHeader, hash::AppHash, validator, Hash, Time};
+    use tendermint_testgen::{Commit, Generator, Header, LightBlock, Validator};
+
+    use super::{now, AppHashCheck, AppHashVerifier, LightBlockProvider, TrustOptions};
+
+    /// Chain of signed headers, with the validators completely replaced after `switch_height`,
+    /// so headers on the two sides can't be verified by skipping from one to the other.
+    struct TestChain {
+        headers: BTreeMap<BlockHeight, SignedHeader>,
+        validators: BTreeMap<BlockHeight, validator::Set>,
+    }
+
+    impl TestChain {
+        fn new(length: BlockHeight, switch_height: BlockHeight) -> Self {
+            let a = ["a1", "a2", "a3"].map(Validator::new);
+            let b = ["b1", "b2", "b3"].map(Validator::new);
+            let validators_at = |height| if height <= switch_height { &a } else { &b };
+
+            let start = now()
+                .unwrap()
+                .checked_sub(Duration::from_secs(length + 60))
+                .unwrap();
+
+            let mut headers = BTreeMap::new();
+            let mut validators = BTreeMap::new();
+            let mut parent_hash: Option<Hash> = None;
+
+            for height in 1..=length {
+                let header = Header::new(validators_at(height))
+                    .next_validators(validators_at(height + 1))
+                    .height(height)
+                    .time(start.checked_add(Duration::from_secs(height)).unwrap());
+
+                let header = match parent_hash {
+                    Some(hash) => header.last_block_id_hash(hash),
+                    None => header,
+                };
+
+                let commit = Commit::new(header.clone(), 1);
+                let block = LightBlock::new(header, commit).generate().unwrap();
+
+                parent_hash = Some(block.signed_header.header.hash());
+                validators.insert(height, block.validators);
+                validators.insert(height + 1, block.next_validators);
+                headers.insert(height, block.signed_header);
+            }
+
+            Self {
+                headers,
+                validators,
+            }
+        }
+
+        fn hash(&self, height: BlockHeight) -> Hash {
+            self.headers[&height].header.hash()
+        }
+
+        fn app_hash(&self, height: BlockHeight) -> AppHash {
+            self.headers[&height].header.app_hash.clone()
+        }
+    }
+
+    #[async_trait]
+    impl LightBlockProvider for TestChain {
+        async fn signed_header(&self, height: BlockHeight) -> anyhow::Result<SignedHeader> {
+            self.headers
+                .get(&height)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no header at height {height}"))
+        }
+
+        async fn validators(&self, height: BlockHeight) -> anyhow::Result<validator::Set> {
+            self.validators
+                .get(&height)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no validators at height {height}"))
+        }
+    }
+
+    fn verifier(chain: TestChain, trust_height: BlockHeight) -> AppHashVerifier<TestChain> {
+        let options = TrustOptions {
+            trust_height,
+            trust_hash: chain.hash(trust_height),
+            trust_period: Duration::from_secs(3600),
+        };
+        AppHashVerifier::new(chain, options)
+    }
+
+    async fn trusted_height(verifier: &AppHashVerifier<TestChain>) -> Option<BlockHeight> {
+        verifier
+            .trusted
+            .lock()
+            .await
+            .as_ref()
+            .map(|block| block.height().value())
+    }
+
+    #[tokio::test]
+    async fn verify_forward_with_bisection() {
+        // The validators of the trusted header have no power at the target,
+        // so it can only be reached through the headers around the switch.
+        let chain = TestChain::new(10, 5);
+        let app_hash = chain.app_hash(10);
+        let verifier = verifier(chain, 1);
+
+        verifier.verify_app_hash(9, &app_hash).await.unwrap();
+        assert_eq!(trusted_height(&verifier).await, Some(10));
+    }
+
+    #[tokio::test]
+    async fn verify_forward_rejects_wrong_app_hash() {
+        let chain = TestChain::new(5, 5);
+        let verifier = verifier(chain, 1);
+        let app_hash = AppHash::try_from(vec![1u8; 32]).unwrap();
+
+        let err = verifier.verify_app_hash(3, &app_hash).await.unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn verify_backward() {
+        let chain = TestChain::new(10, 5);
+        let app_hash = chain.app_hash(4);
+        let verifier = verifier(chain, 8);
+
+        verifier.verify_app_hash(3, &app_hash).await.unwrap();
+        // Going backwards doesn't move the trusted header.
+        assert_eq!(trusted_height(&verifier).await, Some(8));
+    }
+
+    #[tokio::test]
+    async fn verify_backward_rejects_broken_hash_chain() {
+        let mut chain = TestChain::new(10, 5);
+        let app_hash = chain.app_hash(4);
+        let trust_hash = chain.hash(8);
+
+        // Tamper with a header between the trusted one and the target.
+        let header = &mut chain.headers.get_mut(&6).unwrap().header;
+        header.time = Time::unix_epoch();
+
+        let options = TrustOptions {
+            trust_height: 8,
+            trust_hash,
+            trust_period: Duration::from_secs(3600),
+        };
+        let verifier = AppHashVerifier::new(chain, options);
+
+        let err = verifier.verify_app_hash(3, &app_hash).await.unwrap_err();
+        assert!(err.to_string().contains("height 6"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn trusted_hash_mismatch() {
+        let chain = TestChain::new(5, 5);
+        let app_hash = chain.app_hash(4);
+        let options = TrustOptions {
+            trust_height: 1,
+            trust_hash: chain.hash(2),
+            trust_period: Duration::from_secs(3600),
+        };
+        let verifier = AppHashVerifier::new(chain, options);
+
+        let err = verifier.verify_app_hash(3, &app_hash).await.unwrap_err();
+        assert!(err.to_string().contains("trusted height"), "{err}");
+        assert_eq!(trusted_height(&verifier).await, None);
+    }
+}
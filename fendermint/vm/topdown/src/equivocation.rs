@@ -0,0 +1,535 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+//! Detect validators who sign votes for different parent block hashes at the same height,
+//! and keep the signed votes as evidence which can be verified by anyone.
+//!
+//! This is detection only: the evidence is reported in the metrics, the logs, and the
+//! `/topdown/equivocations` query, and handed to an [EvidenceSubmitter], but neither the
+//! gateway nor the subnet actor accepts it yet, so nobody is slashed on chain.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use async_stm::{atomically, retry, Stm, TVar};
+use async_trait::async_trait;
+use fvm_ipld_encoding::strict_bytes;
+use ipc_api::subnet_id::SubnetID;
+use ipc_ipld_resolver::{SignedVoteRecord, ValidatorKey};
+use ipc_observability::{emit, serde::HexEncodableBlockHash};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::observe::ParentFinalityEquivocationDetected;
+use crate::{BlockHash, BlockHeight, Bytes};
+
+/// Maximum number of heights we keep evidence for; the oldest are dropped first.
+const MAX_EVIDENCE_HEIGHTS: usize = 1000;
+
+/// A vote about a parent block hash, along with the signed envelope it arrived in.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedVote {
+    /// The block hash the validator voted for.
+    #[serde(with = "strict_bytes")]
+    pub block_hash: BlockHash,
+    /// Protobuf encoded `SignedEnvelope` of the gossiped vote record.
+    #[serde(with = "strict_bytes")]
+    pub envelope: Bytes,
+}
+
+impl SignedVote {
+    /// Capture the envelope of a vote record, which has been established to be about `block_hash`.
+    pub fn new<C>(record: &SignedVoteRecord<C>, block_hash: BlockHash) -> Self {
+        Self {
+            block_hash,
+            envelope: record.envelope().clone().into_protobuf_encoding(),
+        }
+    }
+}
+
+/// Proof that a validator signed votes for two different block hashes at the same parent height.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EquivocationEvidence {
+    /// The equivocating validator.
+    pub validator: ValidatorKey,
+    /// The subnet the votes were cast in.
+    pub subnet_id: SubnetID,
+    /// The parent height both votes are about.
+    pub block_height: BlockHeight,
+    /// The vote we saw first.
+    pub first: SignedVote,
+    /// The conflicting vote we saw later.
+    pub second: SignedVote,
+}
+
+impl EquivocationEvidence {
+    /// Check that both envelopes carry valid signatures of the validator, and that they
+    /// are about the same height in the same subnet, but vote for different block hashes.
+    ///
+    /// The `extract` function returns the height and block hash the content of the vote is about.
+    pub fn verify<C, F>(&self, extract: F) -> anyhow::Result<()>
+    where
+        C: Serialize + DeserializeOwned,
+        F: Fn(&C) -> Option<(BlockHeight, BlockHash)>,
+    {
+        if self.first.block_hash == self.second.block_hash {
+            bail!("the votes are for the same block hash");
+        }
+        for vote in [&self.first, &self.second] {
+            let signed = SignedVoteRecord::<C>::from_bytes(&vote.envelope)
+                .context("invalid signed vote record")?;
+
+            let record = signed.record();
+
+            if record.public_key != self.validator {
+                bail!("vote signed by a different validator");
+            }
+            if record.subnet_id != self.subnet_id {
+                bail!("vote cast in a different subnet");
+            }
+            match extract(&record.content) {
+                Some((height, hash)) if height == self.block_height && hash == vote.block_hash => {}
+                Some(_) => bail!("vote content does not match the evidence"),
+                None => return Err(anyhow!("vote is not about a parent block")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the evidence in the metrics and the logs.
+    ///
+    /// Call this once the transaction returning it from [EquivocationDetector::observe]
+    /// has committed, so that retries of the transaction don't report it multiple times.
+    pub fn emit(&self) {
+        emit(ParentFinalityEquivocationDetected {
+            validator: &self.validator.to_string(),
+            block_height: self.block_height,
+            first_block_hash: HexEncodableBlockHash(self.first.block_hash.clone()),
+            second_block_hash: HexEncodableBlockHash(self.second.block_hash.clone()),
+        });
+    }
+}
+
+/// Keep track of the first signed vote each validator cast at each parent height,
+/// and collect evidence if they sign a conflicting one.
+///
+/// This is complementary to the [`VoteTally`](crate::voting::VoteTally), which rejects
+/// equivocating votes, but only knows about the validator keys, not the signatures.
+#[derive(Clone, Default)]
+pub struct EquivocationDetector {
+    /// The first vote received from each validator at each height not yet finalized.
+    votes: TVar<im::OrdMap<BlockHeight, im::HashMap<ValidatorKey, SignedVote>>>,
+    /// Evidence collected so far, by height and validator.
+    evidence: TVar<im::OrdMap<BlockHeight, im::HashMap<ValidatorKey, EquivocationEvidence>>>,
+    /// Evidence which has already been handed over to a submitter, by height and validator.
+    ///
+    /// Pruned along with the evidence.
+    submitted: TVar<im::OrdMap<BlockHeight, im::HashSet<ValidatorKey>>>,
+}
+
+impl EquivocationDetector {
+    /// Record a vote; return evidence if it conflicts with a previous vote of the same validator.
+    ///
+    /// Only the first conflict is recorded for any validator at a given height.
+    /// Nothing is reported, because the transaction might be retried; see [EquivocationEvidence::emit].
+    pub fn observe(
+        &self,
+        validator: ValidatorKey,
+        subnet_id: SubnetID,
+        block_height: BlockHeight,
+        vote: SignedVote,
+    ) -> Stm<Option<EquivocationEvidence>> {
+        let already_known = self
+            .evidence
+            .read()?
+            .get(&block_height)
+            .map(|e| e.contains_key(&validator))
+            .unwrap_or_default();
+
+        if already_known {
+            return Ok(None);
+        }
+
+        let mut votes = self.votes.read_clone()?;
+        let votes_at_height = votes.entry(block_height).or_default();
+
+        let first = match votes_at_height.get(&validator) {
+            None => {
+                votes_at_height.insert(validator, vote);
+                self.votes.write(votes)?;
+                return Ok(None);
+            }
+            Some(first) if first.block_hash == vote.block_hash => return Ok(None),
+            Some(first) => first.clone(),
+        };
+
+        let evidence = EquivocationEvidence {
+            validator: validator.clone(),
+            subnet_id,
+            block_height,
+            first,
+            second: vote,
+        };
+
+        let mut evidence_by_height = self.evidence.read_clone()?;
+        evidence_by_height
+            .entry(block_height)
+            .or_default()
+            .insert(validator.clone(), evidence.clone());
+
+        while evidence_by_height.len() > MAX_EVIDENCE_HEIGHTS {
+            evidence_by_height.remove_min();
+        }
+
+        // Forget what was submitted at the heights we no longer have evidence for.
+        if let Some((lowest_height, _)) = evidence_by_height.get_min() {
+            let lowest_height = *lowest_height;
+            self.submitted.update(|submitted| {
+                let (_, at, mut above) = submitted.split_lookup(&lowest_height);
+                if let Some(at) = at {
+                    above.insert(lowest_height, at);
+                }
+                above
+            })?;
+        }
+
+        self.evidence.write(evidence_by_height)?;
+
+        Ok(Some(evidence))
+    }
+
+    /// Forget the votes below a height that has been finalized; they can no longer affect the tally.
+    ///
+    /// The evidence is retained.
+    pub fn prune(&self, finalized_height: BlockHeight) -> Stm<()> {
+        self.votes.update(|votes| {
+            let (_, at, mut above) = votes.split_lookup(&finalized_height);
+            if let Some(at) = at {
+                above.insert(finalized_height, at);
+            }
+            above
+        })
+    }
+
+    /// All the evidence collected, in ascending order of height.
+    pub fn evidence(&self) -> Stm<Vec<EquivocationEvidence>> {
+        let evidence = self.evidence.read()?;
+        Ok(evidence
+            .values()
+            .flat_map(|e| e.values().cloned())
+            .collect())
+    }
+
+    /// Evidence which hasn't been submitted yet.
+    pub fn pending_evidence(&self) -> Stm<Vec<EquivocationEvidence>> {
+        let submitted = self.submitted.read()?;
+        let pending = self
+            .evidence()?
+            .into_iter()
+            .filter(|e| {
+                !submitted
+                    .get(&e.block_height)
+                    .map(|s| s.contains(&e.validator))
+                    .unwrap_or_default()
+            })
+            .collect();
+        Ok(pending)
+    }
+
+    /// Remember that a piece of evidence has been submitted, so it isn't handed out again.
+    pub fn mark_submitted(&self, evidence: &EquivocationEvidence) -> Stm<()> {
+        self.submitted.update_mut(|submitted| {
+            submitted
+                .entry(evidence.block_height)
+                .or_default()
+                .insert(evidence.validator.clone());
+        })
+    }
+}
+
+/// Destination of equivocation evidence, for example a slashing hook on the gateway,
+/// or an actor collecting misbehaviour reports, once there is one.
+#[async_trait]
+pub trait EvidenceSubmitter {
+    async fn submit(&self, evidence: &EquivocationEvidence) -> anyhow::Result<()>;
+}
+
+/// Write the evidence into a directory, one JSON file per equivocating validator and height,
+/// for operators to inspect.
+///
+/// This is the only submitter: neither the gateway nor the subnet actor has an entry point
+/// for equivocation evidence yet, so there is nothing on chain to submit it to; once there is,
+/// a submitter calling it can take the place of this one.
+pub struct DirEvidenceSubmitter {
+    dir: PathBuf,
+}
+
+impl DirEvidenceSubmitter {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl EvidenceSubmitter for DirEvidenceSubmitter {
+    async fn submit(&self, evidence: &EquivocationEvidence) -> anyhow::Result<()> {
+        let json =
+            serde_json::to_vec_pretty(evidence).context("failed to serialize the evidence")?;
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("failed to create {}", self.dir.to_string_lossy()))?;
+
+        let name = format!("{}-{}", evidence.block_height, evidence.validator);
+        let path = self.dir.join(format!("{name}.json"));
+        let part = self.dir.join(format!("{name}.json.part"));
+
+        // Don't let anyone pick up a half written file.
+        tokio::fs::write(&part, json)
+            .await
+            .with_context(|| format!("failed to write {}", part.to_string_lossy()))?;
+        tokio::fs::rename(&part, &path)
+            .await
+            .with_context(|| format!("failed to rename to {}", path.to_string_lossy()))?;
+
+        Ok(())
+    }
+}
+
+/// Wait for new evidence to appear in the detector and hand it over to the submitter.
+///
+/// Failed submissions are retried after `retry_delay`.
+pub async fn submit_evidence_loop<S>(
+    detector: EquivocationDetector,
+    submitter: S,
+    retry_delay: Duration,
+) where
+    S: EvidenceSubmitter,
+{
+    loop {
+        let pending = atomically(|| {
+            let pending = detector.pending_evidence()?;
+            if pending.is_empty() {
+                retry()?;
+            }
+            Ok(pending)
+        })
+        .await;
+
+        let mut failed = false;
+
+        for evidence in pending {
+            match submitter.submit(&evidence).await {
+                Ok(()) => {
+                    tracing::info!(
+                        validator = evidence.validator.to_string(),
+                        block_height = evidence.block_height,
+                        "submitted equivocation evidence"
+                    );
+                    atomically(|| detector.mark_submitted(&evidence)).await;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        error = e.to_string(),
+                        validator = evidence.validator.to_string(),
+                        block_height = evidence.block_height,
+                        "failed to submit equivocation evidence"
+                    );
+                    failed = true;
+                }
+            }
+        }
+
+        if failed {
+            tokio::time::sleep(retry_delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_stm::atomically;
+    use ipc_api::subnet_id::SubnetID;
+    use ipc_ipld_resolver::{ValidatorKey, VoteRecord};
+    use libp2p::identity::Keypair;
+
+    use super::{
+        DirEvidenceSubmitter, EquivocationDetector, EquivocationEvidence, EvidenceSubmitter,
+        SignedVote, MAX_EVIDENCE_HEIGHTS,
+    };
+    use crate::{BlockHash, BlockHeight, IPCParentFinality};
+
+    fn signed_vote(
+        key: &Keypair,
+        subnet_id: &SubnetID,
+        height: BlockHeight,
+        hash: u8,
+    ) -> SignedVote {
+        let block_hash: BlockHash = vec![hash; 32];
+        let content = IPCParentFinality {
+            height,
+            block_hash: block_hash.clone(),
+        };
+        let record = VoteRecord::signed(key, subnet_id.clone(), content).expect("signed vote");
+        SignedVote::new(&record, block_hash)
+    }
+
+    fn extract(f: &IPCParentFinality) -> Option<(BlockHeight, BlockHash)> {
+        Some((f.height, f.block_hash.clone()))
+    }
+
+    #[tokio::test]
+    async fn detects_equivocation() {
+        let key = Keypair::generate_secp256k1();
+        let validator = ValidatorKey::from(key.public());
+        let subnet_id = SubnetID::new_root(123);
+        let detector = EquivocationDetector::default();
+
+        let observe = |vote: SignedVote| {
+            let detector = detector.clone();
+            let validator = validator.clone();
+            let subnet_id = subnet_id.clone();
+            async move {
+                atomically(|| {
+                    detector.observe(validator.clone(), subnet_id.clone(), 10, vote.clone())
+                })
+                .await
+            }
+        };
+
+        assert!(observe(signed_vote(&key, &subnet_id, 10, 1))
+            .await
+            .is_none());
+        // Repeating the same vote is not an equivocation.
+        assert!(observe(signed_vote(&key, &subnet_id, 10, 1))
+            .await
+            .is_none());
+
+        let evidence = observe(signed_vote(&key, &subnet_id, 10, 2))
+            .await
+            .expect("should detect equivocation");
+
+        assert_eq!(evidence.validator, validator);
+        assert_eq!(evidence.block_height, 10);
+        evidence.verify(extract).expect("evidence should be valid");
+
+        // Only reported once.
+        assert!(observe(signed_vote(&key, &subnet_id, 10, 3))
+            .await
+            .is_none());
+
+        let pending = atomically(|| detector.pending_evidence()).await;
+        assert_eq!(pending, vec![evidence.clone()]);
+
+        atomically(|| detector.mark_submitted(&evidence)).await;
+        let pending = atomically(|| detector.pending_evidence()).await;
+        assert!(pending.is_empty());
+
+        let all = atomically(|| detector.evidence()).await;
+        assert_eq!(all.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_evidence() {
+        let key = Keypair::generate_secp256k1();
+        let other = Keypair::generate_secp256k1();
+        let validator = ValidatorKey::from(key.public());
+        let subnet_id = SubnetID::new_root(123);
+        let detector = EquivocationDetector::default();
+
+        // Someone else's vote attributed to the validator.
+        let first = signed_vote(&key, &subnet_id, 10, 1);
+        let second = signed_vote(&other, &subnet_id, 10, 2);
+
+        atomically(|| detector.observe(validator.clone(), subnet_id.clone(), 10, first.clone()))
+            .await;
+
+        let evidence = atomically(|| {
+            detector.observe(validator.clone(), subnet_id.clone(), 10, second.clone())
+        })
+        .await
+        .expect("detector trusts the caller");
+
+        assert!(evidence.verify(extract).is_err());
+    }
+
+    #[tokio::test]
+    async fn prune_forgets_votes() {
+        let key = Keypair::generate_secp256k1();
+        let validator = ValidatorKey::from(key.public());
+        let subnet_id = SubnetID::new_root(123);
+        let detector = EquivocationDetector::default();
+
+        let first = signed_vote(&key, &subnet_id, 10, 1);
+        let second = signed_vote(&key, &subnet_id, 10, 2);
+
+        atomically(|| detector.observe(validator.clone(), subnet_id.clone(), 10, first.clone()))
+            .await;
+
+        atomically(|| detector.prune(11)).await;
+
+        let evidence = atomically(|| {
+            detector.observe(validator.clone(), subnet_id.clone(), 10, second.clone())
+        })
+        .await;
+
+        assert!(evidence.is_none());
+    }
+
+    #[tokio::test]
+    async fn prune_submitted_with_evidence() {
+        let key = Keypair::generate_secp256k1();
+        let validator = ValidatorKey::from(key.public());
+        let subnet_id = SubnetID::new_root(123);
+        let detector = EquivocationDetector::default();
+
+        // The detector doesn't check the content of the votes, so they can be reused.
+        let first = signed_vote(&key, &subnet_id, 0, 1);
+        let second = signed_vote(&key, &subnet_id, 0, 2);
+
+        for height in 0..=MAX_EVIDENCE_HEIGHTS as BlockHeight {
+            let evidence = atomically(|| {
+                detector.observe(validator.clone(), subnet_id.clone(), height, first.clone())?;
+                detector.observe(validator.clone(), subnet_id.clone(), height, second.clone())
+            })
+            .await
+            .expect("should detect equivocation");
+
+            atomically(|| detector.mark_submitted(&evidence)).await;
+        }
+
+        let submitted = atomically(|| detector.submitted.read_clone()).await;
+        assert_eq!(submitted.len(), MAX_EVIDENCE_HEIGHTS);
+        assert!(!submitted.contains_key(&0));
+
+        let pending = atomically(|| detector.pending_evidence()).await;
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dir_submitter_writes_evidence() {
+        let key = Keypair::generate_secp256k1();
+        let subnet_id = SubnetID::new_root(123);
+        let evidence = EquivocationEvidence {
+            validator: ValidatorKey::from(key.public()),
+            subnet_id: subnet_id.clone(),
+            block_height: 10,
+            first: signed_vote(&key, &subnet_id, 10, 1),
+            second: signed_vote(&key, &subnet_id, 10, 2),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let submitter = DirEvidenceSubmitter::new(dir.path().join("equivocations"));
+        submitter.submit(&evidence).await.expect("should submit");
+
+        let path = dir
+            .path()
+            .join("equivocations")
+            .join(format!("10-{}.json", evidence.validator));
+
+        let json = std::fs::read(path).expect("should write a file");
+        let written: EquivocationEvidence = serde_json::from_slice(&json).unwrap();
+        assert_eq!(written, evidence);
+        written.verify(extract).expect("evidence should be valid");
+    }
+}
@@ -9,6 +9,7 @@ pub mod sync;
 pub mod convert;
 pub mod proxy;
 mod toggle;
+pub mod equivocation;
 pub mod voting;
 
 pub mod observe;
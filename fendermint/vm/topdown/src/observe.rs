@@ -33,6 +33,10 @@ register_metrics! {
         );
     TOPDOWN_PARENT_FINALITY_COMMITTED_HEIGHT: IntGauge
         = register_int_gauge!("topdown_parent_finality_committed_height", "Parent finality committed on chain");
+    TOPDOWN_PARENT_FINALITY_EQUIVOCATION_TOTAL: IntCounterVec
+        = register_int_counter_vec!("topdown_parent_finality_equivocation_total", "Parent finality votes found to be equivocating", &["validator"]);
+    TOPDOWN_PARENT_FINALITY_EQUIVOCATION_LATEST_HEIGHT: IntGauge
+        = register_int_gauge!("topdown_parent_finality_equivocation_latest_height", "Latest parent height where an equivocation was detected");
 }
 
 impl_traceables!(
@@ -46,6 +50,12 @@ impl_traceables!(
     ParentFinalityCommitted<'a>
 );
 
+impl_traceables!(
+    TraceLevel::Warn,
+    "Topdown",
+    ParentFinalityEquivocationDetected<'a>
+);
+
 #[derive(Debug)]
 pub struct ParentRpcCalled<'a> {
     pub source: &'a str,
@@ -148,6 +158,24 @@ impl Recordable for ParentFinalityCommitted<'_> {
     }
 }
 
+#[derive(Debug)]
+pub struct ParentFinalityEquivocationDetected<'a> {
+    pub validator: &'a str,
+    pub block_height: BlockHeight,
+    pub first_block_hash: HexEncodableBlockHash,
+    pub second_block_hash: HexEncodableBlockHash,
+}
+
+impl Recordable for ParentFinalityEquivocationDetected<'_> {
+    fn record_metrics(&self) {
+        TOPDOWN_PARENT_FINALITY_EQUIVOCATION_TOTAL
+            .with_label_values(&[self.validator])
+            .inc();
+
+        TOPDOWN_PARENT_FINALITY_EQUIVOCATION_LATEST_HEIGHT.set(self.block_height as i64);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,5 +264,12 @@ mod tests {
             local_height: Some(0),
             proposer: Some("proposerOption"),
         });
+
+        emit(ParentFinalityEquivocationDetected {
+            validator: "validator",
+            block_height: 0,
+            first_block_hash: HexEncodableBlockHash(hash.clone()),
+            second_block_hash: HexEncodableBlockHash(vec![1u8; 32]),
+        });
     }
 }
@@ -0,0 +1,272 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Persistent copy of the Kademlia routing table and the subnet provider records,
+//! so that a restarted node can reconnect to the peers it knew about even if the
+//! static seeds are temporarily unavailable.
+//!
+//! Everything is stored as signed envelopes. Addresses are signed by the local node,
+//! so that a file which was tampered with, or copied from another node, is ignored.
+//! Provider records keep the signature of the peer which published them, and are
+//! checked exactly like the ones received over Gossipsub.
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use fvm_ipld_encoding::strict_bytes;
+use libp2p::core::SignedEnvelope;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::{Multiaddr, PeerId};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::provider_record::SignedProviderRecord;
+use crate::signed_record::{Record, SignedRecord};
+use crate::Timestamp;
+
+#[derive(Debug, Clone)]
+pub struct AddressBookConfig {
+    /// File to save the known peers to; `None` disables persistence.
+    pub path: Option<PathBuf>,
+    /// Addresses of peers we haven't been connected to for longer than this are not reloaded.
+    pub max_age: Duration,
+    /// How often to save the current state of the routing table and the provider cache.
+    pub save_interval: Duration,
+}
+
+/// The addresses of a peer in the routing table, as witnessed by the signer.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AddressRecord {
+    /// The local node which saved the record.
+    pub signer: PeerId,
+    /// The peer the addresses belong to.
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    /// Last time the signer was connected to the peer.
+    pub timestamp: Timestamp,
+}
+
+impl Record for AddressRecord {
+    fn payload_type() -> &'static str {
+        "/ipc/address-record"
+    }
+
+    fn check_signing_key(&self, key: &PublicKey) -> bool {
+        self.signer == key.to_peer_id()
+    }
+}
+
+pub type SignedAddressRecord = SignedRecord<AddressRecord>;
+
+impl AddressRecord {
+    /// Create a new [`SignedAddressRecord`] signed by the local node.
+    pub fn signed(
+        key: &Keypair,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        timestamp: Timestamp,
+    ) -> anyhow::Result<SignedAddressRecord> {
+        let record = AddressRecord {
+            signer: key.public().to_peer_id(),
+            peer_id,
+            addresses,
+            timestamp,
+        };
+        let signed = SignedRecord::new(key, record)?;
+        Ok(signed)
+    }
+}
+
+/// Protobuf encoded [`SignedEnvelope`].
+#[derive(Serialize, Deserialize)]
+struct EnvelopeBytes(#[serde(with = "strict_bytes")] Vec<u8>);
+
+impl From<&SignedEnvelope> for EnvelopeBytes {
+    fn from(value: &SignedEnvelope) -> Self {
+        Self(value.clone().into_protobuf_encoding())
+    }
+}
+
+/// The format of the file on disk.
+#[derive(Serialize, Deserialize, Default)]
+struct AddressBookFile {
+    addresses: Vec<EnvelopeBytes>,
+    providers: Vec<EnvelopeBytes>,
+}
+
+/// The peers we knew about when the node was last running.
+#[derive(Debug, Clone, Default)]
+pub struct AddressBook {
+    pub addresses: Vec<SignedAddressRecord>,
+    pub providers: Vec<SignedProviderRecord>,
+}
+
+impl AddressBook {
+    /// Read the address book from a file, keeping only the records which
+    /// have a valid signature and are newer than the cutoff timestamps.
+    ///
+    /// Addresses are only accepted if they were signed by the local node.
+    ///
+    /// Returns an empty address book if the file doesn't exist yet.
+    pub fn load(
+        path: &Path,
+        local_peer_id: PeerId,
+        address_cutoff: Timestamp,
+        provider_cutoff: Timestamp,
+    ) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let bytes =
+            fs::read(path).with_context(|| format!("failed to read address book from {path:?}"))?;
+
+        let file = fvm_ipld_encoding::from_slice::<AddressBookFile>(&bytes)
+            .with_context(|| format!("failed to decode address book from {path:?}"))?;
+
+        let addresses = file
+            .addresses
+            .into_iter()
+            .filter_map(|bytes| match SignedAddressRecord::from_bytes(&bytes.0) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    debug!("ignoring invalid address record: {e}");
+                    None
+                }
+            })
+            .filter(|r| r.record().signer == local_peer_id && r.record().peer_id != local_peer_id)
+            .filter(|r| r.record().timestamp >= address_cutoff)
+            .collect();
+
+        let providers = file
+            .providers
+            .into_iter()
+            .filter_map(|bytes| match SignedProviderRecord::from_bytes(&bytes.0) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    debug!("ignoring invalid provider record: {e}");
+                    None
+                }
+            })
+            .filter(|r| r.record().peer_id != local_peer_id)
+            .filter(|r| r.record().timestamp >= provider_cutoff)
+            .collect();
+
+        Ok(Self {
+            addresses,
+            providers,
+        })
+    }
+
+    /// Write the address book to a file.
+    ///
+    /// The contents go to a temporary file first, which is then renamed,
+    /// so a crash during the write doesn't leave us with a truncated file.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let file = AddressBookFile {
+            addresses: self.addresses.iter().map(|r| r.envelope().into()).collect(),
+            providers: self.providers.iter().map(|r| r.envelope().into()).collect(),
+        };
+        let bytes = fvm_ipld_encoding::to_vec(&file)?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {dir:?}"))?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp = fs::File::create(&tmp_path)
+                .with_context(|| format!("failed to create {tmp_path:?}"))?;
+            tmp.write_all(&bytes)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to write address book to {path:?}"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::identity::Keypair;
+    use libp2p::{Multiaddr, PeerId};
+
+    use super::{AddressBook, AddressRecord};
+    use crate::provider_record::ProviderRecord;
+    use crate::Timestamp;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("address_book.cbor");
+        let book = AddressBook::load(
+            &path,
+            PeerId::random(),
+            Timestamp::default(),
+            Timestamp::default(),
+        )
+        .unwrap();
+        assert!(book.addresses.is_empty());
+        assert!(book.providers.is_empty());
+    }
+
+    #[test]
+    fn save_and_load_valid_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers").join("address_book.cbor");
+
+        let local_key = Keypair::generate_ed25519();
+        let local_peer_id = local_key.public().to_peer_id();
+        let other_key = Keypair::generate_ed25519();
+        let other_peer_id = other_key.public().to_peer_id();
+
+        let now = Timestamp::now();
+        let old = now - Duration::from_secs(3600);
+
+        let fresh = AddressRecord::signed(&local_key, other_peer_id, vec![addr(1)], now).unwrap();
+        let stale =
+            AddressRecord::signed(&local_key, PeerId::random(), vec![addr(2)], old).unwrap();
+        let foreign =
+            AddressRecord::signed(&other_key, PeerId::random(), vec![addr(3)], now).unwrap();
+        let provider = ProviderRecord::signed(&other_key, vec![]).unwrap();
+        let own_provider = ProviderRecord::signed(&local_key, vec![]).unwrap();
+
+        let book = AddressBook {
+            addresses: vec![fresh.clone(), stale, foreign],
+            providers: vec![provider.clone(), own_provider],
+        };
+        book.save(&path).unwrap();
+
+        let cutoff = now - Duration::from_secs(60);
+        let loaded = AddressBook::load(&path, local_peer_id, cutoff, cutoff).unwrap();
+
+        assert_eq!(loaded.addresses.len(), 1);
+        assert_eq!(loaded.addresses[0].record(), fresh.record());
+        assert_eq!(loaded.providers.len(), 1);
+        assert_eq!(loaded.providers[0].record(), provider.record());
+    }
+
+    #[test]
+    fn load_corrupt_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("address_book.cbor");
+        std::fs::write(&path, b"not an address book").unwrap();
+
+        let res = AddressBook::load(
+            &path,
+            PeerId::random(),
+            Timestamp::default(),
+            Timestamp::default(),
+        );
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Accounting of the bytes we serve to other peers, with egress budgets.
+//!
+//! The [`RateLimiter`](crate::limiter::RateLimiter) stops individual addresses from consuming
+//! too much, but doesn't cap the total. Here we keep a global budget and optional budgets for
+//! specific subnets. Priority subnets, typically the node's own, are only subject to their own
+//! budget: their traffic is charged to the global budget as well, but is served even if it's
+//! exhausted, so serving other subnets cannot starve the resolution of our own checkpoints.
+//!
+//! Requests are attributed to the subnet the peer asked for content from in its latest graph
+//! request, but only if it's a subnet this node provides data for. The requester doesn't have
+//! to provide data for the subnet itself: validators of the parent resolving our checkpoints
+//! provide the parent subnet, not ours. Anyone can claim to resolve content of our subnet,
+//! so a priority subnet should also have a budget of its own if this is a concern.
+//! Bitswap requests carry no such information, so peers which never declared a subnet
+//! are only subject to the global budget.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ipc_api::subnet_id::SubnetID;
+use ipc_observability::emit;
+use libp2p::PeerId;
+use libp2p_bitswap::BitswapServePolicy;
+use lru_time_cache::LruCache;
+
+use crate::behaviour::ContentConfig;
+use crate::observe;
+
+/// How long to remember which subnet a peer asked for content from.
+const PEER_SUBNET_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Label of the traffic which isn't attributed to a subnet with its own budget.
+const OTHER_LABEL: &str = "other";
+
+/// Number of bytes which can be served in a period, refilling continuously.
+///
+/// Serving can go into debt, because we only know the size of what we sent after the fact;
+/// nothing else is served until the debt is paid off.
+struct Budget {
+    bytes: u64,
+    period: Duration,
+    available: f64,
+    updated_at: Instant,
+}
+
+impl Budget {
+    fn new(bytes: u64, period: Duration, now: Instant) -> Self {
+        Self {
+            bytes,
+            period,
+            available: bytes as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let refill = self.bytes as f64 * elapsed.as_secs_f64() / self.period.as_secs_f64();
+        self.available = (self.available + refill).min(self.bytes as f64);
+        self.updated_at = now;
+    }
+
+    fn has_capacity(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.available > 0.0
+    }
+
+    fn consume(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.available -= bytes as f64;
+    }
+}
+
+/// Egress budgets, and the subnets peers are attributed to.
+pub struct Bandwidth {
+    global: Option<Budget>,
+    subnets: HashMap<SubnetID, Budget>,
+    priority_subnets: HashSet<SubnetID>,
+    peer_subnets: LruCache<PeerId, SubnetID>,
+    /// Subnets this node provides data for.
+    own_subnets: HashSet<SubnetID>,
+}
+
+impl Bandwidth {
+    pub fn new(config: &ContentConfig) -> Self {
+        Self::new_at(config, Instant::now())
+    }
+
+    fn new_at(config: &ContentConfig, now: Instant) -> Self {
+        let period = config.egress_period;
+        let enabled = |bytes: u64| bytes > 0 && !period.is_zero();
+
+        let global = Some(config.egress_bytes)
+            .filter(|bytes| enabled(*bytes))
+            .map(|bytes| Budget::new(bytes, period, now));
+
+        let subnets = config
+            .subnet_egress_bytes
+            .iter()
+            .filter(|(_, bytes)| enabled(**bytes))
+            .map(|(subnet_id, bytes)| (subnet_id.clone(), Budget::new(*bytes, period, now)))
+            .collect();
+
+        Self {
+            global,
+            subnets,
+            priority_subnets: config.priority_subnets.iter().cloned().collect(),
+            peer_subnets: LruCache::with_expiry_duration(PEER_SUBNET_TTL),
+            own_subnets: Default::default(),
+        }
+    }
+
+    /// Set the subnets this node provides data for; peers are only attributed to these.
+    pub fn set_own_subnets(&mut self, subnet_ids: impl IntoIterator<Item = SubnetID>) {
+        self.own_subnets = subnet_ids.into_iter().collect();
+    }
+
+    /// Remember the subnet a peer said it wants content from, if this node provides data for it.
+    pub fn set_peer_subnet(&mut self, peer_id: PeerId, subnet_id: Option<SubnetID>) {
+        match subnet_id {
+            Some(subnet_id) if self.own_subnets.contains(&subnet_id) => {
+                self.peer_subnets.insert(peer_id, subnet_id);
+            }
+            _ => {
+                self.peer_subnets.remove(&peer_id);
+            }
+        }
+    }
+
+    /// Check whether there is budget left to serve a peer.
+    pub fn try_serve(&mut self, peer_id: &PeerId) -> bool {
+        self.try_serve_at(peer_id, Instant::now())
+    }
+
+    fn try_serve_at(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        let subnet_id = self.attributed_subnet(peer_id);
+
+        let within_subnet = match subnet_id.as_ref().and_then(|id| self.subnets.get_mut(id)) {
+            Some(budget) => budget.has_capacity(now),
+            None => true,
+        };
+
+        let is_priority = subnet_id
+            .as_ref()
+            .map_or(false, |id| self.priority_subnets.contains(id));
+
+        let within_global = match self.global {
+            Some(ref mut budget) if !is_priority => budget.has_capacity(now),
+            _ => true,
+        };
+
+        let allowed = within_subnet && within_global;
+        if !allowed {
+            emit(observe::BandwidthFailureEvent::Throttled(
+                *peer_id,
+                self.label(subnet_id.as_ref()),
+            ));
+        }
+        allowed
+    }
+
+    /// Charge the bytes sent to a peer to the budgets it's subject to.
+    pub fn served(&mut self, peer_id: &PeerId, bytes: usize) {
+        self.served_at(peer_id, bytes, Instant::now())
+    }
+
+    fn served_at(&mut self, peer_id: &PeerId, bytes: usize, now: Instant) {
+        let subnet_id = self.attributed_subnet(peer_id);
+
+        if let Some(budget) = subnet_id.as_ref().and_then(|id| self.subnets.get_mut(id)) {
+            budget.consume(bytes, now);
+        }
+        if let Some(ref mut budget) = self.global {
+            budget.consume(bytes, now);
+        }
+
+        emit(observe::BandwidthEvent::Served(
+            *peer_id,
+            self.label(subnet_id.as_ref()),
+            bytes,
+        ));
+    }
+
+    /// The subnet a peer is attributed to, unless we stopped providing data for it since.
+    fn attributed_subnet(&mut self, peer_id: &PeerId) -> Option<SubnetID> {
+        let subnet_id = self.peer_subnets.get(peer_id).cloned()?;
+        self.own_subnets.contains(&subnet_id).then_some(subnet_id)
+    }
+
+    /// Label subnets which have their own budget or priority, and lump the rest together,
+    /// so the number of distinct values in the metrics stays bounded.
+    fn label(&self, subnet_id: Option<&SubnetID>) -> String {
+        match subnet_id {
+            Some(id) if self.subnets.contains_key(id) || self.priority_subnets.contains(id) => {
+                id.to_string()
+            }
+            _ => OTHER_LABEL.to_owned(),
+        }
+    }
+}
+
+/// [`Bandwidth`] shared between the [`content::Behaviour`](crate::behaviour::content::Behaviour)
+/// and the [`graph::Behaviour`](crate::behaviour::graph::Behaviour), so both are subject to
+/// the same budgets.
+#[derive(Clone)]
+pub struct SharedBandwidth(Arc<Mutex<Bandwidth>>);
+
+impl SharedBandwidth {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        Self(Arc::new(Mutex::new(bandwidth)))
+    }
+
+    pub fn set_own_subnets(&self, subnet_ids: impl IntoIterator<Item = SubnetID>) {
+        self.lock().set_own_subnets(subnet_ids)
+    }
+
+    pub fn set_peer_subnet(&self, peer_id: PeerId, subnet_id: Option<SubnetID>) {
+        self.lock().set_peer_subnet(peer_id, subnet_id)
+    }
+
+    pub fn try_serve(&self, peer_id: &PeerId) -> bool {
+        self.lock().try_serve(peer_id)
+    }
+
+    pub fn served(&self, peer_id: &PeerId, bytes: usize) {
+        self.lock().served(peer_id, bytes)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bandwidth> {
+        self.0.lock().expect("bandwidth lock poisoned")
+    }
+}
+
+impl BitswapServePolicy for SharedBandwidth {
+    fn allow(&mut self, peer_id: &PeerId) -> bool {
+        self.try_serve(peer_id)
+    }
+
+    fn served(&mut self, peer_id: &PeerId, bytes: usize) {
+        SharedBandwidth::served(self, peer_id, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ipc_api::subnet_id::SubnetID;
+    use libp2p::PeerId;
+
+    use super::Bandwidth;
+    use crate::behaviour::ContentConfig;
+
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    fn config(own: &SubnetID, other: &SubnetID) -> ContentConfig {
+        ContentConfig {
+            rate_limit_bytes: 0,
+            rate_limit_period: Duration::ZERO,
+            graph_max_response_bytes: 1024,
+            max_provided_bytes: 1024,
+            max_provided_cids: 10,
+            max_provide_ttl: Duration::from_secs(60),
+            max_resolved_bytes: 1024,
+            egress_bytes: 1000,
+            egress_period: PERIOD,
+            subnet_egress_bytes: [(other.clone(), 500)].into_iter().collect(),
+            priority_subnets: vec![own.clone()],
+        }
+    }
+
+    fn subnets() -> (SubnetID, SubnetID) {
+        (SubnetID::new_root(1), SubnetID::new_root(2))
+    }
+
+    #[test]
+    fn global_budget_refills() {
+        let (own, other) = subnets();
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth::new_at(&config(&own, &other), now);
+        let peer = PeerId::random();
+
+        assert!(bandwidth.try_serve_at(&peer, now));
+        bandwidth.served_at(&peer, 1500, now);
+        assert!(!bandwidth.try_serve_at(&peer, now), "in debt");
+        assert!(
+            !bandwidth.try_serve_at(&peer, now + PERIOD / 2),
+            "still in debt"
+        );
+        assert!(bandwidth.try_serve_at(&peer, now + PERIOD), "debt paid off");
+    }
+
+    #[test]
+    fn subnet_budget_is_separate() {
+        let (own, other) = subnets();
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth::new_at(&config(&own, &other), now);
+        let peer1 = PeerId::random();
+        let peer2 = PeerId::random();
+
+        bandwidth.set_own_subnets([own, other.clone()]);
+        bandwidth.set_peer_subnet(peer1, Some(other));
+        bandwidth.served_at(&peer1, 500, now);
+
+        assert!(!bandwidth.try_serve_at(&peer1, now), "subnet exhausted");
+        assert!(bandwidth.try_serve_at(&peer2, now), "global has capacity");
+
+        bandwidth.set_peer_subnet(peer1, None);
+        assert!(bandwidth.try_serve_at(&peer1, now), "no longer attributed");
+    }
+
+    #[test]
+    fn priority_subnet_is_not_starved() {
+        let (own, other) = subnets();
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth::new_at(&config(&own, &other), now);
+        let peer1 = PeerId::random();
+        let peer2 = PeerId::random();
+
+        bandwidth.set_own_subnets([own.clone()]);
+        bandwidth.set_peer_subnet(peer1, Some(own));
+        bandwidth.served_at(&peer2, 1000, now);
+
+        assert!(!bandwidth.try_serve_at(&peer2, now), "global exhausted");
+        assert!(
+            bandwidth.try_serve_at(&peer1, now),
+            "priority served anyway"
+        );
+
+        bandwidth.served_at(&peer1, 1000, now);
+        assert!(
+            !bandwidth.try_serve_at(&peer2, now + PERIOD),
+            "priority traffic is charged to the global budget"
+        );
+    }
+
+    #[test]
+    fn parent_validator_resolving_own_subnet_is_prioritised() {
+        let (own, other) = subnets();
+        let parent = SubnetID::new_root(3);
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth::new_at(&config(&own, &other), now);
+        let validator = PeerId::random();
+        let peer = PeerId::random();
+
+        bandwidth.set_own_subnets([own.clone()]);
+        bandwidth.served_at(&peer, 1000, now);
+
+        // The validator only provides the parent subnet, but resolves checkpoints of ours.
+        bandwidth.set_peer_subnet(validator, Some(own.clone()));
+        assert!(
+            bandwidth.try_serve_at(&validator, now),
+            "resolving our subnet"
+        );
+
+        // Subnets we don't provide data for are not attributed.
+        bandwidth.set_peer_subnet(validator, Some(parent));
+        assert!(!bandwidth.try_serve_at(&validator, now), "not our subnet");
+
+        bandwidth.set_peer_subnet(validator, Some(own.clone()));
+        bandwidth.set_own_subnets([other]);
+        assert!(
+            !bandwidth.try_serve_at(&validator, now),
+            "no longer our subnet"
+        );
+    }
+}
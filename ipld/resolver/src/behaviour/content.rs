@@ -8,9 +8,11 @@ use std::{
 };
 
 use crate::{
+    bandwidth::SharedBandwidth,
     limiter::{RateLimit, RateLimiter},
     observe,
 };
+use ipc_api::subnet_id::SubnetID;
 use ipc_observability::emit;
 use libipld::{store::StoreParams, Cid};
 use libp2p::{
@@ -23,17 +25,18 @@ use libp2p::{
     },
     Multiaddr, PeerId,
 };
-use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapResponse, BitswapStore};
+use libp2p_bitswap::{
+    Bitswap, BitswapConfig, BitswapEvent, BitswapResponse, BitswapStore, PeerResponse,
+};
 use log::debug;
 use prometheus::Registry;
 
 pub type QueryId = libp2p_bitswap::QueryId;
 
-// Not much to do here, just hiding the `Progress` event as I don't think we'll need it.
-// We can't really turn it into anything more meaningful; the outer Service, which drives
-// the Swarm events, will have to store the `QueryId` and figure out which CID it was about
-// (there could be multiple queries running over the same CID) and how to respond to the
-// original requestor (e.g. by completing a channel).
+// Not much to do here, just passing on the Bitswap events. We can't really turn them into
+// anything more meaningful; the outer Service, which drives the Swarm events, will have to
+// store the `QueryId` and figure out which CID it was about (there could be multiple queries
+// running over the same CID) and how to respond to the original requestor (e.g. by completing a channel).
 #[derive(Debug)]
 pub enum Event {
     /// Event raised when a resolution request is finished.
@@ -48,6 +51,14 @@ pub enum Event {
     /// whether a retry is necessary.
     Complete(QueryId, anyhow::Result<()>),
 
+    /// Event raised when a block was received during a resolution,
+    /// with the number of blocks known to be still missing.
+    Progress(QueryId, usize),
+
+    /// Event raised when a peer answered one of the requests of a resolution, or failed to,
+    /// so the service can keep score of how useful the peer is.
+    PeerResponse(QueryId, PeerId, PeerResponse),
+
     /// Event raised when we want to execute some logic with the `BitswapResponse`.
     /// This is only raised if we are tracking rate limits. The service has to
     /// do the forwarding between the two oneshot channels, and call this module
@@ -74,6 +85,33 @@ pub struct Config {
     ///
     /// 0 means no limit.
     pub rate_limit_period: Duration,
+    /// Maximum size of a sub-DAG sent or accepted in a single graph response.
+    pub graph_max_response_bytes: u32,
+    /// Maximum total size of a DAG the application can ask us to provide.
+    pub max_provided_bytes: u64,
+    /// Maximum number of root CIDs the application can ask us to provide at the same time.
+    pub max_provided_cids: usize,
+    /// Maximum time the application can ask us to provide a CID for, before it has to ask again.
+    pub max_provide_ttl: Duration,
+    /// Maximum total size of the blocks fetched when resolving content from its providers.
+    ///
+    /// Unlike subnet content, which is only resolved when the ledger asks for it, this is
+    /// up to the application, which shouldn't be able to fill up the store.
+    pub max_resolved_bytes: u64,
+    /// Total number of bytes served to remote peers in a time period, with Bitswap and
+    /// graph responses together.
+    ///
+    /// 0 means no limit.
+    pub egress_bytes: u64,
+    /// Length of the time period at which the egress budgets refill.
+    ///
+    /// 0 means no limit.
+    pub egress_period: Duration,
+    /// Number of bytes served in a time period to peers resolving content from specific subnets.
+    pub subnet_egress_bytes: HashMap<SubnetID, u64>,
+    /// Subnets whose content is served even if the total egress budget is exhausted,
+    /// typically the subnet the node belongs to.
+    pub priority_subnets: Vec<SubnetID>,
 }
 
 /// Behaviour built on [`Bitswap`] to resolve IPLD content from [`Cid`] to raw bytes.
@@ -90,11 +128,16 @@ pub struct Behaviour<P: StoreParams> {
 }
 
 impl<P: StoreParams> Behaviour<P> {
-    pub fn new<S>(config: Config, store: S) -> Self
+    pub fn new<S>(config: Config, store: S, bandwidth: SharedBandwidth) -> Self
     where
         S: BitswapStore<Params = P>,
     {
-        let bitswap = Bitswap::new(BitswapConfig::default(), store);
+        let bitswap_config = BitswapConfig {
+            report_peers: true,
+            ..Default::default()
+        };
+        let mut bitswap = Bitswap::new(bitswap_config, store);
+        bitswap.set_serve_policy(bandwidth);
         let rate_limit = if config.rate_limit_bytes == 0 || config.rate_limit_period.is_zero() {
             None
         } else {
@@ -145,6 +188,11 @@ impl<P: StoreParams> Behaviour<P> {
         self.inner.sync(cid, peers, [].into_iter())
     }
 
+    /// Stop a resolution; no [`Event::Complete`] is raised for it.
+    pub fn cancel(&mut self, query_id: QueryId) -> bool {
+        self.inner.cancel(query_id)
+    }
+
     /// Check whether the peer has already exhaused their rate limit.
     #[allow(dead_code)]
     fn check_rate_limit(&mut self, peer_id: &PeerId, cid: &Cid) -> bool {
@@ -178,6 +226,11 @@ impl<P: StoreParams> Behaviour<P> {
             self.rate_limit = Some(RateLimit::new(bytes, self.rate_limit_period))
         }
     }
+
+    /// Number of remote addresses whose consumption is tracked by the rate limiter.
+    pub fn rate_limited_addresses(&self) -> usize {
+        self.rate_limiter.num_keys()
+    }
 }
 
 impl<P: StoreParams> NetworkBehaviour for Behaviour<P> {
@@ -332,12 +385,19 @@ impl<P: StoreParams> NetworkBehaviour for Behaviour<P> {
             // debug!("BITSWAP POLL: {ev:?}");
             match ev {
                 ToSwarm::GenerateEvent(ev) => match ev {
-                    BitswapEvent::Progress(_, _) => {}
+                    BitswapEvent::Progress(id, missing) => {
+                        let out = Event::Progress(id, missing);
+                        return Poll::Ready(ToSwarm::GenerateEvent(out));
+                    }
                     BitswapEvent::Complete(id, result) => {
                         emit(observe::ResolveEvent::Completed);
                         let out = Event::Complete(id, result);
                         return Poll::Ready(ToSwarm::GenerateEvent(out));
                     }
+                    BitswapEvent::PeerResponse(query_id, peer_id, response) => {
+                        let out = Event::PeerResponse(query_id, peer_id, response);
+                        return Poll::Ready(ToSwarm::GenerateEvent(out));
+                    }
                 },
                 other => {
                     return Poll::Ready(other.map_out(|_| unreachable!("already handled")));
@@ -352,7 +412,7 @@ impl<P: StoreParams> NetworkBehaviour for Behaviour<P> {
 /// Get rid of parts of an address which are considered ephemeral,
 /// keeping just the parts which would stay the same if for example
 /// the same peer opened another connection from a different random port.
-fn select_non_ephemeral(mut addr: Multiaddr) -> Multiaddr {
+pub(crate) fn select_non_ephemeral(mut addr: Multiaddr) -> Multiaddr {
     let mut keep = Vec::new();
     while let Some(proto) = addr.pop() {
         match proto {
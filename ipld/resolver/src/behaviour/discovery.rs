@@ -3,18 +3,23 @@
 // SPDX-License-Identifier: MIT
 use std::{
     cmp,
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     task::{Context, Poll},
     time::Duration,
 };
 
 use super::NetworkConfig;
 use crate::observe;
+use anyhow::anyhow;
 use ipc_observability::emit;
+use libipld::Cid;
 use libp2p::{
     core::Endpoint,
     identify::Info,
-    kad::{self, store::MemoryStore},
+    kad::{
+        self,
+        store::{MemoryStore, RecordStore},
+    },
     multiaddr::Protocol,
     swarm::{
         behaviour::toggle::{Toggle, ToggleConnectionHandler},
@@ -38,8 +43,13 @@ pub enum Event {
 
     /// Event emitted when a peer is removed from the routing table.
     Removed(PeerId),
+
+    /// Event emitted when a provider lookup finished, with the peers found to provide the content.
+    Providers(QueryId, HashSet<PeerId>),
 }
 
+pub type QueryId = kad::QueryId;
+
 /// Configuration for [`discovery::Behaviour`].
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -85,6 +95,8 @@ pub struct Behaviour {
     lookup_interval: Interval,
     /// Buffer incoming identify requests until we have finished the bootstrap.
     bootstrap_buffer: Option<Vec<(PeerId, Info)>>,
+    /// Provider lookups which haven't reported their results yet.
+    provider_queries: HashSet<QueryId>,
     /// Events to return when polled.
     outbox: VecDeque<Event>,
 }
@@ -125,7 +137,8 @@ impl Behaviour {
             kad_config.set_protocol_names(vec![protocol_name.clone()]);
 
             // Disable inserting records into the memory store, so peers cannot send `PutRecord`
-            // messages to store content in the memory of our node.
+            // messages to store content in the memory of our node. Provider records are
+            // added manually, and the memory store limits how many of them we keep.
             kad_config.set_record_filtering(kad::StoreInserts::FilterBoth);
 
             let store = MemoryStore::new(local_peer_id);
@@ -165,6 +178,7 @@ impl Behaviour {
             protocol_name,
             inner: kademlia_opt.into(),
             lookup_interval: tokio::time::interval(Duration::from_secs(1)),
+            provider_queries: Default::default(),
             outbox,
             num_connections: 0,
             bootstrap_buffer,
@@ -182,6 +196,43 @@ impl Behaviour {
         }
     }
 
+    /// Announce to the peers closest to a CID that we can serve it.
+    ///
+    /// Kademlia keeps republishing the record until we stop providing it.
+    pub fn start_providing(&mut self, cid: &Cid) -> anyhow::Result<()> {
+        let kademlia = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| anyhow!("Kademlia is disabled"))?;
+
+        kademlia
+            .start_providing(kad::RecordKey::new(&cid.to_bytes()))
+            .map_err(|e| anyhow!("failed to provide {cid}: {e:?}"))?;
+
+        Ok(())
+    }
+
+    /// Stop announcing a CID; the records others have expire with time.
+    pub fn stop_providing(&mut self, cid: &Cid) {
+        if let Some(kademlia) = self.inner.as_mut() {
+            kademlia.stop_providing(&kad::RecordKey::new(&cid.to_bytes()));
+        }
+    }
+
+    /// Look for peers which announced that they can serve a CID.
+    ///
+    /// The result is returned as an [`Event::Providers`] with the same query ID.
+    pub fn find_providers(&mut self, cid: &Cid) -> anyhow::Result<QueryId> {
+        let kademlia = self
+            .inner
+            .as_mut()
+            .ok_or_else(|| anyhow!("Kademlia is disabled"))?;
+
+        let query_id = kademlia.get_providers(kad::RecordKey::new(&cid.to_bytes()));
+        self.provider_queries.insert(query_id);
+        Ok(query_id)
+    }
+
     /// Check if a peer has a user defined addresses.
     fn is_static(&self, peer_id: PeerId) -> bool {
         self.static_addresses.iter().any(|(id, _)| *id == peer_id)
@@ -217,6 +268,60 @@ impl Behaviour {
         }
     }
 
+    /// Add the addresses of peers we knew about before a restart to Kademlia,
+    /// and bootstrap from them, in case the static addresses are unavailable.
+    ///
+    /// Returns the peers which made it into the routing table.
+    pub fn restore_addresses(&mut self, peers: Vec<(PeerId, Vec<Multiaddr>)>) -> Vec<PeerId> {
+        let Some(kademlia) = self.inner.as_mut() else {
+            return Vec::new();
+        };
+
+        let mut restored = Vec::new();
+        for (peer_id, addresses) in peers {
+            if peer_id == self.peer_id {
+                continue;
+            }
+            let mut added = false;
+            for addr in addresses {
+                if let kad::RoutingUpdate::Success = kademlia.add_address(&peer_id, addr) {
+                    added = true;
+                }
+            }
+            if added {
+                restored.push(peer_id);
+            }
+        }
+
+        if !restored.is_empty() {
+            debug!("restored {} peers to the routing table", restored.len());
+            if kademlia.bootstrap().is_ok() && self.bootstrap_buffer.is_none() {
+                self.bootstrap_buffer = Some(Vec::new());
+            }
+        }
+
+        restored
+    }
+
+    /// List the peers in the routing table with their addresses,
+    /// and whether we are currently connected to them.
+    pub fn known_addresses(&mut self) -> Vec<(PeerId, Vec<Multiaddr>, bool)> {
+        let Some(kademlia) = self.inner.as_mut() else {
+            return Vec::new();
+        };
+
+        let mut known = Vec::new();
+        for bucket in kademlia.kbuckets() {
+            for entry in bucket.iter() {
+                let peer_id = *entry.node.key.preimage();
+                let addresses = entry.node.value.iter().cloned().collect();
+                let is_connected = matches!(entry.status, kad::NodeStatus::Connected);
+                known.push((peer_id, addresses, is_connected));
+            }
+        }
+        known
+    }
+
     fn addresses_of_peer(&mut self, peer_id: PeerId) -> Vec<Multiaddr> {
         self.handle_pending_outbound_connection(
             ConnectionId::new_unchecked(0),
@@ -368,11 +473,42 @@ impl NetworkBehaviour for Behaviour {
                         } => {
                             warn!("disallowed Kademlia requests from {source}",)
                         }
+                        kad::Event::InboundRequest {
+                            request:
+                                kad::InboundRequest::AddProvider {
+                                    record: Some(record),
+                                },
+                        } => {
+                            if let Some(k) = self.inner.as_mut() {
+                                if let Err(e) = k.store_mut().add_provider(record) {
+                                    debug!("failed to add provider record: {e}");
+                                }
+                            }
+                        }
                         // Information only.
                         kad::Event::InboundRequest { .. } => {}
                         kad::Event::ModeChanged { .. } => {}
-                        // Finish bootstrapping.
-                        kad::Event::OutboundQueryProgressed { result, step, .. } => match result {
+                        // Finish bootstrapping and provider lookups.
+                        kad::Event::OutboundQueryProgressed {
+                            id, result, step, ..
+                        } => match result {
+                            // Report the first peers found to have the content, there is no need to wait for all of them.
+                            // The query keeps running, which keeps the addresses it found available for dialing them.
+                            kad::QueryResult::GetProviders(Ok(
+                                kad::GetProvidersOk::FoundProviders { providers, .. },
+                            )) if !providers.is_empty() => {
+                                if self.provider_queries.remove(&id) {
+                                    self.outbox.push_back(Event::Providers(id, providers));
+                                }
+                            }
+                            kad::QueryResult::GetProviders(result) if step.last => {
+                                if self.provider_queries.remove(&id) {
+                                    if let Err(e) = result {
+                                        debug!("provider lookup failed: {e}");
+                                    }
+                                    self.outbox.push_back(Event::Providers(id, HashSet::new()));
+                                }
+                            }
                             kad::QueryResult::Bootstrap(result) if step.last => {
                                 debug!("Bootstrapping finished with {result:?}");
                                 if let Some(buffer) = self.bootstrap_buffer.take() {
@@ -415,6 +551,11 @@ impl NetworkBehaviour for Behaviour {
             }
         }
 
+        // Kademlia events may have added to the outbox, which we must not leave until we are woken up again.
+        if let Some(ev) = self.outbox.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(ev));
+        }
+
         Poll::Pending
     }
 }
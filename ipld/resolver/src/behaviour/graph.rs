@@ -0,0 +1,1162 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Graphsync-style resolution of whole sub-DAGs in a single exchange.
+//!
+//! Bitswap asks for one block per round trip, walking the DAG with `missing_blocks` between them,
+//! which is slow for deep DAGs such as the messages of a checkpoint. Instead, this protocol sends
+//! a root CID with a [`GraphSelector`] and the provider streams back all the selected blocks it has
+//! in a single response, up to a size limit. Whatever is left can still be resolved with Bitswap.
+//!
+//! It's only modelled on Graphsync: it isn't wire compatible with it, and the selectors are not IPLD selectors.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use fvm_ipld_encoding::strict_bytes;
+use ipc_api::subnet_id::SubnetID;
+use ipc_observability::emit;
+use libipld::{store::StoreParams, Block, Cid};
+use libp2p::{
+    core::{ConnectedPoint, Endpoint},
+    futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    request_response::{
+        self, OutboundFailure, OutboundRequestId, ProtocolSupport, ResponseChannel,
+    },
+    swarm::{
+        derive_prelude::FromSwarm, ConnectionDenied, ConnectionId, NetworkBehaviour, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+use libp2p_bitswap::BitswapStore;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::content::{select_non_ephemeral, Config};
+use super::SharedStore;
+use crate::{
+    bandwidth::SharedBandwidth,
+    limiter::{RateLimit, RateLimiter},
+    observe,
+};
+
+pub type RequestId = OutboundRequestId;
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/ipc/ipld/graph/1.0.0");
+
+/// Requests only carry a CID, a selector and a subnet ID.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// Bytes counted towards the size limit for each block on top of its CID and data,
+/// to account for the encoding of the response.
+const BLOCK_OVERHEAD: usize = 16;
+
+/// Responses can be large, so allow more time than for a Bitswap request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of requests being traversed on blocking threads at the same time.
+const MAX_TRAVERSALS: usize = 16;
+
+/// Maximum number of requests of a single peer being traversed at the same time.
+const MAX_TRAVERSALS_PER_PEER: usize = 2;
+
+/// Find the links in a block, so the traversal can follow them.
+///
+/// It's a function pointer so the codec bounds it needs don't spread to every type using the behaviour.
+pub(crate) type References<P> = fn(&Block<P>, &mut Vec<Cid>) -> libipld::Result<()>;
+
+/// Determines which blocks under the root to send.
+///
+/// This is not an IPLD selector: there is no selector DSL, only a fixed set of traversals
+/// which only follow links, without looking at the fields of the nodes. It's an enum so
+/// new kinds of selection can be added without changing the protocol.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphSelector {
+    /// The root and every block reachable from it through links,
+    /// up to a maximum depth, where the root is at depth 0.
+    All { depth: Option<u32> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphRequest {
+    pub root: Cid,
+    pub selector: GraphSelector,
+    /// Maximum size of the response the requestor is willing to accept.
+    pub max_bytes: u32,
+    /// The subnet the requestor is resolving the content from, if any,
+    /// so the provider can account for it in its egress budgets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet_id: Option<SubnetID>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphBlock {
+    pub cid: Cid,
+    #[serde(with = "strict_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum GraphResponse {
+    /// The selected blocks the provider has, in breadth-first order.
+    ///
+    /// It's incomplete if the provider is missing some of the blocks
+    /// or they would not fit into the response.
+    Blocks {
+        blocks: Vec<GraphBlock>,
+        complete: bool,
+    },
+    /// The requestor exhausted its rate limit, or the provider its egress budget,
+    /// or the provider is busy serving other requests.
+    RateLimited,
+}
+
+/// Events emitted by the [`graph::Behaviour`] about the requests we sent.
+#[derive(Debug)]
+pub enum Event {
+    /// The blocks sent by the peer have been verified and stored.
+    Received {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        blocks: usize,
+        bytes: usize,
+        complete: bool,
+        elapsed: Duration,
+    },
+    /// The peer sent a block which didn't match its CID, or which wasn't selected
+    /// by the request; none of the blocks were stored.
+    InvalidBlock {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        bytes: usize,
+        elapsed: Duration,
+    },
+    /// The peer doesn't support the protocol.
+    Unsupported {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+    },
+    /// The peer could not be dialed, or the connection closed before it answered.
+    Unreachable {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+    },
+    /// The request failed for some other reason, e.g. a timeout or rate limiting.
+    Failed {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        error: String,
+    },
+}
+
+/// Work done on a blocking thread, with the outcome sent back to the behaviour.
+enum StoreResult {
+    Traversed {
+        peer_id: PeerId,
+        channel: ResponseChannel<GraphResponse>,
+        result: anyhow::Result<(Vec<GraphBlock>, bool)>,
+    },
+    Inserted {
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        blocks: usize,
+        bytes: usize,
+        complete: bool,
+        result: anyhow::Result<()>,
+    },
+}
+
+/// Length-prefixed DAG-CBOR messages.
+#[derive(Clone)]
+pub struct GraphCodec {
+    max_response_size: usize,
+}
+
+#[async_trait]
+impl request_response::Codec for GraphCodec {
+    type Protocol = StreamProtocol;
+    type Request = GraphRequest;
+    type Response = GraphResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_REQUEST_SIZE).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, self.max_response_size).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &res).await
+    }
+}
+
+async fn read_message<T, M>(io: &mut T, max_size: usize) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes exceeds the limit of {max_size}"),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    io.read_exact(&mut buf).await?;
+    fvm_ipld_encoding::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_message<T, M>(io: &mut T, msg: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let buf = fvm_ipld_encoding::to_vec(msg)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let len = u32::try_from(buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message too large"))?;
+    io.write_all(&len.to_be_bytes()).await?;
+    io.write_all(&buf).await?;
+    io.close().await
+}
+
+/// Behaviour to request and serve selected sub-DAGs.
+pub struct Behaviour<P: StoreParams> {
+    inner: request_response::Behaviour<GraphCodec>,
+    store: SharedStore<P>,
+    references: References<P>,
+    /// Maximum size of the responses we send and accept.
+    max_response_bytes: u32,
+    /// The requests we sent, to check the responses against.
+    sent: HashMap<OutboundRequestId, SentRequest>,
+    /// Number of requests being traversed for each peer.
+    traversals: HashMap<PeerId, usize>,
+    /// Results of the blocking store operations.
+    result_tx: mpsc::UnboundedSender<StoreResult>,
+    result_rx: mpsc::UnboundedReceiver<StoreResult>,
+    /// Remember which address peers connected from, so we can apply the rate limit
+    /// on the address, the same way as [`content::Behaviour`] does.
+    peer_addresses: HashMap<PeerId, Multiaddr>,
+    /// Limit the amount of data served by remote address.
+    rate_limiter: RateLimiter<Multiaddr>,
+    rate_limit_period: Duration,
+    rate_limit: Option<RateLimit>,
+    /// Egress budgets shared with [`content::Behaviour`].
+    bandwidth: SharedBandwidth,
+}
+
+impl<P: StoreParams> Behaviour<P> {
+    pub fn new(
+        config: &Config,
+        store: SharedStore<P>,
+        references: References<P>,
+        bandwidth: SharedBandwidth,
+    ) -> Self {
+        let codec = GraphCodec {
+            max_response_size: config.graph_max_response_bytes as usize + MAX_REQUEST_SIZE,
+        };
+        let inner = request_response::Behaviour::with_codec(
+            codec,
+            std::iter::once((PROTOCOL, ProtocolSupport::Full)),
+            request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT),
+        );
+        let (result_tx, result_rx) = mpsc::unbounded_channel();
+
+        Self {
+            inner,
+            store,
+            references,
+            max_response_bytes: config.graph_max_response_bytes,
+            sent: Default::default(),
+            traversals: Default::default(),
+            result_tx,
+            result_rx,
+            peer_addresses: Default::default(),
+            rate_limiter: RateLimiter::new(config.rate_limit_period),
+            rate_limit_period: config.rate_limit_period,
+            rate_limit: make_rate_limit(config.rate_limit_bytes, config.rate_limit_period),
+            bandwidth,
+        }
+    }
+
+    /// Ask a peer for everything under a root CID, telling it which subnet we expect it from.
+    pub fn resolve(
+        &mut self,
+        peer_id: PeerId,
+        root: Cid,
+        subnet_id: Option<SubnetID>,
+    ) -> OutboundRequestId {
+        let request = GraphRequest {
+            root,
+            selector: GraphSelector::All { depth: None },
+            max_bytes: self.max_response_bytes,
+            subnet_id,
+        };
+        let sent = SentRequest {
+            root: request.root,
+            selector: request.selector.clone(),
+            at: Instant::now(),
+        };
+        let request_id = self.inner.send_request(&peer_id, request);
+        self.sent.insert(request_id, sent);
+        request_id
+    }
+
+    /// Update the rate limit to a new value, keeping the period as-is.
+    pub fn update_rate_limit(&mut self, bytes: u32) {
+        self.rate_limit = make_rate_limit(bytes, self.rate_limit_period);
+    }
+
+    /// The egress budgets, shared with the Bitswap serve policy.
+    pub fn bandwidth(&self) -> &SharedBandwidth {
+        &self.bandwidth
+    }
+
+    /// Number of remote addresses whose consumption is tracked by the rate limiter.
+    pub fn rate_limited_addresses(&self) -> usize {
+        self.rate_limiter.num_keys()
+    }
+
+    fn elapsed(&mut self, request_id: &OutboundRequestId) -> Duration {
+        self.sent
+            .remove(request_id)
+            .map(|sent| sent.at.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Collect the selected blocks on a blocking thread.
+    ///
+    /// Peers which exhausted their rate limit, or would have too many requests traversed
+    /// at the same time, are turned away before doing any work for them.
+    fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        request: GraphRequest,
+        channel: ResponseChannel<GraphResponse>,
+    ) {
+        // Bitswap requests from the same peer are attributed to this subnet as well,
+        // if the peer's signed provider record shows it's one of its subnets.
+        self.bandwidth
+            .set_peer_subnet(peer_id, request.subnet_id.clone());
+
+        let busy = self.traversals.values().sum::<usize>() >= MAX_TRAVERSALS
+            || self.traversals.get(&peer_id).copied().unwrap_or_default()
+                >= MAX_TRAVERSALS_PER_PEER;
+
+        if busy || !self.check_rate_limit(&peer_id, 1) || !self.bandwidth.try_serve(&peer_id) {
+            emit(observe::GraphFailureEvent::RateLimited(peer_id));
+            if self
+                .inner
+                .send_response(channel, GraphResponse::RateLimited)
+                .is_err()
+            {
+                debug!("could not send graph response to {peer_id}; the connection is closed");
+            }
+            return;
+        }
+
+        *self.traversals.entry(peer_id).or_default() += 1;
+
+        let mut store = self.store.clone();
+        let references = self.references;
+        let max_bytes = request.max_bytes.min(self.max_response_bytes) as usize;
+        let result_tx = self.result_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = traverse(&mut store, references, &request, max_bytes);
+            let _ = result_tx.send(StoreResult::Traversed {
+                peer_id,
+                channel,
+                result,
+            });
+        });
+    }
+
+    /// Verify the blocks and insert them into the store on a blocking thread.
+    ///
+    /// The blocks have to be the ones selected by the request, in the order the provider
+    /// finds them, so a provider can't make us store anything else.
+    fn handle_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        response: GraphResponse,
+    ) -> Option<Event> {
+        let (blocks, complete) = match response {
+            GraphResponse::Blocks { blocks, complete } => (blocks, complete),
+            GraphResponse::RateLimited => {
+                self.sent.remove(&request_id);
+                return Some(Event::Failed {
+                    request_id,
+                    peer_id,
+                    error: "rate limited".to_owned(),
+                });
+            }
+        };
+
+        let bytes = blocks.iter().map(|b| b.data.len()).sum::<usize>();
+        let Some(sent) = self.sent.get(&request_id) else {
+            return Some(Event::Failed {
+                request_id,
+                peer_id,
+                error: "response to an unknown request".to_owned(),
+            });
+        };
+        let blocks = match blocks
+            .into_iter()
+            .map(|b| Block::<P>::new(b.cid, b.data))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|blocks| {
+                check_selected(
+                    self.references,
+                    sent.root,
+                    &sent.selector,
+                    &blocks,
+                    complete,
+                )?;
+                Ok(blocks)
+            }) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                debug!("invalid block from {peer_id}: {e}");
+                let elapsed = self.elapsed(&request_id);
+                return Some(Event::InvalidBlock {
+                    request_id,
+                    peer_id,
+                    bytes,
+                    elapsed,
+                });
+            }
+        };
+
+        let mut store = self.store.clone();
+        let result_tx = self.result_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = blocks.iter().try_for_each(|block| store.insert(block));
+            let _ = result_tx.send(StoreResult::Inserted {
+                request_id,
+                peer_id,
+                blocks: blocks.len(),
+                bytes,
+                complete,
+                result,
+            });
+        });
+
+        None
+    }
+
+    /// Send the response to a request, unless the peer has exhausted its rate limit.
+    fn handle_traversed(
+        &mut self,
+        peer_id: PeerId,
+        channel: ResponseChannel<GraphResponse>,
+        result: anyhow::Result<(Vec<GraphBlock>, bool)>,
+    ) {
+        if let Some(count) = self.traversals.get_mut(&peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.traversals.remove(&peer_id);
+            }
+        }
+
+        let response = match result {
+            Err(e) => {
+                warn!("failed to collect blocks for {peer_id}: {e}");
+                GraphResponse::Blocks {
+                    blocks: Vec::new(),
+                    complete: false,
+                }
+            }
+            Ok((blocks, complete)) => {
+                let bytes = blocks.iter().map(|b| b.data.len()).sum::<usize>();
+                if self.check_rate_limit(&peer_id, bytes) {
+                    self.bandwidth.served(&peer_id, bytes);
+                    emit(observe::GraphEvent::Served(peer_id, blocks.len()));
+                    GraphResponse::Blocks { blocks, complete }
+                } else {
+                    emit(observe::GraphFailureEvent::RateLimited(peer_id));
+                    GraphResponse::RateLimited
+                }
+            }
+        };
+        if self.inner.send_response(channel, response).is_err() {
+            debug!("could not send graph response to {peer_id}; the connection is closed");
+        }
+    }
+
+    /// Check whether serving some bytes to a peer fits into its rate limit.
+    fn check_rate_limit(&mut self, peer_id: &PeerId, bytes: usize) -> bool {
+        if let Some(ref rate_limit) = self.rate_limit {
+            if let Some(addr) = self.peer_addresses.get(peer_id).cloned() {
+                let bytes = bytes.try_into().unwrap_or(u32::MAX);
+                return self.rate_limiter.add(rate_limit, addr, bytes);
+            }
+        }
+        true
+    }
+
+    fn handle_inserted(
+        &mut self,
+        request_id: OutboundRequestId,
+        peer_id: PeerId,
+        blocks: usize,
+        bytes: usize,
+        complete: bool,
+        result: anyhow::Result<()>,
+    ) -> Event {
+        let elapsed = self.elapsed(&request_id);
+        match result {
+            Ok(()) => {
+                emit(observe::GraphEvent::Received(peer_id, blocks));
+                Event::Received {
+                    request_id,
+                    peer_id,
+                    blocks,
+                    bytes,
+                    complete,
+                    elapsed,
+                }
+            }
+            Err(e) => Event::Failed {
+                request_id,
+                peer_id,
+                error: format!("failed to store blocks: {e}"),
+            },
+        }
+    }
+}
+
+/// A request we sent, waiting for its response.
+struct SentRequest {
+    root: Cid,
+    selector: GraphSelector,
+    at: Instant,
+}
+
+/// Check that the blocks in a response are the ones the selector selects under the root,
+/// in the breadth-first order [`traverse`] collects them.
+///
+/// The provider may have skipped blocks it doesn't have, and stopped at its size limit,
+/// but then the response can't be complete.
+fn check_selected<P: StoreParams>(
+    references: References<P>,
+    root: Cid,
+    selector: &GraphSelector,
+    blocks: &[Block<P>],
+    complete: bool,
+) -> anyhow::Result<()> {
+    let GraphSelector::All { depth: max_depth } = *selector;
+
+    let mut blocks = blocks.iter().peekable();
+    let mut skipped = false;
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([(root, 0u32)]);
+
+    while let Some((cid, depth)) = queue.pop_front() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let Some(block) = blocks.next_if(|b| *b.cid() == cid) else {
+            skipped = true;
+            continue;
+        };
+        if max_depth.map_or(true, |max| depth < max) {
+            let mut links = Vec::new();
+            references(block, &mut links)?;
+            queue.extend(links.into_iter().map(|link| (link, depth + 1)));
+        }
+    }
+
+    if let Some(block) = blocks.next() {
+        bail!("block {} is not selected under {root}", block.cid());
+    }
+    if skipped && complete {
+        bail!("response under {root} is missing blocks but claims to be complete");
+    }
+    Ok(())
+}
+
+fn make_rate_limit(bytes: u32, period: Duration) -> Option<RateLimit> {
+    if bytes == 0 || period.is_zero() {
+        None
+    } else {
+        Some(RateLimit::new(bytes, period))
+    }
+}
+
+/// Collect the blocks selected under the root, breadth first, until the size limit is reached.
+///
+/// Returns the blocks and whether the selection is complete.
+fn traverse<S: BitswapStore>(
+    store: &mut S,
+    references: References<S::Params>,
+    request: &GraphRequest,
+    max_bytes: usize,
+) -> anyhow::Result<(Vec<GraphBlock>, bool)> {
+    let GraphSelector::All { depth: max_depth } = request.selector;
+
+    let mut blocks = Vec::new();
+    let mut size = 0;
+    let mut complete = true;
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([(request.root, 0u32)]);
+
+    while let Some((cid, depth)) = queue.pop_front() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let Some(data) = store.get(&cid)? else {
+            complete = false;
+            continue;
+        };
+        let block_size = BLOCK_OVERHEAD + cid.to_bytes().len() + data.len();
+        if size + block_size > max_bytes {
+            complete = false;
+            break;
+        }
+        size += block_size;
+
+        let block = Block::<S::Params>::new_unchecked(cid, data);
+        if max_depth.map_or(true, |max| depth < max) {
+            let mut links = Vec::new();
+            if let Err(e) = references(&block, &mut links) {
+                debug!("failed to find links in {cid}: {e}");
+                complete = false;
+            }
+            queue.extend(links.into_iter().map(|link| (link, depth + 1)));
+        }
+        let (cid, data) = block.into_inner();
+        blocks.push(GraphBlock { cid, data });
+    }
+
+    Ok((blocks, complete))
+}
+
+/// Sum up the size of the whole DAG under the root, which must be fully present in the store.
+///
+/// Fails as soon as the total goes over the limit, so an unexpectedly large DAG isn't walked in full.
+pub(crate) fn dag_size<S: BitswapStore>(
+    store: &mut S,
+    references: References<S::Params>,
+    root: Cid,
+    max_bytes: u64,
+) -> anyhow::Result<u64> {
+    let mut size = 0u64;
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([root]);
+
+    while let Some(cid) = queue.pop_front() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let data = store
+            .get(&cid)?
+            .ok_or_else(|| anyhow!("block {cid} under {root} is missing"))?;
+
+        size += data.len() as u64;
+        if size > max_bytes {
+            bail!("DAG under {root} is larger than the limit of {max_bytes} bytes");
+        }
+
+        let block = Block::<S::Params>::new_unchecked(cid, data);
+        let mut links = Vec::new();
+        references(&block, &mut links)?;
+        queue.extend(links);
+    }
+
+    Ok(size)
+}
+
+/// Collect all the blocks of the DAG under the root, which must be fully present in the store.
+pub(crate) fn collect_dag<S: BitswapStore>(
+    store: &mut S,
+    references: References<S::Params>,
+    root: Cid,
+    max_bytes: u64,
+) -> anyhow::Result<Vec<GraphBlock>> {
+    let max_bytes: usize = max_bytes.try_into().unwrap_or(usize::MAX);
+    let request = GraphRequest {
+        root,
+        selector: GraphSelector::All { depth: None },
+        max_bytes: max_bytes.try_into().unwrap_or(u32::MAX),
+        subnet_id: None,
+    };
+    let (blocks, complete) = traverse(store, references, &request, max_bytes)?;
+    if !complete {
+        bail!("DAG under {root} is incomplete or larger than the limit of {max_bytes} bytes");
+    }
+    Ok(blocks)
+}
+
+impl<P: StoreParams> NetworkBehaviour for Behaviour<P> {
+    type ConnectionHandler =
+        <request_response::Behaviour<GraphCodec> as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = Event;
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        // Store the remote address, the same way as `content::Behaviour`.
+        match &event {
+            FromSwarm::ConnectionEstablished(c) => {
+                if c.other_established == 0 {
+                    let peer_addr = match c.endpoint {
+                        ConnectedPoint::Dialer { address, .. } => address.clone(),
+                        ConnectedPoint::Listener { send_back_addr, .. } => {
+                            select_non_ephemeral(send_back_addr.clone())
+                        }
+                    };
+                    self.peer_addresses.insert(c.peer_id, peer_addr);
+                }
+            }
+            FromSwarm::ConnectionClosed(c) => {
+                if c.remaining_established == 0 {
+                    self.peer_addresses.remove(&c.peer_id);
+                }
+            }
+            _ => {}
+        }
+
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        // Finish the work done on the blocking threads.
+        while let Poll::Ready(Some(result)) = self.result_rx.poll_recv(cx) {
+            match result {
+                StoreResult::Traversed {
+                    peer_id,
+                    channel,
+                    result,
+                } => self.handle_traversed(peer_id, channel, result),
+                StoreResult::Inserted {
+                    request_id,
+                    peer_id,
+                    blocks,
+                    bytes,
+                    complete,
+                    result,
+                } => {
+                    let ev =
+                        self.handle_inserted(request_id, peer_id, blocks, bytes, complete, result);
+                    return Poll::Ready(ToSwarm::GenerateEvent(ev));
+                }
+            }
+        }
+
+        while let Poll::Ready(ev) = self.inner.poll(cx) {
+            let ev = match ev {
+                ToSwarm::GenerateEvent(ev) => ev,
+                other => {
+                    return Poll::Ready(other.map_out(|_| unreachable!("already handled")));
+                }
+            };
+            match ev {
+                request_response::Event::Message { peer, message } => match message {
+                    request_response::Message::Request {
+                        request, channel, ..
+                    } => self.handle_request(peer, request, channel),
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    } => {
+                        if let Some(ev) = self.handle_response(request_id, peer, response) {
+                            return Poll::Ready(ToSwarm::GenerateEvent(ev));
+                        }
+                    }
+                },
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    self.sent.remove(&request_id);
+                    let ev = match error {
+                        OutboundFailure::UnsupportedProtocols => {
+                            emit(observe::GraphFailureEvent::Unsupported(peer));
+                            Event::Unsupported {
+                                request_id,
+                                peer_id: peer,
+                            }
+                        }
+                        OutboundFailure::DialFailure | OutboundFailure::ConnectionClosed => {
+                            Event::Unreachable {
+                                request_id,
+                                peer_id: peer,
+                            }
+                        }
+                        error => Event::Failed {
+                            request_id,
+                            peer_id: peer,
+                            error: error.to_string(),
+                        },
+                    };
+                    return Poll::Ready(ToSwarm::GenerateEvent(ev));
+                }
+                request_response::Event::InboundFailure { peer, error, .. } => {
+                    debug!("failed to serve graph request from {peer}: {error}");
+                }
+                request_response::Event::ResponseSent { .. } => {}
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+    use libipld::Block;
+
+    use super::{
+        check_selected, collect_dag, dag_size, traverse, GraphRequest, GraphSelector,
+        BLOCK_OVERHEAD,
+    };
+    use crate::behaviour::SharedStore;
+    use libp2p_bitswap::BitswapStore;
+
+    #[derive(Default)]
+    struct TestStore(std::collections::HashMap<libipld::Cid, Vec<u8>>);
+
+    impl BitswapStore for TestStore {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &libipld::Cid) -> libipld::Result<bool> {
+            Ok(self.0.contains_key(cid))
+        }
+        fn get(&mut self, cid: &libipld::Cid) -> libipld::Result<Option<Vec<u8>>> {
+            Ok(self.0.get(cid).cloned())
+        }
+        fn insert(&mut self, block: &Block<Self::Params>) -> libipld::Result<()> {
+            self.0.insert(*block.cid(), block.data().to_vec());
+            Ok(())
+        }
+        fn missing_blocks(&mut self, cid: &libipld::Cid) -> libipld::Result<Vec<libipld::Cid>> {
+            let mut missing = Vec::new();
+            let mut visited = std::collections::HashSet::new();
+            let mut queue = std::collections::VecDeque::from([*cid]);
+            while let Some(cid) = queue.pop_front() {
+                if !visited.insert(cid) {
+                    continue;
+                }
+                match self.0.get(&cid) {
+                    Some(data) => {
+                        let block = Block::<DefaultParams>::new_unchecked(cid, data.clone());
+                        block.references(&mut queue)?;
+                    }
+                    None => missing.push(cid),
+                }
+            }
+            Ok(missing)
+        }
+    }
+
+    /// Build a chain of blocks where each one links to the previous, returning the CIDs from the head.
+    fn make_chain(store: &mut SharedStore<DefaultParams>, len: usize) -> Vec<libipld::Cid> {
+        let mut cids = Vec::new();
+        for i in 0..len {
+            let node = match cids.last() {
+                None => ipld!({ "n": i as u64 }),
+                Some(prev) => ipld!({ "n": i as u64, "prev": *prev }),
+            };
+            let block = Block::<DefaultParams>::encode(DagCborCodec, Code::Blake2b256, &node)
+                .expect("encode");
+            store.insert(&block).expect("insert");
+            cids.push(*block.cid());
+        }
+        cids.reverse();
+        cids
+    }
+
+    fn request(root: libipld::Cid, depth: Option<u32>) -> GraphRequest {
+        GraphRequest {
+            root,
+            selector: GraphSelector::All { depth },
+            max_bytes: u32::MAX,
+            subnet_id: None,
+        }
+    }
+
+    #[test]
+    fn traverse_selects_blocks() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 5);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+
+        let (blocks, complete) =
+            traverse(&mut store, references, &request(cids[0], None), usize::MAX).unwrap();
+        assert!(complete);
+        assert_eq!(blocks.iter().map(|b| b.cid).collect::<Vec<_>>(), cids);
+
+        let (blocks, complete) = traverse(
+            &mut store,
+            references,
+            &request(cids[0], Some(2)),
+            usize::MAX,
+        )
+        .unwrap();
+        assert!(complete);
+        assert_eq!(blocks.len(), 3);
+
+        // Only room for the root.
+        let max_bytes = BLOCK_OVERHEAD + cids[0].to_bytes().len() + blocks[0].data.len();
+        let (blocks, complete) =
+            traverse(&mut store, references, &request(cids[0], None), max_bytes).unwrap();
+        assert!(!complete);
+        assert_eq!(blocks.len(), 1);
+    }
+
+    #[test]
+    fn traverse_reports_missing_blocks() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 3);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+
+        let mut partial = SharedStore::new(TestStore::default());
+        for cid in cids.iter().take(2) {
+            let data = store.get(cid).unwrap().unwrap();
+            partial.insert(&Block::new_unchecked(*cid, data)).unwrap();
+        }
+
+        let (blocks, complete) = traverse(
+            &mut partial,
+            references,
+            &request(cids[0], None),
+            usize::MAX,
+        )
+        .unwrap();
+        assert!(!complete);
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn dag_size_checks_limit() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 3);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+
+        let total = cids
+            .iter()
+            .map(|cid| store.get(cid).unwrap().unwrap().len() as u64)
+            .sum::<u64>();
+
+        assert_eq!(
+            dag_size(&mut store, references, cids[0], u64::MAX).unwrap(),
+            total
+        );
+        assert!(dag_size(&mut store, references, cids[0], total - 1).is_err());
+
+        let mut partial = SharedStore::new(TestStore::default());
+        let data = store.get(&cids[0]).unwrap().unwrap();
+        partial
+            .insert(&Block::new_unchecked(cids[0], data))
+            .unwrap();
+        assert!(dag_size(&mut partial, references, cids[0], u64::MAX).is_err());
+    }
+
+    #[test]
+    fn collect_dag_requires_all_blocks() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 3);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+
+        let blocks = collect_dag(&mut store, references, cids[0], u64::MAX).unwrap();
+        assert_eq!(blocks.iter().map(|b| b.cid).collect::<Vec<_>>(), cids);
+        assert!(collect_dag(&mut store, references, cids[0], 10).is_err());
+
+        let mut partial = SharedStore::new(TestStore::default());
+        let data = store.get(&cids[0]).unwrap().unwrap();
+        partial
+            .insert(&Block::new_unchecked(cids[0], data))
+            .unwrap();
+        assert!(collect_dag(&mut partial, references, cids[0], u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_store_finds_missing_blocks() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 3);
+        assert!(store.missing_blocks(&cids[0]).unwrap().is_empty());
+
+        let mut partial = SharedStore::new(TestStore::default());
+        let data = store.get(&cids[0]).unwrap().unwrap();
+        partial
+            .insert(&Block::new_unchecked(cids[0], data))
+            .unwrap();
+        assert_eq!(partial.missing_blocks(&cids[0]).unwrap(), vec![cids[1]]);
+    }
+
+    #[test]
+    fn check_selected_accepts_traversal() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 5);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+
+        for depth in [None, Some(2)] {
+            let request = request(cids[0], depth);
+            let (blocks, complete) =
+                traverse(&mut store, references, &request, usize::MAX).unwrap();
+            let blocks = to_blocks(blocks);
+            assert!(
+                check_selected(references, cids[0], &request.selector, &blocks, complete).is_ok()
+            );
+        }
+
+        // Cut short by the size limit.
+        let (blocks, complete) =
+            traverse(&mut store, references, &request(cids[0], None), 200).unwrap();
+        assert!(!complete);
+        let blocks = to_blocks(blocks);
+        let selector = GraphSelector::All { depth: None };
+        assert!(check_selected(references, cids[0], &selector, &blocks, false).is_ok());
+        assert!(check_selected(references, cids[0], &selector, &blocks, true).is_err());
+    }
+
+    #[test]
+    fn check_selected_rejects_unselected_blocks() {
+        let mut store = SharedStore::new(TestStore::default());
+        let cids = make_chain(&mut store, 3);
+        let references =
+            |b: &Block<DefaultParams>, links: &mut Vec<libipld::Cid>| b.references(links);
+        let blocks = cids
+            .iter()
+            .map(|cid| Block::new_unchecked(*cid, store.get(cid).unwrap().unwrap()))
+            .collect::<Vec<Block<DefaultParams>>>();
+        let selector = GraphSelector::All { depth: None };
+
+        // A block which isn't under the root at all.
+        let other = Block::encode(DagCborCodec, Code::Blake2b256, &ipld!({ "other": true }))
+            .expect("encode");
+        let mut injected = blocks.clone();
+        injected.push(other);
+        assert!(check_selected(references, cids[0], &selector, &injected, false).is_err());
+
+        // The right blocks in the wrong order.
+        let reordered = vec![blocks[0].clone(), blocks[2].clone(), blocks[1].clone()];
+        assert!(check_selected(references, cids[0], &selector, &reordered, false).is_err());
+
+        // Blocks beyond the selected depth.
+        let shallow = GraphSelector::All { depth: Some(1) };
+        assert!(check_selected(references, cids[0], &shallow, &blocks, false).is_err());
+
+        // Skipping a block the provider doesn't have is fine, as long as it's not complete.
+        let partial = vec![blocks[0].clone()];
+        assert!(check_selected(references, cids[0], &selector, &partial, false).is_ok());
+        assert!(check_selected(references, cids[0], &selector, &partial, true).is_err());
+    }
+
+    fn to_blocks(blocks: Vec<super::GraphBlock>) -> Vec<Block<DefaultParams>> {
+        blocks
+            .into_iter()
+            .map(|b| Block::new(b.cid, b.data).unwrap())
+            .collect()
+    }
+}
@@ -1,24 +1,26 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: MIT
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::marker::PhantomData;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use super::NetworkConfig;
+use crate::erasure::ErasureChunk;
 use crate::hash::blake2b_256;
 use crate::observe;
 use crate::provider_cache::{ProviderDelta, SubnetProviderCache};
 use crate::provider_record::{ProviderRecord, SignedProviderRecord};
-use crate::vote_record::{SignedVoteRecord, VoteRecord};
+use crate::vote_record::SignedVoteRecord;
 use crate::Timestamp;
 use anyhow::anyhow;
 use ipc_api::subnet_id::SubnetID;
 use ipc_observability::emit;
 use libp2p::core::Endpoint;
 use libp2p::gossipsub::{
-    self, IdentTopic, MessageAuthenticity, MessageId, PublishError, Sha256Topic, SubscriptionError,
-    Topic, TopicHash,
+    self, IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId, PublishError, Sha256Topic,
+    SubscriptionError, Topic, TopicHash,
 };
 use libp2p::identity::Keypair;
 use libp2p::swarm::derive_prelude::FromSwarm;
@@ -38,6 +40,8 @@ const PUBSUB_MEMBERSHIP: &str = "/ipc/membership";
 const PUBSUB_VOTES: &str = "/ipc/ipld/votes";
 /// `Gossipsub` topic identifier for pre-emptively published blocks of data.
 const PUBSUB_PREEMPTIVE: &str = "/ipc/ipld/pre-emptive";
+/// `Gossipsub` topic identifier for erasure coded chunks of content.
+const PUBSUB_CHUNKS: &str = "/ipc/ipld/chunks";
 
 /// Events emitted by the [`membership::Behaviour`] behaviour.
 #[derive(Debug)]
@@ -53,11 +57,20 @@ pub enum Event<V> {
     /// to trigger a lookup by the discovery module to learn the address.
     Skipped(PeerId),
 
-    /// We received a [`VoteRecord`] in one of the subnets we are providing data for.
-    ReceivedVote(Box<VoteRecord<V>>),
+    /// We received a [`SignedVoteRecord`] in one of the subnets we are providing data for.
+    ///
+    /// The signed envelope is kept so it can be used as evidence, e.g. of equivocation.
+    ReceivedVote(Box<SignedVoteRecord<V>>),
 
     /// We received preemptive data published in a subnet we were interested in.
     ReceivedPreemptive(SubnetID, Vec<u8>),
+
+    /// We received a valid erasure coded chunk of content in a subnet we provide data for or pinned,
+    /// published by one of the providers of the subnet.
+    ReceivedChunk(PeerId, Box<ErasureChunk>),
+
+    /// A peer forwarded a message to us which failed validation.
+    InvalidMessage(PeerId),
 }
 
 /// Configuration for [`membership::Behaviour`].
@@ -104,8 +117,14 @@ pub struct Behaviour<V> {
     voting_topics: HashSet<TopicHash>,
     /// Remember which subnet a topic was about.
     preemptive_topics: HashMap<TopicHash, SubnetID>,
+    /// Whether to subscribe to erasure coded chunks.
+    chunks_enabled: bool,
+    /// Chunk topics we are subscribed to, with the subnet they are about.
+    chunk_topics: HashMap<TopicHash, SubnetID>,
     /// Caching the latest state of subnet providers.
     provider_cache: SubnetProviderCache,
+    /// The signed records behind the entries of the provider cache, so they can be persisted.
+    provider_records: HashMap<PeerId, SignedProviderRecord>,
     /// Interval between publishing the currently supported subnets.
     ///
     /// This acts like a heartbeat; if a peer doesn't publish its snapshot for a long time,
@@ -139,6 +158,8 @@ where
             let s = blake2b_256(&msg.data);
             MessageId::from(s)
         });
+        // Only forward messages after we checked them, so Gossipsub can penalize peers spreading invalid ones.
+        gossipsub_config.validate_messages();
 
         let gossipsub_config = gossipsub_config
             .build()
@@ -152,7 +173,7 @@ where
 
         gossipsub
             .with_peer_score(
-                scoring::build_peer_score_params(membership_topic.clone()),
+                scoring::build_peer_score_params(&membership_topic),
                 scoring::build_peer_score_thresholds(),
             )
             .map_err(ConfigError::InvalidGossipsubConfig)?;
@@ -176,7 +197,10 @@ where
             subnet_ids: Default::default(),
             voting_topics: Default::default(),
             preemptive_topics: Default::default(),
+            chunks_enabled: false,
+            chunk_topics: Default::default(),
             provider_cache,
+            provider_records: Default::default(),
             publish_interval: interval,
             min_time_between_publish: mc.min_time_between_publish,
             last_publish_timestamp: Timestamp::default(),
@@ -246,6 +270,12 @@ where
     fn voting_subscribe(&mut self, subnet_id: &SubnetID) -> Result<(), SubscriptionError> {
         let topic = self.voting_topic(subnet_id);
         self.subscribe(&topic)?;
+        if let Err(e) = self
+            .inner
+            .set_topic_params(topic.clone(), scoring::build_topic_score_params())
+        {
+            warn!("failed to set score parameters of {topic}: {e}");
+        }
         self.voting_topics.insert(topic.hash());
         Ok(())
     }
@@ -258,6 +288,69 @@ where
         Ok(())
     }
 
+    /// Construct the topic used to gossip erasure coded chunks of content.
+    ///
+    /// Replaces "/" with "_" to avoid clashes from prefix/suffix overlap.
+    fn chunk_topic(&self, subnet_id: &SubnetID) -> Sha256Topic {
+        Topic::new(format!(
+            "{}/{}/{}",
+            PUBSUB_CHUNKS,
+            self.network_name.replace('/', "_"),
+            subnet_id.to_string().replace('/', "_")
+        ))
+    }
+
+    /// Subscribe to the chunk topic of a subnet, if chunks are enabled and we aren't already.
+    fn chunk_subscribe(&mut self, subnet_id: &SubnetID) -> Result<(), SubscriptionError> {
+        let topic = self.chunk_topic(subnet_id);
+        if !self.chunks_enabled || self.chunk_topics.contains_key(&topic.hash()) {
+            return Ok(());
+        }
+        self.subscribe(&topic)?;
+        if let Err(e) = self
+            .inner
+            .set_topic_params(topic.clone(), scoring::build_topic_score_params())
+        {
+            warn!("failed to set score parameters of {topic}: {e}");
+        }
+        self.chunk_topics.insert(topic.hash(), subnet_id.clone());
+        Ok(())
+    }
+
+    /// Unsubscribe from the chunk topic of a subnet, unless we still provide data for it or pinned it.
+    fn chunk_unsubscribe(&mut self, subnet_id: &SubnetID) -> anyhow::Result<()> {
+        let is_pinned = self
+            .provider_cache
+            .pinned_subnets()
+            .any(|id| id == subnet_id);
+
+        if is_pinned || self.subnet_ids.contains(subnet_id) {
+            return Ok(());
+        }
+        let topic = self.chunk_topic(subnet_id);
+        if self.chunk_topics.remove(&topic.hash()).is_some() {
+            self.unsubscribe(&topic)?;
+        }
+        Ok(())
+    }
+
+    /// Start receiving erasure coded chunks in the subnets we provide data for or pinned,
+    /// so both the validators of a child subnet and the ones of its parent get them.
+    pub fn enable_chunks(&mut self) -> Result<(), SubscriptionError> {
+        self.chunks_enabled = true;
+        let subnet_ids = self
+            .subnet_ids
+            .iter()
+            .chain(self.provider_cache.pinned_subnets())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for subnet_id in subnet_ids {
+            self.chunk_subscribe(&subnet_id)?;
+        }
+        Ok(())
+    }
+
     /// Set all the currently supported subnet IDs, then publish the updated list.
     pub fn set_provided_subnets(&mut self, subnet_ids: Vec<SubnetID>) -> anyhow::Result<()> {
         let old_subnet_ids = std::mem::take(&mut self.subnet_ids);
@@ -274,15 +367,27 @@ where
             }
         }
         self.subnet_ids = subnet_ids;
+        for subnet_id in old_subnet_ids.iter() {
+            self.chunk_unsubscribe(subnet_id)?;
+        }
+        for subnet_id in self.subnet_ids.clone() {
+            self.chunk_subscribe(&subnet_id)?;
+        }
         self.publish_membership()
     }
 
+    /// The subnets this node provides data for.
+    pub fn provided_subnets(&self) -> &[SubnetID] {
+        &self.subnet_ids
+    }
+
     /// Add a subnet to the list of supported subnets, then publish the updated list.
     pub fn add_provided_subnet(&mut self, subnet_id: SubnetID) -> anyhow::Result<()> {
         if self.subnet_ids.contains(&subnet_id) {
             return Ok(());
         }
         self.voting_subscribe(&subnet_id)?;
+        self.chunk_subscribe(&subnet_id)?;
         self.subnet_ids.push(subnet_id);
         self.publish_membership()
     }
@@ -294,6 +399,7 @@ where
         }
         self.voting_unsubscribe(&subnet_id)?;
         self.subnet_ids.retain(|id| id != &subnet_id);
+        self.chunk_unsubscribe(&subnet_id)?;
         self.publish_membership()
     }
 
@@ -305,6 +411,7 @@ where
     /// crowded out during the initial phase of bootstrapping the network.
     pub fn pin_subnet(&mut self, subnet_id: SubnetID) -> Result<(), SubscriptionError> {
         self.preemptive_subscribe(subnet_id.clone())?;
+        self.chunk_subscribe(&subnet_id)?;
         self.provider_cache.pin_subnet(subnet_id);
         Ok(())
     }
@@ -313,6 +420,7 @@ where
     pub fn unpin_subnet(&mut self, subnet_id: &SubnetID) -> anyhow::Result<()> {
         self.preemptive_unsubscribe(subnet_id)?;
         self.provider_cache.unpin_subnet(subnet_id);
+        self.chunk_unsubscribe(subnet_id)?;
         Ok(())
     }
 
@@ -379,6 +487,24 @@ where
         }
     }
 
+    /// Publish an erasure coded chunk to the topic of the subnet it belongs to.
+    pub fn publish_chunk(&mut self, chunk: &ErasureChunk) -> anyhow::Result<()> {
+        let topic = self.chunk_topic(&chunk.subnet_id);
+        let data = chunk.to_bytes()?;
+        match self.inner.publish(topic, data) {
+            Err(e) => {
+                emit(observe::MembershipFailureEvent::PublishFailure(
+                    e.to_string(),
+                ));
+                Err(anyhow!(e))
+            }
+            Ok(_msg_id) => {
+                emit(observe::MembershipEvent::PublishSuccess);
+                Ok(())
+            }
+        }
+    }
+
     /// Mark a peer as routable in the cache.
     ///
     /// Call this method when the discovery service learns the address of a peer.
@@ -395,9 +521,30 @@ where
     /// Call this method when the discovery service forgets the address of a peer.
     pub fn set_unroutable(&mut self, peer_id: PeerId) {
         self.provider_cache.set_unroutable(peer_id);
+        self.provider_records.remove(&peer_id);
         self.outbox.push_back(Event::Removed(peer_id))
     }
 
+    /// The latest signed provider records of the peers in the cache.
+    pub fn provider_records(&self) -> Vec<SignedProviderRecord> {
+        self.provider_records.values().cloned().collect()
+    }
+
+    /// Add provider records we knew about before a restart to the cache,
+    /// the same way as if we received them over Gossipsub.
+    ///
+    /// The peers have to be routable, otherwise the records are skipped.
+    pub fn restore_provider_records(&mut self, records: Vec<SignedProviderRecord>) {
+        for record in records {
+            self.handle_provider_record(record);
+        }
+    }
+
+    /// The current state of the provider cache.
+    pub fn provider_cache(&self) -> &SubnetProviderCache {
+        &self.provider_cache
+    }
+
     /// List the current providers of a subnet.
     ///
     /// Call this method when looking for a peer to resolve content from.
@@ -409,31 +556,79 @@ where
     /// then raise domain event to let the rest of the application know about a
     /// provider. Also update all the book keeping in the behaviour that we use
     /// to answer future queries about the topic.
-    fn handle_message(&mut self, msg: gossipsub::Message) {
+    ///
+    /// Returns whether the message should be forwarded to other peers.
+    fn handle_message(&mut self, msg: gossipsub::Message) -> MessageAcceptance {
         if msg.topic == self.membership_topic.hash() {
-            match SignedProviderRecord::from_bytes(&msg.data).map(|r| r.into_record()) {
-                Ok(record) => self.handle_provider_record(record),
-                Err(e) => emit(
-                    observe::MembershipFailureEvent::GossipInvalidProviderRecord(
-                        msg.source,
-                        e.to_string(),
-                    ),
-                ),
+            match SignedProviderRecord::from_bytes(&msg.data) {
+                Ok(record) => {
+                    self.handle_provider_record(record);
+                    MessageAcceptance::Accept
+                }
+                Err(e) => {
+                    emit(
+                        observe::MembershipFailureEvent::GossipInvalidProviderRecord(
+                            msg.source,
+                            e.to_string(),
+                        ),
+                    );
+                    MessageAcceptance::Reject
+                }
             }
         } else if self.voting_topics.contains(&msg.topic) {
-            match SignedVoteRecord::from_bytes(&msg.data).map(|r| r.into_record()) {
-                Ok(record) => self.handle_vote_record(record),
-                Err(e) => emit(observe::MembershipFailureEvent::GossipInvalidVoteRecord(
-                    msg.source,
-                    e.to_string(),
-                )),
+            match SignedVoteRecord::from_bytes(&msg.data) {
+                Ok(record) => {
+                    self.handle_vote_record(record);
+                    MessageAcceptance::Accept
+                }
+                Err(e) => {
+                    emit(observe::MembershipFailureEvent::GossipInvalidVoteRecord(
+                        msg.source,
+                        e.to_string(),
+                    ));
+                    MessageAcceptance::Reject
+                }
             }
         } else if let Some(subnet_id) = self.preemptive_topics.get(&msg.topic) {
-            self.handle_preemptive_data(subnet_id.clone(), msg.data)
+            // The data is opaque to us, there's nothing to validate.
+            self.handle_preemptive_data(subnet_id.clone(), msg.data);
+            MessageAcceptance::Accept
+        } else if let Some(subnet_id) = self.chunk_topics.get(&msg.topic).cloned() {
+            // Messages are signed, so the source is the peer who published the chunk.
+            let publisher = msg
+                .source
+                .filter(|peer_id| self.provider_cache.is_provider(&subnet_id, peer_id));
+            match (ErasureChunk::from_bytes(&msg.data), publisher) {
+                (Ok(chunk), Some(publisher)) if chunk.subnet_id == subnet_id => {
+                    self.outbox
+                        .push_back(Event::ReceivedChunk(publisher, Box::new(chunk)));
+                    MessageAcceptance::Accept
+                }
+                (Ok(chunk), None) if chunk.subnet_id == subnet_id => {
+                    // We may not have their provider record yet, so don't penalize them.
+                    debug!("ignoring chunk in {subnet_id} from a peer not providing for it");
+                    MessageAcceptance::Ignore
+                }
+                (Ok(chunk), _) => {
+                    emit(observe::MembershipFailureEvent::GossipInvalidChunk(
+                        msg.source,
+                        format!("chunk of {} published to {subnet_id}", chunk.subnet_id),
+                    ));
+                    MessageAcceptance::Reject
+                }
+                (Err(e), _) => {
+                    emit(observe::MembershipFailureEvent::GossipInvalidChunk(
+                        msg.source,
+                        e.to_string(),
+                    ));
+                    MessageAcceptance::Reject
+                }
+            }
         } else {
             emit(observe::MembershipFailureEvent::GossipUnknownTopic(
                 msg.source, msg.topic,
             ));
+            MessageAcceptance::Ignore
         }
     }
 
@@ -441,17 +636,23 @@ where
     ///
     /// If this is the first time we receive a record from the peer,
     /// reciprocate by publishing our own.
-    fn handle_provider_record(&mut self, record: ProviderRecord) {
+    fn handle_provider_record(&mut self, signed_record: SignedProviderRecord) {
+        let record = signed_record.record();
+        let peer_id = record.peer_id;
         debug!("received provider record: {record:?}");
-        let (event, publish) = match self.provider_cache.add_provider(&record) {
+        let (event, publish) = match self.provider_cache.add_provider(record) {
             None => {
-                emit(observe::MembershipEvent::Skipped(record.peer_id));
-                (Some(Event::Skipped(record.peer_id)), false)
+                emit(observe::MembershipEvent::Skipped(peer_id));
+                (Some(Event::Skipped(peer_id)), false)
             }
-            Some(d) if d.is_empty() && !d.is_new => (None, false),
             Some(d) => {
-                let publish = d.is_new;
-                (Some(Event::Updated(record.peer_id, d)), publish)
+                self.keep_provider_record(signed_record);
+                if d.is_empty() && !d.is_new {
+                    (None, false)
+                } else {
+                    let publish = d.is_new;
+                    (Some(Event::Updated(peer_id, d)), publish)
+                }
             }
         };
 
@@ -460,13 +661,27 @@ where
         }
 
         if publish {
-            emit(observe::MembershipEvent::Added(record.peer_id));
-            self.publish_for_new_peer(record.peer_id)
+            emit(observe::MembershipEvent::Added(peer_id));
+            self.publish_for_new_peer(peer_id)
+        }
+    }
+
+    /// Remember the signed record of a provider, unless we already have a newer one.
+    fn keep_provider_record(&mut self, signed_record: SignedProviderRecord) {
+        match self.provider_records.entry(signed_record.record().peer_id) {
+            Entry::Occupied(mut e) => {
+                if e.get().record().timestamp < signed_record.record().timestamp {
+                    e.insert(signed_record);
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(signed_record);
+            }
         }
     }
 
     /// Raise an event to tell we received a new vote.
-    fn handle_vote_record(&mut self, record: VoteRecord<V>) {
+    fn handle_vote_record(&mut self, record: SignedVoteRecord<V>) {
         self.outbox.push_back(Event::ReceivedVote(Box::new(record)))
     }
 
@@ -515,6 +730,7 @@ where
         let cutoff_timestamp = Timestamp::now() - self.max_provider_age;
         let pruned = self.provider_cache.prune_providers(cutoff_timestamp);
         for peer_id in pruned {
+            self.provider_records.remove(&peer_id);
             emit(observe::MembershipEvent::Removed(peer_id));
             self.outbox.push_back(Event::Removed(peer_id))
         }
@@ -633,8 +849,22 @@ where
                         gossipsub::Event::GossipsubNotSupported { peer_id } => {
                             debug!("peer {peer_id} doesn't support gossipsub");
                         }
-                        gossipsub::Event::Message { message, .. } => {
-                            self.handle_message(message);
+                        gossipsub::Event::Message {
+                            propagation_source,
+                            message_id,
+                            message,
+                        } => {
+                            let acceptance = self.handle_message(message);
+                            if matches!(acceptance, MessageAcceptance::Reject) {
+                                self.outbox
+                                    .push_back(Event::InvalidMessage(propagation_source));
+                            }
+                            // The result only tells whether the message was still in the cache.
+                            let _ = self.inner.report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                acceptance,
+                            );
                         }
                     }
                 }
@@ -654,14 +884,26 @@ mod scoring {
 
     use libp2p::gossipsub::{IdentTopic, PeerScoreParams, PeerScoreThresholds, TopicScoreParams};
 
-    pub fn build_peer_score_params(membership_topic: IdentTopic) -> PeerScoreParams {
+    pub fn build_peer_score_params(membership_topic: &IdentTopic) -> PeerScoreParams {
         let mut params = PeerScoreParams::default();
         params
             .topics
-            .insert(membership_topic.hash(), TopicScoreParams::default());
+            .insert(membership_topic.hash(), build_topic_score_params());
         params
     }
 
+    /// Penalize peers which forward invalid messages, but not the ones which deliver
+    /// few of them: votes and membership records are published sporadically, so
+    /// a quiet mesh is normal on these topics.
+    pub fn build_topic_score_params() -> TopicScoreParams {
+        TopicScoreParams {
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            invalid_message_deliveries_weight: -10.0,
+            ..Default::default()
+        }
+    }
+
     pub fn build_peer_score_thresholds() -> PeerScoreThresholds {
         PeerScoreThresholds::default()
     }
@@ -1,23 +1,31 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: MIT
-use libipld::store::StoreParams;
+use std::sync::{Arc, Mutex};
+
+use libipld::{store::StoreParams, Block, Cid};
 use libp2p::{
+    allow_block_list::{self, BlockedPeers},
     connection_limits::{self, ConnectionLimits},
     identify,
     identity::{Keypair, PublicKey},
-    ping,
+    ping, relay,
     swarm::NetworkBehaviour,
     PeerId,
 };
 use libp2p_bitswap::BitswapStore;
 
+use crate::bandwidth::{Bandwidth, SharedBandwidth};
+
 pub mod content;
 pub mod discovery;
+pub mod graph;
 pub mod membership;
+pub mod nat;
 
 pub use content::Config as ContentConfig;
 pub use discovery::Config as DiscoveryConfig;
 pub use membership::Config as MembershipConfig;
+pub use nat::Config as NatConfig;
 use serde::{de::DeserializeOwned, Serialize};
 
 #[derive(Clone, Debug)]
@@ -43,6 +51,10 @@ pub enum ConfigError {
     Discovery(#[from] discovery::ConfigError),
     #[error("Error in the membership configuration")]
     Membership(#[from] membership::ConfigError),
+    #[error("Error in the NAT traversal configuration")]
+    Nat(#[from] nat::ConfigError),
+    #[error("Error in the erasure coding configuration")]
+    Erasure(#[from] crate::erasure::ConfigError),
 }
 
 /// Libp2p behaviour bundle to manage content resolution from other subnets, using:
@@ -50,6 +62,8 @@ pub enum ConfigError {
 /// * Kademlia for peer discovery
 /// * Gossipsub to advertise subnet membership
 /// * Bitswap to resolve CIDs
+/// * Graph requests to resolve whole sub-DAGs from peers supporting it
+/// * Optionally AutoNAT, relays and hole punching to reach peers behind NAT
 #[derive(NetworkBehaviour)]
 pub struct Behaviour<P, V>
 where
@@ -60,7 +74,10 @@ where
     discovery: discovery::Behaviour,
     membership: membership::Behaviour<V>,
     content: content::Behaviour<P>,
+    graph: graph::Behaviour<P>,
     connection_limits: connection_limits::Behaviour,
+    block_list: allow_block_list::Behaviour<BlockedPeers>,
+    nat: nat::Behaviour,
 }
 
 // Unfortunately by using `#[derive(NetworkBehaviour)]` we cannot easily inspects events
@@ -74,17 +91,20 @@ where
     P: StoreParams,
     V: Serialize + DeserializeOwned,
 {
-    pub fn new<S>(
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         nc: NetworkConfig,
         dc: DiscoveryConfig,
         mc: MembershipConfig,
         cc: ContentConfig,
+        natc: NatConfig,
+        relay_client: Option<relay::client::Behaviour>,
         limits: ConnectionLimits,
-        store: S,
-    ) -> Result<Self, ConfigError>
-    where
-        S: BitswapStore<Params = P>,
-    {
+        store: SharedStore<P>,
+        references: graph::References<P>,
+    ) -> Result<Self, ConfigError> {
+        let local_peer_id = nc.local_peer_id();
+        let bandwidth = SharedBandwidth::new(Bandwidth::new(&cc));
         Ok(Self {
             ping: Default::default(),
             identify: identify::Behaviour::new(identify::Config::new(
@@ -93,8 +113,11 @@ where
             )),
             discovery: discovery::Behaviour::new(nc.clone(), dc)?,
             membership: membership::Behaviour::new(nc, mc)?,
-            content: content::Behaviour::new(cc, store),
+            graph: graph::Behaviour::new(&cc, store.clone(), references, bandwidth.clone()),
+            content: content::Behaviour::new(cc, store, bandwidth),
             connection_limits: connection_limits::Behaviour::new(limits),
+            block_list: Default::default(),
+            nat: nat::Behaviour::new(local_peer_id, &natc, relay_client),
         })
     }
 
@@ -109,4 +132,56 @@ where
     pub fn content_mut(&mut self) -> &mut content::Behaviour<P> {
         &mut self.content
     }
+
+    pub fn graph_mut(&mut self) -> &mut graph::Behaviour<P> {
+        &mut self.graph
+    }
+
+    pub fn block_list_mut(&mut self) -> &mut allow_block_list::Behaviour<BlockedPeers> {
+        &mut self.block_list
+    }
+}
+
+/// Store shared between the [`content::Behaviour`], the [`graph::Behaviour`] and the service.
+///
+/// Every call locks the underlying store, which is fine as they are all short,
+/// and the stores are typically thread safe handles to a database anyway.
+pub struct SharedStore<P: StoreParams>(Arc<Mutex<dyn BitswapStore<Params = P>>>);
+
+impl<P: StoreParams> SharedStore<P> {
+    pub fn new<S>(store: S) -> Self
+    where
+        S: BitswapStore<Params = P>,
+    {
+        Self(Arc::new(Mutex::new(store)))
+    }
+}
+
+impl<P: StoreParams> Clone for SharedStore<P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<P: StoreParams> BitswapStore for SharedStore<P> {
+    type Params = P;
+
+    fn contains(&mut self, cid: &Cid) -> libipld::Result<bool> {
+        self.0.lock().expect("store lock poisoned").contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> libipld::Result<Option<Vec<u8>>> {
+        self.0.lock().expect("store lock poisoned").get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<P>) -> libipld::Result<()> {
+        self.0.lock().expect("store lock poisoned").insert(block)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> libipld::Result<Vec<Cid>> {
+        self.0
+            .lock()
+            .expect("store lock poisoned")
+            .missing_blocks(cid)
+    }
 }
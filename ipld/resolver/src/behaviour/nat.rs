@@ -0,0 +1,211 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! NAT traversal, so that peers which cannot be dialled directly can still take part in resolution.
+//!
+//! * AutoNAT asks the peers we connect to to dial us back, to find out whether we are publicly reachable.
+//! * Circuit relay v2 lets a peer behind NAT reserve a slot on a public relay and be dialled through it.
+//! * DCUtR uses a relayed connection to coordinate a simultaneous dial, upgrading it to a direct one.
+use std::time::Duration;
+
+use libp2p::{
+    autonat,
+    core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version},
+    dcutr,
+    futures::future::Either,
+    identity::Keypair,
+    multiaddr::Protocol,
+    noise, relay,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    yamux, Multiaddr, PeerId, Transport,
+};
+
+/// Configuration for NAT traversal.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Find out whether we are publicly reachable by asking peers to dial us back.
+    pub enable_autonat: bool,
+    /// Relay connections for peers which are not publicly reachable.
+    pub enable_relay_server: bool,
+    /// Dial peers through relays, which is necessary to reach peers behind NAT.
+    pub enable_relay_client: bool,
+    /// Relays to reserve a slot on, so that peers can reach us through them.
+    ///
+    /// The addresses must end with a `/p2p/<peer-id>` part. Requires `enable_relay_client`.
+    pub relay_addresses: Vec<Multiaddr>,
+    /// Try to upgrade relayed connections to direct ones with hole punching.
+    ///
+    /// Requires `enable_relay_client`.
+    pub enable_hole_punching: bool,
+    /// Maximum duration of a connection we relay for others.
+    pub relay_max_circuit_duration: Duration,
+    /// Maximum number of bytes we relay on a single connection for others.
+    pub relay_max_circuit_bytes: u64,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("relay addresses require the relay client to be enabled")]
+    RelayClientDisabled,
+    #[error("hole punching requires the relay client to be enabled")]
+    HolePunchingWithoutRelay,
+    #[error("invalid relay address: {0}")]
+    InvalidRelayAddress(Multiaddr),
+    #[error("error setting up the relay transport: {0}")]
+    Noise(#[from] noise::Error),
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.enable_relay_client {
+            if !self.relay_addresses.is_empty() {
+                return Err(ConfigError::RelayClientDisabled);
+            }
+            if self.enable_hole_punching {
+                return Err(ConfigError::HolePunchingWithoutRelay);
+            }
+        }
+        for addr in self.relay_addresses.iter() {
+            if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(ConfigError::InvalidRelayAddress(addr.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses to listen on to accept connections relayed through our relays.
+    pub fn relay_listen_addresses(&self) -> impl Iterator<Item = Multiaddr> + '_ {
+        self.relay_addresses
+            .iter()
+            .map(|addr| addr.clone().with(Protocol::P2pCircuit))
+    }
+}
+
+/// The optional NAT traversal behaviours, each of which can be turned off.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    autonat: Toggle<autonat::Behaviour>,
+    relay: Toggle<relay::Behaviour>,
+    relay_client: Toggle<relay::client::Behaviour>,
+    dcutr: Toggle<dcutr::Behaviour>,
+}
+
+impl Behaviour {
+    /// Create the behaviours enabled in the config.
+    ///
+    /// The relay client behaviour is created along with its transport by [`with_relay_client`].
+    pub fn new(
+        local_peer_id: PeerId,
+        config: &Config,
+        relay_client: Option<relay::client::Behaviour>,
+    ) -> Self {
+        let autonat = config
+            .enable_autonat
+            .then(|| autonat::Behaviour::new(local_peer_id, Default::default()));
+
+        let relay = config.enable_relay_server.then(|| {
+            let relay_config = relay::Config {
+                max_circuit_duration: config.relay_max_circuit_duration,
+                max_circuit_bytes: config.relay_max_circuit_bytes,
+                ..Default::default()
+            };
+            relay::Behaviour::new(local_peer_id, relay_config)
+        });
+
+        let dcutr = config
+            .enable_hole_punching
+            .then(|| dcutr::Behaviour::new(local_peer_id));
+
+        Self {
+            autonat: autonat.into(),
+            relay: relay.into(),
+            relay_client: relay_client.into(),
+            dcutr: dcutr.into(),
+        }
+    }
+}
+
+/// Extend a transport with the ability to dial and listen through relays,
+/// returning the behaviour which drives the relay client.
+///
+/// Relayed connections are always secured with Noise, end to end, regardless of
+/// what the underlying transport uses, since the relay must not be able to see into them.
+pub fn with_relay_client(
+    transport: Boxed<(PeerId, StreamMuxerBox)>,
+    local_key: &Keypair,
+) -> Result<(Boxed<(PeerId, StreamMuxerBox)>, relay::client::Behaviour), ConfigError> {
+    let (relay_transport, relay_client) = relay::client::new(local_key.public().to_peer_id());
+
+    let relay_transport = relay_transport
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(local_key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+
+    let transport = relay_transport
+        .or_transport(transport)
+        .map(|either, _| match either {
+            Either::Left(output) => output,
+            Either::Right(output) => output,
+        })
+        .boxed();
+
+    Ok((transport, relay_client))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libp2p::{Multiaddr, PeerId};
+
+    use super::{Config, ConfigError};
+
+    fn config() -> Config {
+        Config {
+            enable_autonat: true,
+            enable_relay_server: false,
+            enable_relay_client: true,
+            relay_addresses: Vec::new(),
+            enable_hole_punching: true,
+            relay_max_circuit_duration: Duration::from_secs(120),
+            relay_max_circuit_bytes: 1 << 17,
+        }
+    }
+
+    #[test]
+    fn validate_config() {
+        let relay_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/26655/p2p/{}", PeerId::random())
+            .parse()
+            .unwrap();
+
+        let mut c = config();
+        c.relay_addresses = vec![relay_addr.clone()];
+        assert!(c.validate().is_ok());
+        assert_eq!(
+            c.relay_listen_addresses().collect::<Vec<_>>(),
+            vec![format!("{relay_addr}/p2p-circuit").parse().unwrap()]
+        );
+
+        c.relay_addresses = vec!["/ip4/127.0.0.1/tcp/26655".parse().unwrap()];
+        assert!(matches!(
+            c.validate(),
+            Err(ConfigError::InvalidRelayAddress(_))
+        ));
+
+        let mut c = config();
+        c.enable_relay_client = false;
+        assert!(matches!(
+            c.validate(),
+            Err(ConfigError::HolePunchingWithoutRelay)
+        ));
+
+        let mut c = config();
+        c.enable_relay_client = false;
+        c.enable_hole_punching = false;
+        c.relay_addresses = vec![relay_addr];
+        assert!(matches!(
+            c.validate(),
+            Err(ConfigError::RelayClientDisabled)
+        ));
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: MIT
 use anyhow::anyhow;
+use std::time::Duration;
+
 use async_trait::async_trait;
 use ipc_api::subnet_id::SubnetID;
 use libipld::Cid;
@@ -8,7 +10,8 @@ use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
 
 use crate::{
-    service::{Request, ResolveResult},
+    info::ResolverInfo,
+    service::{ProgressCallback, Request, ResolveResult},
     vote_record::SignedVoteRecord,
 };
 
@@ -83,6 +86,68 @@ impl<V> Client<V> {
         let req = Request::PublishPreemptive(subnet_id, data);
         self.send_request(req)
     }
+
+    /// Announce that this node can serve the DAG under a CID to anyone who asks,
+    /// regardless of subnets, until the TTL expires or it's withdrawn with [`Client::unprovide_content`].
+    ///
+    /// The whole DAG has to be in the store already, and be within the configured size limit.
+    /// Providing the same CID again extends the TTL.
+    pub async fn provide_content(&self, cid: Cid, ttl: Duration) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::ProvideContent(cid, ttl, tx);
+        self.send_request(req)?;
+        rx.await?
+    }
+
+    /// Erasure code the DAG under a CID and publish the chunks to the agents providing data for
+    /// the subnet or pinning it, who can reconstruct the DAG from enough of them, even if the
+    /// peers serving the content go offline. Returns the number of chunks published.
+    ///
+    /// The whole DAG has to be in the store already, and be within the configured size limit.
+    pub async fn publish_erasure_coded(
+        &self,
+        subnet_id: SubnetID,
+        cid: Cid,
+    ) -> anyhow::Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::PublishErasureCoded(subnet_id, cid, tx);
+        self.send_request(req)?;
+        rx.await?
+    }
+
+    /// Take a snapshot of the peers, subnets and queries of the [`Service`], for troubleshooting.
+    pub async fn inspect(&self) -> anyhow::Result<ResolverInfo> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::Inspect(tx);
+        self.send_request(req)?;
+        let info = rx.await?;
+        Ok(info)
+    }
+
+    /// Stop announcing that this node can serve a CID.
+    ///
+    /// The records already held by other peers remain until they expire.
+    pub fn unprovide_content(&self, cid: Cid) -> anyhow::Result<()> {
+        let req = Request::UnprovideContent(cid);
+        self.send_request(req)
+    }
+
+    /// Send a CID for resolution from whoever announced to provide it, await its completion,
+    /// then return the result, to be inspected by the caller.
+    ///
+    /// The optional callback is invoked as providers are found and blocks arrive.
+    /// Upon success, the data should be found in the store.
+    pub async fn resolve_content(
+        &self,
+        cid: Cid,
+        progress: Option<ProgressCallback>,
+    ) -> anyhow::Result<ResolveResult> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::ResolveContent(cid, progress, tx);
+        self.send_request(req)?;
+        let res = rx.await?;
+        Ok(res)
+    }
 }
 
 /// Trait to limit the capabilities to resolving CIDs.
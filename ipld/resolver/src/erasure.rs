@@ -0,0 +1,533 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Erasure coding of DAGs into chunks gossiped to the validators of a subnet and its parent.
+//!
+//! Resolving the messages of a bottom-up checkpoint depends on the peers of the child subnet
+//! being online to serve them. To make the content available even when they are not, the blocks
+//! of a DAG can be serialized together and split into `data_chunks` pieces, extended with
+//! `parity_chunks` more using Reed-Solomon coding, and published on a Gossipsub topic which
+//! the agents providing data for the subnet and the ones pinning it are subscribed to.
+//! Any `data_chunks` of them are enough to reconstruct the DAG into the store.
+//!
+//! Every chunk carries the digests of all the chunks of the same content, so it can be checked
+//! on its own before it's forwarded, and chunks only mix with others having the same digests
+//! and the same publisher. Only the providers of a subnet can publish chunks in it, and each of
+//! them can only have a few contents collected at the same time, so a misbehaving one can't
+//! push out the contents published by the others.
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use fvm_ipld_encoding::strict_bytes;
+use ipc_api::subnet_id::SubnetID;
+use libipld::Cid;
+use libp2p::PeerId;
+use log::debug;
+use lru_time_cache::LruCache;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::graph::GraphBlock;
+use crate::hash::blake2b_256;
+
+/// Size of the digest of a chunk.
+const DIGEST_LEN: usize = 32;
+
+/// Maximum number of chunks the Galois field of 2^8 elements can encode.
+const MAX_CHUNKS: usize = 256;
+
+/// Maximum size of the data in a chunk, so it fits into a Gossipsub message.
+const MAX_CHUNK_BYTES: u64 = 1 << 20;
+
+/// Maximum number of different contents to collect chunks for at the same time.
+const MAX_PENDING: usize = 64;
+
+/// Maximum number of different contents to collect chunks for from the same publisher.
+const MAX_PENDING_PER_PUBLISHER: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Subscribe to the chunks published in the subnets we provide data for or pinned,
+    /// and allow publishing chunks of our own.
+    pub enabled: bool,
+    /// Number of chunks the content is split into; this many of all the chunks
+    /// are enough to reconstruct it.
+    pub data_chunks: usize,
+    /// Number of extra chunks; this many can be lost without losing the content.
+    pub parity_chunks: usize,
+    /// Maximum size of the serialized DAG to encode, or reconstruct from chunks.
+    pub max_bytes: u64,
+    /// How long to wait for enough chunks of some content to arrive before giving up on it.
+    pub chunk_ttl: Duration,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("the number of data and parity chunks must be positive")]
+    NoChunks,
+    #[error("the total number of chunks cannot be more than {MAX_CHUNKS}")]
+    TooManyChunks,
+    #[error("chunks of the maximum content size would be larger than {MAX_CHUNK_BYTES} bytes")]
+    TooLargeChunks,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.data_chunks == 0 || self.parity_chunks == 0 {
+            return Err(ConfigError::NoChunks);
+        }
+        if self.data_chunks + self.parity_chunks > MAX_CHUNKS {
+            return Err(ConfigError::TooManyChunks);
+        }
+        if chunk_len(self.max_bytes, self.data_chunks) as u64 > MAX_CHUNK_BYTES {
+            return Err(ConfigError::TooLargeChunks);
+        }
+        Ok(())
+    }
+}
+
+/// One piece of erasure coded content, published through Gossipsub.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureChunk {
+    /// The subnet whose topic the chunk is published on.
+    pub subnet_id: SubnetID,
+    /// Root CID of the DAG the chunk is part of.
+    pub root: Cid,
+    pub data_chunks: u16,
+    pub parity_chunks: u16,
+    /// Size of the serialized blocks, before they were padded to fill the data chunks.
+    pub size: u64,
+    /// Digests of all the chunks, concatenated in order of their index.
+    #[serde(with = "strict_bytes")]
+    pub digests: Vec<u8>,
+    /// Position of this chunk; the data chunks come first, then the parity chunks.
+    pub index: u16,
+    #[serde(with = "strict_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl ErasureChunk {
+    pub fn total_chunks(&self) -> usize {
+        self.data_chunks as usize + self.parity_chunks as usize
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(fvm_ipld_encoding::to_vec(self)?)
+    }
+
+    /// Deserialize a chunk and check that it's consistent with the digests it carries.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let chunk = fvm_ipld_encoding::from_slice::<Self>(bytes)?;
+        chunk.validate()?;
+        Ok(chunk)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let total = self.total_chunks();
+        if self.data_chunks == 0 || self.parity_chunks == 0 || total > MAX_CHUNKS {
+            bail!("invalid number of chunks: {total}");
+        }
+        if self.index as usize >= total {
+            bail!("chunk index {} out of range", self.index);
+        }
+        if self.digests.len() != total * DIGEST_LEN {
+            bail!("expected {total} digests");
+        }
+        let len = chunk_len(self.size, self.data_chunks as usize);
+        if len as u64 > MAX_CHUNK_BYTES || self.data.len() != len {
+            bail!("unexpected chunk length: {}", self.data.len());
+        }
+        if blake2b_256(&self.data)[..] != *self.digest(self.index as usize) {
+            bail!("chunk {} doesn't match its digest", self.index);
+        }
+        Ok(())
+    }
+
+    fn digest(&self, index: usize) -> &[u8] {
+        &self.digests[index * DIGEST_LEN..(index + 1) * DIGEST_LEN]
+    }
+
+    /// Digest of everything the chunks of the same content have in common.
+    fn manifest(&self) -> [u8; 32] {
+        let mut bytes = self.root.to_bytes();
+        bytes.extend(self.subnet_id.to_string().as_bytes());
+        bytes.extend(self.data_chunks.to_be_bytes());
+        bytes.extend(self.parity_chunks.to_be_bytes());
+        bytes.extend(self.size.to_be_bytes());
+        bytes.extend(&self.digests);
+        blake2b_256(&bytes)
+    }
+}
+
+/// Length of each chunk when content of a certain size is split into some number of chunks.
+fn chunk_len(size: u64, data_chunks: usize) -> usize {
+    let len = size.div_ceil(data_chunks as u64).max(1);
+    len.try_into().unwrap_or(usize::MAX)
+}
+
+fn reed_solomon(data_chunks: usize, parity_chunks: usize) -> anyhow::Result<ReedSolomon> {
+    ReedSolomon::new(data_chunks, parity_chunks)
+        .map_err(|e| anyhow!("invalid erasure coding parameters: {e:?}"))
+}
+
+/// Serialize the blocks of a DAG and split them into erasure coded chunks.
+pub fn encode(
+    config: &Config,
+    subnet_id: SubnetID,
+    root: Cid,
+    blocks: &[GraphBlock],
+) -> anyhow::Result<Vec<ErasureChunk>> {
+    let mut payload = fvm_ipld_encoding::to_vec(blocks)?;
+    let size = payload.len() as u64;
+    if size > config.max_bytes {
+        bail!(
+            "DAG under {root} is {size} bytes, larger than the limit of {} bytes",
+            config.max_bytes
+        );
+    }
+
+    let len = chunk_len(size, config.data_chunks);
+    let total = config.data_chunks + config.parity_chunks;
+    payload.resize(len * config.data_chunks, 0);
+
+    let mut shards = payload.chunks(len).map(|c| c.to_vec()).collect::<Vec<_>>();
+    shards.resize(total, vec![0u8; len]);
+
+    reed_solomon(config.data_chunks, config.parity_chunks)?
+        .encode(&mut shards)
+        .map_err(|e| anyhow!("failed to encode {root}: {e:?}"))?;
+
+    let digests = shards
+        .iter()
+        .flat_map(|shard| blake2b_256(shard))
+        .collect::<Vec<_>>();
+
+    let chunks = shards
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| ErasureChunk {
+            subnet_id: subnet_id.clone(),
+            root,
+            data_chunks: config.data_chunks as u16,
+            parity_chunks: config.parity_chunks as u16,
+            size,
+            digests: digests.clone(),
+            index: index as u16,
+            data,
+        })
+        .collect();
+
+    Ok(chunks)
+}
+
+/// Enough chunks of some content to try to reconstruct it.
+pub struct Reconstruction {
+    pub subnet_id: SubnetID,
+    pub root: Cid,
+    data_chunks: usize,
+    parity_chunks: usize,
+    size: u64,
+    digests: Vec<u8>,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl Reconstruction {
+    /// Recover the missing data chunks and deserialize the blocks.
+    ///
+    /// The blocks still have to be checked against their CIDs before they are stored.
+    pub fn decode(mut self) -> anyhow::Result<Vec<GraphBlock>> {
+        reed_solomon(self.data_chunks, self.parity_chunks)?
+            .reconstruct_data(&mut self.shards)
+            .map_err(|e| anyhow!("failed to reconstruct {}: {e:?}", self.root))?;
+
+        let mut payload = Vec::new();
+        for (index, shard) in self.shards.into_iter().take(self.data_chunks).enumerate() {
+            let shard = shard.ok_or_else(|| anyhow!("chunk {index} is missing"))?;
+            let digest = &self.digests[index * DIGEST_LEN..(index + 1) * DIGEST_LEN];
+            if blake2b_256(&shard)[..] != *digest {
+                bail!("reconstructed chunk {index} doesn't match its digest");
+            }
+            payload.extend(shard);
+        }
+        payload.truncate(self.size as usize);
+
+        let blocks = fvm_ipld_encoding::from_slice::<Vec<GraphBlock>>(&payload)?;
+        Ok(blocks)
+    }
+}
+
+/// Chunks received for content which doesn't have enough of them yet.
+struct Pending {
+    reconstruction: Reconstruction,
+    received: usize,
+}
+
+/// Collects the chunks arriving from Gossipsub until any content can be reconstructed.
+pub struct ChunkCollector {
+    max_bytes: u64,
+    /// Chunks by publisher, root CID and the digest of what the chunks of the same content have in common.
+    pending: LruCache<(PeerId, Cid, [u8; 32]), Pending>,
+    /// Content we already handed out for reconstruction, to ignore the chunks arriving late.
+    completed: LruCache<(PeerId, Cid, [u8; 32]), ()>,
+}
+
+impl ChunkCollector {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_bytes: config.max_bytes,
+            pending: LruCache::with_expiry_duration_and_capacity(config.chunk_ttl, MAX_PENDING),
+            completed: LruCache::with_expiry_duration(config.chunk_ttl),
+        }
+    }
+
+    /// Add a validated chunk from the peer who published it, returning the content
+    /// to reconstruct once we have enough chunks.
+    ///
+    /// Every content is only returned once.
+    pub fn add(&mut self, publisher: PeerId, chunk: ErasureChunk) -> Option<Reconstruction> {
+        if chunk.size > self.max_bytes {
+            debug!(
+                "ignoring chunk of {} with {} bytes of content",
+                chunk.root, chunk.size
+            );
+            return None;
+        }
+
+        let key = (publisher, chunk.root, chunk.manifest());
+        if self.completed.contains_key(&key) {
+            return None;
+        }
+
+        let index = chunk.index as usize;
+        if !self.pending.contains_key(&key) {
+            let published = self
+                .pending
+                .peek_iter()
+                .filter(|((p, _, _), _)| *p == publisher)
+                .count();
+            if published >= MAX_PENDING_PER_PUBLISHER {
+                debug!(
+                    "ignoring chunk of {}; {publisher} has too many pending contents",
+                    chunk.root
+                );
+                return None;
+            }
+            let total = chunk.total_chunks();
+            let pending = Pending {
+                reconstruction: Reconstruction {
+                    subnet_id: chunk.subnet_id,
+                    root: chunk.root,
+                    data_chunks: chunk.data_chunks as usize,
+                    parity_chunks: chunk.parity_chunks as usize,
+                    size: chunk.size,
+                    digests: chunk.digests,
+                    shards: vec![None; total],
+                },
+                received: 0,
+            };
+            self.pending.insert(key, pending);
+        }
+        let pending = self.pending.get_mut(&key)?;
+
+        let shard = &mut pending.reconstruction.shards[index];
+        if shard.is_none() {
+            *shard = Some(chunk.data);
+            pending.received += 1;
+        }
+
+        if pending.received < pending.reconstruction.data_chunks {
+            return None;
+        }
+
+        self.completed.insert(key, ());
+        self.pending
+            .remove(&key)
+            .map(|pending| pending.reconstruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ipc_api::subnet_id::SubnetID;
+    use libipld::multihash::{Code, MultihashDigest};
+    use libipld::Cid;
+    use libp2p::PeerId;
+
+    use super::{
+        encode, ChunkCollector, Config, ConfigError, ErasureChunk, MAX_PENDING_PER_PUBLISHER,
+    };
+    use crate::behaviour::graph::GraphBlock;
+
+    const RAW: u64 = 0x55;
+
+    fn config() -> Config {
+        Config {
+            enabled: true,
+            data_chunks: 4,
+            parity_chunks: 2,
+            max_bytes: 1 << 20,
+            chunk_ttl: Duration::from_secs(60),
+        }
+    }
+
+    fn blocks(n: usize) -> Vec<GraphBlock> {
+        (0..n)
+            .map(|i| {
+                let data = format!("block {i}").repeat(10 + i).into_bytes();
+                let cid = Cid::new_v1(RAW, Code::Blake2b256.digest(&data));
+                GraphBlock { cid, data }
+            })
+            .collect()
+    }
+
+    fn encode_blocks(blocks: &[GraphBlock]) -> Vec<ErasureChunk> {
+        encode(&config(), SubnetID::new_root(1), blocks[0].cid, blocks).unwrap()
+    }
+
+    fn cids(blocks: &[GraphBlock]) -> Vec<Cid> {
+        blocks.iter().map(|b| b.cid).collect()
+    }
+
+    #[test]
+    fn validate_config() {
+        assert!(config().validate().is_ok());
+
+        let mut c = config();
+        c.parity_chunks = 0;
+        assert!(matches!(c.validate(), Err(ConfigError::NoChunks)));
+
+        let mut c = config();
+        c.data_chunks = 200;
+        c.parity_chunks = 100;
+        assert!(matches!(c.validate(), Err(ConfigError::TooManyChunks)));
+
+        let mut c = config();
+        c.max_bytes = 100 << 20;
+        assert!(matches!(c.validate(), Err(ConfigError::TooLargeChunks)));
+
+        c.enabled = false;
+        assert!(c.validate().is_ok());
+    }
+
+    #[test]
+    fn chunks_roundtrip() {
+        let chunks = encode_blocks(&blocks(5));
+        assert_eq!(chunks.len(), 6);
+        for chunk in chunks {
+            let bytes = chunk.to_bytes().unwrap();
+            assert_eq!(ErasureChunk::from_bytes(&bytes).unwrap(), chunk);
+        }
+    }
+
+    #[test]
+    fn tampered_chunk_is_invalid() {
+        let mut chunk = encode_blocks(&blocks(5)).remove(1);
+        chunk.data[0] ^= 1;
+        let bytes = chunk.to_bytes().unwrap();
+        assert!(ErasureChunk::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn reconstruct_from_any_data_chunks() {
+        let blocks = blocks(5);
+        let chunks = encode_blocks(&blocks);
+
+        let publisher = PeerId::random();
+
+        // Lose as many chunks as there are parity chunks, including some of the data.
+        for lost in [[0, 1], [2, 5], [4, 5]] {
+            let mut collector = ChunkCollector::new(&config());
+            let mut reconstruction = None;
+            for chunk in chunks
+                .iter()
+                .filter(|c| !lost.contains(&(c.index as usize)))
+            {
+                assert!(reconstruction.is_none(), "only returned once");
+                reconstruction = collector.add(publisher, chunk.clone());
+            }
+            let decoded = reconstruction.expect("enough chunks").decode().unwrap();
+            assert_eq!(cids(&decoded), cids(&blocks));
+            assert_eq!(decoded[4].data, blocks[4].data);
+        }
+    }
+
+    #[test]
+    fn not_enough_chunks() {
+        let chunks = encode_blocks(&blocks(5));
+        let publisher = PeerId::random();
+        let mut collector = ChunkCollector::new(&config());
+        for chunk in chunks.iter().take(3) {
+            assert!(collector.add(publisher, chunk.clone()).is_none());
+            assert!(
+                collector.add(publisher, chunk.clone()).is_none(),
+                "duplicates don't count"
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_of_different_content_dont_mix() {
+        let chunks1 = encode_blocks(&blocks(5));
+        let mut chunks2 = encode_blocks(&blocks(6));
+        // Same root, different content.
+        for chunk in chunks2.iter_mut() {
+            chunk.root = chunks1[0].root;
+        }
+        let publisher = PeerId::random();
+        let mut collector = ChunkCollector::new(&config());
+        for (c1, c2) in chunks1.iter().zip(chunks2.iter()).take(3) {
+            assert!(collector.add(publisher, c1.clone()).is_none());
+            assert!(collector.add(publisher, c2.clone()).is_none());
+        }
+    }
+
+    #[test]
+    fn chunks_of_different_publishers_dont_mix() {
+        let chunks = encode_blocks(&blocks(5));
+        let mut collector = ChunkCollector::new(&config());
+        for chunk in chunks.iter().take(3) {
+            assert!(collector.add(PeerId::random(), chunk.clone()).is_none());
+        }
+    }
+
+    #[test]
+    fn pending_contents_limited_per_publisher() {
+        let flooder = PeerId::random();
+        let mut collector = ChunkCollector::new(&config());
+
+        // Start collecting as many contents as allowed, then one more, which is ignored.
+        let contents = (0..=MAX_PENDING_PER_PUBLISHER)
+            .map(|i| encode_blocks(&blocks(5 + i)))
+            .collect::<Vec<_>>();
+        for chunks in contents.iter() {
+            assert!(collector.add(flooder, chunks[0].clone()).is_none());
+        }
+        let ignored = contents.last().unwrap();
+        for chunk in ignored.iter().skip(1).take(3) {
+            assert!(collector.add(flooder, chunk.clone()).is_none());
+        }
+
+        // The ones already started can still complete.
+        let started = &contents[0];
+        let reconstruction = started
+            .iter()
+            .skip(1)
+            .take(3)
+            .filter_map(|chunk| collector.add(flooder, chunk.clone()))
+            .next();
+        assert!(reconstruction.is_some());
+
+        // Other publishers are unaffected.
+        let honest = PeerId::random();
+        let reconstruction = ignored
+            .iter()
+            .take(4)
+            .filter_map(|chunk| collector.add(honest, chunk.clone()))
+            .next();
+        assert!(reconstruction.is_some());
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Snapshot of the internal state of the [`Service`](crate::Service), for operators
+//! to inspect at runtime what the resolver is connected to and what it is doing.
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolverInfo {
+    pub peer_id: PeerId,
+    /// Addresses we are listening on.
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Addresses we were told or found out we are reachable on.
+    pub external_addresses: Vec<Multiaddr>,
+    /// Peers we currently have connections with.
+    pub peers: Vec<PeerInfo>,
+    /// Peers banned because of their low score.
+    pub banned_peers: Vec<PeerId>,
+    /// Number of peers whose address we know, and thus can be providers of subnets.
+    pub routable_peers: usize,
+    /// The subnets in the provider cache.
+    pub subnets: Vec<SubnetInfo>,
+    /// Resolutions which haven't finished yet.
+    pub queries: Vec<QueryInfo>,
+    pub rate_limit: RateLimitInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Remote addresses of the open connections.
+    pub addresses: Vec<Multiaddr>,
+    /// Addresses the peer said it listens on, through `identify`.
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Protocols the peer said it supports, through `identify`.
+    pub protocols: Vec<String>,
+    pub agent_version: Option<String>,
+    /// Local reputation of the peer.
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubnetInfo {
+    pub subnet_id: String,
+    /// Pinned subnets are never pruned from the cache.
+    pub pinned: bool,
+    /// Peers which announced that they can serve data from the subnet.
+    pub providers: Vec<PeerId>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryStage {
+    /// Looking up the providers of the content.
+    Providers,
+    /// Waiting for a graph response.
+    Graph,
+    /// Resolving with Bitswap.
+    Bitswap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryInfo {
+    pub cid: String,
+    /// The subnet the content is resolved from, or `None` for content looked up by its providers.
+    pub subnet_id: Option<String>,
+    pub stage: QueryStage,
+    /// Seconds since the query started.
+    pub age_secs: u64,
+    /// Number of peers left to try if the current attempt fails.
+    pub fallback_peers: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitInfo {
+    /// Number of bytes remote addresses can consume in a period; 0 means no limit.
+    pub bytes: u32,
+    pub period_secs: u64,
+    /// Number of remote addresses whose consumption is tracked for Bitswap.
+    pub bitswap_addresses: usize,
+    /// Number of remote addresses whose consumption is tracked for graph requests.
+    pub graph_addresses: usize,
+}
@@ -1,10 +1,15 @@
 // Copyright 2022-2024 Protocol Labs
 // SPDX-License-Identifier: MIT
+mod address_book;
+mod bandwidth;
 mod behaviour;
 mod client;
+mod erasure;
 mod hash;
+mod info;
 mod limiter;
 mod observe;
+mod peer_scores;
 mod service;
 mod timestamp;
 
@@ -19,8 +24,15 @@ mod arb;
 #[cfg(feature = "missing_blocks")]
 pub mod missing_blocks;
 
-pub use behaviour::{ContentConfig, DiscoveryConfig, MembershipConfig, NetworkConfig};
+pub use address_book::AddressBookConfig;
+pub use behaviour::{ContentConfig, DiscoveryConfig, MembershipConfig, NatConfig, NetworkConfig};
 pub use client::{Client, Resolver};
-pub use service::{Config, ConnectionConfig, Event, NoKnownPeers, Service};
+pub use erasure::Config as ErasureConfig;
+pub use info::{PeerInfo, QueryInfo, QueryStage, RateLimitInfo, ResolverInfo, SubnetInfo};
+pub use peer_scores::ScoringConfig;
+pub use service::{
+    build_transport, Config, ConnectionConfig, ContentTooLarge, Event, NoKnownPeers,
+    NoKnownProviders, ProgressCallback, ResolveProgress, Service,
+};
 pub use timestamp::Timestamp;
-pub use vote_record::{ValidatorKey, VoteRecord};
+pub use vote_record::{SignedVoteRecord, ValidatorKey, VoteRecord};
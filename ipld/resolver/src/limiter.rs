@@ -40,6 +40,11 @@ where
 
         state.check_and_modify_at(limit, at, cost).is_ok()
     }
+
+    /// Number of keys whose consumption is currently tracked.
+    pub fn num_keys(&self) -> usize {
+        self.cache.len()
+    }
 }
 
 #[cfg(test)]
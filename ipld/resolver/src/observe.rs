@@ -6,10 +6,10 @@ use ipc_observability::{
 use lazy_static::lazy_static;
 use libipld::cid::Cid;
 use libp2p::gossipsub::TopicHash;
-use libp2p::PeerId;
+use libp2p::{Multiaddr, PeerId};
 use prometheus::{
-    register_histogram, register_int_counter, register_int_gauge, Histogram, IntCounter, IntGauge,
-    Registry,
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Histogram, IntCounter, IntCounterVec, IntGauge, Registry,
 };
 use std::fmt;
 use std::time::Duration;
@@ -83,6 +83,84 @@ register_metrics! {
 
     IPLD_RESOLVER_CONTENT_RATE_LIMITED: IntCounter =
         register_int_counter!("ipld_resolver_content_rate_limited", "Number of rate limited requests");
+
+    IPLD_RESOLVER_PEER_RESPONSE_TIME: Histogram =
+        register_histogram!("ipld_resolver_peer_response_time", "Time it took peers to respond to Bitswap requests");
+
+    IPLD_RESOLVER_PEER_BYTES_RECEIVED: IntCounter =
+        register_int_counter!("ipld_resolver_peer_bytes_received", "Number of bytes received in valid blocks from peers");
+
+    IPLD_RESOLVER_PEER_INVALID_BLOCK: IntCounter =
+        register_int_counter!("ipld_resolver_peer_invalid_block", "Number of blocks received from peers which didn't match their CID");
+
+    IPLD_RESOLVER_PEER_REQUEST_FAILURE: IntCounter =
+        register_int_counter!("ipld_resolver_peer_request_failure", "Number of Bitswap requests to peers which failed");
+
+    IPLD_RESOLVER_PEER_INVALID_MESSAGE: IntCounter =
+        register_int_counter!("ipld_resolver_peer_invalid_message", "Number of invalid Gossipsub messages forwarded by peers");
+
+    IPLD_RESOLVER_PEER_BANS: IntCounter =
+        register_int_counter!("ipld_resolver_peer_bans", "Number of times peers were banned");
+
+    IPLD_RESOLVER_PEER_BANNED: IntGauge =
+        register_int_gauge!("ipld_resolver_peer_banned", "Number of currently banned peers");
+
+    IPLD_RESOLVER_PEER_SCORE: Histogram =
+        register_histogram!("ipld_resolver_peer_score", "Score of peers at the time they were banned");
+
+    IPLD_RESOLVER_GRAPH_BLOCKS_RECEIVED: IntCounter =
+        register_int_counter!("ipld_resolver_graph_blocks_received", "Number of blocks received in graph responses");
+
+    IPLD_RESOLVER_GRAPH_BLOCKS_SERVED: IntCounter =
+        register_int_counter!("ipld_resolver_graph_blocks_served", "Number of blocks sent in graph responses");
+
+    IPLD_RESOLVER_GRAPH_UNSUPPORTED: IntCounter =
+        register_int_counter!("ipld_resolver_graph_unsupported", "Number of graph requests to peers not supporting the protocol");
+
+    IPLD_RESOLVER_NAT_PUBLIC: IntGauge =
+        register_int_gauge!("ipld_resolver_nat_public", "Whether AutoNAT found the node to be publicly reachable");
+
+    IPLD_RESOLVER_NAT_RELAY_RESERVATIONS: IntCounter =
+        register_int_counter!("ipld_resolver_nat_relay_reservations", "Number of slots reserved on relays");
+
+    IPLD_RESOLVER_NAT_HOLE_PUNCH_SUCCESS: IntCounter =
+        register_int_counter!("ipld_resolver_nat_hole_punch_success", "Number of relayed connections upgraded to direct ones");
+
+    IPLD_RESOLVER_NAT_HOLE_PUNCH_FAILURE: IntCounter =
+        register_int_counter!("ipld_resolver_nat_hole_punch_failure", "Number of failed hole punching attempts");
+
+    IPLD_RESOLVER_PROVIDED_CIDS: IntGauge =
+        register_int_gauge!("ipld_resolver_provided_cids", "Number of root CIDs the application asked us to provide");
+
+    IPLD_RESOLVER_PROVIDED_BYTES: Histogram =
+        register_histogram!("ipld_resolver_provided_bytes", "Size of the DAGs the application asked us to provide");
+
+    IPLD_RESOLVER_ADDRESS_BOOK_RESTORED_PEERS: IntCounter =
+        register_int_counter!("ipld_resolver_address_book_restored_peers", "Number of peers restored to the routing table from the address book");
+
+    IPLD_RESOLVER_ADDRESS_BOOK_SAVED_PEERS: IntGauge =
+        register_int_gauge!("ipld_resolver_address_book_saved_peers", "Number of peers in the last saved address book");
+
+    IPLD_RESOLVER_ADDRESS_BOOK_FAILURE: IntCounter =
+        register_int_counter!("ipld_resolver_address_book_failure", "Number of failures to load or save the address book");
+
+    IPLD_RESOLVER_BANDWIDTH_SERVED_BYTES: IntCounterVec =
+        register_int_counter_vec!("ipld_resolver_bandwidth_served_bytes", "Number of bytes served to peers with Bitswap and graph responses", &["subnet"]);
+
+    IPLD_RESOLVER_BANDWIDTH_THROTTLED: IntCounterVec =
+        register_int_counter_vec!("ipld_resolver_bandwidth_throttled", "Number of requests refused because of an exhausted egress budget", &["subnet"]);
+
+    IPLD_RESOLVER_ERASURE_CHUNKS_PUBLISHED: IntCounter =
+        register_int_counter!("ipld_resolver_erasure_chunks_published", "Number of erasure coded chunks published");
+
+    IPLD_RESOLVER_ERASURE_CHUNKS_RECEIVED: IntCounter =
+        register_int_counter!("ipld_resolver_erasure_chunks_received", "Number of valid erasure coded chunks received");
+
+    IPLD_RESOLVER_ERASURE_RECONSTRUCTED: IntCounter =
+        register_int_counter!("ipld_resolver_erasure_reconstructed", "Number of DAGs reconstructed from erasure coded chunks");
+
+    IPLD_RESOLVER_ERASURE_FAILURE: IntCounter =
+        register_int_counter!("ipld_resolver_erasure_failure", "Number of failures to erasure code or reconstruct a DAG");
 }
 
 const DOMAIN: &str = "IPLD";
@@ -96,6 +174,19 @@ impl_traceables!(TraceLevel::Info, DOMAIN, MembershipEvent);
 impl_traceables!(TraceLevel::Warn, DOMAIN, MembershipFailureEvent);
 impl_traceables!(TraceLevel::Info, DOMAIN, ResolveEvent);
 impl_traceables!(TraceLevel::Warn, DOMAIN, ResolveFailureEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, PeerEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, PeerFailureEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, GraphEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, GraphFailureEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, NatEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, NatFailureEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, ProvideEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, AddressBookEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, AddressBookFailureEvent);
+impl_traceables!(TraceLevel::Debug, DOMAIN, BandwidthEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, BandwidthFailureEvent);
+impl_traceables!(TraceLevel::Info, DOMAIN, ErasureEvent);
+impl_traceables!(TraceLevel::Warn, DOMAIN, ErasureFailureEvent);
 
 #[allow(dead_code)]
 pub enum PingEvent {
@@ -279,6 +370,7 @@ pub enum MembershipFailureEvent {
     PublishFailure(String),
     GossipInvalidProviderRecord(Option<PeerId>, String),
     GossipInvalidVoteRecord(Option<PeerId>, String),
+    GossipInvalidChunk(Option<PeerId>, String),
     GossipUnknownTopic(Option<PeerId>, TopicHash),
 }
 
@@ -290,6 +382,7 @@ impl Recordable for MembershipFailureEvent {
                 IPLD_RESOLVER_MEMBERSHIP_INVALID_MESSAGE.inc()
             }
             Self::GossipInvalidVoteRecord(_, _) => IPLD_RESOLVER_MEMBERSHIP_INVALID_MESSAGE.inc(),
+            Self::GossipInvalidChunk(_, _) => IPLD_RESOLVER_MEMBERSHIP_INVALID_MESSAGE.inc(),
             Self::GossipUnknownTopic(_, _) => IPLD_RESOLVER_MEMBERSHIP_UNKNOWN_TOPIC.inc(),
         }
     }
@@ -315,6 +408,13 @@ impl fmt::Debug for MembershipFailureEvent {
                     peer_id, record
                 )
             }
+            MembershipFailureEvent::GossipInvalidChunk(peer_id, error) => {
+                write!(
+                    f,
+                    "Membership::GossipInvalidChunk({:?}, {:?})",
+                    peer_id, error
+                )
+            }
             MembershipFailureEvent::GossipUnknownTopic(peer_id, topic) => {
                 write!(
                     f,
@@ -402,6 +502,401 @@ impl fmt::Debug for ResolveFailureEvent {
     }
 }
 
+#[allow(dead_code)]
+pub enum PeerEvent {
+    Response(PeerId, Duration),
+    BlockReceived(PeerId, usize),
+    Unbanned(PeerId),
+}
+
+impl Recordable for PeerEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Response(_, elapsed) => {
+                IPLD_RESOLVER_PEER_RESPONSE_TIME.observe(elapsed.as_millis() as f64)
+            }
+            Self::BlockReceived(_, bytes) => {
+                IPLD_RESOLVER_PEER_BYTES_RECEIVED.inc_by(*bytes as u64)
+            }
+            Self::Unbanned(_) => IPLD_RESOLVER_PEER_BANNED.dec(),
+        }
+    }
+}
+
+impl fmt::Debug for PeerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerEvent::Response(peer_id, elapsed) => {
+                write!(f, "Peer::Response({:?}, {:?})", peer_id, elapsed)
+            }
+            PeerEvent::BlockReceived(peer_id, bytes) => {
+                write!(f, "Peer::BlockReceived({:?}, {:?})", peer_id, bytes)
+            }
+            PeerEvent::Unbanned(peer_id) => {
+                write!(f, "Peer::Unbanned({:?})", peer_id)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum PeerFailureEvent {
+    InvalidBlock(PeerId),
+    RequestFailure(PeerId),
+    InvalidMessage(PeerId),
+    Banned(PeerId, f64),
+}
+
+impl Recordable for PeerFailureEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::InvalidBlock(_) => IPLD_RESOLVER_PEER_INVALID_BLOCK.inc(),
+            Self::RequestFailure(_) => IPLD_RESOLVER_PEER_REQUEST_FAILURE.inc(),
+            Self::InvalidMessage(_) => IPLD_RESOLVER_PEER_INVALID_MESSAGE.inc(),
+            Self::Banned(_, score) => {
+                IPLD_RESOLVER_PEER_BANS.inc();
+                IPLD_RESOLVER_PEER_BANNED.inc();
+                IPLD_RESOLVER_PEER_SCORE.observe(*score);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PeerFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerFailureEvent::InvalidBlock(peer_id) => {
+                write!(f, "Peer::InvalidBlock({:?})", peer_id)
+            }
+            PeerFailureEvent::RequestFailure(peer_id) => {
+                write!(f, "Peer::RequestFailure({:?})", peer_id)
+            }
+            PeerFailureEvent::InvalidMessage(peer_id) => {
+                write!(f, "Peer::InvalidMessage({:?})", peer_id)
+            }
+            PeerFailureEvent::Banned(peer_id, score) => {
+                write!(f, "Peer::Banned({:?}, {:?})", peer_id, score)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum GraphEvent {
+    Received(PeerId, usize),
+    Served(PeerId, usize),
+}
+
+impl Recordable for GraphEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Received(_, blocks) => IPLD_RESOLVER_GRAPH_BLOCKS_RECEIVED.inc_by(*blocks as u64),
+            Self::Served(_, blocks) => IPLD_RESOLVER_GRAPH_BLOCKS_SERVED.inc_by(*blocks as u64),
+        }
+    }
+}
+
+impl fmt::Debug for GraphEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphEvent::Received(peer_id, blocks) => {
+                write!(f, "Graph::Received({:?}, {:?})", peer_id, blocks)
+            }
+            GraphEvent::Served(peer_id, blocks) => {
+                write!(f, "Graph::Served({:?}, {:?})", peer_id, blocks)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum GraphFailureEvent {
+    Unsupported(PeerId),
+    RateLimited(PeerId),
+}
+
+impl Recordable for GraphFailureEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Unsupported(_) => IPLD_RESOLVER_GRAPH_UNSUPPORTED.inc(),
+            Self::RateLimited(_) => IPLD_RESOLVER_CONTENT_RATE_LIMITED.inc(),
+        }
+    }
+}
+
+impl fmt::Debug for GraphFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphFailureEvent::Unsupported(peer_id) => {
+                write!(f, "Graph::Unsupported({:?})", peer_id)
+            }
+            GraphFailureEvent::RateLimited(peer_id) => {
+                write!(f, "Graph::RateLimited({:?})", peer_id)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum NatEvent {
+    Public(Multiaddr),
+    Private,
+    ReservationAccepted(PeerId),
+    HolePunched(PeerId),
+}
+
+impl Recordable for NatEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Public(_) => IPLD_RESOLVER_NAT_PUBLIC.set(1),
+            Self::Private => IPLD_RESOLVER_NAT_PUBLIC.set(0),
+            Self::ReservationAccepted(_) => IPLD_RESOLVER_NAT_RELAY_RESERVATIONS.inc(),
+            Self::HolePunched(_) => IPLD_RESOLVER_NAT_HOLE_PUNCH_SUCCESS.inc(),
+        }
+    }
+}
+
+impl fmt::Debug for NatEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatEvent::Public(addr) => write!(f, "Nat::Public({:?})", addr),
+            NatEvent::Private => write!(f, "Nat::Private"),
+            NatEvent::ReservationAccepted(peer_id) => {
+                write!(f, "Nat::ReservationAccepted({:?})", peer_id)
+            }
+            NatEvent::HolePunched(peer_id) => write!(f, "Nat::HolePunched({:?})", peer_id),
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum NatFailureEvent {
+    HolePunchFailed(PeerId, String),
+}
+
+impl Recordable for NatFailureEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::HolePunchFailed(_, _) => IPLD_RESOLVER_NAT_HOLE_PUNCH_FAILURE.inc(),
+        }
+    }
+}
+
+impl fmt::Debug for NatFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatFailureEvent::HolePunchFailed(peer_id, error) => {
+                write!(f, "Nat::HolePunchFailed({:?}, {:?})", peer_id, error)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum ProvideEvent {
+    Started(Cid, u64),
+    Stopped(Cid),
+}
+
+impl Recordable for ProvideEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Started(_, bytes) => {
+                IPLD_RESOLVER_PROVIDED_CIDS.inc();
+                IPLD_RESOLVER_PROVIDED_BYTES.observe(*bytes as f64);
+            }
+            Self::Stopped(_) => IPLD_RESOLVER_PROVIDED_CIDS.dec(),
+        }
+    }
+}
+
+impl fmt::Debug for ProvideEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvideEvent::Started(cid, bytes) => {
+                write!(f, "Provide::Started({:?}, {:?})", cid, bytes)
+            }
+            ProvideEvent::Stopped(cid) => write!(f, "Provide::Stopped({:?})", cid),
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum AddressBookEvent {
+    /// Number of peers restored to the routing table, and the number of provider records.
+    Loaded(usize, usize),
+    /// Number of peers and provider records saved.
+    Saved(usize, usize),
+}
+
+impl Recordable for AddressBookEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Loaded(peers, _) => {
+                IPLD_RESOLVER_ADDRESS_BOOK_RESTORED_PEERS.inc_by(*peers as u64)
+            }
+            Self::Saved(peers, _) => IPLD_RESOLVER_ADDRESS_BOOK_SAVED_PEERS.set(*peers as i64),
+        }
+    }
+}
+
+impl fmt::Debug for AddressBookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBookEvent::Loaded(peers, providers) => {
+                write!(f, "AddressBook::Loaded({:?}, {:?})", peers, providers)
+            }
+            AddressBookEvent::Saved(peers, providers) => {
+                write!(f, "AddressBook::Saved({:?}, {:?})", peers, providers)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum AddressBookFailureEvent {
+    Load(String),
+    Save(String),
+}
+
+impl Recordable for AddressBookFailureEvent {
+    fn record_metrics(&self) {
+        IPLD_RESOLVER_ADDRESS_BOOK_FAILURE.inc();
+    }
+}
+
+impl fmt::Debug for AddressBookFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBookFailureEvent::Load(error) => {
+                write!(f, "AddressBookFailure::Load({:?})", error)
+            }
+            AddressBookFailureEvent::Save(error) => {
+                write!(f, "AddressBookFailure::Save({:?})", error)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum BandwidthEvent {
+    /// Bytes sent to a peer, labelled with the subnet they were attributed to.
+    Served(PeerId, String, usize),
+}
+
+impl Recordable for BandwidthEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Served(_, subnet, bytes) => IPLD_RESOLVER_BANDWIDTH_SERVED_BYTES
+                .with_label_values(&[subnet])
+                .inc_by(*bytes as u64),
+        }
+    }
+}
+
+impl fmt::Debug for BandwidthEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BandwidthEvent::Served(peer_id, subnet, bytes) => {
+                write!(
+                    f,
+                    "Bandwidth::Served({:?}, {:?}, {:?})",
+                    peer_id, subnet, bytes
+                )
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum BandwidthFailureEvent {
+    /// A request was refused because an egress budget is exhausted.
+    Throttled(PeerId, String),
+}
+
+impl Recordable for BandwidthFailureEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Throttled(_, subnet) => IPLD_RESOLVER_BANDWIDTH_THROTTLED
+                .with_label_values(&[subnet])
+                .inc(),
+        }
+    }
+}
+
+impl fmt::Debug for BandwidthFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BandwidthFailureEvent::Throttled(peer_id, subnet) => {
+                write!(f, "Bandwidth::Throttled({:?}, {:?})", peer_id, subnet)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum ErasureEvent {
+    /// Number of chunks published for a root CID.
+    Published(Cid, usize),
+    /// Index of a chunk received for a root CID.
+    ChunkReceived(Cid, u16),
+    /// Number of blocks reconstructed under a root CID.
+    Reconstructed(Cid, usize),
+}
+
+impl Recordable for ErasureEvent {
+    fn record_metrics(&self) {
+        match self {
+            Self::Published(_, chunks) => {
+                IPLD_RESOLVER_ERASURE_CHUNKS_PUBLISHED.inc_by(*chunks as u64)
+            }
+            Self::ChunkReceived(_, _) => IPLD_RESOLVER_ERASURE_CHUNKS_RECEIVED.inc(),
+            Self::Reconstructed(_, _) => IPLD_RESOLVER_ERASURE_RECONSTRUCTED.inc(),
+        }
+    }
+}
+
+impl fmt::Debug for ErasureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErasureEvent::Published(cid, chunks) => {
+                write!(f, "Erasure::Published({:?}, {:?})", cid, chunks)
+            }
+            ErasureEvent::ChunkReceived(cid, index) => {
+                write!(f, "Erasure::ChunkReceived({:?}, {:?})", cid, index)
+            }
+            ErasureEvent::Reconstructed(cid, blocks) => {
+                write!(f, "Erasure::Reconstructed({:?}, {:?})", cid, blocks)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub enum ErasureFailureEvent {
+    Publish(Cid, String),
+    Reconstruct(Cid, String),
+}
+
+impl Recordable for ErasureFailureEvent {
+    fn record_metrics(&self) {
+        IPLD_RESOLVER_ERASURE_FAILURE.inc();
+    }
+}
+
+impl fmt::Debug for ErasureFailureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErasureFailureEvent::Publish(cid, error) => {
+                write!(f, "ErasureFailure::Publish({:?}, {:?})", cid, error)
+            }
+            ErasureFailureEvent::Reconstruct(cid, error) => {
+                write!(f, "ErasureFailure::Reconstruct({:?}, {:?})", cid, error)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -442,6 +937,10 @@ mod tests {
             Some(peer_id),
             err_str.clone(),
         ));
+        emit(MembershipFailureEvent::GossipInvalidChunk(
+            Some(peer_id),
+            err_str.clone(),
+        ));
         emit(MembershipFailureEvent::GossipUnknownTopic(
             Some(peer_id),
             TopicHash::from_raw("topic".to_string()),
@@ -454,5 +953,37 @@ mod tests {
         emit(ResolveEvent::ConnectedPeers(Default::default()));
         emit(ResolveFailureEvent::Failure(cid));
         emit(ResolveFailureEvent::Fallback(cid));
+        emit(PeerEvent::Response(peer_id, rtt));
+        emit(PeerEvent::BlockReceived(peer_id, Default::default()));
+        emit(PeerEvent::Unbanned(peer_id));
+        emit(PeerFailureEvent::InvalidBlock(peer_id));
+        emit(PeerFailureEvent::RequestFailure(peer_id));
+        emit(PeerFailureEvent::InvalidMessage(peer_id));
+        emit(PeerFailureEvent::Banned(peer_id, -100.0));
+        emit(GraphEvent::Received(peer_id, Default::default()));
+        emit(GraphEvent::Served(peer_id, Default::default()));
+        emit(GraphFailureEvent::Unsupported(peer_id));
+        emit(GraphFailureEvent::RateLimited(peer_id));
+        emit(NatEvent::Public(Multiaddr::empty()));
+        emit(NatEvent::Private);
+        emit(NatEvent::ReservationAccepted(peer_id));
+        emit(NatEvent::HolePunched(peer_id));
+        emit(NatFailureEvent::HolePunchFailed(peer_id, err_str.clone()));
+        emit(ProvideEvent::Started(cid, Default::default()));
+        emit(ProvideEvent::Stopped(cid));
+        emit(AddressBookEvent::Loaded(1, 2));
+        emit(AddressBookEvent::Saved(1, 2));
+        emit(AddressBookFailureEvent::Load(err_str.clone()));
+        emit(AddressBookFailureEvent::Save(err_str.clone()));
+        emit(BandwidthEvent::Served(peer_id, "other".to_owned(), 1024));
+        emit(BandwidthFailureEvent::Throttled(
+            peer_id,
+            "other".to_owned(),
+        ));
+        emit(ErasureEvent::Published(cid, 6));
+        emit(ErasureEvent::ChunkReceived(cid, 0));
+        emit(ErasureEvent::Reconstructed(cid, 10));
+        emit(ErasureFailureEvent::Publish(cid, err_str.clone()));
+        emit(ErasureFailureEvent::Reconstruct(cid, err_str.clone()));
     }
 }
@@ -0,0 +1,405 @@
+// Copyright 2022-2024 Protocol Labs
+// SPDX-License-Identifier: MIT
+//! Local reputation of peers, based on how they served our Bitswap requests
+//! and on the Gossipsub messages they forwarded to us.
+//!
+//! Scores start at zero, go up with useful responses and down with failures
+//! and invalid data, and decay towards zero over time, so that a peer can
+//! redeem itself after a ban, and a peer which was useful in the past cannot
+//! live off its reputation forever.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+use libp2p_bitswap::PeerResponse;
+
+/// Reward for a response, before it's discounted by the latency.
+const RESPONSE_REWARD: f64 = 1.0;
+/// Penalty for a request that timed out.
+///
+/// Peers which couldn't be dialed, or disconnected, aren't penalized: that happens to honest
+/// peers too, and they are already sorted behind the connected ones.
+const FAILURE_PENALTY: f64 = -2.0;
+/// Penalty for a block that didn't match its CID.
+const INVALID_BLOCK_PENALTY: f64 = -20.0;
+/// Penalty for forwarding a Gossipsub message that failed validation.
+const INVALID_MESSAGE_PENALTY: f64 = -10.0;
+/// Upper limit on the score, so a peer can't build up credit to spend on misbehaving.
+const MAX_SCORE: f64 = 100.0;
+/// Weight of the latest sample in the moving average of the latency.
+const LATENCY_ALPHA: f64 = 0.2;
+/// Scores closer to zero than this are as good as forgotten.
+const NEGLIGIBLE_SCORE: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct ScoringConfig {
+    /// Peers whose score falls below this (negative) value are disconnected and banned.
+    pub ban_threshold: f64,
+    /// How long a peer stays banned.
+    pub ban_duration: Duration,
+    /// Time it takes for a score to lose half of its value.
+    pub decay_half_life: Duration,
+}
+
+/// What we know about the behaviour of a peer.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub score: f64,
+    /// Number of responses to Bitswap requests, with or without a block.
+    pub responses: u64,
+    /// Number of Bitswap requests which failed.
+    pub failures: u64,
+    /// Number of blocks which didn't match their CID.
+    pub invalid_blocks: u64,
+    /// Number of Gossipsub messages which failed validation.
+    pub invalid_messages: u64,
+    /// Bytes of valid blocks received.
+    pub bytes_received: u64,
+    /// Moving average of the response time.
+    pub latency: Option<Duration>,
+    /// Last time the score was decayed.
+    updated_at: Instant,
+}
+
+impl PeerStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            score: 0.0,
+            responses: 0,
+            failures: 0,
+            invalid_blocks: 0,
+            invalid_messages: 0,
+            bytes_received: 0,
+            latency: None,
+            updated_at: now,
+        }
+    }
+
+    fn decayed_score(&self, half_life: Duration, now: Instant) -> f64 {
+        if half_life.is_zero() {
+            return self.score;
+        }
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let halvings = elapsed.as_secs_f64() / half_life.as_secs_f64();
+        self.score * 0.5f64.powf(halvings)
+    }
+
+    fn observe_latency(&mut self, elapsed: Duration) {
+        self.latency = Some(match self.latency {
+            None => elapsed,
+            Some(avg) => avg.mul_f64(1.0 - LATENCY_ALPHA) + elapsed.mul_f64(LATENCY_ALPHA),
+        });
+    }
+}
+
+/// Keeps score of peers and tracks which ones are banned.
+pub struct PeerScores {
+    config: ScoringConfig,
+    peers: HashMap<PeerId, PeerStats>,
+    /// Banned peers with the time their ban expires.
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl PeerScores {
+    pub fn new(config: ScoringConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+            banned: Default::default(),
+        }
+    }
+
+    /// Current score of a peer; unknown peers have 0.
+    pub fn score(&self, peer_id: &PeerId, now: Instant) -> f64 {
+        self.peers
+            .get(peer_id)
+            .map(|s| s.decayed_score(self.config.decay_half_life, now))
+            .unwrap_or_default()
+    }
+
+    pub fn stats(&self, peer_id: &PeerId) -> Option<&PeerStats> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned.contains_key(peer_id)
+    }
+
+    /// Peers currently banned.
+    pub fn banned(&self) -> impl Iterator<Item = &PeerId> {
+        self.banned.keys()
+    }
+
+    /// Update the score with the response of a peer to a Bitswap request.
+    ///
+    /// Returns `true` if the peer has to be banned as a result.
+    pub fn record_response(
+        &mut self,
+        peer_id: PeerId,
+        response: &PeerResponse,
+        now: Instant,
+    ) -> bool {
+        self.update(peer_id, now, |stats| match *response {
+            PeerResponse::Have { elapsed, .. } => {
+                stats.responses += 1;
+                stats.observe_latency(elapsed);
+                latency_reward(elapsed)
+            }
+            PeerResponse::Block {
+                bytes,
+                valid: true,
+                elapsed,
+            } => {
+                stats.responses += 1;
+                stats.bytes_received += bytes as u64;
+                stats.observe_latency(elapsed);
+                latency_reward(elapsed)
+            }
+            // Staying within a bandwidth budget is legitimate, it's neither rewarded nor penalized.
+            PeerResponse::Throttled { elapsed } => {
+                stats.responses += 1;
+                stats.observe_latency(elapsed);
+                0.0
+            }
+            PeerResponse::Block { valid: false, .. } => {
+                stats.invalid_blocks += 1;
+                INVALID_BLOCK_PENALTY
+            }
+            PeerResponse::Unreachable => {
+                stats.failures += 1;
+                0.0
+            }
+            PeerResponse::Failure => {
+                stats.failures += 1;
+                FAILURE_PENALTY
+            }
+        })
+    }
+
+    /// Penalize a peer for forwarding a message that failed validation.
+    ///
+    /// Returns `true` if the peer has to be banned as a result.
+    pub fn record_invalid_message(&mut self, peer_id: PeerId, now: Instant) -> bool {
+        self.update(peer_id, now, |stats| {
+            stats.invalid_messages += 1;
+            INVALID_MESSAGE_PENALTY
+        })
+    }
+
+    /// Ban a peer regardless of its score.
+    pub fn ban(&mut self, peer_id: PeerId, now: Instant) {
+        self.banned.insert(peer_id, now + self.config.ban_duration);
+    }
+
+    /// Remove the bans which have expired, returning the peers which can be let back in.
+    pub fn expire_bans(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+
+        for peer_id in expired.iter() {
+            self.banned.remove(peer_id);
+        }
+        expired
+    }
+
+    /// Forget the peers which aren't banned and whose score decayed to nothing, or which
+    /// disconnected without a negative score that would have to be remembered.
+    pub fn prune<F>(&mut self, now: Instant, is_connected: F)
+    where
+        F: Fn(&PeerId) -> bool,
+    {
+        let half_life = self.config.decay_half_life;
+        let banned = &self.banned;
+        self.peers.retain(|peer_id, stats| {
+            let score = stats.decayed_score(half_life, now);
+            banned.contains_key(peer_id)
+                || score <= -NEGLIGIBLE_SCORE
+                || (score >= NEGLIGIBLE_SCORE && is_connected(peer_id))
+        });
+    }
+
+    /// Sort peers by descending score, keeping the original order between equal scores.
+    pub fn sort_by_score(&self, peers: &mut [PeerId], now: Instant) {
+        peers.sort_by(|a, b| {
+            self.score(b, now)
+                .partial_cmp(&self.score(a, now))
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    fn update<F>(&mut self, peer_id: PeerId, now: Instant, f: F) -> bool
+    where
+        F: FnOnce(&mut PeerStats) -> f64,
+    {
+        let half_life = self.config.decay_half_life;
+        let stats = self
+            .peers
+            .entry(peer_id)
+            .or_insert_with(|| PeerStats::new(now));
+
+        let delta = f(stats);
+        stats.score = (stats.decayed_score(half_life, now) + delta).min(MAX_SCORE);
+        stats.updated_at = now;
+
+        if stats.score < self.config.ban_threshold && !self.is_banned(&peer_id) {
+            self.ban(peer_id, now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Reward fast responses more than slow ones.
+fn latency_reward(elapsed: Duration) -> f64 {
+    RESPONSE_REWARD / (1.0 + elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use libp2p::PeerId;
+    use libp2p_bitswap::PeerResponse;
+
+    use super::{PeerScores, ScoringConfig};
+
+    fn new_scores() -> PeerScores {
+        PeerScores::new(ScoringConfig {
+            ban_threshold: -25.0,
+            ban_duration: Duration::from_secs(60),
+            decay_half_life: Duration::from_secs(10),
+        })
+    }
+
+    fn block(valid: bool) -> PeerResponse {
+        PeerResponse::Block {
+            bytes: 100,
+            valid,
+            elapsed: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn sort_by_score() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let good = PeerId::random();
+        let bad = PeerId::random();
+        let unknown = PeerId::random();
+
+        assert!(!scores.record_response(good, &block(true), now));
+        assert!(!scores.record_response(bad, &PeerResponse::Failure, now));
+
+        let mut peers = vec![bad, unknown, good];
+        scores.sort_by_score(&mut peers, now);
+        assert_eq!(peers, vec![good, unknown, bad]);
+
+        let stats = scores.stats(&good).unwrap();
+        assert_eq!(stats.responses, 1);
+        assert_eq!(stats.bytes_received, 100);
+        assert_eq!(stats.latency, Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn throttling_is_neutral() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+        let throttled = PeerResponse::Throttled {
+            elapsed: Duration::from_millis(100),
+        };
+
+        for _ in 0..100 {
+            assert!(!scores.record_response(peer_id, &throttled, now));
+        }
+        assert_eq!(scores.score(&peer_id, now), 0.0);
+        assert_eq!(scores.stats(&peer_id).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn score_decays() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+
+        scores.record_response(peer_id, &block(false), now);
+        assert_eq!(scores.score(&peer_id, now), -20.0);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(scores.score(&peer_id, later), -10.0);
+    }
+
+    #[test]
+    fn ban_below_threshold() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+
+        assert!(!scores.record_response(peer_id, &block(false), now));
+        assert!(!scores.is_banned(&peer_id));
+        assert!(scores.record_invalid_message(peer_id, now));
+        assert!(scores.is_banned(&peer_id));
+
+        // Already banned, so it doesn't need to be banned again.
+        assert!(!scores.record_invalid_message(peer_id, now));
+
+        assert!(scores.expire_bans(now + Duration::from_secs(59)).is_empty());
+        assert_eq!(
+            scores.expire_bans(now + Duration::from_secs(60)),
+            vec![peer_id]
+        );
+        assert!(!scores.is_banned(&peer_id));
+    }
+
+    #[test]
+    fn unreachable_is_not_penalized() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let peer_id = PeerId::random();
+
+        for _ in 0..100 {
+            assert!(!scores.record_response(peer_id, &PeerResponse::Unreachable, now));
+        }
+        assert_eq!(scores.score(&peer_id, now), 0.0);
+        assert_eq!(scores.stats(&peer_id).unwrap().failures, 100);
+    }
+
+    #[test]
+    fn prune_forgets_irrelevant_peers() {
+        let mut scores = new_scores();
+        let now = Instant::now();
+        let good = PeerId::random();
+        let gone = PeerId::random();
+        let bad = PeerId::random();
+        let banned = PeerId::random();
+        let idle = PeerId::random();
+
+        scores.record_response(good, &block(true), now);
+        scores.record_response(gone, &block(true), now);
+        scores.record_response(bad, &block(false), now);
+        scores.record_response(idle, &PeerResponse::Unreachable, now);
+        scores.ban(banned, now);
+        scores.record_response(banned, &block(true), now);
+
+        scores.prune(now, |peer_id| *peer_id != gone);
+
+        assert!(scores.stats(&good).is_some());
+        assert!(scores.stats(&gone).is_none(), "disconnected");
+        assert!(scores.stats(&bad).is_some(), "negative score is kept");
+        assert!(scores.stats(&banned).is_some(), "banned");
+        assert!(scores.stats(&idle).is_none(), "no score");
+
+        // After enough half-lives every score is negligible.
+        scores.prune(now + Duration::from_secs(200), |_| true);
+
+        assert!(scores.stats(&good).is_none());
+        assert!(scores.stats(&bad).is_none());
+        assert!(scores.stats(&banned).is_some(), "still banned");
+    }
+}
@@ -80,7 +80,7 @@ impl SubnetProviderCache {
     }
 
     /// Number of routable peers.
-    pub fn num_routable(&mut self) -> usize {
+    pub fn num_routable(&self) -> usize {
         self.routable_peers.len()
     }
 
@@ -89,6 +89,16 @@ impl SubnetProviderCache {
         self.routable_peers.contains(peer_id)
     }
 
+    /// Subnets which are never pruned from the cache.
+    pub fn pinned_subnets(&self) -> impl Iterator<Item = &SubnetID> {
+        self.pinned_subnets.iter()
+    }
+
+    /// All subnets in the cache with their providers.
+    pub fn subnet_providers(&self) -> impl Iterator<Item = (&SubnetID, &HashSet<PeerId>)> {
+        self.subnet_providers.iter()
+    }
+
     /// Check whether we have received recent updates from a peer.
     pub fn has_timestamp(&self, peer_id: &PeerId) -> bool {
         self.peer_timestamps.contains_key(peer_id)
@@ -197,6 +207,13 @@ impl SubnetProviderCache {
         to_prune
     }
 
+    /// Check whether a peer is a known provider of a subnet.
+    pub fn is_provider(&self, subnet_id: &SubnetID, peer_id: &PeerId) -> bool {
+        self.subnet_providers
+            .get(subnet_id)
+            .map_or(false, |hs| hs.contains(peer_id))
+    }
+
     /// List any known providers of a subnet.
     pub fn providers_of_subnet(&self, subnet_id: &SubnetID) -> Vec<PeerId> {
         self.subnet_providers
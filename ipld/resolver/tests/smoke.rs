@@ -3,6 +3,9 @@
 //! Test that a cluster of IPLD resolver can be started in memory,
 //! that they bootstrap from  each other and are able to resolve CIDs.
 //!
+//! Some of the tests are repeated over the TCP and QUIC transports the service uses in production.
+//! NAT is simulated with an in-memory transport which refuses inbound connections.
+//!
 //! Run the tests as follows:
 //! ```ignore
 //! RUST_LOG=debug cargo test -p ipc_ipld_resolver --test smoke resolve
@@ -18,7 +21,11 @@
 // (although these might be orthogonal).
 
 use std::{
-    sync::atomic::{AtomicU64, Ordering},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
@@ -29,14 +36,17 @@ use fvm_ipld_hamt::Hamt;
 use fvm_shared::{address::Address, ActorID};
 use ipc_api::subnet_id::SubnetID;
 use ipc_ipld_resolver::{
-    Client, Config, ConnectionConfig, ContentConfig, DiscoveryConfig, Event, MembershipConfig,
-    NetworkConfig, Resolver, Service, VoteRecord,
+    AddressBookConfig, Client, Config, ConnectionConfig, ContentConfig, ContentTooLarge,
+    DiscoveryConfig, ErasureConfig, Event, MembershipConfig, NatConfig, NetworkConfig,
+    ResolveProgress, Resolver, ScoringConfig, Service, VoteRecord,
 };
 use libp2p::{
     core::{
         muxing::StreamMuxerBox,
         transport::{Boxed, MemoryTransport},
+        ConnectedPoint,
     },
+    futures::future,
     identity::Keypair,
     multiaddr::Protocol,
     plaintext, yamux, Multiaddr, PeerId, Transport,
@@ -59,6 +69,8 @@ struct Agent {
     client: Client<TestVote>,
     events: broadcast::Receiver<Event<TestVote>>,
     store: TestBlockstore,
+    /// The addresses the service is actually listening on.
+    listen_addrs: Vec<Multiaddr>,
 }
 
 struct Cluster {
@@ -79,64 +91,173 @@ impl Cluster {
     }
 }
 
+/// The transport the nodes in a test cluster communicate over.
+#[derive(Clone, Copy, Debug)]
+enum TestTransport {
+    /// In-memory transport with plaintext authentication.
+    Memory,
+    /// In-memory transport which refuses inbound connections, like a peer behind NAT.
+    MemoryBehindNat,
+    /// The default transport of the service, listening on TCP.
+    Tcp,
+    /// The default transport of the service, listening on QUIC.
+    Quic,
+    /// The default transport of the service, listening on TCP and QUIC at the same time.
+    TcpAndQuic,
+}
+
+impl TestTransport {
+    /// Pick an address to listen on which no other test is using.
+    ///
+    /// TCP and QUIC listen on port 0, so the OS assigns a free port,
+    /// which can be looked up once the service is running.
+    fn listen_addr(&self, rng: &mut StdRng) -> Multiaddr {
+        match self {
+            TestTransport::Memory | TestTransport::MemoryBehindNat => {
+                Multiaddr::from(Protocol::Memory(rng.gen::<u64>()))
+            }
+            TestTransport::Tcp | TestTransport::TcpAndQuic => {
+                "/ip4/127.0.0.1/tcp/0".parse().unwrap()
+            }
+            TestTransport::Quic => quic_listen_addr(),
+        }
+    }
+
+    /// The extra address to listen on for QUIC, if any.
+    fn quic_listen_addr(&self) -> Option<Multiaddr> {
+        match self {
+            TestTransport::TcpAndQuic => Some(quic_listen_addr()),
+            _ => None,
+        }
+    }
+
+    /// Whether the OS picks the port, so the address has to be looked up after the service started.
+    fn is_ephemeral(&self) -> bool {
+        !matches!(self, TestTransport::Memory | TestTransport::MemoryBehindNat)
+    }
+
+    fn build(&self, local_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+        match self {
+            TestTransport::Memory => build_transport(local_key),
+            TestTransport::MemoryBehindNat => build_transport_behind_nat(local_key),
+            TestTransport::Tcp | TestTransport::Quic | TestTransport::TcpAndQuic => {
+                ipc_ipld_resolver::build_transport(local_key)
+            }
+        }
+    }
+}
+
+fn quic_listen_addr() -> Multiaddr {
+    "/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()
+}
+
 struct ClusterBuilder {
     size: u32,
+    transport: TestTransport,
     rng: StdRng,
-    services: Vec<Service<TestStoreParams, TestVote>>,
     agents: Vec<Agent>,
 }
 
 impl ClusterBuilder {
-    fn new(size: u32) -> Self {
+    fn new(size: u32, transport: TestTransport) -> Self {
         // Each port has to be unique, so each test must use a different seed.
         // This is shared between all instances.
         static COUNTER: AtomicU64 = AtomicU64::new(0);
         let seed = COUNTER.fetch_add(1, Ordering::Relaxed);
-        Self::new_with_seed(size, seed)
+        Self::new_with_seed(size, transport, seed)
     }
 
-    fn new_with_seed(size: u32, seed: u64) -> Self {
+    fn new_with_seed(size: u32, transport: TestTransport, seed: u64) -> Self {
         Self {
             size,
+            transport,
             rng: rand::rngs::StdRng::seed_from_u64(seed),
-            services: Default::default(),
             agents: Default::default(),
         }
     }
 
     /// Add a node with randomized address, optionally bootstrapping from an existing node.
-    fn add_node(&mut self, bootstrap: Option<usize>) {
-        let bootstrap_addr = bootstrap.map(|i| {
-            let config = &self.agents[i].config;
-            let peer_id = config.network.local_peer_id();
-            let mut addr = config.connection.listen_addr.clone();
-            addr.push(Protocol::P2p(peer_id));
-            addr
-        });
-        let config = make_config(&mut self.rng, self.size, bootstrap_addr);
-        let (service, store) = make_service(config.clone());
+    async fn add_node(&mut self, bootstrap: Option<usize>) {
+        self.add_node_with(bootstrap, self.transport, |_| {}).await
+    }
+
+    /// Add a node with a specific transport, adjusting the default configuration.
+    ///
+    /// The service is started right away, so that later nodes can bootstrap from its address.
+    async fn add_node_with<F>(
+        &mut self,
+        bootstrap: Option<usize>,
+        transport: TestTransport,
+        configure: F,
+    ) where
+        F: FnOnce(&mut Config),
+    {
+        let bootstrap_addr = bootstrap.map(|i| self.node_addr(i));
+        let listen_addr = transport.listen_addr(&mut self.rng);
+        let mut config = make_config(listen_addr, self.size, bootstrap_addr);
+        config.connection.quic_listen_addr = transport.quic_listen_addr();
+        configure(&mut config);
+        let (service, store) = make_service(config.clone(), transport);
         let client = service.client();
         let events = service.subscribe();
-        self.services.push(service);
+        tokio::task::spawn(async move { service.run().await.expect("error running service") });
+
+        let listen_addrs = if transport.is_ephemeral() {
+            let count = 1 + config.connection.quic_listen_addr.iter().count();
+            await_listen_addrs(&client, count).await
+        } else {
+            vec![config.connection.listen_addr.clone()]
+        };
+
         self.agents.push(Agent {
             config,
             client,
             events,
             store,
+            listen_addrs,
         });
     }
 
-    /// Start running all services
+    /// The address of a node, including its peer ID.
+    fn node_addr(&self, i: usize) -> Multiaddr {
+        self.node_addrs(i).remove(0)
+    }
+
+    /// All the addresses a node listens on, including its peer ID.
+    fn node_addrs(&self, i: usize) -> Vec<Multiaddr> {
+        let agent = &self.agents[i];
+        let peer_id = agent.config.network.local_peer_id();
+        agent
+            .listen_addrs
+            .iter()
+            .map(|addr| addr.clone().with(Protocol::P2p(peer_id)))
+            .collect()
+    }
+
+    /// Finish building the cluster; the services are already running.
     fn run(self) -> Cluster {
-        for service in self.services {
-            tokio::task::spawn(async move { service.run().await.expect("error running service") });
-        }
         Cluster {
             agents: self.agents,
         }
     }
 }
 
+/// Wait until the service is listening on the expected number of addresses,
+/// to find out which ports the OS assigned to it.
+async fn await_listen_addrs(client: &Client<TestVote>, count: usize) -> Vec<Multiaddr> {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let info = client.inspect().await.expect("failed to inspect service");
+            if info.listen_addresses.len() >= count {
+                return info.listen_addresses;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timeout waiting for the service to listen")
+}
+
 /// Run the tests with `RUST_LOG=debug` to see the logs, for example:
 ///
 /// ```text
@@ -155,6 +276,20 @@ fn init_log() {
 /// make available some content on one agent and resolve it from another.
 #[tokio::test]
 async fn single_bootstrap_single_provider_resolve_one() {
+    single_provider_resolve_one(TestTransport::Memory).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_single_provider_resolve_one_tcp() {
+    single_provider_resolve_one(TestTransport::Tcp).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_single_provider_resolve_one_quic() {
+    single_provider_resolve_one(TestTransport::Quic).await
+}
+
+async fn single_provider_resolve_one(transport: TestTransport) {
     init_log();
 
     // Choose agents.
@@ -163,8 +298,97 @@ async fn single_bootstrap_single_provider_resolve_one() {
     let provider_idx = 1;
     let resolver_idx = 2;
 
-    let mut cluster = make_cluster_with_bootstrap(cluster_size, bootstrap_idx).await;
+    let mut cluster = make_cluster_with_bootstrap(cluster_size, bootstrap_idx, transport).await;
+
+    resolve_one(&mut cluster, provider_idx, resolver_idx).await;
+}
+
+/// Start a bootstrap node listening on TCP and QUIC at the same time,
+/// with the other agents connecting to it over one or the other.
+#[tokio::test]
+async fn single_bootstrap_tcp_and_quic_resolve_one() {
+    init_log();
 
+    let bootstrap_idx = 0;
+    let provider_idx = 1;
+    let resolver_idx = 2;
+
+    let mut builder = ClusterBuilder::new(3, TestTransport::TcpAndQuic);
+    builder.add_node(None).await;
+
+    let bootstrap_addrs = builder.node_addrs(bootstrap_idx);
+    let is_quic = |addr: &Multiaddr| addr.iter().any(|p| matches!(p, Protocol::QuicV1));
+    let (quic_addrs, tcp_addrs): (Vec<_>, Vec<_>) = bootstrap_addrs.into_iter().partition(is_quic);
+    assert_eq!(quic_addrs.len(), 1, "should listen on QUIC");
+    assert_eq!(tcp_addrs.len(), 1, "should listen on TCP");
+
+    for addrs in [quic_addrs, tcp_addrs] {
+        builder
+            .add_node_with(None, TestTransport::TcpAndQuic, |c| {
+                c.discovery.static_addresses = addrs;
+            })
+            .await;
+    }
+
+    let mut cluster = builder.run();
+    cluster.await_connect().await;
+
+    for idx in [provider_idx, resolver_idx] {
+        let info = cluster.agents[idx].client.inspect().await.unwrap();
+        assert!(
+            info.peers
+                .iter()
+                .any(|p| p.peer_id == cluster.agents[bootstrap_idx].config.network.local_peer_id()),
+            "agent {idx} should be connected to the bootstrap node"
+        );
+    }
+
+    resolve_one(&mut cluster, provider_idx, resolver_idx).await;
+}
+
+/// Start a relay, a provider behind NAT which can only be reached through the relay,
+/// and resolve content from the provider on a third agent.
+#[tokio::test]
+async fn single_relay_provider_behind_nat_resolve_one() {
+    init_log();
+
+    let relay_idx = 0;
+    let provider_idx = 1;
+    let resolver_idx = 2;
+
+    let mut builder = ClusterBuilder::new(3, TestTransport::Memory);
+
+    builder
+        .add_node_with(None, TestTransport::Memory, |c| {
+            c.nat.enable_relay_server = true;
+        })
+        .await;
+
+    let relay_addr = builder.node_addr(relay_idx);
+
+    builder
+        .add_node_with(Some(relay_idx), TestTransport::MemoryBehindNat, |c| {
+            c.nat.enable_relay_client = true;
+            c.nat.enable_hole_punching = true;
+            c.nat.relay_addresses = vec![relay_addr];
+        })
+        .await;
+
+    builder
+        .add_node_with(Some(relay_idx), TestTransport::Memory, |c| {
+            c.nat.enable_relay_client = true;
+            c.nat.enable_hole_punching = true;
+        })
+        .await;
+
+    let mut cluster = builder.run();
+    cluster.await_connect().await;
+
+    resolve_one(&mut cluster, provider_idx, resolver_idx).await;
+}
+
+/// Make available some content on the provider and resolve it from the resolver.
+async fn resolve_one(cluster: &mut Cluster, provider_idx: usize, resolver_idx: usize) {
     // Insert a CID of a complex recursive data structure.
     let cid = insert_test_data(&mut cluster.agents[provider_idx]).expect("failed to insert data");
 
@@ -199,12 +423,125 @@ async fn single_bootstrap_single_provider_resolve_one() {
     check_test_data(&mut cluster.agents[resolver_idx], &cid).expect("failed to resolve from store");
 }
 
+/// Start a cluster from a single bootstrap node, provide some content on one agent
+/// without any subnets involved, and resolve it from another through the provider records.
+#[tokio::test]
+async fn single_bootstrap_provide_resolve_content() {
+    init_log();
+
+    let cluster_size = 3;
+    let bootstrap_idx = 0;
+    let provider_idx = 1;
+    let resolver_idx = 2;
+
+    let mut cluster =
+        make_cluster_with_bootstrap(cluster_size, bootstrap_idx, TestTransport::Memory).await;
+
+    let cid = insert_test_data(&mut cluster.agents[provider_idx]).expect("failed to insert data");
+
+    // Content which isn't in the store cannot be provided.
+    let missing = Cid::new_v1(IPLD_RAW, Code::Sha2_256.digest(b"missing"));
+    cluster.agents[provider_idx]
+        .client
+        .provide_content(missing, Duration::from_secs(60))
+        .await
+        .expect_err("should not provide missing content");
+
+    cluster.agents[provider_idx]
+        .client
+        .provide_content(cid, Duration::from_secs(60))
+        .await
+        .expect("failed to provide content");
+
+    // Wait a little for the provider record to reach the other peers.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let progress_rec = progress.clone();
+
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        cluster.agents[resolver_idx].client.resolve_content(
+            cid,
+            Some(Box::new(move |p: ResolveProgress| {
+                progress_rec.lock().unwrap().push(p)
+            })),
+        ),
+    )
+    .await
+    .expect("timeout resolving content")
+    .expect("failed to send request")
+    .expect("failed to resolve content");
+
+    check_test_data(&mut cluster.agents[resolver_idx], &cid).expect("failed to resolve from store");
+
+    let progress = progress.lock().unwrap();
+    assert!(
+        matches!(progress.first(), Some(ResolveProgress::Providers(n)) if *n > 0),
+        "should report the providers first: {progress:?}"
+    );
+    assert!(progress.len() > 1, "should report received blocks");
+}
+
+/// Resolving content from its providers stops once it's larger than the limit of the resolver.
+#[tokio::test]
+async fn single_bootstrap_resolve_content_too_large() {
+    init_log();
+
+    let mut builder = ClusterBuilder::new(3, TestTransport::Memory);
+    builder.add_node(None).await;
+    builder.add_node(Some(0)).await;
+    builder
+        .add_node_with(Some(0), TestTransport::Memory, |config| {
+            config.content.max_resolved_bytes = 16;
+        })
+        .await;
+    let mut cluster = builder.run();
+    cluster.await_connect().await;
+
+    let cid = insert_test_data(&mut cluster.agents[1]).expect("failed to insert data");
+
+    cluster.agents[1]
+        .client
+        .provide_content(cid, Duration::from_secs(60))
+        .await
+        .expect("failed to provide content");
+
+    // Wait a little for the provider record to reach the other peers.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let err = tokio::time::timeout(
+        Duration::from_secs(5),
+        cluster.agents[2].client.resolve_content(cid, None),
+    )
+    .await
+    .expect("timeout resolving content")
+    .expect("failed to send request")
+    .expect_err("should not resolve content over the limit");
+
+    assert!(err.is::<ContentTooLarge>(), "unexpected error: {err:#}");
+}
+
 /// Start two agents, subscribe to the same subnet, publish and receive a vote.
 #[tokio::test]
 async fn single_bootstrap_publish_receive_vote() {
+    publish_receive_vote(TestTransport::Memory).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_publish_receive_vote_tcp() {
+    publish_receive_vote(TestTransport::Tcp).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_publish_receive_vote_quic() {
+    publish_receive_vote(TestTransport::Quic).await
+}
+
+async fn publish_receive_vote(transport: TestTransport) {
     init_log();
 
-    let mut cluster = make_cluster_with_bootstrap(2, 0).await;
+    let mut cluster = make_cluster_with_bootstrap(2, 0, transport).await;
 
     // Announce the support of some subnet.
     let subnet_id = make_subnet_id(1001);
@@ -239,7 +576,7 @@ async fn single_bootstrap_publish_receive_vote() {
         .expect("error receiving vote");
 
     if let Event::ReceivedVote(v) = event {
-        assert_eq!(&*v, vote.record());
+        assert_eq!(v.record(), vote.record());
     } else {
         panic!("unexpected {event:?}")
     }
@@ -248,9 +585,23 @@ async fn single_bootstrap_publish_receive_vote() {
 /// Start two agents, pin a subnet, publish preemptively and receive.
 #[tokio::test]
 async fn single_bootstrap_publish_receive_preemptive() {
+    publish_receive_preemptive(TestTransport::Memory).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_publish_receive_preemptive_tcp() {
+    publish_receive_preemptive(TestTransport::Tcp).await
+}
+
+#[tokio::test]
+async fn single_bootstrap_publish_receive_preemptive_quic() {
+    publish_receive_preemptive(TestTransport::Quic).await
+}
+
+async fn publish_receive_preemptive(transport: TestTransport) {
     init_log();
 
-    let mut cluster = make_cluster_with_bootstrap(2, 0).await;
+    let mut cluster = make_cluster_with_bootstrap(2, 0, transport).await;
 
     // Pin a subnet on the bootstrap node.
     let subnet_id = make_subnet_id(1001);
@@ -284,22 +635,108 @@ async fn single_bootstrap_publish_receive_preemptive() {
     }
 }
 
+/// Start two agents, one providing data for a subnet and the other pinning it,
+/// publish the erasure coded chunks of some content from the first and reconstruct it on the second.
+#[tokio::test]
+async fn single_bootstrap_publish_reconstruct_erasure_coded() {
+    init_log();
+
+    let mut cluster = make_cluster_with_bootstrap(2, 0, TestTransport::Memory).await;
+
+    let subnet_id = make_subnet_id(1001);
+
+    cluster.agents[0]
+        .client
+        .pin_subnet(subnet_id.clone())
+        .expect("failed to pin subnet");
+
+    cluster.agents[1]
+        .client
+        .add_provided_subnet(subnet_id.clone())
+        .expect("failed to add provided subnet");
+
+    let cid = insert_test_data(&mut cluster.agents[1]).expect("failed to insert data");
+
+    // TODO: Wait on some condition instead of sleep.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let chunks = cluster.agents[1]
+        .client
+        .publish_erasure_coded(subnet_id.clone(), cid)
+        .await
+        .expect("failed to publish chunks");
+
+    assert_eq!(chunks, 6);
+
+    // The content arrives into the store of the other agent without asking for it.
+    timeout(Duration::from_secs(3), async {
+        while check_test_data(&mut cluster.agents[0], &cid).is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("timeout reconstructing content");
+}
+
+#[tokio::test]
+async fn single_bootstrap_inspect() {
+    init_log();
+
+    let cluster = make_cluster_with_bootstrap(2, 0, TestTransport::Memory).await;
+
+    let subnet_id = make_subnet_id(1001);
+    cluster.agents[0]
+        .client
+        .pin_subnet(subnet_id.clone())
+        .expect("failed to pin subnet");
+
+    let info = cluster.agents[0]
+        .client
+        .inspect()
+        .await
+        .expect("failed to inspect");
+
+    let peer_id = cluster.agents[1].config.network.local_peer_id();
+
+    assert_eq!(
+        info.peer_id,
+        cluster.agents[0].config.network.local_peer_id()
+    );
+    assert!(!info.listen_addresses.is_empty());
+    assert!(
+        info.peers.iter().any(|p| p.peer_id == peer_id),
+        "connected to the other agent"
+    );
+    assert!(info
+        .subnets
+        .iter()
+        .any(|s| s.subnet_id == subnet_id.to_string() && s.pinned));
+    assert!(info.queries.is_empty());
+}
+
 #[tokio::test]
 async fn can_register_metrics() {
     let mut rng = rand::rngs::StdRng::seed_from_u64(0);
-    let config = make_config(&mut rng, 1, None);
-    let (mut service, _) = make_service(config);
+    let listen_addr = TestTransport::Memory.listen_addr(&mut rng);
+    let config = make_config(listen_addr, 1, None);
+    let (mut service, _) = make_service(config, TestTransport::Memory);
     let registry = prometheus::Registry::new();
     service.register_metrics(&registry).unwrap();
 }
 
-async fn make_cluster_with_bootstrap(cluster_size: u32, bootstrap_idx: usize) -> Cluster {
+async fn make_cluster_with_bootstrap(
+    cluster_size: u32,
+    bootstrap_idx: usize,
+    transport: TestTransport,
+) -> Cluster {
     // TODO: Get the seed from QuickCheck
-    let mut builder = ClusterBuilder::new(cluster_size);
+    let mut builder = ClusterBuilder::new(cluster_size, transport);
 
     // Build a cluster of nodes.
     for i in 0..builder.size {
-        builder.add_node(if i == 0 { None } else { Some(bootstrap_idx) });
+        builder
+            .add_node(if i == 0 { None } else { Some(bootstrap_idx) })
+            .await;
     }
 
     // Start the swarms.
@@ -308,16 +745,25 @@ async fn make_cluster_with_bootstrap(cluster_size: u32, bootstrap_idx: usize) ->
     cluster
 }
 
-fn make_service(config: Config) -> (Service<TestStoreParams, TestVote>, TestBlockstore) {
+fn make_service(
+    config: Config,
+    transport: TestTransport,
+) -> (Service<TestStoreParams, TestVote>, TestBlockstore) {
     let store = TestBlockstore::default();
-    let svc = Service::new_with_transport(config, store.clone(), build_transport).unwrap();
+    let svc =
+        Service::new_with_transport(config, store.clone(), |key| transport.build(key)).unwrap();
     (svc, store)
 }
 
-fn make_config(rng: &mut StdRng, cluster_size: u32, bootstrap_addr: Option<Multiaddr>) -> Config {
+fn make_config(
+    listen_addr: Multiaddr,
+    cluster_size: u32,
+    bootstrap_addr: Option<Multiaddr>,
+) -> Config {
     let config = Config {
         connection: ConnectionConfig {
-            listen_addr: Multiaddr::from(Protocol::Memory(rng.gen::<u64>())),
+            listen_addr,
+            quic_listen_addr: None,
             external_addresses: vec![],
             expected_peer_count: cluster_size,
             max_incoming: cluster_size,
@@ -343,6 +789,41 @@ fn make_config(rng: &mut StdRng, cluster_size: u32, bootstrap_addr: Option<Multi
         content: ContentConfig {
             rate_limit_bytes: 1 << 20,
             rate_limit_period: Duration::from_secs(60),
+            graph_max_response_bytes: 1 << 20,
+            max_provided_bytes: 1 << 20,
+            max_provided_cids: 10,
+            max_provide_ttl: Duration::from_secs(60 * 60),
+            max_resolved_bytes: 1 << 20,
+            egress_bytes: 0,
+            egress_period: Duration::from_secs(60),
+            subnet_egress_bytes: Default::default(),
+            priority_subnets: vec![],
+        },
+        scoring: ScoringConfig {
+            ban_threshold: -50.0,
+            ban_duration: Duration::from_secs(60),
+            decay_half_life: Duration::from_secs(60),
+        },
+        nat: NatConfig {
+            enable_autonat: false,
+            enable_relay_server: false,
+            enable_relay_client: false,
+            relay_addresses: vec![],
+            enable_hole_punching: false,
+            relay_max_circuit_duration: Duration::from_secs(60),
+            relay_max_circuit_bytes: 1 << 20,
+        },
+        address_book: AddressBookConfig {
+            path: None,
+            max_age: Duration::from_secs(60 * 60),
+            save_interval: Duration::from_secs(60),
+        },
+        erasure: ErasureConfig {
+            enabled: true,
+            data_chunks: 4,
+            parity_chunks: 2,
+            max_bytes: 1 << 20,
+            chunk_ttl: Duration::from_secs(60),
         },
     };
 
@@ -371,6 +852,23 @@ fn build_transport(local_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
         .boxed()
 }
 
+/// Builds an in-memory transport which only allows outbound connections,
+/// so the peer can only be reached through a relay.
+fn build_transport_behind_nat(local_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+    build_transport(local_key)
+        .and_then(|output, endpoint: ConnectedPoint| {
+            future::ready(if endpoint.is_listener() {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "inbound connections are blocked by NAT",
+                ))
+            } else {
+                Ok(output)
+            })
+        })
+        .boxed()
+}
+
 /// Make a subnet under a rootnet.
 fn make_subnet_id(actor_id: ActorID) -> SubnetID {
     let act = Address::new_id(actor_id);